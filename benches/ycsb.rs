@@ -0,0 +1,288 @@
+use std::{path::Path, pin::pin, sync::Arc};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use elsm::{
+    oracle::LocalOracle,
+    raw::{Entry, Key},
+    wal::provider::{fs::Fs, in_mem::InMemProvider, StorageProvider},
+    Db, DbOption, ReadOptions,
+};
+use executor::{futures::StreamExt, ExecutorBuilder};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tempfile::TempDir;
+
+const RECORD_COUNT: u64 = 10_000;
+const OPERATION_COUNT: usize = 1_000;
+const VALUE_SIZE: usize = 100;
+const MAX_SCAN_LENGTH: usize = 100;
+
+#[derive(Debug, Clone, Copy)]
+struct Workload {
+    name: &'static str,
+    read: f64,
+    update: f64,
+    insert: f64,
+    scan: f64,
+    read_modify_write: f64,
+}
+
+/// The core YCSB workloads, D being left out since its latest distribution needs a shared insert
+/// counter across runs.
+const WORKLOADS: [Workload; 5] = [
+    Workload {
+        name: "a",
+        read: 0.5,
+        update: 0.5,
+        insert: 0.0,
+        scan: 0.0,
+        read_modify_write: 0.0,
+    },
+    Workload {
+        name: "b",
+        read: 0.95,
+        update: 0.05,
+        insert: 0.0,
+        scan: 0.0,
+        read_modify_write: 0.0,
+    },
+    Workload {
+        name: "c",
+        read: 1.0,
+        update: 0.0,
+        insert: 0.0,
+        scan: 0.0,
+        read_modify_write: 0.0,
+    },
+    Workload {
+        name: "e",
+        read: 0.0,
+        update: 0.0,
+        insert: 0.05,
+        scan: 0.95,
+        read_modify_write: 0.0,
+    },
+    Workload {
+        name: "f",
+        read: 0.5,
+        update: 0.0,
+        insert: 0.0,
+        scan: 0.0,
+        read_modify_write: 0.5,
+    },
+];
+
+#[derive(Debug, Clone, Copy)]
+enum Operation {
+    Read(u64),
+    Update(u64),
+    Insert(u64),
+    Scan(u64, usize),
+    ReadModifyWrite(u64),
+}
+
+struct Zipfian {
+    items: u64,
+    theta: f64,
+    zeta_n: f64,
+    alpha: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(items: u64) -> Self {
+        let theta = 0.99;
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zeta_n = zeta(items);
+
+        Zipfian {
+            items,
+            theta,
+            zeta_n,
+            alpha: 1.0 / (1.0 - theta),
+            eta: (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta(2) / zeta_n),
+        }
+    }
+
+    /// Returns a rank scrambled over the key space, so that hot keys do not cluster.
+    fn sample(&self, rng: &mut StdRng) -> u64 {
+        let u = rng.gen::<f64>();
+        let uz = u * self.zeta_n;
+        let rank = if uz < 1.0 {
+            0
+        } else if uz < 1.0 + 0.5f64.powf(self.theta) {
+            1
+        } else {
+            (self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64
+        };
+
+        rank.wrapping_mul(0x9E37_79B9_7F4A_7C15) % self.items
+    }
+}
+
+struct Generator {
+    workload: Workload,
+    rng: StdRng,
+    zipfian: Zipfian,
+    next_insert: u64,
+}
+
+impl Generator {
+    fn new(workload: Workload) -> Self {
+        let total = workload.read
+            + workload.update
+            + workload.insert
+            + workload.scan
+            + workload.read_modify_write;
+        assert!(
+            (total - 1.0).abs() < 1e-9,
+            "workload {} proportions",
+            workload.name
+        );
+
+        Generator {
+            workload,
+            rng: StdRng::seed_from_u64(0),
+            zipfian: Zipfian::new(RECORD_COUNT),
+            next_insert: RECORD_COUNT,
+        }
+    }
+
+    fn next(&mut self) -> Operation {
+        let Workload {
+            read,
+            update,
+            insert,
+            scan,
+            ..
+        } = self.workload;
+        let choice = self.rng.gen::<f64>();
+        let id = self.zipfian.sample(&mut self.rng);
+
+        if choice < read {
+            Operation::Read(id)
+        } else if choice < read + update {
+            Operation::Update(id)
+        } else if choice < read + update + insert {
+            self.next_insert += 1;
+            Operation::Insert(self.next_insert)
+        } else if choice < read + update + insert + scan {
+            Operation::Scan(id, self.rng.gen_range(1..=MAX_SCAN_LENGTH))
+        } else {
+            Operation::ReadModifyWrite(id)
+        }
+    }
+}
+
+fn key(id: u64) -> Key {
+    Key(format!("user{:010}", id).into_bytes().into())
+}
+
+fn entry(id: u64) -> Entry {
+    Entry {
+        key: key(id),
+        value: vec![(id % 256) as u8; VALUE_SIZE].into(),
+    }
+}
+
+type BenchDb<WP> = Db<Entry, LocalOracle<Key>, WP>;
+
+async fn load<WP>(wal_provider: WP, path: &Path) -> Arc<BenchDb<WP>>
+where
+    WP: StorageProvider,
+    WP::File: futures::AsyncWrite + futures::AsyncRead,
+{
+    let db = Db::new(LocalOracle::default(), wal_provider, DbOption::new(path))
+        .await
+        .unwrap();
+
+    for id in 0..RECORD_COUNT {
+        db.put(entry(id)).await.unwrap();
+    }
+    Arc::new(db)
+}
+
+async fn execute(db: &Arc<BenchDb<InMemProvider>>, operation: Operation) {
+    match operation {
+        Operation::Read(id) => {
+            let _ = db.get_with_options(&key(id), &ReadOptions::default()).await;
+        }
+        Operation::Update(id) | Operation::Insert(id) => {
+            db.put(entry(id)).await.unwrap();
+        }
+        Operation::Scan(id, len) => {
+            let txn = db.new_txn();
+            let lower = key(id);
+            let mut stream = pin!(txn.range(&lower..).await.unwrap());
+
+            for _ in 0..len {
+                if stream.next().await.is_none() {
+                    break;
+                }
+            }
+        }
+        Operation::ReadModifyWrite(id) => {
+            let mut txn = db.new_txn();
+            let key = key(id);
+
+            if let Some(mut entry) = txn.get(&key).await {
+                let mut value = entry.value.to_vec();
+                value[0] = value[0].wrapping_add(1);
+                entry.value = value.into();
+                txn.set(key, entry);
+            }
+            // conflicting commits are part of the workload
+            let _ = txn.commit().await;
+        }
+    }
+}
+
+fn ycsb(c: &mut Criterion) {
+    let executor = ExecutorBuilder::new().build().unwrap();
+    let mut group = c.benchmark_group("ycsb");
+    group.throughput(Throughput::Elements(OPERATION_COUNT as u64));
+
+    for workload in WORKLOADS {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor.block_on(load(InMemProvider::default(), temp_dir.path()));
+        let mut generator = Generator::new(workload);
+
+        group.bench_function(BenchmarkId::from_parameter(workload.name), |b| {
+            b.iter(|| {
+                executor.block_on(async {
+                    for _ in 0..OPERATION_COUNT {
+                        execute(&db, generator.next()).await;
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+fn recovery(c: &mut Criterion) {
+    let executor = ExecutorBuilder::new().build().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path();
+
+    drop(executor.block_on(load(Fs::new(path).unwrap(), path)));
+
+    let mut group = c.benchmark_group("recovery");
+    group
+        .sample_size(10)
+        .throughput(Throughput::Elements(RECORD_COUNT));
+    group.bench_function("wal", |b| {
+        b.iter(|| {
+            executor
+                .block_on(BenchDb::new(
+                    LocalOracle::default(),
+                    Fs::new(path).unwrap(),
+                    DbOption::new(path),
+                ))
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, ycsb, recovery);
+criterion_main!(benches);
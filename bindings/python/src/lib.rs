@@ -0,0 +1,333 @@
+use std::{fmt::Display, ops::Bound, path::PathBuf, sync::Arc, vec};
+
+use arrow::{
+    array::{ArrayRef, LargeBinaryArray, RecordBatch},
+    pyarrow::ToPyArrow,
+};
+use elsm::{
+    blocking,
+    oracle::LocalOracle,
+    raw::{Entry, Key},
+    schema::Schema,
+    stream::ScanError,
+    wal::provider::fs::Fs,
+    DbOption,
+};
+use pyo3::{
+    exceptions::{PyIOError, PyValueError},
+    prelude::*,
+    types::PyBytes,
+};
+
+type RawDb = blocking::Db<Entry, LocalOracle<Key>, Fs>;
+type RawTransaction = blocking::Transaction<Entry, LocalOracle<Key>, Fs>;
+type Rows = Vec<(Key, Option<Entry>)>;
+type Pages = Box<dyn Iterator<Item = Result<Vec<Entry>, ScanError<Key, Entry>>>>;
+
+/// Rows read at a time by the scans of a db, and yielded at a time by `scan_arrow`.
+const SCAN_BATCH_ROWS: usize = 1024;
+
+fn io_error(err: impl Display) -> PyErr {
+    PyIOError::new_err(err.to_string())
+}
+
+/// Lets a blocking call into the db run with the GIL released. `allow_threads` runs it on the
+/// calling thread, and only asks for `Send` to keep Python objects out of it, which the handles
+/// of the db are not.
+struct Unbound<T>(T);
+
+unsafe impl<T> Send for Unbound<T> {}
+
+impl<T> Unbound<T> {
+    fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+fn without_gil<R>(py: Python<'_>, f: impl FnOnce() -> R) -> R {
+    let f = Unbound(f);
+    py.allow_threads(move || Unbound(f.into_inner()()))
+        .into_inner()
+}
+
+fn next_page(py: Python<'_>, pages: &mut Pages) -> PyResult<Option<Vec<Entry>>> {
+    without_gil(py, || pages.next())
+        .transpose()
+        .map_err(io_error)
+}
+
+/// Pages of the rows a transaction scanned, which are read at once since they include the writes
+/// buffered in it.
+fn chunked(rows: Rows, batch_rows: usize) -> Pages {
+    let entries = rows
+        .into_iter()
+        .filter_map(|(_, entry)| entry)
+        .collect::<Vec<_>>();
+    let pages = entries
+        .chunks(batch_rows.max(1))
+        .map(|page| Ok(page.to_vec()))
+        .collect::<Vec<_>>();
+
+    Box::new(pages.into_iter())
+}
+
+fn bound(key: Option<Vec<u8>>) -> Bound<Key> {
+    key.map_or(Bound::Unbounded, |key| Bound::Included(Key(key.into())))
+}
+
+fn to_bytes(py: Python<'_>, bytes: &[u8]) -> Py<PyBytes> {
+    PyBytes::new(py, bytes).into()
+}
+
+fn to_record_batch(py: Python<'_>, entries: Vec<Entry>) -> PyResult<PyObject> {
+    let (keys, values): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .map(|entry| (entry.key.0, entry.value))
+        .unzip();
+    let batch = RecordBatch::try_new(
+        Entry::arrow_schema(),
+        vec![
+            Arc::new(LargeBinaryArray::from_iter_values(keys)) as ArrayRef,
+            Arc::new(LargeBinaryArray::from_iter_values(values)) as ArrayRef,
+        ],
+    )
+    .map_err(io_error)?;
+
+    batch.to_pyarrow(py)
+}
+
+/// Iterates over the `(key, value)` pairs of a scan, reading the next page of rows once one runs
+/// out.
+#[pyclass(unsendable, name = "Scan")]
+struct PyScan {
+    pages: Pages,
+    rows: vec::IntoIter<Entry>,
+}
+
+#[pymethods]
+impl PyScan {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<(Py<PyBytes>, Py<PyBytes>)>> {
+        loop {
+            if let Some(entry) = self.rows.next() {
+                return Ok(Some((
+                    to_bytes(py, &entry.key.0),
+                    to_bytes(py, &entry.value),
+                )));
+            }
+            match next_page(py, &mut self.pages)? {
+                Some(page) => self.rows = page.into_iter(),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Iterates over the pages of a scan as `pyarrow.RecordBatch`es with `key` and `value` columns.
+#[pyclass(unsendable, name = "ArrowScan")]
+struct PyArrowScan {
+    pages: Pages,
+}
+
+#[pymethods]
+impl PyArrowScan {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        next_page(py, &mut self.pages)?
+            .map(|page| to_record_batch(py, page))
+            .transpose()
+    }
+}
+
+#[pyclass(unsendable, name = "Db")]
+struct PyDb {
+    inner: RawDb,
+}
+
+#[pymethods]
+impl PyDb {
+    #[new]
+    fn new(py: Python<'_>, path: PathBuf) -> PyResult<Self> {
+        let wal = Fs::new(&path).map_err(io_error)?;
+        let inner = without_gil(py, || {
+            RawDb::new(LocalOracle::default(), wal, DbOption::new(path))
+        })
+        .map_err(io_error)?;
+
+        Ok(PyDb { inner })
+    }
+
+    fn get(&self, py: Python<'_>, key: Vec<u8>) -> Option<Py<PyBytes>> {
+        without_gil(py, || self.inner.get(&Key(key.into()))).map(|entry| to_bytes(py, &entry.value))
+    }
+
+    fn put(&self, py: Python<'_>, key: Vec<u8>, value: Vec<u8>) -> PyResult<u64> {
+        without_gil(py, || {
+            self.inner.set(Entry {
+                key: Key(key.into()),
+                value: value.into(),
+            })
+        })
+        .map_err(io_error)
+    }
+
+    fn delete(&self, py: Python<'_>, key: Vec<u8>) -> PyResult<u64> {
+        without_gil(py, || self.inner.remove(Key(key.into()))).map_err(io_error)
+    }
+
+    /// Iterates over the live pairs in the range, all read at the timestamp of the call, in
+    /// pages of `batch_rows`.
+    #[pyo3(signature = (lower = None, upper = None, batch_rows = SCAN_BATCH_ROWS))]
+    fn scan(&self, lower: Option<Vec<u8>>, upper: Option<Vec<u8>>, batch_rows: usize) -> PyScan {
+        PyScan {
+            pages: self.pages(lower, upper, batch_rows),
+            rows: Vec::new().into_iter(),
+        }
+    }
+
+    /// Like `scan`, yielding each page as a `pyarrow.RecordBatch`.
+    #[pyo3(signature = (lower = None, upper = None, batch_rows = SCAN_BATCH_ROWS))]
+    fn scan_arrow(
+        &self,
+        lower: Option<Vec<u8>>,
+        upper: Option<Vec<u8>>,
+        batch_rows: usize,
+    ) -> PyArrowScan {
+        PyArrowScan {
+            pages: self.pages(lower, upper, batch_rows),
+        }
+    }
+
+    fn transaction(&self) -> PyTransaction {
+        PyTransaction {
+            inner: Some(self.inner.new_txn()),
+        }
+    }
+}
+
+impl PyDb {
+    fn pages(&self, lower: Option<Vec<u8>>, upper: Option<Vec<u8>>, batch_rows: usize) -> Pages {
+        Box::new(
+            self.inner
+                .scan_pages((bound(lower), bound(upper)), batch_rows),
+        )
+    }
+}
+
+#[pyclass(unsendable, name = "Transaction")]
+struct PyTransaction {
+    inner: Option<RawTransaction>,
+}
+
+impl PyTransaction {
+    fn txn(&mut self) -> PyResult<&mut RawTransaction> {
+        self.inner.as_mut().ok_or_else(finished)
+    }
+
+    fn scan_pages(
+        &mut self,
+        py: Python<'_>,
+        lower: Option<Vec<u8>>,
+        upper: Option<Vec<u8>>,
+        batch_rows: usize,
+    ) -> PyResult<Pages> {
+        let txn = self.txn()?;
+        let rows = without_gil(py, || txn.scan((bound(lower), bound(upper)))).map_err(io_error)?;
+
+        Ok(chunked(rows, batch_rows))
+    }
+}
+
+fn finished() -> PyErr {
+    PyValueError::new_err("transaction is already committed or aborted")
+}
+
+#[pymethods]
+impl PyTransaction {
+    fn get(&mut self, py: Python<'_>, key: Vec<u8>) -> PyResult<Option<Py<PyBytes>>> {
+        let txn = self.txn()?;
+        Ok(without_gil(py, || txn.get(&Key(key.into()))).map(|entry| to_bytes(py, &entry.value)))
+    }
+
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> PyResult<()> {
+        let key = Key(key.into());
+        self.txn()?.set(
+            key.clone(),
+            Entry {
+                key,
+                value: value.into(),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> PyResult<()> {
+        self.txn()?.remove(Key(key.into()));
+
+        Ok(())
+    }
+
+    /// Iterates over the live pairs in the range along with the writes buffered in the
+    /// transaction, which are read at once.
+    #[pyo3(signature = (lower = None, upper = None, batch_rows = SCAN_BATCH_ROWS))]
+    fn scan(
+        &mut self,
+        py: Python<'_>,
+        lower: Option<Vec<u8>>,
+        upper: Option<Vec<u8>>,
+        batch_rows: usize,
+    ) -> PyResult<PyScan> {
+        Ok(PyScan {
+            pages: self.scan_pages(py, lower, upper, batch_rows)?,
+            rows: Vec::new().into_iter(),
+        })
+    }
+
+    /// Like `scan`, yielding pages of `batch_rows` as `pyarrow.RecordBatch`es.
+    #[pyo3(signature = (lower = None, upper = None, batch_rows = SCAN_BATCH_ROWS))]
+    fn scan_arrow(
+        &mut self,
+        py: Python<'_>,
+        lower: Option<Vec<u8>>,
+        upper: Option<Vec<u8>>,
+        batch_rows: usize,
+    ) -> PyResult<PyArrowScan> {
+        Ok(PyArrowScan {
+            pages: self.scan_pages(py, lower, upper, batch_rows)?,
+        })
+    }
+
+    fn commit(&mut self, py: Python<'_>) -> PyResult<u64> {
+        let txn = self.inner.take().ok_or_else(finished)?;
+
+        without_gil(py, || txn.commit()).map_err(|err| PyIOError::new_err(format!("{:?}", err)))
+    }
+
+    /// Drops the writes buffered in the transaction and releases its read. Aborting a
+    /// transaction committed or aborted already does nothing.
+    fn abort(&mut self) {
+        self.inner = None;
+    }
+
+    /// Same as `abort`.
+    fn rollback(&mut self) {
+        self.abort()
+    }
+}
+
+#[pymodule]
+fn elsm_py(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyDb>()?;
+    m.add_class::<PyTransaction>()?;
+    m.add_class::<PyScan>()?;
+    m.add_class::<PyArrowScan>()?;
+
+    Ok(())
+}
@@ -0,0 +1,34 @@
+mod service;
+mod worker;
+
+mod proto {
+    tonic::include_proto!("elsm");
+}
+
+use std::{env, error::Error, net::SocketAddr};
+
+use elsm::{wal::provider::fs::Fs, DbOption};
+use tokio::sync::broadcast;
+use tonic::transport::Server;
+
+use crate::{proto::elsm_server::ElsmServer, service::Service};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:50051";
+const WATCH_CHANNEL_BUFFER: usize = 1024;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args().skip(1);
+    let path = args.next().ok_or("usage: elsm-server <path> [addr]")?;
+    let addr: SocketAddr = args.next().as_deref().unwrap_or(DEFAULT_ADDR).parse()?;
+
+    let (events, _) = broadcast::channel(WATCH_CHANNEL_BUFFER);
+    let commands = worker::spawn(DbOption::new(&path), Fs::new(&path)?, events.clone())?;
+
+    Server::builder()
+        .add_service(ElsmServer::new(Service::new(commands, events)))
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,159 @@
+use std::{pin::Pin, sync::mpsc};
+
+use futures::Stream;
+use tokio::sync::{broadcast, oneshot};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamExt,
+};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    proto::{
+        elsm_server::Elsm, AbortRequest, AbortResponse, BeginRequest, BeginResponse, CommitRequest,
+        CommitResponse, DeleteRequest, GetRequest, GetResponse, KeyValue, PutRequest, ScanRequest,
+        WatchEvent, WatchRequest, WriteResponse,
+    },
+    worker::{Command, Reply, Write},
+};
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+pub(crate) struct Service {
+    commands: mpsc::Sender<Command>,
+    events: broadcast::Sender<WatchEvent>,
+}
+
+impl Service {
+    /// `events` is where the worker publishes the changes it applied.
+    pub(crate) fn new(
+        commands: mpsc::Sender<Command>,
+        events: broadcast::Sender<WatchEvent>,
+    ) -> Self {
+        Service { commands, events }
+    }
+
+    async fn call<T, F>(&self, command: F) -> Result<T, Status>
+    where
+        F: FnOnce(Reply<T>) -> Command + Send,
+        T: Send,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .send(command(tx))
+            .map_err(|_| Status::unavailable("db worker exited"))?;
+
+        rx.await
+            .map_err(|_| Status::unavailable("db worker exited"))?
+    }
+
+    async fn write(
+        &self,
+        txn: Option<u64>,
+        write: Write,
+    ) -> Result<Response<WriteResponse>, Status> {
+        let seq = self
+            .call(|reply| Command::Write { txn, write, reply })
+            .await?;
+
+        Ok(Response::new(WriteResponse { seq }))
+    }
+}
+
+fn in_range(key: &[u8], lower: Option<&[u8]>, upper: Option<&[u8]>) -> bool {
+    lower.is_none_or(|lower| lower <= key) && upper.is_none_or(|upper| key <= upper)
+}
+
+#[tonic::async_trait]
+impl Elsm for Service {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let GetRequest { txn, key } = request.into_inner();
+        let value = self.call(|reply| Command::Get { txn, key, reply }).await?;
+
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<WriteResponse>, Status> {
+        let PutRequest { txn, key, value } = request.into_inner();
+
+        self.write(txn, (key, Some(value))).await
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<WriteResponse>, Status> {
+        let DeleteRequest { txn, key } = request.into_inner();
+
+        self.write(txn, (key, None)).await
+    }
+
+    type ScanStream = ResponseStream<KeyValue>;
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let ScanRequest { txn, lower, upper } = request.into_inner();
+        let rows = self
+            .call(|reply| Command::Scan {
+                txn,
+                lower,
+                upper,
+                reply,
+            })
+            .await?;
+
+        Ok(Response::new(Box::pin(tokio_stream::iter(
+            rows.into_iter()
+                .map(|(key, value)| Ok(KeyValue { key, value })),
+        ))))
+    }
+
+    async fn begin(&self, _: Request<BeginRequest>) -> Result<Response<BeginResponse>, Status> {
+        let txn = self.call(|reply| Command::Begin { reply }).await?;
+
+        Ok(Response::new(BeginResponse { txn }))
+    }
+
+    async fn commit(
+        &self,
+        request: Request<CommitRequest>,
+    ) -> Result<Response<CommitResponse>, Status> {
+        let CommitRequest { txn } = request.into_inner();
+        let seq = self.call(|reply| Command::Commit { txn, reply }).await?;
+
+        Ok(Response::new(CommitResponse { seq }))
+    }
+
+    async fn abort(
+        &self,
+        request: Request<AbortRequest>,
+    ) -> Result<Response<AbortResponse>, Status> {
+        let AbortRequest { txn } = request.into_inner();
+        self.call(|reply| Command::Abort { txn, reply }).await?;
+
+        Ok(Response::new(AbortResponse {}))
+    }
+
+    type WatchStream = ResponseStream<WatchEvent>;
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let WatchRequest { lower, upper } = request.into_inner();
+        let events =
+            BroadcastStream::new(self.events.subscribe()).filter_map(move |event| match event {
+                Ok(event) => {
+                    in_range(&event.key, lower.as_deref(), upper.as_deref()).then_some(Ok(event))
+                }
+                Err(BroadcastStreamRecvError::Lagged(n)) => Some(Err(Status::data_loss(format!(
+                    "watch lagged behind by {} events",
+                    n
+                )))),
+            });
+
+        Ok(Response::new(Box::pin(events)))
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    collections::HashMap,
+    ops::Bound,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use elsm::{
+    blocking::{Db, Transaction},
+    oracle::{LocalOracle, TimeStamp},
+    raw::{Entry, Key},
+    wal::provider::fs::Fs,
+    DbOption,
+};
+use tokio::sync::{broadcast, oneshot};
+use tonic::Status;
+
+use crate::proto::WatchEvent;
+
+type RawDb = Db<Entry, LocalOracle<Key>, Fs>;
+type RawTransaction = Transaction<Entry, LocalOracle<Key>, Fs>;
+
+/// Transactions no request carried for this long are aborted, so that clients which went away do
+/// not hold their reads open.
+const TXN_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the worker reaps idle transactions and publishes the changes applied since the last
+/// time, whether requests come in or not.
+const TICK: Duration = Duration::from_millis(10);
+
+pub(crate) type Write = (Vec<u8>, Option<Vec<u8>>);
+pub(crate) type Reply<T> = oneshot::Sender<Result<T, Status>>;
+
+pub(crate) enum Command {
+    Get {
+        txn: Option<u64>,
+        key: Vec<u8>,
+        reply: Reply<Option<Vec<u8>>>,
+    },
+    Write {
+        txn: Option<u64>,
+        write: Write,
+        reply: Reply<Option<u64>>,
+    },
+    Scan {
+        txn: Option<u64>,
+        lower: Option<Vec<u8>>,
+        upper: Option<Vec<u8>>,
+        reply: Reply<Vec<(Vec<u8>, Vec<u8>)>>,
+    },
+    Begin {
+        reply: Reply<u64>,
+    },
+    Commit {
+        txn: u64,
+        reply: Reply<u64>,
+    },
+    Abort {
+        txn: u64,
+        reply: Reply<()>,
+    },
+}
+
+/// Owns the db on a dedicated thread, since its futures are bound to the thread-per-core executor
+/// and can not be polled by the tonic runtime.
+struct Worker {
+    db: RawDb,
+    txns: HashMap<u64, (RawTransaction, Instant)>,
+    next_txn: u64,
+    events: broadcast::Sender<WatchEvent>,
+    /// the sequence the changes were published up to
+    published: TimeStamp,
+    ticked: Instant,
+}
+
+/// Watch events are published to `events` from the changes the db applied, in the order of their
+/// commits, rather than by the requests writing them.
+pub(crate) fn spawn(
+    option: DbOption,
+    wal: Fs,
+    events: broadcast::Sender<WatchEvent>,
+) -> Result<mpsc::Sender<Command>, Status> {
+    let (tx, rx) = mpsc::channel();
+    let (ready_tx, ready_rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let db = match RawDb::new(LocalOracle::default(), wal, option) {
+            Ok(db) => {
+                let _ = ready_tx.send(Ok(()));
+                db
+            }
+            Err(err) => {
+                let _ = ready_tx.send(Err(Status::internal(err.to_string())));
+                return;
+            }
+        };
+        let mut worker = Worker {
+            published: db.latest_sequence(),
+            db,
+            txns: HashMap::new(),
+            next_txn: 0,
+            events,
+            ticked: Instant::now(),
+        };
+
+        loop {
+            match rx.recv_timeout(TICK) {
+                Ok(command) => worker.handle(command),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if worker.ticked.elapsed() >= TICK {
+                worker.tick();
+            }
+        }
+    });
+    ready_rx
+        .recv()
+        .map_err(|_| Status::internal("db worker exited"))??;
+
+    Ok(tx)
+}
+
+impl Worker {
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Get { txn, key, reply } => {
+                let result = match txn {
+                    Some(txn) => self.txn(txn).map(|txn| txn.get(&Key(key.into()))),
+                    None => Ok(self.db.get(&Key(key.into()))),
+                };
+                let _ = reply.send(result.map(|entry| entry.map(|entry| entry.value.to_vec())));
+            }
+            Command::Write { txn, write, reply } => {
+                let result = match txn {
+                    Some(txn) => self.txn(txn).map(|txn| {
+                        Self::apply(txn, write);
+                        None
+                    }),
+                    None => {
+                        let (key, value) = write;
+                        let result = match value {
+                            Some(value) => self.db.set(Entry {
+                                key: Key(key.into()),
+                                value: value.into(),
+                            }),
+                            None => self.db.remove(Key(key.into())),
+                        };
+                        result
+                            .map(Some)
+                            .map_err(|err| Status::internal(err.to_string()))
+                    }
+                };
+                let _ = reply.send(result);
+            }
+            Command::Scan {
+                txn,
+                lower,
+                upper,
+                reply,
+            } => {
+                let range = (Self::bound(lower), Self::bound(upper));
+                let result = match txn {
+                    Some(txn) => self.txn(txn).map(|txn| txn.scan(range)),
+                    None => Ok(self.db.scan(range)),
+                }
+                .and_then(|scan| scan.map_err(|err| Status::internal(err.to_string())))
+                .map(|rows| {
+                    rows.into_iter()
+                        .filter_map(|(_, entry)| entry)
+                        .map(|entry| (entry.key.0.to_vec(), entry.value.to_vec()))
+                        .collect()
+                });
+                let _ = reply.send(result);
+            }
+            Command::Begin { reply } => {
+                let txn = self.next_txn;
+                self.next_txn += 1;
+                self.txns.insert(txn, (self.db.new_txn(), Instant::now()));
+
+                let _ = reply.send(Ok(txn));
+            }
+            Command::Commit { txn, reply } => {
+                let result = match self.txns.remove(&txn) {
+                    Some((txn, _)) => txn
+                        .commit()
+                        .map_err(|err| Status::aborted(format!("{:?}", err))),
+                    None => Err(Self::missing(txn)),
+                };
+                let _ = reply.send(result);
+            }
+            Command::Abort { txn, reply } => {
+                // dropping the transaction releases its read
+                let result = match self.txns.remove(&txn) {
+                    Some(_) => Ok(()),
+                    None => Err(Self::missing(txn)),
+                };
+                let _ = reply.send(result);
+            }
+        }
+    }
+
+    fn txn(&mut self, txn: u64) -> Result<&mut RawTransaction, Status> {
+        let (txn, touched) = self.txns.get_mut(&txn).ok_or_else(|| Self::missing(txn))?;
+        *touched = Instant::now();
+
+        Ok(txn)
+    }
+
+    fn tick(&mut self) {
+        self.ticked = Instant::now();
+        self.txns
+            .retain(|_, (_, touched)| touched.elapsed() < TXN_IDLE_TIMEOUT);
+        self.publish();
+    }
+
+    /// Sends the changes applied since the last time to the watchers, ordered by their commits.
+    fn publish(&mut self) {
+        let latest = self.db.latest_sequence();
+        if latest <= self.published {
+            return;
+        }
+        if self.events.receiver_count() == 0 {
+            self.published = latest;
+            return;
+        }
+        let (upto, mut changes) = match self.db.changes_since(self.published) {
+            Ok(changes) => changes,
+            Err(err) => {
+                // retried on the next tick
+                eprintln!("[Watch]: failed to read the changes: {}", err);
+                return;
+            }
+        };
+        changes.sort_by_key(|(_, seq, _)| *seq);
+        for (key, seq, entry) in changes {
+            let _ = self.events.send(WatchEvent {
+                key: key.0.to_vec(),
+                value: entry.map(|entry| entry.value.to_vec()),
+                seq,
+            });
+        }
+        self.published = upto;
+    }
+
+    fn apply(txn: &mut RawTransaction, (key, value): Write) {
+        let key = Key(key.into());
+
+        match value {
+            Some(value) => txn.set(
+                key.clone(),
+                Entry {
+                    key,
+                    value: value.into(),
+                },
+            ),
+            None => txn.remove(key),
+        }
+    }
+
+    fn bound(key: Option<Vec<u8>>) -> Bound<Key> {
+        key.map_or(Bound::Unbounded, |key| Bound::Included(Key(key.into())))
+    }
+
+    fn missing(txn: u64) -> Status {
+        Status::not_found(format!("transaction {} does not exist", txn))
+    }
+}
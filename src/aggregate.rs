@@ -0,0 +1,296 @@
+use std::{cmp, collections::BTreeMap, io, marker::PhantomData};
+
+use crate::{
+    oracle::TimeStamp,
+    record::SystemRecord,
+    system::{
+        SystemEdit, SystemTable, AGGREGATE_APPLIED_KEY, AGGREGATE_COMMIT_PREFIX, AGGREGATE_PREFIX,
+    },
+};
+
+/// An additive aggregate over the rows of a db, e.g. a count or a sum per group of keys, which
+/// commits keep up to date in the system keyspace, see [`crate::Db::with_aggregate`].
+pub trait Aggregate<K, V>: Send + Sync + 'static {
+    /// Tells the aggregate apart from the others of the db.
+    fn name(&self) -> &str;
+
+    /// The group `key` belongs to and what a change of it from `old` to `new` adds to the value
+    /// of the group, or `None` if the change does not count.
+    fn delta(&self, key: &K, old: Option<&V>, new: Option<&V>) -> Option<(String, i64)>;
+}
+
+/// Counts the rows of each group `group` puts keys in, e.g. the rows of each tenant.
+pub struct CountBy<K, F> {
+    name: String,
+    group: F,
+    _p: PhantomData<fn(&K)>,
+}
+
+impl<K, F> CountBy<K, F>
+where
+    F: Fn(&K) -> Option<String>,
+{
+    pub fn new(name: impl Into<String>, group: F) -> Self {
+        CountBy {
+            name: name.into(),
+            group,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<K, V, F> Aggregate<K, V> for CountBy<K, F>
+where
+    K: 'static,
+    F: Fn(&K) -> Option<String> + Send + Sync + 'static,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn delta(&self, key: &K, old: Option<&V>, new: Option<&V>) -> Option<(String, i64)> {
+        let delta = match (old, new) {
+            (None, Some(_)) => 1,
+            (Some(_), None) => -1,
+            _ => return None,
+        };
+        Some(((self.group)(key)?, delta))
+    }
+}
+
+pub(crate) fn system_key(name: &str, group: &str) -> String {
+    format!("{}{}/{}", AGGREGATE_PREFIX, name, group)
+}
+
+pub(crate) fn value(bytes: Option<&[u8]>) -> i64 {
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(i64::from_le_bytes)
+        .unwrap_or(0)
+}
+
+fn commit_key(ts: TimeStamp) -> String {
+    format!("{}{:016x}", AGGREGATE_COMMIT_PREFIX, ts)
+}
+
+/// The timestamp up to which every commit has its deltas applied.
+fn applied(data: &BTreeMap<String, Vec<u8>>) -> TimeStamp {
+    data.get(AGGREGATE_APPLIED_KEY)
+        .and_then(|bytes| bytes.as_slice().try_into().ok())
+        .map(TimeStamp::from_le_bytes)
+        .unwrap_or(0)
+}
+
+pub(crate) fn is_delta(record: &SystemRecord) -> bool {
+    record.key.starts_with(AGGREGATE_PREFIX)
+}
+
+/// The deltas of the commit at `ts`, by system key, logged in the wal along with its writes.
+pub(crate) fn records(deltas: &BTreeMap<String, i64>, ts: TimeStamp) -> Vec<SystemRecord> {
+    deltas
+        .iter()
+        .filter(|(_, delta)| **delta != 0)
+        .map(|(key, delta)| SystemRecord {
+            key: key.clone(),
+            value: delta.to_le_bytes().to_vec(),
+            ts,
+        })
+        .collect()
+}
+
+/// Adds `deltas` to the aggregates and marks the commit at `ts` applied, so that recovery does
+/// not add them twice. Marks at or below `done`, up to which every commit is applied, are
+/// dropped for one watermark.
+fn edits(
+    data: &BTreeMap<String, Vec<u8>>,
+    deltas: &BTreeMap<String, i64>,
+    ts: TimeStamp,
+    done: TimeStamp,
+) -> Vec<SystemEdit> {
+    let done = cmp::max(done, applied(data));
+    let mut edits = deltas
+        .iter()
+        .filter(|(_, delta)| **delta != 0)
+        .map(|(key, delta)| SystemEdit::Set {
+            key: key.clone(),
+            value: (value(data.get(key).map(Vec::as_slice)) + delta)
+                .to_le_bytes()
+                .to_vec(),
+        })
+        .collect::<Vec<_>>();
+    if edits.is_empty() {
+        return edits;
+    }
+    // the marks sort by timestamp, as they are zero padded
+    edits.extend(
+        data.range(AGGREGATE_COMMIT_PREFIX.to_string()..=commit_key(done))
+            .map(|(key, _)| SystemEdit::Remove { key: key.clone() }),
+    );
+    if ts > done {
+        edits.push(SystemEdit::Set {
+            key: commit_key(ts),
+            value: Vec::new(),
+        });
+    }
+    edits.push(SystemEdit::Set {
+        key: AGGREGATE_APPLIED_KEY.to_string(),
+        value: done.to_le_bytes().to_vec(),
+    });
+    edits
+}
+
+/// Adds the deltas of the commit at `ts`, by system key, to the aggregates in one write of the
+/// system log. Every commit at or below `done` has been applied.
+pub(crate) async fn apply(
+    system: &SystemTable,
+    deltas: BTreeMap<String, i64>,
+    ts: TimeStamp,
+    done: TimeStamp,
+) -> io::Result<()> {
+    system
+        .apply_with(|data| edits(data, &deltas, ts, done))
+        .await
+}
+
+/// Applies the deltas logged in the wal by the commits not marked applied, e.g. those of a
+/// commit that crashed between its wal and the system log.
+pub(crate) async fn recover(system: &SystemTable, records: Vec<SystemRecord>) -> io::Result<()> {
+    let mut commits = BTreeMap::<TimeStamp, BTreeMap<String, i64>>::new();
+    for record in records {
+        *commits
+            .entry(record.ts)
+            .or_default()
+            .entry(record.key)
+            .or_default() += value(Some(&record.value));
+    }
+    for (ts, deltas) in commits {
+        system
+            .apply_with(|data| {
+                if ts <= applied(data) || data.contains_key(&commit_key(ts)) {
+                    return Vec::new();
+                }
+                edits(data, &deltas, ts, 0)
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use executor::ExecutorBuilder;
+    use tempfile::TempDir;
+
+    use super::CountBy;
+    use crate::{
+        oracle::LocalOracle,
+        tests::{user, with_db, UserInner},
+        wal::{provider::fs::Fs, WriteError},
+        Db, DbOption, ImportMode,
+    };
+
+    fn tenants() -> CountBy<u64, fn(&u64) -> Option<String>> {
+        CountBy::new("tenant", |id: &u64| Some((id / 10).to_string()))
+    }
+
+    #[test]
+    fn count_per_prefix() {
+        with_db(|db| async move {
+            let db = Arc::new(db.with_aggregate(CountBy::new("tenant", |id: &u64| {
+                (*id < 100).then(|| (id / 10).to_string())
+            })));
+
+            let mut txn = db.new_txn();
+            for id in [1, 2, 11, 100] {
+                txn.set(id, user(id));
+            }
+            txn.commit().await.unwrap();
+            assert_eq!(db.aggregate("tenant", "0").await, 2);
+            assert_eq!(db.aggregate("tenant", "1").await, 1);
+            assert_eq!(db.aggregate("tenant", "10").await, 0);
+
+            let mut txn = db.new_txn();
+            txn.set(1, user(1));
+            txn.remove(2);
+            txn.remove(3);
+            txn.set(12, user(12));
+            txn.commit().await.unwrap();
+            assert_eq!(db.aggregate("tenant", "0").await, 1);
+            assert_eq!(db.aggregate("tenant", "1").await, 2);
+        });
+    }
+
+    #[test]
+    fn count_every_write() {
+        with_db(|db| async move {
+            let db = Arc::new(db.with_aggregate(tenants()));
+
+            db.put(user(1)).await.unwrap();
+            db.put(user(1)).await.unwrap();
+            db.put(user(2)).await.unwrap();
+            db.delete(2).await.unwrap();
+            db.delete(3).await.unwrap();
+            assert_eq!(db.aggregate("tenant", "0").await, 1);
+
+            for id in [10, 11, 12] {
+                db.put(user(id)).await.unwrap();
+            }
+            let mut txn = db.new_txn();
+            txn.remove_range(11..=19);
+            txn.set(12, user(12));
+            txn.commit().await.unwrap();
+            assert_eq!(db.aggregate("tenant", "1").await, 2);
+            assert_eq!(db.get(&11, &u64::MAX).await, None);
+
+            assert!(matches!(
+                db.write_sorted_bulk([user(20)]).await,
+                Err(WriteError::ImportAggregated)
+            ));
+            assert!(matches!(
+                db.write_bulk_with_timestamps([(20, 1, Some(user(20)))], ImportMode::History)
+                    .await,
+                Err(WriteError::ImportAggregated)
+            ));
+        });
+    }
+
+    #[test]
+    fn recover_from_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let option = || DbOption::new(temp_dir.path().to_path_buf());
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let open = || async {
+                Arc::new(
+                    Db::<UserInner, _, _>::new(
+                        LocalOracle::default(),
+                        Fs::new(temp_dir.path()).unwrap(),
+                        option(),
+                    )
+                    .await
+                    .unwrap()
+                    .with_aggregate(tenants()),
+                )
+            };
+            let db = open().await;
+            let mut txn = db.new_txn();
+            txn.set(1, user(1));
+            txn.set(2, user(2));
+            txn.commit().await.unwrap();
+            db.put(user(3)).await.unwrap();
+            drop(db);
+
+            // the deltas are recovered from the wal as if the system log missed them
+            std::fs::remove_file(option().system_path()).unwrap();
+            let db = open().await;
+            assert_eq!(db.aggregate("tenant", "0").await, 3);
+            drop(db);
+
+            // and not added twice once applied
+            let db = open().await;
+            assert_eq!(db.aggregate("tenant", "0").await, 3);
+        });
+    }
+}
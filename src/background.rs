@@ -0,0 +1,158 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use async_channel::{unbounded, Receiver, Sender};
+use futures::future::BoxFuture;
+
+/// Most urgent first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TaskPriority {
+    /// moving frozen mem tables out of memory, which writes wait for once the immutables pile up
+    Flush,
+    Compaction,
+    /// removing the tables no version refers to anymore
+    Gc,
+}
+
+impl TaskPriority {
+    /// The worker running the tasks, see [`BackgroundPool`].
+    fn worker(self) -> usize {
+        match self {
+            TaskPriority::Flush => 0,
+            TaskPriority::Compaction | TaskPriority::Gc => 1,
+        }
+    }
+}
+
+type Queues = Mutex<[VecDeque<BoxFuture<'static, ()>>; 3]>;
+
+/// Runs the db's background work on two workers, each one task at a time: flushes on a worker of
+/// their own, so that they never wait for a compaction or a table removal already running, and
+/// compactions before table removals on the other.
+pub(crate) struct BackgroundPool {
+    queues: Arc<Queues>,
+    wakes: [Sender<()>; 2],
+}
+
+/// Drives the tasks of some priorities of a [`BackgroundPool`], the most urgent one queued
+/// next, until the pool is dropped.
+pub(crate) struct BackgroundWorker {
+    queues: Arc<Queues>,
+    priorities: &'static [TaskPriority],
+    wake: Receiver<()>,
+}
+
+impl BackgroundPool {
+    /// The pool and its workers, the flush worker first.
+    pub(crate) fn new() -> (Self, [BackgroundWorker; 2]) {
+        let queues = Arc::new(Mutex::new(Default::default()));
+        let (flush_tx, flush_rx) = unbounded();
+        let (other_tx, other_rx) = unbounded();
+
+        (
+            BackgroundPool {
+                queues: queues.clone(),
+                wakes: [flush_tx, other_tx],
+            },
+            [
+                BackgroundWorker {
+                    queues: queues.clone(),
+                    priorities: &[TaskPriority::Flush],
+                    wake: flush_rx,
+                },
+                BackgroundWorker {
+                    queues,
+                    priorities: &[TaskPriority::Compaction, TaskPriority::Gc],
+                    wake: other_rx,
+                },
+            ],
+        )
+    }
+
+    pub(crate) fn spawn(
+        &self,
+        priority: TaskPriority,
+        task: impl Future<Output = ()> + Send + 'static,
+    ) {
+        self.queues.lock().unwrap()[priority as usize].push_back(Box::pin(task));
+        let _ = self.wakes[priority.worker()].try_send(());
+    }
+}
+
+impl BackgroundWorker {
+    pub(crate) async fn run(self) {
+        while self.wake.recv().await.is_ok() {
+            while let Some(task) = self.next() {
+                task.await;
+            }
+        }
+    }
+
+    fn next(&self) -> Option<BoxFuture<'static, ()>> {
+        let mut queues = self.queues.lock().unwrap();
+        self.priorities
+            .iter()
+            .find_map(|priority| queues[*priority as usize].pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::{channel::oneshot, executor::block_on, join};
+
+    use super::{BackgroundPool, TaskPriority};
+
+    #[test]
+    fn most_urgent_first() {
+        block_on(async {
+            let (pool, [_, worker]) = BackgroundPool::new();
+            let ran = Arc::new(Mutex::new(Vec::new()));
+            let (release_tx, release_rx) = oneshot::channel::<()>();
+
+            pool.spawn(TaskPriority::Gc, async move {
+                let _ = release_rx.await;
+            });
+            let queued = ran.clone();
+            // queued while the first task runs
+            join!(worker.run(), async move {
+                for priority in [TaskPriority::Gc, TaskPriority::Compaction] {
+                    let ran = queued.clone();
+                    pool.spawn(priority, async move { ran.lock().unwrap().push(priority) });
+                }
+                release_tx.send(()).unwrap();
+                drop(pool);
+            });
+            assert_eq!(
+                *ran.lock().unwrap(),
+                vec![TaskPriority::Compaction, TaskPriority::Gc]
+            );
+        });
+    }
+
+    #[test]
+    fn flush_past_running_compaction() {
+        block_on(async {
+            let (pool, [flushes, others]) = BackgroundPool::new();
+            let (release_tx, release_rx) = oneshot::channel::<()>();
+            let (flushed_tx, flushed_rx) = oneshot::channel::<()>();
+
+            pool.spawn(TaskPriority::Compaction, async move {
+                let _ = release_rx.await;
+            });
+            pool.spawn(TaskPriority::Flush, async move {
+                let _ = flushed_tx.send(());
+            });
+            join!(flushes.run(), others.run(), async move {
+                // the compaction only finishes once the flush did
+                flushed_rx.await.unwrap();
+                release_tx.send(()).unwrap();
+                drop(pool);
+            });
+        });
+    }
+}
@@ -0,0 +1,352 @@
+use std::{
+    io,
+    ops::{Bound, RangeBounds},
+    pin::pin,
+    sync::Arc,
+};
+
+use executor::{
+    futures::{AsyncRead, StreamExt},
+    Executor, ExecutorBuilder,
+};
+use futures::AsyncWrite;
+
+use crate::{
+    oracle::{Oracle, TimeStamp, TimestampProvider},
+    record::Record,
+    schema::Schema,
+    serdes::{Decode, Encode},
+    stream::{merge_stream::MergeStream, ScanError},
+    transaction::{self, CommitError},
+    wal::{provider::StorageProvider, WriteError},
+    DbOption, ScanOptions,
+};
+
+type Scan<S> = Vec<(<S as Schema>::PrimaryKey, Option<S>)>;
+type Changes<S> = Vec<(<S as Schema>::PrimaryKey, TimeStamp, Option<S>)>;
+
+/// Synchronous handle over [`crate::Db`] that drives its own executor.
+pub struct Db<S, O, WP>
+where
+    S: Schema,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+{
+    executor: Arc<Executor>,
+    inner: Arc<crate::Db<S, O, WP>>,
+}
+
+impl<S, O, WP> Db<S, O, WP>
+where
+    S: Schema,
+    O: Oracle<S::PrimaryKey> + 'static,
+    WP: StorageProvider,
+    WP::File: AsyncWrite + AsyncRead,
+    io::Error: From<<S as Decode>::Error>,
+{
+    pub fn new(
+        oracle: O,
+        wal_provider: WP,
+        option: DbOption,
+    ) -> Result<Self, WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        let executor = ExecutorBuilder::new().build().map_err(WriteError::Io)?;
+        let inner = executor.block_on(crate::Db::new(oracle, wal_provider, option))?;
+
+        Ok(Db {
+            executor: Arc::new(executor),
+            inner: Arc::new(inner),
+        })
+    }
+
+    pub fn get(&self, key: &S::PrimaryKey) -> Option<S> {
+        let ts = self.inner.start_read();
+        let value = self.executor.block_on(self.inner.get(key, &ts));
+        self.inner.read_commit(ts);
+
+        value
+    }
+
+    pub fn set(
+        &self,
+        value: S,
+    ) -> Result<TimeStamp, WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        self.executor.block_on(self.inner.put(value))
+    }
+
+    pub fn remove(
+        &self,
+        key: S::PrimaryKey,
+    ) -> Result<TimeStamp, WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        self.executor.block_on(self.inner.delete(key))
+    }
+
+    pub fn scan(
+        &self,
+        range: impl RangeBounds<S::PrimaryKey>,
+    ) -> Result<Scan<S>, ScanError<S::PrimaryKey, S>> {
+        let ts = self.inner.start_read();
+        let items = self.executor.block_on(async {
+            collect(
+                self.inner
+                    .range(range.start_bound(), range.end_bound(), &ts)
+                    .await?,
+            )
+            .await
+        });
+        self.inner.read_commit(ts);
+
+        items
+    }
+
+    /// Pages through the live rows of `range`, up to `rows` at a time, all read at the timestamp
+    /// the pages were opened at. The read is held until they are dropped.
+    pub fn scan_pages(
+        &self,
+        range: impl RangeBounds<S::PrimaryKey>,
+        rows: usize,
+    ) -> Pages<S, O, WP> {
+        Pages {
+            executor: self.executor.clone(),
+            ts: self.inner.start_read(),
+            inner: self.inner.clone(),
+            lower: range.start_bound().cloned(),
+            upper: range.end_bound().cloned(),
+            rows: rows.max(1),
+            done: false,
+        }
+    }
+
+    /// See [`crate::Db::latest_sequence`].
+    pub fn latest_sequence(&self) -> TimeStamp {
+        self.inner.latest_sequence()
+    }
+
+    /// The rows changed after `since` along with the sequence they go up to, see
+    /// [`crate::Db::changes_since`].
+    pub fn changes_since(
+        &self,
+        since: TimeStamp,
+    ) -> Result<(TimeStamp, Changes<S>), ScanError<S::PrimaryKey, S>> {
+        self.executor.block_on(async {
+            let (upto, stream) = self.inner.changes_since(since).await?;
+            let mut stream = pin!(stream);
+            let mut changes = Vec::new();
+
+            while let Some(change) = stream.next().await {
+                changes.push(change?);
+            }
+            Ok((upto, changes))
+        })
+    }
+
+    pub fn new_txn(&self) -> Transaction<S, O, WP> {
+        Transaction {
+            executor: self.executor.clone(),
+            inner: self.inner.new_txn(),
+        }
+    }
+}
+
+/// See [`Db::scan_pages`].
+pub struct Pages<S, O, WP>
+where
+    S: Schema,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+{
+    executor: Arc<Executor>,
+    inner: Arc<crate::Db<S, O, WP>>,
+    ts: TimeStamp,
+    lower: Bound<S::PrimaryKey>,
+    upper: Bound<S::PrimaryKey>,
+    rows: usize,
+    done: bool,
+}
+
+impl<S, O, WP> Iterator for Pages<S, O, WP>
+where
+    S: Schema,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+    WP::File: AsyncWrite,
+    io::Error: From<<S as Decode>::Error>,
+{
+    type Item = Result<Vec<S>, ScanError<S::PrimaryKey, S>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let options = ScanOptions::<S>::default().limit(self.rows);
+        let page = self.executor.block_on(async {
+            let stream = self
+                .inner
+                .range_with_options(self.lower.as_ref(), self.upper.as_ref(), &self.ts, &options)
+                .await?;
+            let mut stream = pin!(stream);
+            let mut page = Vec::with_capacity(self.rows);
+
+            while let Some(item) = stream.next().await {
+                if let (_, Some(value)) = item? {
+                    page.push(value);
+                }
+            }
+            Ok(page)
+        });
+        // a page short of `rows` is the last one
+        match page.as_ref().ok().filter(|page| page.len() == self.rows) {
+            Some(page) => self.lower = Bound::Excluded(page[page.len() - 1].primary_key()),
+            None => self.done = true,
+        }
+        match page {
+            Ok(page) if page.is_empty() => None,
+            page => Some(page),
+        }
+    }
+}
+
+impl<S, O, WP> Drop for Pages<S, O, WP>
+where
+    S: Schema,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+{
+    fn drop(&mut self) {
+        self.inner.read_commit(self.ts);
+    }
+}
+
+async fn collect<S: Schema>(
+    stream: MergeStream<'_, S>,
+) -> Result<Scan<S>, ScanError<S::PrimaryKey, S>> {
+    let mut stream = pin!(stream);
+    let mut items = Vec::new();
+
+    while let Some(item) = stream.next().await {
+        items.push(item?);
+    }
+    Ok(items)
+}
+
+pub struct Transaction<S, O, WP>
+where
+    S: Schema,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+    WP::File: AsyncWrite,
+    io::Error: From<<S as Decode>::Error>,
+{
+    executor: Arc<Executor>,
+    inner: transaction::Transaction<S, crate::Db<S, O, WP>>,
+}
+
+impl<S, O, WP> Transaction<S, O, WP>
+where
+    S: Schema,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+    WP::File: AsyncWrite,
+    io::Error: From<<S as Decode>::Error>,
+{
+    pub fn get(&self, key: &S::PrimaryKey) -> Option<S> {
+        self.executor.block_on(self.inner.get(key))
+    }
+
+    pub fn set(&mut self, key: S::PrimaryKey, value: S) {
+        self.inner.set(key, value)
+    }
+
+    pub fn remove(&mut self, key: S::PrimaryKey) {
+        self.inner.remove(key)
+    }
+
+    pub fn scan(
+        &self,
+        range: impl RangeBounds<S::PrimaryKey>,
+    ) -> Result<Scan<S>, ScanError<S::PrimaryKey, S>> {
+        self.executor
+            .block_on(async { collect(self.inner.range(range).await?).await })
+    }
+
+    pub fn commit(self) -> Result<TimeStamp, CommitError<S::PrimaryKey>> {
+        self.executor.block_on(self.inner.commit())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use tempfile::TempDir;
+
+    use super::Db;
+    use crate::{
+        oracle::LocalOracle, tests::UserInner, wal::provider::in_mem::InMemProvider, DbOption,
+    };
+
+    #[test]
+    fn blocking() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = Db::<UserInner, _, _>::new(
+            LocalOracle::default(),
+            InMemProvider::default(),
+            DbOption::new(temp_dir.path().to_path_buf()),
+        )
+        .unwrap();
+        let user_0 = UserInner::new(0, "0".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+        let user_1 = UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+
+        db.set(user_0.clone()).unwrap();
+        assert_eq!(db.get(&0), Some(user_0.clone()));
+
+        let mut txn = db.new_txn();
+        txn.set(1, user_1.clone());
+        txn.remove(0);
+        assert_eq!(txn.get(&0), None);
+        txn.commit().unwrap();
+
+        assert_eq!(db.get(&0), None);
+        assert_eq!(db.scan(..).unwrap(), vec![(1, Some(user_1.clone()))]);
+
+        let (upto, changes) = db.changes_since(0).unwrap();
+        assert_eq!(upto, db.latest_sequence());
+        assert_eq!(
+            changes
+                .into_iter()
+                .map(|(key, _, value)| (key, value))
+                .collect::<Vec<_>>(),
+            vec![(0, None), (1, Some(user_1))]
+        );
+        assert!(db.changes_since(upto).unwrap().1.is_empty());
+
+        for id in 2..7 {
+            db.set(UserInner::new(
+                id,
+                id.to_string(),
+                false,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ))
+            .unwrap();
+        }
+        let mut pages = db.scan_pages(2.., 2);
+        // rows written after the first page are not seen by the ones after it
+        let first = pages.next().unwrap().unwrap();
+        db.remove(4).unwrap();
+        let pages = iter::once(first)
+            .chain(pages.map(Result::unwrap))
+            .map(|page| {
+                page.into_iter()
+                    .map(|user| user.inner.id)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(pages, vec![vec![2, 3], vec![4, 5], vec![6]]);
+    }
+}
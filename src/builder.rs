@@ -0,0 +1,122 @@
+use std::io;
+
+use executor::futures::AsyncRead;
+use futures::AsyncWrite;
+use thiserror::Error;
+
+use crate::{
+    oracle::Oracle,
+    record::Record,
+    schema::Schema,
+    serdes::{Decode, Encode},
+    wal::{provider::StorageProvider, WriteError},
+    Db, DbOption,
+};
+
+/// Assembles a [`Db`] piece by piece, so that the oracle and wal provider types are fixed as they
+/// are set and only the schema is left to [`DbBuilder::open`].
+#[derive(Debug)]
+pub struct DbBuilder<O = (), WP = ()> {
+    oracle: O,
+    wal: WP,
+    option: Option<DbOption>,
+}
+
+#[derive(Debug, Error)]
+pub enum OpenError<E: std::error::Error> {
+    #[error("db open error: option is not set")]
+    MissingOption,
+    #[error("db open error: {0}")]
+    Write(#[from] WriteError<E>),
+}
+
+impl DbBuilder {
+    pub fn new() -> Self {
+        DbBuilder {
+            oracle: (),
+            wal: (),
+            option: None,
+        }
+    }
+}
+
+impl Default for DbBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<O, WP> DbBuilder<O, WP> {
+    pub fn oracle<T>(self, oracle: T) -> DbBuilder<T, WP> {
+        DbBuilder {
+            oracle,
+            wal: self.wal,
+            option: self.option,
+        }
+    }
+
+    pub fn wal<T: StorageProvider>(self, wal: T) -> DbBuilder<O, T> {
+        DbBuilder {
+            oracle: self.oracle,
+            wal,
+            option: self.option,
+        }
+    }
+
+    pub fn option(mut self, option: DbOption) -> Self {
+        self.option = Some(option);
+        self
+    }
+
+    pub async fn open<S>(
+        self,
+    ) -> Result<Db<S, O, WP>, OpenError<<Record<S::PrimaryKey, S> as Encode>::Error>>
+    where
+        S: Schema,
+        O: Oracle<S::PrimaryKey> + 'static,
+        WP: StorageProvider,
+        WP::File: AsyncWrite + AsyncRead,
+        io::Error: From<<S as Decode>::Error>,
+    {
+        let option = self.option.ok_or(OpenError::MissingOption)?;
+
+        Ok(Db::new(self.oracle, self.wal, option).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use executor::ExecutorBuilder;
+    use tempfile::TempDir;
+
+    use super::{DbBuilder, OpenError};
+    use crate::{
+        oracle::LocalOracle, tests::UserInner, wal::provider::in_mem::InMemProvider, DbOption,
+    };
+
+    #[test]
+    fn open() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let err = DbBuilder::new()
+                .oracle(LocalOracle::default())
+                .wal(InMemProvider::default())
+                .open::<UserInner>()
+                .await;
+            assert!(matches!(err, Err(OpenError::MissingOption)));
+
+            let db = DbBuilder::new()
+                .oracle(LocalOracle::default())
+                .wal(InMemProvider::default())
+                .option(DbOption::new(temp_dir.path().to_path_buf()))
+                .open::<UserInner>()
+                .await
+                .unwrap();
+            let user = UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+
+            let ts = db.put(user.clone()).await.unwrap();
+            assert_eq!(db.get_at_least(&1, ts).await, Some(user));
+        });
+    }
+}
@@ -1,22 +1,42 @@
-use std::{cmp, collections::VecDeque, fmt::Debug, fs::File, mem, pin::pin, sync::Arc};
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    mem,
+    ops::{Bound, RangeInclusive},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
-use executor::{fs, futures::StreamExt};
+use arrow::record_batch::RecordBatch;
+use async_lock::RwLockUpgradableReadGuard;
+use bytes::Bytes;
+use executor::spawn;
 use futures::channel::oneshot;
-use parquet::arrow::{ArrowWriter, AsyncArrowWriter};
+use parquet::arrow::ArrowWriter;
 use snowflake::ProcessUniqueId;
 use thiserror::Error;
+use tracing::error;
 
 use crate::{
+    comparator::Comparator,
     index_batch::IndexBatch,
-    schema::{Builder, Schema},
-    scope::Scope,
+    oracle::TimeStamp,
+    schema::{Builder, Op, Schema},
+    scope::{Scope, TableStats},
     serdes::Encode,
     stream::{
         level_stream::LevelStream, merge_stream::MergeStream, table_stream::TableStream,
-        EStreamImpl, StreamError,
+        EStreamImpl, ScanError,
     },
-    version::{edit::VersionEdit, set::VersionSet, Version, VersionError, MAX_LEVEL},
-    DbOption, Immutable,
+    version::{
+        edit::VersionEdit,
+        set::{Reservation, VersionSet},
+        Version, VersionError, MAX_LEVEL,
+    },
+    wal::provider::{table::writer_properties, TableStore, TableStoreRef},
+    CompactionStyle, DbOption, Immutable,
 };
 
 pub(crate) struct Compactor<S>
@@ -26,6 +46,9 @@ where
     pub(crate) option: Arc<DbOption>,
     pub(crate) immutable: Immutable<S>,
     pub(crate) version_set: VersionSet<S>,
+    pub(crate) table_store: TableStoreRef,
+    /// the oldest timestamp reads may still happen at, see [`Compactor::major_compaction`]
+    pub(crate) retention: Arc<AtomicU64>,
 }
 
 impl<S> Compactor<S>
@@ -36,11 +59,15 @@ where
         immutable: Immutable<S>,
         option: Arc<DbOption>,
         version_set: VersionSet<S>,
+        table_store: TableStoreRef,
+        retention: Arc<AtomicU64>,
     ) -> Self {
         Compactor::<S> {
             option,
             immutable,
             version_set,
+            table_store,
+            retention,
         }
     }
 
@@ -50,33 +77,53 @@ where
     ) -> Result<(), CompactionError<S>> {
         let mut guard = self.immutable.write().await;
 
-        if guard.len() > self.option.immutable_chunk_num {
-            let excess = guard.split_off(self.option.immutable_chunk_num);
+        if guard.iter().map(|batch| batch.chunks).sum::<usize>() > self.option.immutable_chunk_num {
+            let mut chunks = 0;
+            let split = guard
+                .iter()
+                .position(|batch| {
+                    chunks += batch.chunks;
+                    chunks >= self.option.immutable_chunk_num
+                })
+                .map_or(guard.len(), |i| i + 1);
+            let excess = guard.split_off(split);
 
             if let Some(scope) =
-                Self::minor_compaction(&self.option, mem::replace(&mut guard, excess)).await?
+                Self::minor_compaction(self.table_store.as_ref(), mem::replace(&mut guard, excess))
+                    .await?
             {
-                let version_ref = self.version_set.current().await;
-                let mut version_edits = vec![];
-                let mut delete_gens = vec![];
-
-                if self.option.is_threshold_exceeded_major(&version_ref, 0) {
-                    Self::major_compaction(
-                        &version_ref,
-                        &self.option,
-                        &scope.min,
-                        &scope.max,
-                        &mut version_edits,
-                        &mut delete_gens,
-                    )
-                    .await?;
-                }
-                version_edits.insert(0, VersionEdit::Add { level: 0, scope });
-
+                let (min, max) = (scope.min.clone(), scope.max.clone());
                 self.version_set
-                    .apply_edits(version_edits, Some(delete_gens), false)
+                    .apply_edits(vec![VersionEdit::Add { level: 0, scope }], None, false)
                     .await
                     .map_err(CompactionError::Version)?;
+
+                if self
+                    .option
+                    .is_threshold_exceeded_level_0(&*self.version_set.current().await)
+                {
+                    // jobs over disjoint key ranges run side by side, see `VersionSet::reserve`
+                    let version_set = self.version_set.clone();
+                    let option = self.option.clone();
+                    let table_store = self.table_store.clone();
+                    let retention = self.retention.load(Ordering::Acquire);
+
+                    spawn(async move {
+                        if let Err(err) = Self::compact_level_0(
+                            &version_set,
+                            &option,
+                            &table_store,
+                            min,
+                            max,
+                            retention,
+                        )
+                        .await
+                        {
+                            error!("[Compaction Error]: {}", err)
+                        }
+                    })
+                    .detach();
+                }
             }
         }
         if let Some(tx) = option_tx {
@@ -85,8 +132,34 @@ where
         Ok(())
     }
 
+    /// Concatenates the freshly frozen batches at the tail of the queue once there are more than
+    /// `immutable_merge_threshold` of them, so that lookups walk fewer batches. The merged batch
+    /// stays within `max_batch_size`.
+    pub(crate) async fn merge_immutables(&mut self) {
+        let guard = self.immutable.upgradable_read().await;
+        let mut size = 0;
+        let fresh = guard
+            .iter()
+            .rev()
+            .take_while(|batch| {
+                size += batch.batch.get_array_memory_size();
+                batch.chunks == 1 && size <= self.option.max_batch_size
+            })
+            .count();
+
+        if fresh <= self.option.immutable_merge_threshold {
+            return;
+        }
+        let start = guard.len() - fresh;
+        let merged = IndexBatch::merge(guard.range(start..));
+
+        let mut guard = RwLockUpgradableReadGuard::upgrade(guard).await;
+        guard.truncate(start);
+        guard.push_back(merged);
+    }
+
     pub(crate) async fn minor_compaction(
-        option: &DbOption,
+        table_store: &dyn TableStore,
         batches: VecDeque<IndexBatch<S>>,
     ) -> Result<Option<Scope<S::PrimaryKey>>, CompactionError<S>> {
         if !batches.is_empty() {
@@ -94,192 +167,824 @@ where
             let mut max = None;
 
             let gen = ProcessUniqueId::new();
+            let mut stats = TableStats::new();
 
-            let mut writer = AsyncArrowWriter::try_new(
-                fs::File::from(File::create(option.table_path(&gen)).map_err(CompactionError::Io)?),
-                S::inner_schema(),
-                None,
-            )
-            .map_err(CompactionError::Parquet)?;
+            let schema = S::inner_schema();
+            let mut writer =
+                ArrowWriter::try_new(Vec::new(), schema.clone(), Some(writer_properties(&schema)))
+                    .map_err(CompactionError::Parquet)?;
 
             for batch in batches {
+                for offset in 0..batch.len() {
+                    stats.add(
+                        Op::from_batch(&batch.batch, offset).is_tombstone(),
+                        offset + 1 < batch.len() && batch.key(offset) == batch.key(offset + 1),
+                    );
+                }
                 if let Some((batch_min, batch_max)) = batch.scope() {
-                    if matches!(min.as_ref().map(|min| min > batch_min), Some(true) | None) {
-                        min = Some(batch_min.clone())
+                    if min
+                        .as_ref()
+                        .is_none_or(|min| S::Comparator::compare(min, &batch_min).is_gt())
+                    {
+                        min = Some(batch_min)
                     }
-                    if matches!(max.as_ref().map(|max| max < batch_max), Some(true) | None) {
-                        max = Some(batch_max.clone())
+                    if max
+                        .as_ref()
+                        .is_none_or(|max| S::Comparator::compare(max, &batch_max).is_lt())
+                    {
+                        max = Some(batch_max)
                     }
                 }
                 writer
                     .write(&batch.batch)
-                    .await
                     .map_err(CompactionError::Parquet)?;
             }
-            writer.close().await.map_err(CompactionError::Parquet)?;
+            let bytes = writer.into_inner().map_err(CompactionError::Parquet)?;
+            stats.size = bytes.len() as u64;
+            table_store
+                .create_table(&gen, Bytes::from(bytes))
+                .await
+                .map_err(CompactionError::Io)?;
             return Ok(Some(Scope {
                 min: min.ok_or(CompactionError::EmptyLevel)?,
                 max: max.ok_or(CompactionError::EmptyLevel)?,
                 gen,
+                stats,
             }));
         }
         Ok(None)
     }
 
-    pub(crate) async fn major_compaction(
-        version: &Version<S>,
-        option: &DbOption,
-        mut min: &S::PrimaryKey,
-        mut max: &S::PrimaryKey,
-        version_edits: &mut Vec<VersionEdit<S::PrimaryKey>>,
-        delete_gens: &mut Vec<ProcessUniqueId>,
-    ) -> Result<(), CompactionError<S>> {
-        let mut level = 0;
+    /// Writes `rows`, sorted by key and the newest version of a key first, into tables installed
+    /// on the deepest level that neither the tables of that level nor those above it overlap, or
+    /// on level 0 when there is none. With `disjoint`, no table at all may overlap them, since
+    /// they could hold newer versions. Returns the level.
+    pub(crate) async fn bulk_load(
+        version_set: &VersionSet<S>,
+        option: &Arc<DbOption>,
+        table_store: &TableStoreRef,
+        rows: Vec<(S::PrimaryKey, TimeStamp, Option<S>)>,
+        disjoint: bool,
+        retention: TimeStamp,
+    ) -> Result<Option<usize>, CompactionError<S>> {
+        let (Some((min, _, _)), Some((max, _, _))) = (rows.first(), rows.last()) else {
+            return Ok(None);
+        };
+        let (range_min, range_max) = (min.clone(), max.clone());
+        let (min, max) = (&range_min, &range_max);
+        let clear_until = |version: &Version<S>| {
+            (0..MAX_LEVEL - 1)
+                .take_while(|level| {
+                    Self::overlapping(&version.level_slice[*level], min, max)
+                        .next()
+                        .is_none()
+                })
+                .last()
+        };
 
-        while level < MAX_LEVEL - 2 {
-            if !option.is_threshold_exceeded_major(version, level) {
-                break;
+        // a compaction writing into the level meanwhile would overlap the new tables, so the
+        // range is reserved like the inputs of a compaction are
+        let level = clear_until(&*version_set.current().await).filter(|level| *level > 0);
+        if disjoint && level != Some(MAX_LEVEL - 2) {
+            return Err(CompactionError::Overlap);
+        }
+        let reservation = match level {
+            Some(level) => {
+                version_set
+                    .reserve(level, |version| {
+                        (clear_until(version)? >= level).then(|| {
+                            let range = Scope {
+                                min: range_min.clone(),
+                                max: range_max.clone(),
+                                gen: ProcessUniqueId::new(),
+                                stats: TableStats::new(),
+                            };
+                            (vec![range], Vec::new())
+                        })
+                    })
+                    .await
             }
+            None => None,
+        };
+        if disjoint && reservation.is_none() {
+            return Err(CompactionError::Overlap);
+        }
+        let level = reservation
+            .as_ref()
+            .map_or(0, |reservation| reservation.level);
 
-            let mut meet_scopes_l = Vec::new();
-            {
-                let index = Version::<S>::scope_search(min, &version.level_slice[level]);
+        let mut version_edits = Vec::new();
+        let mut builder = S::builder();
+        let target_size = option.target_file_size(level);
+        let mut written_size = 0;
+        let mut stats = TableStats::new();
+        let mut min = None;
+        let mut max = None;
 
-                for scope in version.level_slice[level][index..].iter() {
-                    if scope.is_between(min) || scope.is_between(max) {
-                        meet_scopes_l.push(scope);
-                    }
-                }
-                if meet_scopes_l.is_empty() {
-                    return Ok(());
-                }
+        for (key, ts, value) in rows {
+            if written_size >= target_size && max.as_ref() != Some(&key) {
+                Self::build_table(
+                    table_store.as_ref(),
+                    &mut version_edits,
+                    level,
+                    &mut builder,
+                    &mut stats,
+                    &mut min,
+                    &mut max,
+                )
+                .await?;
+                written_size = 0;
             }
-            let mut meet_scopes_ll = Vec::new();
-            {
-                if !version.level_slice[level + 1].is_empty() {
-                    let min_key = &meet_scopes_l
-                        .first()
-                        .ok_or(CompactionError::EmptyLevel)?
-                        .min;
-                    let max_key = &meet_scopes_l.last().ok_or(CompactionError::EmptyLevel)?.max;
-                    min = min_key;
-                    max = max_key;
-
-                    let min_index =
-                        Version::<S>::scope_search(min_key, &version.level_slice[level + 1]);
-                    let max_index =
-                        Version::<S>::scope_search(max_key, &version.level_slice[level + 1]);
-
-                    let next_level_len = version.level_slice[level + 1].len();
-                    for scope in version.level_slice[level + 1]
-                        [min_index..cmp::min(max_index + 1, next_level_len - 1)]
-                        .iter()
-                    {
-                        if scope.is_between(min) || scope.is_between(max) {
-                            meet_scopes_ll.push(scope);
-                        }
-                    }
+            if min.is_none() {
+                min = Some(key.clone())
+            }
+            stats.add(value.is_none(), max.as_ref() == Some(&key));
+            max = Some(key.clone());
+
+            written_size += key.size() + value.as_ref().map_or(0, Encode::size);
+            builder.add(&key, ts, Op::of(value.as_ref()), value);
+        }
+        Self::build_table(
+            table_store.as_ref(),
+            &mut version_edits,
+            level,
+            &mut builder,
+            &mut stats,
+            &mut min,
+            &mut max,
+        )
+        .await?;
+
+        version_set
+            .apply_edits(version_edits, None, false)
+            .await
+            .map_err(CompactionError::Version)?;
+        drop(reservation);
+
+        if level == 0 && option.is_threshold_exceeded_level_0(&*version_set.current().await) {
+            let version_set = version_set.clone();
+            let option = option.clone();
+            let table_store = table_store.clone();
+            let (min, max) = (range_min, range_max);
+
+            spawn(async move {
+                if let Err(err) =
+                    Self::compact_level_0(&version_set, &option, &table_store, min, max, retention)
+                        .await
+                {
+                    error!("[Compaction Error]: {}", err)
                 }
+            })
+            .detach();
+        }
+        Ok(Some(level))
+    }
+
+    /// Runs the compactions the compaction style calls for once tables within `min..=max` were
+    /// added to level 0.
+    pub(crate) async fn compact_level_0(
+        version_set: &VersionSet<S>,
+        option: &Arc<DbOption>,
+        table_store: &TableStoreRef,
+        min: S::PrimaryKey,
+        max: S::PrimaryKey,
+        retention: TimeStamp,
+    ) -> Result<(), CompactionError<S>> {
+        match option.compaction_style {
+            CompactionStyle::Leveled => {
+                Self::major_compaction(version_set, option, table_store, min, max, retention).await
+            }
+            CompactionStyle::Tiered {
+                max_runs,
+                size_ratio,
+            } => {
+                Self::tiered_compaction(
+                    version_set,
+                    option,
+                    table_store,
+                    max_runs,
+                    size_ratio,
+                    retention,
+                )
+                .await
             }
-            let mut streams = Vec::with_capacity(meet_scopes_l.len() + meet_scopes_ll.len());
+        }
+    }
 
-            // This Level
-            if level == 0 {
-                for scope in meet_scopes_l.iter() {
-                    streams.push(EStreamImpl::Table(
-                        TableStream::new(option, &scope.gen, None, None)
+    /// Merges the newest sorted runs picked by [`Compactor::pick_runs`] into one, for as long as
+    /// there are `max_runs`.
+    pub(crate) async fn tiered_compaction(
+        version_set: &VersionSet<S>,
+        option: &Arc<DbOption>,
+        table_store: &TableStoreRef,
+        max_runs: usize,
+        size_ratio: u64,
+        retention: TimeStamp,
+    ) -> Result<(), CompactionError<S>> {
+        while let Some(reservation) = version_set
+            .reserve_levels(|version| Self::pick_runs(version, max_runs, size_ratio))
+            .await
+        {
+            let version = &reservation.version;
+            let last_level = reservation.last_level;
+            let is_input = |scope: &Scope<S::PrimaryKey>| {
+                reservation
+                    .inputs
+                    .iter()
+                    .any(|input| input.gen == scope.gen)
+            };
+            let retention = Retention {
+                below: retention,
+                bottommost: version.level_slice[last_level + 1..].iter().all(|scopes| {
+                    Self::overlapping(scopes, &reservation.min, &reservation.max)
+                        .next()
+                        .is_none()
+                }),
+            };
+
+            let mut streams = Vec::new();
+            let mut version_edits = Vec::new();
+            let mut delete_gens = Vec::new();
+            for (level, scopes) in version.level_slice[..=last_level].iter().enumerate() {
+                let gens = scopes
+                    .iter()
+                    .filter(|scope| is_input(scope))
+                    .map(|scope| scope.gen)
+                    .collect::<Vec<_>>();
+                if level == 0 {
+                    for gen in gens.iter().rev() {
+                        streams.push(EStreamImpl::Table(
+                            TableStream::new(
+                                table_store.as_ref(),
+                                gen,
+                                Bound::Unbounded,
+                                Bound::Unbounded,
+                                TimeStamp::MAX,
+                                None,
+                            )
                             .await
                             .map_err(CompactionError::Stream)?,
+                        ));
+                    }
+                } else if !gens.is_empty() {
+                    streams.push(EStreamImpl::Level(
+                        LevelStream::new(
+                            table_store.as_ref(),
+                            gens.clone(),
+                            Bound::Unbounded,
+                            Bound::Unbounded,
+                            TimeStamp::MAX,
+                            None,
+                        )
+                        .await
+                        .map_err(CompactionError::Stream)?,
                     ));
                 }
-            } else {
-                let gens = meet_scopes_l
+                for gen in gens {
+                    version_edits.push(VersionEdit::Remove {
+                        level: level as u8,
+                        gen,
+                    });
+                    delete_gens.push(gen);
+                }
+            }
+            let stream = MergeStream::<S>::new(streams)
+                .await
+                .map_err(CompactionError::Stream)?;
+            let mut outputs =
+                Self::write_merged(stream, last_level, retention, option, table_store.as_ref())
+                    .await?;
+            outputs.append(&mut version_edits);
+
+            version_set
+                .apply_edits(outputs, Some(delete_gens), false)
+                .await
+                .map_err(CompactionError::Version)?;
+        }
+        Ok(())
+    }
+
+    /// Once there are `max_runs` sorted runs, the newest ones for as long as the next is at most
+    /// `size_ratio` percent larger than those picked so far, and at least two. The merged run
+    /// replaces the oldest one picked, so that it stays below the newer runs and above the older
+    /// ones: picking a level 0 table picks the older ones too, and their merge goes to the
+    /// deepest empty level above the runs below, merging the run below in when there is none.
+    #[allow(clippy::type_complexity)]
+    fn pick_runs(
+        version: &Version<S>,
+        max_runs: usize,
+        size_ratio: u64,
+    ) -> Option<(
+        RangeInclusive<usize>,
+        Vec<Scope<S::PrimaryKey>>,
+        Vec<Scope<S::PrimaryKey>>,
+    )> {
+        if version.sorted_runs() < max_runs.max(2) {
+            return None;
+        }
+        let runs = version.level_slice[0]
+            .iter()
+            .rev()
+            .map(|scope| (0, std::slice::from_ref(scope)))
+            .chain(
+                version.level_slice[1..]
                     .iter()
-                    .map(|scope| scope.gen)
+                    .enumerate()
+                    .filter(|(_, scopes)| !scopes.is_empty())
+                    .map(|(level, scopes)| (level + 1, scopes.as_slice())),
+            )
+            .collect::<Vec<_>>();
+        let size = |(_, scopes): &(usize, &[Scope<S::PrimaryKey>])| {
+            scopes.iter().map(|scope| scope.stats.size).sum::<u64>()
+        };
+
+        let mut picked = 1;
+        let mut picked_size = size(&runs[0]);
+        while picked < runs.len() && size(&runs[picked]) * 100 <= picked_size * (100 + size_ratio) {
+            picked_size += size(&runs[picked]);
+            picked += 1;
+        }
+        picked = picked.max(2);
+        let last_level = loop {
+            match (runs[picked - 1].0, runs.get(picked)) {
+                (0, Some((0, _))) | (0, Some((1, _))) => picked += 1,
+                (0, Some((level, _))) => break level - 1,
+                (0, None) => break MAX_LEVEL - 1,
+                (level, _) => break level,
+            }
+        };
+
+        let inputs = runs[..picked]
+            .iter()
+            .flat_map(|(_, scopes)| scopes.iter().cloned())
+            .collect();
+        Some((runs[0].0..=last_level, inputs, Vec::new()))
+    }
+
+    /// Compacts tables of each level into the level below, starting with the level 0 tables
+    /// overlapping `min..=max`, for as long as levels exceed their thresholds. Versions no read
+    /// at or above `retention` can see are dropped, see [`Retention`].
+    pub(crate) async fn major_compaction(
+        version_set: &VersionSet<S>,
+        option: &Arc<DbOption>,
+        table_store: &TableStoreRef,
+        mut min: S::PrimaryKey,
+        mut max: S::PrimaryKey,
+        retention: TimeStamp,
+    ) -> Result<(), CompactionError<S>> {
+        for level in 0..MAX_LEVEL - 2 {
+            let reservation = version_set
+                .reserve(level, |version| {
+                    if !option.is_threshold_exceeded_major(version, level) {
+                        return None;
+                    }
+                    Self::pick_inputs(version, level, &min, &max)
+                })
+                .await;
+            let Some(reservation) = reservation else {
+                break;
+            };
+            let mut version_edits = Vec::new();
+            let mut delete_gens = Vec::new();
+
+            Self::compact(
+                &reservation,
+                option,
+                table_store,
+                &mut version_edits,
+                &mut delete_gens,
+                retention,
+            )
+            .await?;
+            version_set
+                .apply_edits(version_edits, Some(delete_gens), false)
+                .await
+                .map_err(CompactionError::Version)?;
+
+            min = reservation.min.clone();
+            max = reservation.max.clone();
+        }
+
+        Ok(())
+    }
+
+    /// Compacts the table `gen` into the level below, e.g. to rewrite a table reads found damaged
+    /// or one lookups keep reading past without finding their key. The tables of the level below
+    /// it overlaps and, on level 0, the tables it overlaps there are compacted along with it.
+    /// Nothing is done when the table is gone or a running compaction reserved it, which rewrites
+    /// it as well.
+    pub(crate) async fn rewrite_table(
+        version_set: &VersionSet<S>,
+        option: &Arc<DbOption>,
+        table_store: &TableStoreRef,
+        gen: ProcessUniqueId,
+        retention: TimeStamp,
+    ) -> Result<(), CompactionError<S>> {
+        let find = |version: &Version<S>| {
+            version.level_slice[..MAX_LEVEL - 1]
+                .iter()
+                .enumerate()
+                .find_map(|(level, scopes)| {
+                    scopes
+                        .iter()
+                        .find(|scope| scope.gen == gen)
+                        .map(|scope| (level, scope.clone()))
+                })
+        };
+        let Some((level, _)) = find(&*version_set.current().await) else {
+            return Ok(());
+        };
+        let reservation = version_set
+            .reserve(level, |version| {
+                let (_, scope) = find(version).filter(|(found, _)| *found == level)?;
+                if level == 0 {
+                    return Self::pick_inputs(version, 0, &scope.min, &scope.max);
+                }
+                let next_inputs =
+                    Self::overlapping(&version.level_slice[level + 1], &scope.min, &scope.max)
+                        .cloned()
+                        .collect();
+                Some((vec![scope], next_inputs))
+            })
+            .await;
+        let Some(reservation) = reservation else {
+            return Ok(());
+        };
+        let mut version_edits = Vec::new();
+        let mut delete_gens = Vec::new();
+
+        Self::compact(
+            &reservation,
+            option,
+            table_store,
+            &mut version_edits,
+            &mut delete_gens,
+            retention,
+        )
+        .await?;
+        version_set
+            .apply_edits(version_edits, Some(delete_gens), false)
+            .await
+            .map_err(CompactionError::Version)
+    }
+
+    /// Compacts the tables overlapping `min..=max` level by level down to the last one, where the
+    /// deletions older than `retention` are dropped. Levels another compaction is running on are
+    /// skipped.
+    pub(crate) async fn compact_key_range(
+        version_set: &VersionSet<S>,
+        option: &Arc<DbOption>,
+        table_store: &TableStoreRef,
+        min: S::PrimaryKey,
+        max: S::PrimaryKey,
+        retention: TimeStamp,
+    ) -> Result<(), CompactionError<S>> {
+        for level in 0..MAX_LEVEL - 1 {
+            let reservation = version_set
+                .reserve(level, |version| {
+                    if level == 0 {
+                        return Self::pick_inputs(version, 0, &min, &max);
+                    }
+                    let inputs = Self::overlapping(&version.level_slice[level], &min, &max)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    let lower = inputs.first()?.min.clone();
+                    let upper = inputs.last()?.max.clone();
+                    let next_inputs =
+                        Self::overlapping(&version.level_slice[level + 1], &lower, &upper)
+                            .cloned()
+                            .collect();
+                    Some((inputs, next_inputs))
+                })
+                .await;
+            let Some(reservation) = reservation else {
+                continue;
+            };
+            let mut version_edits = Vec::new();
+            let mut delete_gens = Vec::new();
+
+            Self::compact(
+                &reservation,
+                option,
+                table_store,
+                &mut version_edits,
+                &mut delete_gens,
+                retention,
+            )
+            .await?;
+            version_set
+                .apply_edits(version_edits, Some(delete_gens), false)
+                .await
+                .map_err(CompactionError::Version)?;
+        }
+        Ok(())
+    }
+
+    /// On level 0, whose tables overlap each other, every table overlapping `min..=max`, widened
+    /// until no table left out overlaps the inputs, an older version of one of their keys could
+    /// otherwise outlive the newer one compacted into level 1. Deeper, the one table whose
+    /// compaction is estimated to reclaim the most bytes, counting the tables of the level below
+    /// it overlaps. Then the tables of the level below overlapping the inputs.
+    fn pick_inputs(
+        version: &Version<S>,
+        level: usize,
+        min: &S::PrimaryKey,
+        max: &S::PrimaryKey,
+    ) -> Option<(Vec<Scope<S::PrimaryKey>>, Vec<Scope<S::PrimaryKey>>)> {
+        let next_level = &version.level_slice[level + 1];
+        let inputs = if level == 0 {
+            let mut inputs = Self::overlapping(&version.level_slice[0], min, max)
+                .cloned()
+                .collect::<Vec<_>>();
+            loop {
+                let min = S::Comparator::min(inputs.iter().map(|scope| &scope.min))?;
+                let max = S::Comparator::max(inputs.iter().map(|scope| &scope.max))?;
+                let widened = Self::overlapping(&version.level_slice[0], min, max)
+                    .cloned()
                     .collect::<Vec<_>>();
-                streams.push(EStreamImpl::Level(
-                    LevelStream::new(option, gens, Some(min), Some(max))
+                if widened.len() == inputs.len() {
+                    break inputs;
+                }
+                inputs = widened;
+            }
+        } else {
+            let seed = version.level_slice[level].iter().max_by_key(|scope| {
+                scope.stats.garbage_bytes()
+                    + Self::overlapping(next_level, &scope.min, &scope.max)
+                        .map(|scope| scope.stats.garbage_bytes())
+                        .sum::<u64>()
+            })?;
+            vec![seed.clone()]
+        };
+        let min = S::Comparator::min(inputs.iter().map(|scope| &scope.min))?;
+        let max = S::Comparator::max(inputs.iter().map(|scope| &scope.max))?;
+
+        let next_inputs = Self::overlapping(next_level, min, max).cloned().collect();
+        Some((inputs, next_inputs))
+    }
+
+    fn overlapping<'a>(
+        scopes: &'a [Scope<S::PrimaryKey>],
+        min: &'a S::PrimaryKey,
+        max: &'a S::PrimaryKey,
+    ) -> impl Iterator<Item = &'a Scope<S::PrimaryKey>> {
+        scopes.iter().filter(move |scope| {
+            S::Comparator::compare(&scope.min, max).is_le()
+                && S::Comparator::compare(min, &scope.max).is_le()
+        })
+    }
+
+    /// Splits the reserved key range at table boundaries into up to `max_subcompactions`
+    /// sub-ranges, compacts them on separate tasks and stitches their tables back in key order.
+    async fn compact(
+        reservation: &Reservation<S>,
+        option: &Arc<DbOption>,
+        table_store: &TableStoreRef,
+        version_edits: &mut Vec<VersionEdit<S::PrimaryKey>>,
+        delete_gens: &mut Vec<ProcessUniqueId>,
+        retention: TimeStamp,
+    ) -> Result<(), CompactionError<S>> {
+        let level = reservation.level;
+        let splits = Self::split_points(reservation, option.max_subcompactions);
+        let retention = Retention {
+            below: retention,
+            bottommost: reservation.version.level_slice[level + 2..]
+                .iter()
+                .all(|scopes| {
+                    Self::overlapping(scopes, &reservation.min, &reservation.max)
+                        .next()
+                        .is_none()
+                })
+                && (level > 0 || !Self::leaves_out_level_0(reservation)),
+        };
+
+        let mut lower = Bound::Unbounded;
+        let mut tasks = Vec::with_capacity(splits.len() + 1);
+        for upper in splits
+            .into_iter()
+            .map(Bound::Excluded)
+            .chain([Bound::Unbounded])
+        {
+            let next_lower = match &upper {
+                Bound::Excluded(split) => Bound::Included(split.clone()),
+                _ => Bound::Unbounded,
+            };
+            let inputs = Self::inputs_within(&reservation.inputs, &lower, &upper);
+            let next_inputs = Self::inputs_within(&reservation.next_inputs, &lower, &upper);
+            let option = option.clone();
+            let table_store = table_store.clone();
+
+            tasks.push(spawn(async move {
+                Self::compact_range(
+                    level,
+                    inputs,
+                    next_inputs,
+                    (lower, upper),
+                    retention,
+                    &option,
+                    table_store.as_ref(),
+                )
+                .await
+            }));
+            lower = next_lower;
+        }
+        for task in tasks {
+            version_edits.extend(task.await?);
+        }
+
+        for scope in reservation.inputs.iter() {
+            version_edits.push(VersionEdit::Remove {
+                level: level as u8,
+                gen: scope.gen,
+            });
+            delete_gens.push(scope.gen);
+        }
+        for scope in reservation.next_inputs.iter() {
+            version_edits.push(VersionEdit::Remove {
+                level: (level + 1) as u8,
+                gen: scope.gen,
+            });
+            delete_gens.push(scope.gen);
+        }
+        Ok(())
+    }
+
+    /// Whether a level 0 table the reservation left out overlaps its level 0 inputs. An older
+    /// version such a table holds of one of their keys would outlive a tombstone dropped from them.
+    fn leaves_out_level_0(reservation: &Reservation<S>) -> bool {
+        let inputs = &reservation.inputs;
+        let (Some(min), Some(max)) = (
+            S::Comparator::min(inputs.iter().map(|scope| &scope.min)),
+            S::Comparator::max(inputs.iter().map(|scope| &scope.max)),
+        ) else {
+            return false;
+        };
+        Self::overlapping(&reservation.version.level_slice[0], min, max)
+            .any(|scope| inputs.iter().all(|input| input.gen != scope.gen))
+    }
+
+    /// Evenly spaced table min keys, each starting a sub-range.
+    fn split_points(reservation: &Reservation<S>, subcompactions: usize) -> Vec<S::PrimaryKey> {
+        let mut candidates = reservation
+            .inputs
+            .iter()
+            .chain(reservation.next_inputs.iter())
+            .map(|scope| &scope.min)
+            .filter(|min| S::Comparator::compare(min, &reservation.min).is_gt())
+            .collect::<Vec<_>>();
+        candidates.sort_by(|lhs, rhs| S::Comparator::compare(lhs, rhs));
+        candidates.dedup();
+
+        let ranges = subcompactions.clamp(1, candidates.len() + 1);
+        let mut splits = (1..ranges)
+            .map(|i| candidates[i * (candidates.len() + 1) / ranges - 1].clone())
+            .collect::<Vec<_>>();
+        splits.dedup();
+        splits
+    }
+
+    fn inputs_within(
+        scopes: &[Scope<S::PrimaryKey>],
+        lower: &Bound<S::PrimaryKey>,
+        upper: &Bound<S::PrimaryKey>,
+    ) -> Vec<ProcessUniqueId> {
+        scopes
+            .iter()
+            .filter(|scope| scope.overlaps::<S::Comparator>(lower.as_ref(), upper.as_ref()))
+            .map(|scope| scope.gen)
+            .collect()
+    }
+
+    async fn compact_range(
+        level: usize,
+        inputs: Vec<ProcessUniqueId>,
+        next_inputs: Vec<ProcessUniqueId>,
+        (lower, upper): (Bound<S::PrimaryKey>, Bound<S::PrimaryKey>),
+        retention: Retention,
+        option: &DbOption,
+        table_store: &dyn TableStore,
+    ) -> Result<Vec<VersionEdit<S::PrimaryKey>>, CompactionError<S>> {
+        let (lower, upper) = (lower.as_ref(), upper.as_ref());
+        let mut streams = Vec::with_capacity(inputs.len() + 1);
+
+        // This Level
+        if level == 0 {
+            for gen in inputs.iter().rev() {
+                streams.push(EStreamImpl::Table(
+                    TableStream::new(table_store, gen, lower, upper, TimeStamp::MAX, None)
                         .await
                         .map_err(CompactionError::Stream)?,
                 ));
             }
-            // Next Level
-            let gens = meet_scopes_ll
-                .iter()
-                .map(|scope| scope.gen)
-                .collect::<Vec<_>>();
+        } else {
             streams.push(EStreamImpl::Level(
-                LevelStream::new(option, gens, None, None)
+                LevelStream::new(table_store, inputs, lower, upper, TimeStamp::MAX, None)
                     .await
                     .map_err(CompactionError::Stream)?,
             ));
-            let stream = MergeStream::<S>::new(streams)
+        }
+        // Next Level
+        streams.push(EStreamImpl::Level(
+            LevelStream::new(table_store, next_inputs, lower, upper, TimeStamp::MAX, None)
                 .await
-                .map_err(CompactionError::Stream)?;
+                .map_err(CompactionError::Stream)?,
+        ));
+        let stream = MergeStream::<S>::new(streams)
+            .await
+            .map_err(CompactionError::Stream)?;
 
-            let mut stream = pin!(stream);
-            let mut builder = S::builder();
-            let mut written_size = 0;
-            let mut min = None;
-            let mut max = None;
+        Self::write_merged(stream, level + 1, retention, option, table_store).await
+    }
+
+    /// Writes the versions of `stream` that `retention` keeps into tables of `level`. Operands at
+    /// or below `retention.below` are folded as far as the inputs go, and onto nothing where no
+    /// deeper level is left.
+    async fn write_merged(
+        stream: MergeStream<'_, S>,
+        level: usize,
+        retention: Retention,
+        option: &DbOption,
+        table_store: &dyn TableStore,
+    ) -> Result<Vec<VersionEdit<S::PrimaryKey>>, CompactionError<S>> {
+        let mut version_edits = Vec::new();
+        let mut builder = S::builder();
+        let target_size = option.target_file_size(level);
+        let mut written_size = 0;
+        let mut stats = TableStats::new();
+        let mut min = None;
+        let mut max = None;
+        let mut current = None;
+        let mut retained = false;
+        let mut stream = stream.keep_versions(retention.below);
 
-            while let Some(result) = stream.next().await {
-                let (key, value) = result.map_err(CompactionError::Stream)?;
-                if min.is_none() {
-                    min = Some(key.clone())
+        while let Some(result) = stream.next_versioned().await {
+            let (key, ts, mut value) = result.map_err(CompactionError::Stream)?;
+            if retention.bottommost && ts <= retention.below {
+                value = value.map(|value| match value.is_operand() {
+                    true => S::merge(None, &value),
+                    false => value,
+                });
+            }
+            if current.as_ref() != Some(&key) {
+                current = Some(key.clone());
+                retained = false;
+            }
+            if ts <= retention.below {
+                // the newest version at or below `retention` is the one the oldest reads see, it
+                // shadows the older ones for every read
+                if mem::replace(&mut retained, true) {
+                    continue;
                 }
-                max = Some(key.clone());
-
-                written_size += key.size();
-                builder.add(&key, value);
-
-                if written_size >= option.max_sst_file_size {
-                    Self::build_table(
-                        option,
-                        version_edits,
-                        level,
-                        &mut builder,
-                        &mut min,
-                        &mut max,
-                    )?;
-                    written_size = 0;
+                if value.is_none() && retention.bottommost {
+                    continue;
                 }
             }
-            if written_size > 0 {
+            // tables are only cut between keys, so that all versions of a key stay in the
+            // one table a lookup picks for it
+            if written_size >= target_size && max.as_ref() != Some(&key) {
                 Self::build_table(
-                    option,
-                    version_edits,
+                    table_store,
+                    &mut version_edits,
                     level,
                     &mut builder,
+                    &mut stats,
                     &mut min,
                     &mut max,
-                )?;
+                )
+                .await?;
+                written_size = 0;
             }
-            for scope in meet_scopes_l {
-                version_edits.push(VersionEdit::Remove {
-                    level: level as u8,
-                    gen: scope.gen,
-                });
-                delete_gens.push(scope.gen);
+            if min.is_none() {
+                min = Some(key.clone())
             }
-            for scope in meet_scopes_ll {
-                version_edits.push(VersionEdit::Remove {
-                    level: (level + 1) as u8,
-                    gen: scope.gen,
-                });
-                delete_gens.push(scope.gen);
-            }
-            level += 1;
-        }
+            // the stream yields the newest version of a key first
+            stats.add(value.is_none(), max.as_ref() == Some(&key));
+            max = Some(key.clone());
 
-        Ok(())
+            written_size += key.size() + value.as_ref().map_or(0, Encode::size);
+            builder.add(&key, ts, Op::of(value.as_ref()), value);
+        }
+        if written_size > 0 {
+            Self::build_table(
+                table_store,
+                &mut version_edits,
+                level,
+                &mut builder,
+                &mut stats,
+                &mut min,
+                &mut max,
+            )
+            .await?;
+        }
+        Ok(version_edits)
     }
 
-    fn build_table(
-        option: &DbOption,
+    async fn build_table(
+        table_store: &dyn TableStore,
         version_edits: &mut Vec<VersionEdit<S::PrimaryKey>>,
         level: usize,
         builder: &mut S::Builder,
+        stats: &mut TableStats,
         min: &mut Option<S::PrimaryKey>,
         max: &mut Option<S::PrimaryKey>,
     ) -> Result<(), CompactionError<S>> {
@@ -287,25 +992,49 @@ where
         assert!(max.is_some());
 
         let gen = ProcessUniqueId::new();
-        let batch = builder.finish();
-        let mut writer = ArrowWriter::try_new(
-            File::create(option.table_path(&gen)).map_err(CompactionError::Io)?,
-            S::inner_schema(),
-            None,
-        )
-        .map_err(CompactionError::Parquet)?;
-        writer.write(&batch).map_err(CompactionError::Parquet)?;
-        writer.close().map_err(CompactionError::Parquet)?;
+        let size = Self::write_table(table_store, &gen, &builder.finish()).await?;
         version_edits.push(VersionEdit::Add {
-            level: (level + 1) as u8,
+            level: level as u8,
             scope: Scope {
                 min: min.take().ok_or(CompactionError::EmptyLevel)?,
                 max: max.take().ok_or(CompactionError::EmptyLevel)?,
                 gen,
+                stats: TableStats {
+                    size,
+                    ..mem::replace(stats, TableStats::new())
+                },
             },
         });
         Ok(())
     }
+
+    async fn write_table(
+        table_store: &dyn TableStore,
+        gen: &ProcessUniqueId,
+        batch: &RecordBatch,
+    ) -> Result<u64, CompactionError<S>> {
+        let schema = S::inner_schema();
+        let mut writer =
+            ArrowWriter::try_new(Vec::new(), schema.clone(), Some(writer_properties(&schema)))
+                .map_err(CompactionError::Parquet)?;
+        writer.write(batch).map_err(CompactionError::Parquet)?;
+        let bytes = writer.into_inner().map_err(CompactionError::Parquet)?;
+        let size = bytes.len() as u64;
+
+        table_store
+            .create_table(gen, Bytes::from(bytes))
+            .await
+            .map_err(CompactionError::Io)?;
+        Ok(size)
+    }
+}
+
+/// Which versions a major compaction drops: all but the newest of each key at or below `below`,
+/// and that one too if it is a tombstone with nothing left to hide in deeper levels.
+#[derive(Debug, Clone, Copy)]
+struct Retention {
+    below: TimeStamp,
+    bottommost: bool,
 }
 
 #[derive(Debug, Error)]
@@ -320,74 +1049,64 @@ where
     #[error("compaction version error: {0}")]
     Version(#[source] VersionError<S>),
     #[error("compaction stream error: {0}")]
-    Stream(#[source] StreamError<S::PrimaryKey, S>),
+    Stream(#[source] ScanError<S::PrimaryKey, S>),
     #[error("the level being compacted does not have a table")]
     EmptyLevel,
+    #[error("the bulk loaded key range overlaps existing tables")]
+    Overlap,
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{
-        collections::{BTreeMap, VecDeque},
-        fs::File,
-    };
+    use std::{collections::VecDeque, sync::Arc};
 
     use executor::ExecutorBuilder;
     use futures::channel::mpsc::channel;
-    use parquet::arrow::ArrowWriter;
     use snowflake::ProcessUniqueId;
     use tempfile::TempDir;
 
     use crate::{
         compactor::Compactor,
+        counter::Counter,
         index_batch::IndexBatch,
-        mem_table::InternalKey,
+        mem_table::MemTable,
         schema,
-        schema::Builder,
-        scope::Scope,
-        tests::UserInner,
-        version::{edit::VersionEdit, Version},
+        schema::{Builder, Op, Schema},
+        scope::{Scope, TableStats},
+        tests::{user, UserInner},
+        version::{edit::VersionEdit, read::VersionRead, set::VersionSet, Version, MAX_LEVEL},
+        wal::provider::{fs::Fs, in_mem::InMemProvider, TableStore, TableStoreRef},
         DbOption,
     };
 
-    async fn build_index_batch<S>(items: Vec<(S, bool)>) -> IndexBatch<S>
+    async fn build_index_batch<S>(mut items: Vec<(S, bool)>) -> IndexBatch<S>
     where
         S: schema::Schema,
     {
-        let mut index = BTreeMap::new();
         let mut builder = S::builder();
 
-        for (offset, (schema, is_deleted)) in items.into_iter().enumerate() {
-            index.insert(
-                InternalKey {
-                    key: schema.primary_key(),
-                    ts: 0,
-                },
-                offset as u32,
-            );
-            builder.add(&schema.primary_key(), is_deleted.then(|| schema));
+        items.sort_by_key(|(schema, _)| schema.primary_key());
+        for (schema, is_deleted) in items {
+            let primary_key = schema.primary_key();
+            let value = is_deleted.then(|| schema);
+            builder.add(&primary_key, 0, Op::of(value.as_ref()), value);
         }
 
         let batch = builder.finish();
 
-        IndexBatch { batch, index }
+        IndexBatch::new(batch)
     }
 
     async fn build_parquet_table<S: schema::Schema>(
-        option: &DbOption,
+        table_store: &dyn TableStore,
         gen: ProcessUniqueId,
         items: Vec<(S, bool)>,
     ) {
         let batch = build_index_batch(items).await;
 
-        let mut writer = ArrowWriter::try_new(
-            File::create(option.table_path(&gen)).unwrap(),
-            S::inner_schema(),
-            None,
-        )
-        .unwrap();
-        writer.write(&batch.batch).unwrap();
-        writer.close().unwrap();
+        Compactor::<S>::write_table(table_store, &gen, &batch.batch)
+            .await
+            .unwrap();
     }
 
     #[test]
@@ -395,7 +1114,7 @@ mod tests {
         let temp_dir = TempDir::new().unwrap();
 
         ExecutorBuilder::new().build().unwrap().block_on(async {
-            let option = DbOption::new(temp_dir.path().to_path_buf());
+            let store = Fs::new(temp_dir.path()).unwrap();
 
             let batch_1 = build_index_batch::<UserInner>(vec![
                 (
@@ -429,7 +1148,7 @@ mod tests {
             .await;
 
             let scope = Compactor::<UserInner>::minor_compaction(
-                &option,
+                &store,
                 VecDeque::from(vec![batch_2, batch_1]),
             )
             .await
@@ -440,6 +1159,46 @@ mod tests {
         })
     }
 
+    #[test]
+    fn query_version_from_table() {
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let store = InMemProvider::default();
+            let user = UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+
+            let mut mem_table = MemTable::default();
+            mem_table.insert(1, 0, Some(user.clone()));
+            mem_table.insert(1, 2, None);
+            let batches = mem_table.to_batches(usize::MAX);
+
+            let scope = Compactor::<UserInner>::minor_compaction(&store, VecDeque::from(batches))
+                .await
+                .unwrap()
+                .unwrap();
+            let (sender, _) = channel(1);
+            let mut version = Version::<UserInner> {
+                num: 0,
+                level_slice: Version::<UserInner>::level_slice_new(),
+                clean_sender: sender,
+            };
+            version.level_slice[0].push(scope);
+
+            let query = |ts| {
+                let version = &version;
+                let store = &store;
+                async move {
+                    VersionRead::new(version, store)
+                        .get(&1, ts)
+                        .await
+                        .unwrap()
+                        .map(|batch| UserInner::from_batch(&batch, 0).1)
+                }
+            };
+            assert_eq!(query(0).await, Some(Some(user.clone())));
+            assert_eq!(query(1).await, Some(Some(user)));
+            assert_eq!(query(2).await, Some(None));
+        })
+    }
+
     #[test]
     fn major_compaction() {
         let temp_dir = TempDir::new().unwrap();
@@ -447,12 +1206,15 @@ mod tests {
         ExecutorBuilder::new().build().unwrap().block_on(async {
             let mut option = DbOption::new(temp_dir.path().to_path_buf());
             option.major_threshold_with_sst_size = 2;
+            option.max_subcompactions = 2;
+            let option = Arc::new(option);
+            let store: TableStoreRef = Arc::new(Fs::new(temp_dir.path()).unwrap());
 
             // level 1
             let table_gen_1 = ProcessUniqueId::new();
             let table_gen_2 = ProcessUniqueId::new();
             build_parquet_table(
-                &option,
+                &store,
                 table_gen_1,
                 vec![
                     (
@@ -471,7 +1233,7 @@ mod tests {
             )
             .await;
             build_parquet_table(
-                &option,
+                &store,
                 table_gen_2,
                 vec![
                     (
@@ -495,7 +1257,7 @@ mod tests {
             let table_gen_4 = ProcessUniqueId::new();
             let table_gen_5 = ProcessUniqueId::new();
             build_parquet_table(
-                &option,
+                &store,
                 table_gen_3,
                 vec![
                     (
@@ -514,7 +1276,7 @@ mod tests {
             )
             .await;
             build_parquet_table(
-                &option,
+                &store,
                 table_gen_4,
                 vec![
                     (
@@ -533,7 +1295,7 @@ mod tests {
             )
             .await;
             build_parquet_table(
-                &option,
+                &store,
                 table_gen_5,
                 vec![
                     (
@@ -552,80 +1314,563 @@ mod tests {
             )
             .await;
 
-            let (sender, _) = channel(1);
+            // every replaced version reports to the cleaner, which this test does not run
+            let (sender, _receiver) = channel(16);
+            let version_set = VersionSet::<UserInner>::new(&option, sender).await.unwrap();
+            version_set
+                .apply_edits(
+                    vec![
+                        VersionEdit::Add {
+                            level: 0,
+                            scope: Scope {
+                                min: 1,
+                                max: 3,
+                                gen: table_gen_1,
+                                stats: Default::default(),
+                            },
+                        },
+                        VersionEdit::Add {
+                            level: 0,
+                            scope: Scope {
+                                min: 4,
+                                max: 6,
+                                gen: table_gen_2,
+                                stats: Default::default(),
+                            },
+                        },
+                        VersionEdit::Add {
+                            level: 1,
+                            scope: Scope {
+                                min: 1,
+                                max: 3,
+                                gen: table_gen_3,
+                                stats: Default::default(),
+                            },
+                        },
+                        VersionEdit::Add {
+                            level: 1,
+                            scope: Scope {
+                                min: 4,
+                                max: 6,
+                                gen: table_gen_4,
+                                stats: Default::default(),
+                            },
+                        },
+                        VersionEdit::Add {
+                            level: 1,
+                            scope: Scope {
+                                min: 7,
+                                max: 9,
+                                gen: table_gen_5,
+                                stats: Default::default(),
+                            },
+                        },
+                    ],
+                    None,
+                    false,
+                )
+                .await
+                .unwrap();
 
-            let mut version = Version::<UserInner> {
-                num: 0,
-                level_slice: Version::<UserInner>::level_slice_new(),
-                clean_sender: sender,
+            Compactor::<UserInner>::major_compaction(&version_set, &option, &store, 2, 5, 0)
+                .await
+                .unwrap();
+
+            let version = version_set.current().await;
+            assert!(version.level_slice[0].is_empty());
+            // split at the first key of the tables starting at 4
+            assert_eq!(version.level_slice[1].len(), 3);
+            assert_eq!(version.level_slice[1][0].min, 1);
+            assert_eq!(version.level_slice[1][0].max, 3);
+            assert_eq!(version.level_slice[1][1].min, 4);
+            assert_eq!(version.level_slice[1][1].max, 6);
+            assert_eq!(
+                version.level_slice[1][2],
+                Scope {
+                    min: 7,
+                    max: 9,
+                    gen: table_gen_5,
+                    stats: Default::default(),
+                }
+            );
+        })
+    }
+
+    #[test]
+    fn drop_shadowed_versions() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let mut option = DbOption::new(temp_dir.path().to_path_buf());
+            option.major_threshold_with_sst_size = 1;
+            let option = Arc::new(option);
+            let store: TableStoreRef = Arc::new(InMemProvider::default());
+            let user = |ts: u64| UserInner::new(1, ts.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+
+            let mut mem_table = MemTable::default();
+            for ts in [0, 1, 3] {
+                mem_table.insert(1, ts, Some(user(ts)));
+            }
+            mem_table.insert(2, 0, Some(user(0)));
+            mem_table.insert(2, 2, None);
+            mem_table.insert(3, 1, None);
+            let gen = ProcessUniqueId::new();
+            Compactor::<UserInner>::write_table(
+                store.as_ref(),
+                &gen,
+                &mem_table.to_batches(usize::MAX).remove(0).batch,
+            )
+            .await
+            .unwrap();
+
+            let (sender, _receiver) = channel(16);
+            let version_set = VersionSet::<UserInner>::new(&option, sender).await.unwrap();
+            version_set
+                .apply_edits(
+                    vec![VersionEdit::Add {
+                        level: 0,
+                        scope: Scope {
+                            min: 1,
+                            max: 3,
+                            gen,
+                            stats: Default::default(),
+                        },
+                    }],
+                    None,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            // reads at 2 see the version of 1 at 1 and nothing of 2 and 3
+            Compactor::<UserInner>::major_compaction(&version_set, &option, &store, 1, 3, 2)
+                .await
+                .unwrap();
+
+            let version = version_set.current().await;
+            assert_eq!(version.level_slice[1].len(), 1);
+            assert_eq!(version.level_slice[1][0].min, 1);
+            assert_eq!(version.level_slice[1][0].max, 1);
+
+            let query = |ts| {
+                let version = &version;
+                let store = &store;
+                async move {
+                    VersionRead::new(version, store.as_ref())
+                        .get(&1, ts)
+                        .await
+                        .unwrap()
+                        .map(|batch| UserInner::from_batch(&batch, 0).1)
+                }
             };
-            version.level_slice[0].push(Scope {
-                min: 1,
-                max: 3,
-                gen: table_gen_1,
-            });
-            version.level_slice[0].push(Scope {
-                min: 4,
-                max: 6,
-                gen: table_gen_2,
-            });
-            version.level_slice[1].push(Scope {
-                min: 1,
-                max: 3,
-                gen: table_gen_3,
-            });
-            version.level_slice[1].push(Scope {
-                min: 4,
-                max: 6,
-                gen: table_gen_4,
-            });
-            version.level_slice[1].push(Scope {
-                min: 7,
-                max: 9,
-                gen: table_gen_5,
-            });
+            assert_eq!(query(3).await, Some(Some(user(3))));
+            assert_eq!(query(2).await, Some(Some(user(1))));
+            assert_eq!(query(0).await, None);
+        })
+    }
 
-            let min = 2;
-            let max = 5;
-            let mut version_edits = Vec::new();
+    #[test]
+    fn fold_operands() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let mut option = DbOption::new(temp_dir.path().to_path_buf());
+            option.major_threshold_with_sst_size = 1;
+            let option = Arc::new(option);
+            let store: TableStoreRef = Arc::new(InMemProvider::default());
+            let (a, b) = ("a".to_string(), "b".to_string());
+
+            let mut mem_table = MemTable::default();
+            mem_table.insert(a.clone(), 0, Some(Counter::new("a", 10)));
+            mem_table.insert(a.clone(), 1, Some(Counter::increment("a", 2)));
+            mem_table.insert(a.clone(), 3, Some(Counter::increment("a", 3)));
+            mem_table.insert(b.clone(), 0, Some(Counter::increment("b", 1)));
+            mem_table.insert(b.clone(), 1, Some(Counter::increment("b", 1)));
+            let gen = ProcessUniqueId::new();
+            Compactor::<Counter>::write_table(
+                store.as_ref(),
+                &gen,
+                &mem_table.to_batches(usize::MAX).remove(0).batch,
+            )
+            .await
+            .unwrap();
 
-            Compactor::<UserInner>::major_compaction(
-                &version,
+            let (sender, _receiver) = channel(16);
+            let version_set = VersionSet::<Counter>::new(&option, sender).await.unwrap();
+            version_set
+                .apply_edits(
+                    vec![VersionEdit::Add {
+                        level: 0,
+                        scope: Scope {
+                            min: a.clone(),
+                            max: b.clone(),
+                            gen,
+                            stats: Default::default(),
+                        },
+                    }],
+                    None,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            // reads at 2 see the increment of a at 1 folded onto its value, b has nothing left
+            // to fold onto in deeper levels
+            Compactor::<Counter>::major_compaction(
+                &version_set,
                 &option,
-                &min,
-                &max,
-                &mut version_edits,
-                &mut vec![],
+                &store,
+                a.clone(),
+                b.clone(),
+                2,
             )
             .await
             .unwrap();
 
-            if let VersionEdit::Add { level, scope } = &version_edits[0] {
-                assert_eq!(*level, 1);
-                assert_eq!(scope.min, 1);
-                assert_eq!(scope.max, 6);
-            }
+            let version = version_set.current().await;
+            let query = |key: &String, ts| {
+                let version = &version;
+                let store = &store;
+                let key = key.clone();
+                async move {
+                    VersionRead::new(version, store.as_ref())
+                        .get(&key, ts)
+                        .await
+                        .unwrap()
+                        .map(|batch| Counter::from_batch(&batch, 0).1)
+                }
+            };
+            assert_eq!(query(&a, 3).await, Some(Some(Counter::increment("a", 3))));
+            assert_eq!(query(&a, 2).await, Some(Some(Counter::new("a", 12))));
+            assert_eq!(query(&a, 0).await, None);
+            assert_eq!(query(&b, 3).await, Some(Some(Counter::new("b", 2))));
+        })
+    }
+
+    #[test]
+    fn level_0_left_out() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let option = Arc::new(DbOption::new(temp_dir.path().to_path_buf()));
+            let (sender, _receiver) = channel(16);
+            let version_set = VersionSet::<UserInner>::new(&option, sender).await.unwrap();
+            let scope = |min, max| Scope {
+                min,
+                max,
+                gen: ProcessUniqueId::new(),
+                stats: Default::default(),
+            };
+            version_set
+                .apply_edits(
+                    vec![
+                        VersionEdit::Add {
+                            level: 0,
+                            scope: scope(1, 3),
+                        },
+                        VersionEdit::Add {
+                            level: 0,
+                            scope: scope(2, 2),
+                        },
+                        VersionEdit::Add {
+                            level: 0,
+                            scope: scope(5, 6),
+                        },
+                    ],
+                    None,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            // the older table over 1..=3 holds versions the newer one over 2 may remove
+            let reservation = version_set
+                .reserve(0, |version| {
+                    Some((vec![version.level_slice[0][1].clone()], Vec::new()))
+                })
+                .await
+                .unwrap();
+            assert!(Compactor::<UserInner>::leaves_out_level_0(&reservation));
+            drop(reservation);
+
+            let reservation = version_set
+                .reserve(0, |version| {
+                    Some((version.level_slice[0][..2].to_vec(), Vec::new()))
+                })
+                .await
+                .unwrap();
+            assert!(!Compactor::<UserInner>::leaves_out_level_0(&reservation));
+        })
+    }
+
+    #[test]
+    fn rewrite_table() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let option = Arc::new(DbOption::new(temp_dir.path().to_path_buf()));
+            let store: TableStoreRef = Arc::new(InMemProvider::default());
+
+            let (damaged, other) = (ProcessUniqueId::new(), ProcessUniqueId::new());
+            build_parquet_table::<UserInner>(
+                store.as_ref(),
+                damaged,
+                vec![(user(1), true), (user(2), true)],
+            )
+            .await;
+            build_parquet_table::<UserInner>(store.as_ref(), other, vec![(user(5), true)]).await;
+            let (sender, _receiver) = channel(16);
+            let version_set = VersionSet::<UserInner>::new(&option, sender).await.unwrap();
+            let add = |min, max, gen| VersionEdit::Add {
+                level: 1,
+                scope: Scope {
+                    min,
+                    max,
+                    gen,
+                    stats: Default::default(),
+                },
+            };
+            version_set
+                .apply_edits(vec![add(1, 2, damaged), add(5, 5, other)], None, false)
+                .await
+                .unwrap();
+
+            Compactor::<UserInner>::rewrite_table(&version_set, &option, &store, damaged, 0)
+                .await
+                .unwrap();
+
+            let version = version_set.current().await;
             assert_eq!(
-                version_edits[1..5].to_vec(),
-                vec![
-                    VersionEdit::Remove {
-                        level: 0,
-                        gen: table_gen_1,
-                    },
-                    VersionEdit::Remove {
-                        level: 0,
-                        gen: table_gen_2,
-                    },
-                    VersionEdit::Remove {
-                        level: 1,
-                        gen: table_gen_3,
-                    },
-                    VersionEdit::Remove {
-                        level: 1,
-                        gen: table_gen_4,
-                    },
-                ]
+                version.level_slice[1]
+                    .iter()
+                    .map(|scope| scope.gen)
+                    .collect::<Vec<_>>(),
+                vec![other]
+            );
+            assert_eq!(version.level_slice[2].len(), 1);
+            assert_eq!(
+                (version.level_slice[2][0].min, version.level_slice[2][0].max),
+                (1, 2)
+            );
+            let row = VersionRead::new(&version, store.as_ref())
+                .get(&2, 0)
+                .await
+                .unwrap()
+                .map(|batch| UserInner::from_batch(&batch, 0).1);
+            assert_eq!(row, Some(Some(user(2))));
+        })
+    }
+
+    #[test]
+    fn compact_key_range() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let option = Arc::new(DbOption::new(temp_dir.path().to_path_buf()));
+            let store: TableStoreRef = Arc::new(InMemProvider::default());
+
+            let (drained, other) = (ProcessUniqueId::new(), ProcessUniqueId::new());
+            build_parquet_table::<UserInner>(
+                store.as_ref(),
+                drained,
+                vec![(user(1), false), (user(2), false), (user(3), true)],
             )
+            .await;
+            build_parquet_table::<UserInner>(store.as_ref(), other, vec![(user(50), true)]).await;
+            let (sender, _receiver) = channel(16);
+            let version_set = VersionSet::<UserInner>::new(&option, sender).await.unwrap();
+            let add = |min, max, gen| VersionEdit::Add {
+                level: 1,
+                scope: Scope {
+                    min,
+                    max,
+                    gen,
+                    stats: Default::default(),
+                },
+            };
+            version_set
+                .apply_edits(vec![add(1, 3, drained), add(50, 50, other)], None, false)
+                .await
+                .unwrap();
+
+            Compactor::<UserInner>::compact_key_range(&version_set, &option, &store, 1, 2, 0)
+                .await
+                .unwrap();
+
+            let version = version_set.current().await;
+            assert_eq!(
+                version.level_slice[1]
+                    .iter()
+                    .map(|scope| scope.gen)
+                    .collect::<Vec<_>>(),
+                vec![other]
+            );
+            let bottom = &version.level_slice[MAX_LEVEL - 1];
+            assert_eq!(bottom.len(), 1);
+            assert_eq!((bottom[0].min, bottom[0].max), (3, 3));
+            let row = VersionRead::new(&version, store.as_ref())
+                .get(&1, 0)
+                .await
+                .unwrap();
+            assert!(row.is_none());
+        })
+    }
+
+    #[test]
+    fn tiered_compaction() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let option = Arc::new(DbOption::new(temp_dir.path().to_path_buf()));
+            let store: TableStoreRef = Arc::new(InMemProvider::default());
+            let user =
+                |name: &str| UserInner::new(1, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+            let (sender, _receiver) = channel(16);
+            let version_set = VersionSet::<UserInner>::new(&option, sender).await.unwrap();
+
+            for (ts, name) in [(1, "a"), (2, "b"), (3, "c")] {
+                let mut mem_table = MemTable::default();
+                mem_table.insert(1, ts, Some(user(name)));
+                mem_table.insert(ts + 1, ts, Some(user(name)));
+                let scope = Compactor::<UserInner>::minor_compaction(
+                    store.as_ref(),
+                    VecDeque::from(mem_table.to_batches(usize::MAX)),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+                version_set
+                    .apply_edits(vec![VersionEdit::Add { level: 0, scope }], None, false)
+                    .await
+                    .unwrap();
+            }
+            Compactor::<UserInner>::tiered_compaction(&version_set, &option, &store, 4, 0, 0)
+                .await
+                .unwrap();
+            assert_eq!(version_set.current().await.sorted_runs(), 3);
+
+            // the three tables merge into the deepest level, the versions of 1 but the newest
+            // are dropped
+            Compactor::<UserInner>::tiered_compaction(&version_set, &option, &store, 3, 0, 3)
+                .await
+                .unwrap();
+            let version = version_set.current().await;
+            assert_eq!(version.sorted_runs(), 1);
+            assert_eq!(version.level_slice[MAX_LEVEL - 1].len(), 1);
+            assert_eq!(version.level_slice[MAX_LEVEL - 1][0].stats.rows, 4);
+            let get = |key| {
+                let version = &version;
+                let store = &store;
+                async move {
+                    VersionRead::new(version, store.as_ref())
+                        .get(&key, 3)
+                        .await
+                        .unwrap()
+                        .map(|batch| UserInner::from_batch(&batch, 0).1)
+                }
+            };
+            assert_eq!(get(1).await, Some(Some(user("c"))));
+            assert_eq!(get(2).await, Some(Some(user("a"))));
         })
     }
+
+    #[test]
+    fn pick_runs() {
+        let (sender, _receiver) = channel(1);
+        let mut version = Version::<UserInner> {
+            num: 0,
+            level_slice: Version::<UserInner>::level_slice_new(),
+            clean_sender: sender,
+        };
+        let scope = |min, size| Scope {
+            min,
+            max: min,
+            gen: ProcessUniqueId::new(),
+            stats: TableStats {
+                size,
+                ..Default::default()
+            },
+        };
+        // runs, newest first: 10, 10, 30 on level 0, 100 on level 3 and 1000 on level 5
+        version.level_slice[0].push(scope(1, 30));
+        version.level_slice[0].push(scope(1, 10));
+        version.level_slice[0].push(scope(1, 10));
+        version.level_slice[3].push(scope(1, 100));
+        version.level_slice[5].push(scope(1, 1000));
+        let picked = |version: &Version<UserInner>, max_runs, size_ratio| {
+            Compactor::<UserInner>::pick_runs(version, max_runs, size_ratio)
+                .map(|(levels, inputs, _)| (levels, inputs.len()))
+        };
+
+        assert_eq!(picked(&version, 6, 0), None);
+        // level 0 goes into the empty level 2 above level 3
+        assert_eq!(picked(&version, 5, 0), Some((0..=2, 3)));
+        // the size ratio takes level 3 in as well
+        assert_eq!(picked(&version, 5, 100), Some((0..=3, 4)));
+
+        version.level_slice[1].push(scope(1, 100));
+        version.level_slice[0].truncate(2);
+        // no level is left between level 0 and level 1, the run of level 1 merges in
+        assert_eq!(picked(&version, 2, 0), Some((0..=1, 3)));
+    }
+
+    #[test]
+    fn pick_most_garbage() {
+        let (sender, _receiver) = channel(1);
+        let mut version = Version::<UserInner> {
+            num: 0,
+            level_slice: Version::<UserInner>::level_slice_new(),
+            clean_sender: sender,
+        };
+        let scope = |min, max, tombstones| Scope {
+            min,
+            max,
+            gen: ProcessUniqueId::new(),
+            stats: TableStats {
+                rows: 10,
+                tombstones,
+                shadowed: 0,
+                size: 1000,
+                created_at: 0,
+            },
+        };
+        version.level_slice[1].push(scope(1, 3, 1));
+        version.level_slice[1].push(scope(4, 6, 8));
+        version.level_slice[1].push(scope(7, 9, 2));
+        version.level_slice[2].push(scope(1, 5, 0));
+        version.level_slice[2].push(scope(6, 9, 0));
+
+        let (inputs, next_inputs) =
+            Compactor::<UserInner>::pick_inputs(&version, 1, &0, &0).unwrap();
+        assert_eq!(inputs, vec![version.level_slice[1][1].clone()]);
+        assert_eq!(next_inputs, version.level_slice[2]);
+    }
+
+    #[test]
+    fn pick_overlapping_level_0() {
+        let (sender, _receiver) = channel(1);
+        let mut version = Version::<UserInner> {
+            num: 0,
+            level_slice: Version::<UserInner>::level_slice_new(),
+            clean_sender: sender,
+        };
+        let scope = |min, max| Scope {
+            min,
+            max,
+            gen: ProcessUniqueId::new(),
+            stats: TableStats::default(),
+        };
+        // 1..=4 overlaps the range, 4..=8 overlaps that table only and 8..=9 overlaps 4..=8 only
+        version.level_slice[0].push(scope(8, 9));
+        version.level_slice[0].push(scope(4, 8));
+        version.level_slice[0].push(scope(1, 4));
+        version.level_slice[0].push(scope(12, 15));
+        version.level_slice[1].push(scope(9, 10));
+        version.level_slice[1].push(scope(11, 20));
+
+        let (inputs, next_inputs) =
+            Compactor::<UserInner>::pick_inputs(&version, 0, &2, &3).unwrap();
+        assert_eq!(inputs, version.level_slice[0][..3]);
+        assert_eq!(next_inputs, version.level_slice[1][..1]);
+    }
 }
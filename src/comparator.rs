@@ -0,0 +1,62 @@
+//! The order of primary keys in mem tables, tables and scans, see
+//! [`crate::schema::Schema::Comparator`].
+
+use std::{cmp::Ordering, ops::Bound};
+
+/// Orders the primary keys of a schema, e.g. case-insensitively or by a locale, without wrapping
+/// them in a newtype. Keys it finds equal must be equal, it may only order keys differently than
+/// their `Ord` does, breaking ties of a case-insensitive order by case for instance.
+///
+/// Ranges are read in this order too, so a range is given from its first key in this order to its
+/// last one.
+pub trait Comparator<K>: Send + Sync + 'static {
+    /// Whether the order is the one of `Ord`, which table scans then push their key bounds down
+    /// to the table reader with rather than filtering the rows read.
+    const NATIVE: bool = false;
+
+    fn compare(lhs: &K, rhs: &K) -> Ordering;
+
+    fn min<'a>(keys: impl IntoIterator<Item = &'a K>) -> Option<&'a K>
+    where
+        K: 'a,
+    {
+        keys.into_iter().min_by(|lhs, rhs| Self::compare(lhs, rhs))
+    }
+
+    fn max<'a>(keys: impl IntoIterator<Item = &'a K>) -> Option<&'a K>
+    where
+        K: 'a,
+    {
+        keys.into_iter().max_by(|lhs, rhs| Self::compare(lhs, rhs))
+    }
+
+    /// Whether `key` lies between the bounds.
+    fn contains(lower: Bound<&K>, upper: Bound<&K>, key: &K) -> bool {
+        let above = match lower {
+            Bound::Included(lower) => Self::compare(lower, key).is_le(),
+            Bound::Excluded(lower) => Self::compare(lower, key).is_lt(),
+            Bound::Unbounded => true,
+        };
+        above
+            && match upper {
+                Bound::Included(upper) => Self::compare(key, upper).is_le(),
+                Bound::Excluded(upper) => Self::compare(key, upper).is_lt(),
+                Bound::Unbounded => true,
+            }
+    }
+}
+
+/// Orders keys by their `Ord`, the comparator of schemas that pick none.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OrdComparator;
+
+impl<K> Comparator<K> for OrdComparator
+where
+    K: Ord,
+{
+    const NATIVE: bool = true;
+
+    fn compare(lhs: &K, rhs: &K) -> Ordering {
+        lhs.cmp(rhs)
+    }
+}
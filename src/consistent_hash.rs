@@ -1,4 +1,106 @@
+use std::{
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use async_lock::{RwLock, RwLockReadGuardArc, RwLockWriteGuardArc};
+use thiserror::Error;
+
 const JUMP: u64 = 1 << 31;
+/// Keys are hashed onto this many virtual nodes, which are what moves between shards.
+pub(crate) const VNODES: usize = 256;
+
+/// The shard out of `shards` that `key` lives on, before any virtual node is moved.
+pub(crate) fn shard_of<K: Hash>(key: &K, shards: usize) -> usize {
+    default_shard(vnode_of(key), shards)
+}
+
+pub(crate) fn vnode_of<K: Hash>(key: &K) -> usize {
+    jump_consistent_hash(fxhash::hash64(key), VNODES) as usize
+}
+
+fn default_shard(vnode: usize, shards: usize) -> usize {
+    jump_consistent_hash(fxhash::hash64(&vnode), shards) as usize
+}
+
+/// Maps virtual nodes to shards. Reads and writes hold the routes for as long as they rely on
+/// them, so that a virtual node only moves in between.
+#[derive(Debug)]
+pub(crate) struct Router {
+    routes: Arc<RwLock<()>>,
+    shards: Vec<AtomicUsize>,
+    writes: Vec<AtomicU64>,
+}
+
+impl Router {
+    pub(crate) fn new(shards: usize) -> Self {
+        Router {
+            routes: Arc::new(RwLock::new(())),
+            shards: (0..VNODES)
+                .map(|vnode| AtomicUsize::new(default_shard(vnode, shards)))
+                .collect(),
+            writes: (0..VNODES).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Owned, so that the tasks applying a write can hold the routes past the write itself.
+    pub(crate) async fn read(&self) -> RwLockReadGuardArc<()> {
+        self.routes.read_arc().await
+    }
+
+    /// Owned, so that the task moving a virtual node holds the routes until the move is done.
+    pub(crate) async fn write(&self) -> RwLockWriteGuardArc<()> {
+        self.routes.write_arc().await
+    }
+
+    pub(crate) fn shard_of<K: Hash>(&self, key: &K) -> usize {
+        self.shard_of_vnode(vnode_of(key))
+    }
+
+    /// Like [`Router::shard_of`], counting the write to the key's virtual node.
+    pub(crate) fn route_write<K: Hash>(&self, key: &K) -> usize {
+        let vnode = vnode_of(key);
+        self.writes[vnode].fetch_add(1, Ordering::Relaxed);
+        self.shard_of_vnode(vnode)
+    }
+
+    pub(crate) fn shard_of_vnode(&self, vnode: usize) -> usize {
+        self.shards[vnode].load(Ordering::Acquire)
+    }
+
+    /// Must be called while holding the routes for writing.
+    pub(crate) fn assign(&self, vnode: usize, shard: usize) {
+        self.shards[vnode].store(shard, Ordering::Release)
+    }
+
+    /// The shard and the writes so far of every virtual node.
+    pub(crate) fn vnodes(&self) -> Vec<(usize, u64)> {
+        self.shards
+            .iter()
+            .zip(self.writes.iter())
+            .map(|(shard, writes)| {
+                (
+                    shard.load(Ordering::Acquire),
+                    writes.load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Why [`crate::Db::move_vnode`] did not move a virtual node.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MoveError {
+    #[error("vnode {0} is out of range")]
+    VnodeOutOfRange(usize),
+    #[error("shard {0} is out of range")]
+    ShardOutOfRange(usize),
+    #[error("the db is closed")]
+    Closed,
+}
 
 // rust version of https://arxiv.org/ftp/arxiv/papers/1406/1406.2294.pdf
 pub(crate) fn jump_consistent_hash(key: u64, buckets: usize) -> u32 {
@@ -14,6 +116,22 @@ pub(crate) fn jump_consistent_hash(key: u64, buckets: usize) -> u32 {
 
 #[cfg(test)]
 mod tests {
+    use super::{shard_of, vnode_of, Router};
+
+    #[test]
+    fn router() {
+        let router = Router::new(4);
+        for key in 0..64_u64 {
+            assert_eq!(router.shard_of(&key), shard_of(&key, 4));
+        }
+
+        let vnode = vnode_of(&7_u64);
+        let shard = (router.shard_of(&7_u64) + 1) % 4;
+        router.assign(vnode, shard);
+        assert_eq!(router.route_write(&7_u64), shard);
+        assert_eq!(router.vnodes()[vnode], (shard, 1));
+    }
+
     #[test]
     fn jump_consistent_hash() {
         let x = "the answer of life, universe and everything";
@@ -0,0 +1,327 @@
+//! Counters kept as rows whose increments are merge operands, see [`Schema::is_operand`]. A
+//! [`crate::Db::put`] of an increment neither reads the row nor conflicts with concurrent ones in
+//! the oracle, and reads fold the increments visible at their timestamp onto the last value set,
+//! as scans and compactions do.
+
+use std::{io, sync::Arc};
+
+use arrow::{
+    array::{
+        AsArray, Int64Builder, Int8Builder, RecordBatch, StringArray, StringBuilder, StructBuilder,
+        UInt64Builder,
+    },
+    datatypes::{DataType, Field, Fields, Int64Type, Schema as ArrowSchema, SchemaRef},
+};
+use futures::{AsyncRead, AsyncWrite};
+use once_cell::sync::Lazy;
+
+use crate::{
+    comparator::OrdComparator,
+    oracle::TimeStamp,
+    schema::{Builder, Op, Schema, OP_COLUMN_NAME, TS_COLUMN_NAME},
+    serdes::{Decode, Encode},
+};
+
+static COUNTER_INNER_FIELDS: Lazy<Fields> =
+    Lazy::new(|| Fields::from(vec![Field::new("value", DataType::Int64, false)]));
+static COUNTER_SCHEMA: Lazy<SchemaRef> = Lazy::new(|| {
+    Arc::new(ArrowSchema::new(vec![
+        Field::new("name", DataType::Utf8, false),
+        Field::new("value", DataType::Int64, false),
+    ]))
+});
+static COUNTER_INNER_SCHEMA: Lazy<SchemaRef> = Lazy::new(|| {
+    Arc::new(ArrowSchema::new(vec![
+        Field::new("name", DataType::Utf8, false),
+        Field::new(
+            "inner",
+            DataType::Struct(COUNTER_INNER_FIELDS.clone()),
+            true,
+        ),
+        Field::new(TS_COLUMN_NAME, DataType::UInt64, false),
+        Field::new(OP_COLUMN_NAME, DataType::Int8, false),
+    ]))
+});
+
+/// Schema of named counters. A counter put with [`Counter::new`] is set to its value, one put
+/// with [`Counter::increment`] adds its value to the one before, or to 0 if there is none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counter {
+    pub name: String,
+    pub value: i64,
+    increment: bool,
+}
+
+impl Counter {
+    pub fn new(name: impl Into<String>, value: i64) -> Self {
+        Counter {
+            name: name.into(),
+            value,
+            increment: false,
+        }
+    }
+
+    pub fn increment(name: impl Into<String>, delta: i64) -> Self {
+        Counter {
+            name: name.into(),
+            value: delta,
+            increment: true,
+        }
+    }
+}
+
+impl Encode for Counter {
+    type Error = io::Error;
+
+    async fn encode<W: AsyncWrite + Unpin + Send + Sync>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), Self::Error> {
+        self.name.encode(writer).await?;
+        self.value.encode(writer).await?;
+        self.increment.encode(writer).await
+    }
+
+    fn size(&self) -> usize {
+        self.name.size() + self.value.size() + self.increment.size()
+    }
+}
+
+impl Decode for Counter {
+    type Error = io::Error;
+
+    async fn decode<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, Self::Error> {
+        let name = String::decode(reader).await?;
+        let value = i64::decode(reader).await?;
+        let increment = bool::decode(reader).await?;
+
+        Ok(Counter {
+            name,
+            value,
+            increment,
+        })
+    }
+}
+
+impl Schema for Counter {
+    type PrimaryKey = String;
+    type Builder = CounterBuilder;
+    type PrimaryKeyArray = StringArray;
+    type Comparator = OrdComparator;
+
+    fn arrow_schema() -> SchemaRef {
+        COUNTER_SCHEMA.clone()
+    }
+
+    fn inner_schema() -> SchemaRef {
+        COUNTER_INNER_SCHEMA.clone()
+    }
+
+    fn primary_key(&self) -> Self::PrimaryKey {
+        self.name.clone()
+    }
+
+    fn builder() -> Self::Builder {
+        CounterBuilder {
+            name: StringBuilder::new(),
+            ts: UInt64Builder::new(),
+            op: Int8Builder::new(),
+            inner: StructBuilder::new(
+                COUNTER_INNER_FIELDS.clone(),
+                vec![Box::new(Int64Builder::new())],
+            ),
+        }
+    }
+
+    fn from_batch(batch: &RecordBatch, offset: usize) -> (Self::PrimaryKey, Option<Self>) {
+        let name = Self::primary_key_from_batch(batch, offset);
+        let op = Op::from_batch(batch, offset);
+        if op.is_tombstone() {
+            return (name, None);
+        }
+        let inner = batch.column(1).as_struct();
+        let value = inner.column(0).as_primitive::<Int64Type>().value(offset);
+
+        (
+            name.clone(),
+            Some(Counter {
+                name,
+                value,
+                increment: op == Op::Merge,
+            }),
+        )
+    }
+
+    fn primary_key_from_batch(batch: &RecordBatch, offset: usize) -> Self::PrimaryKey {
+        batch.column(0).as_string::<i32>().value(offset).to_string()
+    }
+
+    fn to_primary_key_array(keys: Vec<Self::PrimaryKey>) -> Self::PrimaryKeyArray {
+        StringArray::from(keys)
+    }
+
+    fn is_operand(&self) -> bool {
+        self.increment
+    }
+
+    fn merge(older: Option<&Self>, operand: &Self) -> Self {
+        Counter {
+            name: operand.name.clone(),
+            value: older.map_or(0, |older| older.value) + operand.value,
+            increment: older.is_some_and(|older| older.increment),
+        }
+    }
+}
+
+pub struct CounterBuilder {
+    name: StringBuilder,
+    ts: UInt64Builder,
+    op: Int8Builder,
+    inner: StructBuilder,
+}
+
+impl Builder<Counter> for CounterBuilder {
+    fn add(&mut self, primary_key: &String, ts: TimeStamp, op: Op, schema: Option<Counter>) {
+        self.name.append_value(primary_key);
+        self.ts.append_value(ts);
+        self.op.append_value(op as i8);
+
+        let value = self.inner.field_builder::<Int64Builder>(0).unwrap();
+        match schema {
+            Some(counter) => {
+                value.append_value(counter.value);
+                self.inner.append(true);
+            }
+            None => {
+                value.append_null();
+                self.inner.append_null();
+            }
+        }
+    }
+
+    fn finish(&mut self) -> RecordBatch {
+        RecordBatch::try_new(
+            Counter::inner_schema(),
+            vec![
+                Arc::new(self.name.finish()),
+                Arc::new(self.inner.finish()),
+                Arc::new(self.ts.finish()),
+                Arc::new(self.op.finish()),
+            ],
+        )
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use executor::{futures::StreamExt, ExecutorBuilder};
+    use futures::future::join_all;
+    use tempfile::TempDir;
+
+    use super::Counter;
+    use crate::{
+        mem_table::MemTable,
+        oracle::{LocalOracle, TimestampProvider},
+        record::RecordType,
+        wal::provider::in_mem::InMemProvider,
+        Db, DbOption, ImportMode,
+    };
+
+    #[test]
+    fn concurrent_increments() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Db<Counter, _, _> = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            let get = |name: &str| {
+                let db = &db;
+                let name = name.to_string();
+                async move {
+                    let ts = db.start_read();
+                    let counter = db.get(&name, &ts).await;
+                    db.read_commit(ts);
+                    counter
+                }
+            };
+
+            let results = join_all((0..10).map(|_| db.put(Counter::increment("hits", 2)))).await;
+            assert!(results.iter().all(Result::is_ok));
+            db.put(Counter::increment("hits", -5)).await.unwrap();
+            assert_eq!(get("hits").await, Some(Counter::new("hits", 15)));
+
+            db.put(Counter::new("hits", 100)).await.unwrap();
+            db.put(Counter::increment("hits", 1)).await.unwrap();
+            assert_eq!(get("hits").await, Some(Counter::new("hits", 101)));
+
+            db.delete("hits".to_string()).await.unwrap();
+            db.put(Counter::increment("hits", 1)).await.unwrap();
+            assert_eq!(get("hits").await, Some(Counter::new("hits", 1)));
+            assert_eq!(get("misses").await, None);
+
+            let mut txn = db.txn();
+            txn.set("hits".to_string(), Counter::increment("hits", 2));
+            txn.set("hits".to_string(), Counter::increment("hits", 3));
+            assert_eq!(
+                txn.get(&"hits".to_string()).await,
+                Some(Counter::new("hits", 6))
+            );
+            txn.commit().await.unwrap();
+            assert_eq!(get("hits").await, Some(Counter::new("hits", 6)));
+        });
+    }
+
+    #[test]
+    fn fold_across_sources() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Db<Counter, _, _> = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            let name = "hits".to_string();
+
+            db.write_bulk_with_timestamps(
+                [(name.clone(), 1, Some(Counter::new("hits", 10)))],
+                ImportMode::Ahead,
+            )
+            .await
+            .unwrap();
+            let mut mem_table = MemTable::default();
+            mem_table.insert(name.clone(), 2, Some(Counter::increment("hits", 2)));
+            mem_table.insert(name.clone(), 3, Some(Counter::increment("hits", 3)));
+            db.immutable
+                .write()
+                .await
+                .extend(mem_table.to_batches(usize::MAX));
+            db.write(RecordType::Full, 4, Counter::increment("hits", 4))
+                .await
+                .unwrap();
+
+            // reads see the increments committed up to their timestamp
+            for (ts, value) in [(1, 10), (2, 12), (3, 15), (4, 19)] {
+                assert_eq!(db.get(&name, &ts).await, Some(Counter::new("hits", value)));
+            }
+            let rows = db
+                .range(Bound::Unbounded, Bound::Unbounded, &3)
+                .await
+                .unwrap()
+                .map(|result| result.unwrap())
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(rows, vec![(name.clone(), Some(Counter::new("hits", 15)))]);
+        });
+    }
+}
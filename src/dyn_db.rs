@@ -0,0 +1,114 @@
+use std::{error, io};
+
+use bytes::Bytes;
+use futures::{future::LocalBoxFuture, io::Cursor, AsyncWrite, FutureExt};
+use thiserror::Error;
+
+use crate::{
+    oracle::{Oracle, TimeStamp},
+    schema::Schema,
+    serdes::{Decode, Encode},
+    wal::provider::StorageProvider,
+    Db,
+};
+
+/// Object-safe facade over [`Db`] working on encoded keys and values, so that databases of
+/// different schemas can be held behind one `dyn DynDb`.
+pub trait DynDb {
+    fn get<'a>(&'a self, key: &'a [u8]) -> LocalBoxFuture<'a, Result<Option<Bytes>, DynDbError>>;
+
+    fn put(&self, value: Bytes) -> LocalBoxFuture<'_, Result<TimeStamp, DynDbError>>;
+
+    fn delete(&self, key: Bytes) -> LocalBoxFuture<'_, Result<TimeStamp, DynDbError>>;
+}
+
+#[derive(Debug, Error)]
+pub enum DynDbError {
+    #[error("dyn db decode error: {0}")]
+    Decode(#[source] Box<dyn error::Error + Send + Sync + 'static>),
+    #[error("dyn db encode error: {0}")]
+    Encode(#[source] Box<dyn error::Error + Send + Sync + 'static>),
+    #[error("dyn db write error: {0}")]
+    Write(#[source] Box<dyn error::Error + Send + Sync + 'static>),
+}
+
+async fn encode<T: Encode>(value: &T) -> Result<Bytes, DynDbError> {
+    let mut writer = Cursor::new(Vec::with_capacity(value.size()));
+    value
+        .encode(&mut writer)
+        .await
+        .map_err(|err| DynDbError::Encode(Box::new(err)))?;
+
+    Ok(writer.into_inner().into())
+}
+
+async fn decode<T: Decode>(bytes: &[u8]) -> Result<T, DynDbError> {
+    T::decode(&mut Cursor::new(bytes))
+        .await
+        .map_err(|err| DynDbError::Decode(Box::new(err)))
+}
+
+impl<S, O, WP> DynDb for Db<S, O, WP>
+where
+    S: Schema,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+    WP::File: AsyncWrite,
+    io::Error: From<<S as Decode>::Error>,
+{
+    fn get<'a>(&'a self, key: &'a [u8]) -> LocalBoxFuture<'a, Result<Option<Bytes>, DynDbError>> {
+        async move {
+            let key = decode::<S::PrimaryKey>(key).await?;
+
+            match self.get(&key, &self.watermark.applied()).await {
+                Some(value) => Ok(Some(encode(&value).await?)),
+                None => Ok(None),
+            }
+        }
+        .boxed_local()
+    }
+
+    fn put(&self, value: Bytes) -> LocalBoxFuture<'_, Result<TimeStamp, DynDbError>> {
+        async move {
+            let value = decode::<S>(&value).await?;
+
+            Db::put(self, value)
+                .await
+                .map_err(|err| DynDbError::Write(Box::new(err)))
+        }
+        .boxed_local()
+    }
+
+    fn delete(&self, key: Bytes) -> LocalBoxFuture<'_, Result<TimeStamp, DynDbError>> {
+        async move {
+            let key = decode::<S::PrimaryKey>(&key).await?;
+
+            Db::delete(self, key)
+                .await
+                .map_err(|err| DynDbError::Write(Box::new(err)))
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, DynDb};
+    use crate::tests::{user, with_db};
+
+    #[test]
+    fn put_get_delete() {
+        with_db(|db| async move {
+            let db: Box<dyn DynDb> = Box::new(db);
+            let key = encode(&1u64).await.unwrap();
+            let value = encode(&user(1)).await.unwrap();
+
+            db.put(value.clone()).await.unwrap();
+            assert_eq!(db.get(&key).await.unwrap(), Some(value));
+
+            db.delete(key.clone()).await.unwrap();
+            assert_eq!(db.get(&key).await.unwrap(), None);
+            assert!(db.get(&[0]).await.is_err());
+        });
+    }
+}
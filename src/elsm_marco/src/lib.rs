@@ -4,13 +4,22 @@ mod schema_model;
 use proc_macro::TokenStream;
 use proc_macro2::Ident;
 use quote::quote;
-use syn::{parse_macro_input, Data, DeriveInput, Fields, Type};
+use syn::{parse_macro_input, AttributeArgs, Data, DeriveInput, Fields, Type};
 
-use crate::{keys::PrimaryKey, schema_model::ModelAttributes};
+use crate::{
+    keys::PrimaryKey,
+    schema_model::{parse_comparator, ModelAttributes},
+};
 
 #[proc_macro_attribute]
-pub fn elsm_schema(_args: TokenStream, input: TokenStream) -> TokenStream {
+pub fn elsm_schema(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as AttributeArgs);
     let ast = parse_macro_input!(input as DeriveInput);
+    let comparator = match parse_comparator(args) {
+        Ok(Some(comparator)) => quote!(#comparator),
+        Ok(None) => quote!(OrdComparator),
+        Err(err) => return TokenStream::from(err.to_compile_error()),
+    };
     let struct_name = ast.ident.clone();
 
     let mut attrs = ModelAttributes {
@@ -33,10 +42,14 @@ pub fn elsm_schema(_args: TokenStream, input: TokenStream) -> TokenStream {
     let mut encode_size_fields: Vec<proc_macro2::TokenStream> = Vec::new();
 
     let mut decode_method_fields: Vec<proc_macro2::TokenStream> = Vec::new();
+    let mut decode_defaulted_method_fields: Vec<proc_macro2::TokenStream> = Vec::new();
 
     let mut builder_append_value: Vec<proc_macro2::TokenStream> = Vec::new();
     let mut builder_append_null: Vec<proc_macro2::TokenStream> = Vec::new();
 
+    let mut defaulted_fields: Vec<proc_macro2::TokenStream> = Vec::new();
+    let mut defaulted_count = 0usize;
+
     if let Data::Struct(data_struct) = &ast.data {
         if let Fields::Named(fields) = &data_struct.fields {
             for field in fields.named.iter() {
@@ -127,8 +140,38 @@ pub fn elsm_schema(_args: TokenStream, input: TokenStream) -> TokenStream {
                 encode_size_fields.push(quote! {
                     + self.inner.#field_name.size()
                 });
+                let default = match attrs.parse_default(field) {
+                    Ok(default) => default,
+                    Err(err) => return TokenStream::from(err.to_compile_error()),
+                };
+                decode_defaulted_method_fields.push(match &default {
+                    Some(default) => {
+                        let index = defaulted_count;
+                        defaulted_count += 1;
+                        quote! {
+                            let #field_name = if #index < defaulted {
+                                #field_ty::decode(reader).await?
+                            } else {
+                                #default
+                            };
+                        }
+                    }
+                    None => quote! {
+                        let #field_name = #field_ty::decode(reader).await?;
+                    },
+                });
                 match attrs.parse_field(field) {
                     Ok(false) => {
+                        if default.is_none() && defaulted_count > 0 {
+                            return TokenStream::from(
+                                syn::Error::new_spanned(
+                                    field,
+                                    "fields without a default value have to come before the ones \
+                                     with `#[default_value]`, only those are looked up by name",
+                                )
+                                .to_compile_error(),
+                            );
+                        }
                         inner_field_definitions.push(quote! {
                             Field::new(stringify!(#field_name), #mapped_type, false),
                         });
@@ -138,13 +181,33 @@ pub fn elsm_schema(_args: TokenStream, input: TokenStream) -> TokenStream {
                             &format!("array_{}", normal_field_count),
                             struct_name.span(),
                         );
-                        inner_from_batch_arrays.push(quote! {
-                            let #array_name = struct_array
-                                .column(#normal_field_count)
-                                .as_any()
-                                .downcast_ref::<#array_ty>()
-                                .unwrap();
-                            let #field_name = #array_name.value(offset).to_owned();
+                        inner_from_batch_arrays.push(match default {
+                            // looked up by name, tables written before the field was added lack
+                            // its column
+                            Some(default) => {
+                                defaulted_fields.push(quote! { stringify!(#field_name), });
+                                quote! {
+                                    let #field_name = match struct_array
+                                        .column_by_name(stringify!(#field_name))
+                                    {
+                                        Some(#array_name) => #array_name
+                                            .as_any()
+                                            .downcast_ref::<#array_ty>()
+                                            .unwrap()
+                                            .value(offset)
+                                            .to_owned(),
+                                        None => #default,
+                                    };
+                                }
+                            }
+                            None => quote! {
+                                let #array_name = struct_array
+                                    .column(#normal_field_count)
+                                    .as_any()
+                                    .downcast_ref::<#array_ty>()
+                                    .unwrap();
+                                let #field_name = #array_name.value(offset).to_owned();
+                            },
                         });
                         builder_append_value.push({
                             let field = if is_string {
@@ -214,6 +277,8 @@ pub fn elsm_schema(_args: TokenStream, input: TokenStream) -> TokenStream {
                 Arc::new(arrow::datatypes::Schema::new(vec![
                     #schema_field_token
                     Field::new("inner", DataType::Struct(#inner_fields_name.clone()), true),
+                    Field::new("_ts", DataType::UInt64, false),
+                    Field::new("_op", DataType::Int8, false),
                 ]))
             };
             pub static ref #schema_name: SchemaRef = {
@@ -253,6 +318,7 @@ pub fn elsm_schema(_args: TokenStream, input: TokenStream) -> TokenStream {
             type PrimaryKey = #base_ty;
             type Builder = #builder_name;
             type PrimaryKeyArray = #array_ty;
+            type Comparator = #comparator;
 
             fn arrow_schema() -> SchemaRef {
                 #schema_name.clone()
@@ -262,6 +328,22 @@ pub fn elsm_schema(_args: TokenStream, input: TokenStream) -> TokenStream {
                 #inner_schema_name.clone()
             }
 
+            fn defaulted_fields() -> &'static [&'static str] {
+                &[#(#defaulted_fields)*]
+            }
+
+            async fn decode_defaulted<R: AsyncRead + Unpin>(
+                reader: &mut R,
+                defaulted: usize,
+            ) -> Result<Self, io::Error> {
+                let _ = defaulted;
+                #(#decode_defaulted_method_fields)*
+
+                Ok(#inner_struct_name {
+                    inner: Arc::new(#struct_name { #(#new_fields_definitions)* }),
+                })
+            }
+
             fn primary_key(&self) -> Self::PrimaryKey {
                 self.inner.#primary_key_name
             }
@@ -269,6 +351,8 @@ pub fn elsm_schema(_args: TokenStream, input: TokenStream) -> TokenStream {
             fn builder() -> Self::Builder {
                 #builder_name {
                     #primary_key_name: Default::default(),
+                    _ts: UInt64Builder::new(),
+                    _op: Int8Builder::new(),
                     inner: StructBuilder::new(
                         #inner_fields_name.clone(),
                         vec![#(#init_inner_builders)*],
@@ -288,7 +372,7 @@ pub fn elsm_schema(_args: TokenStream, input: TokenStream) -> TokenStream {
                     .as_any()
                     .downcast_ref::<StructArray>()
                     .unwrap();
-                if struct_array.is_null(offset) {
+                if Op::from_batch(batch, offset).is_tombstone() {
                     return (#primary_key_name, None);
                 }
 
@@ -301,6 +385,15 @@ pub fn elsm_schema(_args: TokenStream, input: TokenStream) -> TokenStream {
                 )
             }
 
+            fn primary_key_from_batch(batch: &RecordBatch, offset: usize) -> Self::PrimaryKey {
+                batch
+                    .column(0)
+                    .as_any()
+                    .downcast_ref::<#array_ty>()
+                    .unwrap()
+                    .value(offset)
+            }
+
             fn to_primary_key_array(keys: Vec<Self::PrimaryKey>) -> Self::PrimaryKeyArray {
                 #array_ty::from(keys)
             }
@@ -337,12 +430,16 @@ pub fn elsm_schema(_args: TokenStream, input: TokenStream) -> TokenStream {
 
         pub(crate) struct #builder_name {
             #primary_key_name: #builder_ty,
+            _ts: UInt64Builder,
+            _op: Int8Builder,
             inner: StructBuilder,
         }
 
         impl Builder<#inner_struct_name> for #builder_name {
-            fn add(&mut self, primary_key: &<#inner_struct_name as Schema>::PrimaryKey, schema: Option<#inner_struct_name>) {
+            fn add(&mut self, primary_key: &<#inner_struct_name as Schema>::PrimaryKey, ts: u64, op: Op, schema: Option<#inner_struct_name>) {
                 self.#primary_key_name.append_value(*primary_key);
+                self._ts.append_value(ts);
+                self._op.append_value(op as i8);
 
                 if let Some(schema) = schema {
                     #(#builder_append_value)*
@@ -354,14 +451,14 @@ pub fn elsm_schema(_args: TokenStream, input: TokenStream) -> TokenStream {
             }
 
             fn finish(&mut self) -> RecordBatch {
-                RecordBatch::try_new(#inner_struct_name::inner_schema(), vec![Arc::new(self.#primary_key_name.finish()), Arc::new(self.inner.finish())]).unwrap()
+                RecordBatch::try_new(#inner_struct_name::inner_schema(), vec![Arc::new(self.#primary_key_name.finish()), Arc::new(self.inner.finish()), Arc::new(self._ts.finish()), Arc::new(self._op.finish())]).unwrap()
             }
         }
     };
     gen.into()
 }
 
-#[proc_macro_derive(KeyAttributes, attributes(primary_key))]
+#[proc_macro_derive(KeyAttributes, attributes(primary_key, default_value))]
 pub fn key_attributes(_input: TokenStream) -> TokenStream {
     let gen = quote::quote! {};
     gen.into()
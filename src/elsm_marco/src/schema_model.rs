@@ -1,5 +1,5 @@
 use proc_macro2::Ident;
-use syn::{parse::Result, Field};
+use syn::{parse::Result, AttributeArgs, Error, Expr, Field, Lit, Meta, NestedMeta, Type};
 
 use crate::keys::KeyDefinition;
 
@@ -8,6 +8,29 @@ pub(crate) struct ModelAttributes {
     pub(crate) primary_key: Option<KeyDefinition>,
 }
 
+/// The type of `#[elsm_schema(comparator = "..")]` ordering the primary keys, `None` for their
+/// `Ord`.
+pub(crate) fn parse_comparator(args: AttributeArgs) -> Result<Option<Type>> {
+    let mut comparator = None;
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(name_value))
+                if name_value.path.is_ident("comparator") =>
+            {
+                let Lit::Str(ty) = &name_value.lit else {
+                    return Err(Error::new_spanned(
+                        &name_value.lit,
+                        "the comparator is given as a string",
+                    ));
+                };
+                comparator = Some(ty.parse()?);
+            }
+            arg => return Err(Error::new_spanned(arg, "unknown elsm_schema argument")),
+        }
+    }
+    Ok(comparator)
+}
+
 impl ModelAttributes {
     pub(crate) fn parse_field(&mut self, field: &Field) -> Result<bool> {
         for attr in &field.attrs {
@@ -21,4 +44,27 @@ impl ModelAttributes {
         }
         Ok(false)
     }
+
+    /// The expression of `#[default_value(..)]`, filling the field in rows of tables written
+    /// before it was added.
+    pub(crate) fn parse_default(&self, field: &Field) -> Result<Option<Expr>> {
+        let Some(attr) = field
+            .attrs
+            .iter()
+            .find(|attr| attr.path.is_ident("default_value"))
+        else {
+            return Ok(None);
+        };
+        if field
+            .attrs
+            .iter()
+            .any(|attr| attr.path.is_ident("primary_key"))
+        {
+            return Err(Error::new_spanned(
+                attr,
+                "the primary key can not have a default value",
+            ));
+        }
+        attr.parse_args().map(Some)
+    }
 }
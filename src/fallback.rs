@@ -0,0 +1,99 @@
+use std::{io, sync::Arc};
+
+use futures::{future::LocalBoxFuture, AsyncWrite, FutureExt};
+
+use crate::{
+    oracle::{Oracle, TimeStamp},
+    schema::Schema,
+    serdes::Decode,
+    wal::provider::StorageProvider,
+    Db,
+};
+
+/// A store lookups fall through to when a [`Db`] holds no version of the key, see
+/// [`Db::with_fallback`]. Its timestamps must be of the same domain as those of the db, e.g. a
+/// db the cold keys were moved to.
+pub trait Fallback<S>: Send + Sync + 'static
+where
+    S: Schema,
+{
+    fn get<'a>(&'a self, key: &'a S::PrimaryKey, ts: TimeStamp) -> LocalBoxFuture<'a, Option<S>>;
+}
+
+impl<S, O, WP> Fallback<S> for Db<S, O, WP>
+where
+    S: Schema,
+    O: Oracle<S::PrimaryKey> + Send + Sync + 'static,
+    WP: StorageProvider,
+    WP::File: AsyncWrite,
+    io::Error: From<<S as Decode>::Error>,
+{
+    fn get<'a>(&'a self, key: &'a S::PrimaryKey, ts: TimeStamp) -> LocalBoxFuture<'a, Option<S>> {
+        async move { Db::get(self, key, &ts).await }.boxed_local()
+    }
+}
+
+impl<S, F> Fallback<S> for Arc<F>
+where
+    S: Schema,
+    F: Fallback<S>,
+{
+    fn get<'a>(&'a self, key: &'a S::PrimaryKey, ts: TimeStamp) -> LocalBoxFuture<'a, Option<S>> {
+        F::get(self, key, ts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use executor::ExecutorBuilder;
+    use tempfile::TempDir;
+
+    use crate::{
+        oracle::LocalOracle,
+        record::RecordType,
+        tests::{user, UserInner},
+        wal::provider::in_mem::InMemProvider,
+        Db, DbOption,
+    };
+
+    #[test]
+    fn misses_fall_through() {
+        let hot_dir = TempDir::new().unwrap();
+        let cold_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let cold: Arc<Db<UserInner, _, _>> = Arc::new(
+                Db::new(
+                    LocalOracle::default(),
+                    InMemProvider::default(),
+                    DbOption::new(cold_dir.path().to_path_buf()),
+                )
+                .await
+                .unwrap(),
+            );
+            let hot: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(hot_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap()
+            .with_fallback(cold.clone());
+
+            for id in 0..3 {
+                cold.write(RecordType::Full, 0, user(id)).await.unwrap();
+            }
+            let moved = UserInner::new(1, "moved".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+            hot.write(RecordType::Full, 1, moved.clone()).await.unwrap();
+            hot.remove(RecordType::Full, 1, 2).await.unwrap();
+
+            assert_eq!(hot.get(&0, &1).await, Some(user(0)));
+            assert_eq!(hot.get(&1, &1).await, Some(moved));
+            assert_eq!(hot.get(&1, &0).await, Some(user(1)));
+            assert_eq!(hot.get(&2, &1).await, None);
+            assert_eq!(hot.get(&3, &1).await, None);
+        });
+    }
+}
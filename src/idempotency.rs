@@ -0,0 +1,280 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{channel::oneshot, future::Shared, FutureExt};
+
+use crate::{
+    oracle::TimeStamp,
+    record::SystemRecord,
+    system::{SystemTable, IDEMPOTENCY_PREFIX},
+};
+
+/// Resolves once the commit of a token in progress succeeded, cancelled if it failed.
+type Committed = Shared<oneshot::Receiver<()>>;
+
+/// The tokens of the commits in progress, a commit of a token another commit is in progress for
+/// waits for its result rather than racing it.
+#[derive(Default, Clone)]
+pub(crate) struct Pending {
+    tokens: Arc<Mutex<HashMap<String, Committed>>>,
+}
+
+impl fmt::Debug for Pending {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pending")
+            .field("tokens", &self.tokens.lock().unwrap().keys())
+            .finish()
+    }
+}
+
+impl Pending {
+    /// Reserves `token` once no other commit of it is in progress, unless one of them succeeded
+    /// or `committed` finds it committed before.
+    pub(crate) async fn reserve<F>(
+        &self,
+        token: &str,
+        committed: impl FnOnce() -> F,
+    ) -> io::Result<Option<Reservation>>
+    where
+        F: Future<Output = io::Result<bool>>,
+    {
+        let reservation = loop {
+            let in_progress = {
+                let mut tokens = self.tokens.lock().unwrap();
+                match tokens.get(token) {
+                    Some(in_progress) => in_progress.clone(),
+                    None => {
+                        let (tx, rx) = oneshot::channel();
+                        tokens.insert(token.to_string(), rx.shared());
+                        break Reservation {
+                            tokens: self.tokens.clone(),
+                            token: token.to_string(),
+                            committed: Some(tx),
+                        };
+                    }
+                }
+            };
+            if in_progress.await.is_ok() {
+                return Ok(None);
+            }
+        };
+        if committed().await? {
+            return Ok(None);
+        }
+        Ok(Some(reservation))
+    }
+}
+
+/// A token reserved for one commit. Dropping it before [`Reservation::commit`] hands the token to
+/// the next commit of it.
+pub(crate) struct Reservation {
+    tokens: Arc<Mutex<HashMap<String, Committed>>>,
+    token: String,
+    committed: Option<oneshot::Sender<()>>,
+}
+
+impl Reservation {
+    pub(crate) fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Tells the commits waiting for the token that it committed.
+    pub(crate) fn commit(mut self) {
+        if let Some(committed) = self.committed.take() {
+            let _ = committed.send(());
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.tokens.lock().unwrap().remove(&self.token);
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct IdempotencyTable {
+    retention: Duration,
+    pending: Pending,
+}
+
+impl IdempotencyTable {
+    pub(crate) fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            pending: Pending::default(),
+        }
+    }
+
+    /// Drops the tokens past the retention window, once on open rather than on every commit.
+    pub(crate) async fn expire(&self, system: &SystemTable) -> io::Result<()> {
+        let now = now();
+        system
+            .retain_prefix(IDEMPOTENCY_PREFIX, |reserved_at| {
+                !self.expired(reserved_at, now)
+            })
+            .await
+    }
+
+    /// Reserves the token for a commit, `None` if a commit of it succeeded within the retention
+    /// window.
+    pub(crate) async fn reserve(
+        &self,
+        system: &SystemTable,
+        token: &str,
+    ) -> io::Result<Option<Reservation>> {
+        self.pending
+            .reserve(token, || async {
+                let reserved_at = system.get(&Self::system_key(token)).await;
+                Ok(reserved_at.is_some_and(|reserved_at| !self.expired(&reserved_at, now())))
+            })
+            .await
+    }
+
+    /// Persists the token of a commit logged along with `record`, then tells the commits waiting
+    /// for it. A token failed to persist is still recovered from the wal.
+    pub(crate) async fn commit(
+        &self,
+        system: &SystemTable,
+        reservation: Reservation,
+        record: SystemRecord,
+    ) -> io::Result<()> {
+        let result = system.set(record.key, record.value).await;
+        reservation.commit();
+        result
+    }
+
+    /// Persists a token recovered from the wal, unless it was persisted before or is past the
+    /// retention window.
+    pub(crate) async fn recover(
+        &self,
+        system: &SystemTable,
+        record: SystemRecord,
+    ) -> io::Result<()> {
+        if self.expired(&record.value, now()) {
+            return Ok(());
+        }
+        system
+            .update(&record.key, |reserved_at| {
+                reserved_at.is_none().then_some(record.value)
+            })
+            .await
+            .map(|_| ())
+    }
+
+    fn expired(&self, reserved_at: &[u8], now: u64) -> bool {
+        let reserved_at = u64::from_le_bytes(reserved_at.try_into().unwrap_or_default());
+        now.saturating_sub(reserved_at) >= self.retention.as_millis() as u64
+    }
+
+    fn system_key(token: &str) -> String {
+        format!("{}{}", IDEMPOTENCY_PREFIX, token)
+    }
+}
+
+/// The system key logged along with the commit `reservation` is for, at `ts`.
+pub(crate) fn record(reservation: &Reservation, ts: TimeStamp) -> SystemRecord {
+    SystemRecord {
+        key: IdempotencyTable::system_key(reservation.token()),
+        value: now().to_le_bytes().to_vec(),
+        ts,
+    }
+}
+
+/// Whether `record` is the token of a commit, see [`record`].
+pub(crate) fn is_token(record: &SystemRecord) -> bool {
+    record.key.starts_with(IDEMPOTENCY_PREFIX)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use executor::ExecutorBuilder;
+    use futures::future::join;
+    use tempfile::TempDir;
+
+    use super::{record, IdempotencyTable};
+    use crate::{system::SystemTable, DbOption};
+
+    #[test]
+    fn reserve() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let system = SystemTable::new(&DbOption::new(temp_dir.path().to_path_buf()))
+                .await
+                .unwrap();
+            let table = IdempotencyTable::new(Duration::from_secs(60));
+
+            // a commit of a token in progress is waited for
+            let reservation = table.reserve(&system, "token_0").await.unwrap().unwrap();
+            let committed = record(&reservation, 1);
+            let (reserved, _) = join(
+                table.reserve(&system, "token_0"),
+                table.commit(&system, reservation, committed.clone()),
+            )
+            .await;
+            assert!(reserved.unwrap().is_none());
+            assert!(table.reserve(&system, "token_0").await.unwrap().is_none());
+
+            // and the token handed over if it failed
+            let reservation = table.reserve(&system, "token_1").await.unwrap().unwrap();
+            let (reserved, _) = join(table.reserve(&system, "token_1"), async {
+                drop(reservation)
+            })
+            .await;
+            assert!(reserved.unwrap().is_some());
+
+            let table = IdempotencyTable::new(Duration::ZERO);
+            let reservation = table.reserve(&system, "token_2").await.unwrap().unwrap();
+            let committed = record(&reservation, 2);
+            table.commit(&system, reservation, committed).await.unwrap();
+            assert!(table.reserve(&system, "token_2").await.unwrap().is_some());
+
+            table.expire(&system).await.unwrap();
+            assert_eq!(system.get("idempotency/token_0").await, None);
+        });
+    }
+
+    #[test]
+    fn recover() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let system = SystemTable::new(&DbOption::new(temp_dir.path().to_path_buf()))
+                .await
+                .unwrap();
+            let table = IdempotencyTable::new(Duration::from_secs(60));
+
+            let reservation = table.reserve(&system, "token_0").await.unwrap().unwrap();
+            let committed = record(&reservation, 1);
+            drop(reservation);
+            table.recover(&system, committed.clone()).await.unwrap();
+            assert!(table.reserve(&system, "token_0").await.unwrap().is_none());
+
+            IdempotencyTable::new(Duration::ZERO)
+                .expire(&system)
+                .await
+                .unwrap();
+            IdempotencyTable::new(Duration::ZERO)
+                .recover(&system, committed)
+                .await
+                .unwrap();
+            assert!(table.reserve(&system, "token_0").await.unwrap().is_some());
+        });
+    }
+}
@@ -1,64 +1,155 @@
 pub(crate) mod stream;
 
 use std::{
-    collections::{BTreeMap, Bound},
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
     fmt::Debug,
-    iter::Iterator,
+    marker::PhantomData,
 };
 
-use arrow::array::RecordBatch;
+use arrow::array::{RecordBatch, UInt64Array};
 
-use crate::{mem_table::InternalKey, oracle::TimeStamp, schema::Schema};
+use crate::{
+    comparator::Comparator,
+    mem_table::InternalKey,
+    oracle::TimeStamp,
+    schema::{self, Builder, Op, Schema},
+};
 
+/// Rows of `batch` are sorted in `InternalKey` order, key ascending in the order of the schema's
+/// comparator then timestamp descending, and `timestamps` is its timestamp column.
 #[derive(Debug)]
 pub(crate) struct IndexBatch<S>
 where
     S: Schema,
 {
     pub(crate) batch: RecordBatch,
-    pub(crate) index: BTreeMap<InternalKey<S::PrimaryKey>, u32>,
+    pub(crate) timestamps: UInt64Array,
+    /// number of frozen mem tables merged into this batch
+    pub(crate) chunks: usize,
+    _p: PhantomData<S>,
 }
 
 impl<S> IndexBatch<S>
 where
     S: Schema,
 {
-    pub(crate) async fn find(&self, key: &S::PrimaryKey, ts: &TimeStamp) -> Option<Option<S>> {
-        let internal_key = InternalKey {
-            key: key.clone(),
-            ts: *ts,
-        };
-        if let Some((InternalKey { key: item_key, .. }, offset)) = self
-            .index
-            .range((Bound::Included(&internal_key), Bound::Unbounded))
-            .next()
-        {
-            if item_key == key {
-                let (_, item) = S::from_batch(&self.batch, *offset as usize);
-
-                return Some(item);
+    pub(crate) fn new(batch: RecordBatch) -> Self {
+        IndexBatch {
+            timestamps: schema::timestamps(&batch).clone(),
+            batch,
+            chunks: 1,
+            _p: Default::default(),
+        }
+    }
+
+    pub(crate) fn merge<'a>(batches: impl IntoIterator<Item = &'a IndexBatch<S>>) -> Self {
+        let batches = batches.into_iter().collect::<Vec<_>>();
+        let mut heap = BinaryHeap::with_capacity(batches.len());
+
+        for (i, batch) in batches.iter().enumerate() {
+            if batch.len() > 0 {
+                heap.push(Reverse((batch.internal_key(0), i, 0)));
             }
         }
-        None
+        let mut builder = S::builder();
+
+        while let Some(Reverse((InternalKey { key, ts, .. }, i, offset))) = heap.pop() {
+            let batch = &batches[i].batch;
+            builder.add(
+                &key,
+                ts,
+                Op::from_batch(batch, offset),
+                S::from_batch(batch, offset).1,
+            );
+
+            if offset + 1 < batches[i].len() {
+                heap.push(Reverse((
+                    batches[i].internal_key(offset + 1),
+                    i,
+                    offset + 1,
+                )));
+            }
+        }
+        let mut merged = IndexBatch::new(builder.finish());
+        merged.chunks = batches.iter().map(|batch| batch.chunks).sum();
+
+        merged
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.batch.num_rows()
+    }
+
+    pub(crate) fn key(&self, offset: usize) -> S::PrimaryKey {
+        S::primary_key_from_batch(&self.batch, offset)
+    }
+
+    fn internal_key(&self, offset: usize) -> InternalKey<S::PrimaryKey, S::Comparator> {
+        InternalKey::new(self.key(offset), self.timestamps.value(offset))
+    }
+
+    /// Returns the first offset whose internal key is not less than `(key, ts)`.
+    pub(crate) fn lower_bound(&self, key: &S::PrimaryKey, ts: TimeStamp) -> usize {
+        self.partition_point(key, ts, |ordering| ordering == Ordering::Less)
+    }
+
+    /// Returns the first offset whose internal key is greater than `(key, ts)`.
+    pub(crate) fn upper_bound(&self, key: &S::PrimaryKey, ts: TimeStamp) -> usize {
+        self.partition_point(key, ts, |ordering| ordering != Ordering::Greater)
+    }
+
+    fn partition_point(
+        &self,
+        key: &S::PrimaryKey,
+        ts: TimeStamp,
+        pred: impl Fn(Ordering) -> bool,
+    ) -> usize {
+        let (mut low, mut high) = (0, self.len());
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let ordering = S::Comparator::compare(&self.key(mid), key)
+                .then_with(|| ts.cmp(&self.timestamps.value(mid)));
+
+            if pred(ordering) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
     }
 
-    pub(crate) fn scope(&self) -> Option<(&S::PrimaryKey, &S::PrimaryKey)> {
-        if let (Some((min, _)), Some((max, _))) =
-            (self.index.first_key_value(), self.index.last_key_value())
-        {
-            return Some((&min.key, &max.key));
+    pub(crate) async fn find(&self, key: &S::PrimaryKey, ts: &TimeStamp) -> Option<Option<S>> {
+        let offset = self.lower_bound(key, *ts);
+
+        if offset < self.len() && &self.key(offset) == key {
+            let (_, item) = S::from_batch(&self.batch, offset);
+
+            return Some(item);
         }
         None
     }
+
+    pub(crate) fn scope(&self) -> Option<(S::PrimaryKey, S::PrimaryKey)> {
+        if self.len() == 0 {
+            return None;
+        }
+        Some((self.key(0), self.key(self.len() - 1)))
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use arrow::array::UInt64Array;
     use executor::ExecutorBuilder;
 
     use crate::{
-        mem_table::MemTable, oracle::LocalOracle, tests::UserInner,
-        wal::provider::in_mem::InMemProvider, Db,
+        index_batch::IndexBatch,
+        mem_table::MemTable,
+        schema::Op,
+        tests::{user, UserInner},
     };
 
     #[test]
@@ -103,9 +194,7 @@ mod tests {
             );
             mem_table.insert(3, 0, None);
 
-            let batch = Db::<UserInner, LocalOracle<u64>, InMemProvider>::freeze(mem_table)
-                .await
-                .unwrap();
+            let batch = mem_table.to_batches(usize::MAX).remove(0);
 
             assert_eq!(
                 batch.find(&1, &0).await,
@@ -142,6 +231,64 @@ mod tests {
                 )))
             );
             assert_eq!(batch.find(&3, &0).await, Some(None));
+            assert_eq!(batch.find(&0, &0).await, None);
+            assert_eq!(batch.find(&4, &1).await, None);
+            assert_eq!(batch.scope(), Some((1, 3)));
+        });
+    }
+
+    #[test]
+    fn merge() {
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let user_1 = UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+            let user_2 = UserInner::new(2, "2".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+
+            let mut mem_table_0 = MemTable::default();
+            mem_table_0.insert(1, 0, Some(user_1.clone()));
+            mem_table_0.insert(3, 0, None);
+            let mut mem_table_1 = MemTable::default();
+            mem_table_1.insert(1, 1, None);
+            mem_table_1.insert(2, 1, Some(user_2.clone()));
+
+            let batch_0 = mem_table_0.to_batches(usize::MAX).remove(0);
+            let batch_1 = mem_table_1.to_batches(usize::MAX).remove(0);
+
+            let batch = IndexBatch::merge([&batch_0, &batch_1]);
+
+            assert_eq!(batch.chunks, 2);
+            assert_eq!(batch.timestamps, UInt64Array::from(vec![1, 0, 1, 0]));
+            assert_eq!(
+                (0..batch.len())
+                    .map(|offset| Op::from_batch(&batch.batch, offset))
+                    .collect::<Vec<_>>(),
+                vec![Op::Delete, Op::Put, Op::Put, Op::Delete]
+            );
+            assert_eq!(batch.find(&1, &0).await, Some(Some(user_1)));
+            assert_eq!(batch.find(&1, &1).await, Some(None));
+            assert_eq!(batch.find(&2, &1).await, Some(Some(user_2)));
+            assert_eq!(batch.find(&2, &0).await, None);
+            assert_eq!(batch.find(&3, &1).await, Some(None));
+        });
+    }
+
+    #[test]
+    fn freeze_cut() {
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let mut mem_table = MemTable::default();
+            mem_table.insert(1, 0, Some(user(1)));
+            mem_table.insert(1, 1, None);
+            mem_table.insert(2, 0, Some(user(2)));
+            mem_table.insert(3, 0, Some(user(3)));
+
+            let batches = mem_table.to_batches(1);
+
+            assert_eq!(
+                batches.iter().map(IndexBatch::len).collect::<Vec<_>>(),
+                vec![2, 1, 1]
+            );
+            assert_eq!(batches[0].find(&1, &0).await, Some(Some(user(1))));
+            assert_eq!(batches[0].find(&1, &1).await, Some(None));
+            assert_eq!(batches[2].scope(), Some((3, 3)));
         });
     }
 }
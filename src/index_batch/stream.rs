@@ -1,54 +1,83 @@
 use std::{
-    collections::{btree_map::Range, Bound},
     fmt::Debug,
-    pin::{pin, Pin},
+    marker::PhantomData,
+    ops::{Bound, Range},
+    pin::Pin,
     task::{Context, Poll},
 };
 
-use arrow::array::RecordBatch;
-use executor::futures::{Stream, StreamExt};
+use arrow::{
+    array::{AsArray, BooleanArray, RecordBatch, UInt64Array},
+    compute::{
+        and, concat, filter_record_batch,
+        kernels::cmp::{distinct, lt_eq},
+        not, or,
+    },
+    datatypes::Int8Type,
+};
+use executor::futures::Stream;
 use pin_project::pin_project;
 
 use crate::{
-    index_batch::IndexBatch, mem_table::InternalKey, oracle::TimeStamp, schema::Schema,
-    stream::StreamError,
+    comparator::Comparator,
+    index_batch::IndexBatch,
+    oracle::TimeStamp,
+    schema::{self, Op, Schema, OP_COLUMN},
+    stream::{mask, ScanError, ScanFilter},
 };
 
+/// Yields the rows selected by [`IndexBatch::range`], decoding each as it is yielded.
 #[pin_project]
-#[derive(Debug)]
-pub(crate) struct IndexBatchStream<'a, S>
+pub(crate) struct IndexBatchStream<S>
 where
     S: Schema,
 {
-    batch: &'a RecordBatch,
-    item_buf: Option<(S::PrimaryKey, Option<S>)>,
-    inner: Range<'a, InternalKey<S::PrimaryKey>, u32>,
-    ts: TimeStamp,
+    selected: RecordBatch,
+    inner: Range<usize>,
+    filter: Option<ScanFilter<S>>,
+    _p: PhantomData<S>,
 }
 
-impl<'a, S> Stream for IndexBatchStream<'a, S>
+impl<S> IndexBatchStream<S>
 where
     S: Schema,
 {
-    type Item = Result<(S::PrimaryKey, Option<S>), StreamError<S::PrimaryKey, S>>;
+    /// Masks the rows `filter` rejects as deletions as they are yielded, see [`mask`].
+    pub(crate) fn mask(mut self, filter: Option<&ScanFilter<S>>) -> Self {
+        self.filter = filter.cloned();
+        self
+    }
 
-    fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let this = self.project();
-        for (InternalKey { key, ts }, offset) in this.inner.by_ref() {
-            if ts <= this.ts
-                && matches!(
-                    this.item_buf.as_ref().map(|(k, _)| k != key),
-                    Some(true) | None
-                )
+    /// Moves to the first row at or after `key`, by a binary search over those left.
+    pub(crate) fn seek(&mut self, key: &S::PrimaryKey) {
+        let (mut start, mut end) = (self.inner.start, self.inner.end);
+        while start < end {
+            let mid = start + (end - start) / 2;
+            if S::Comparator::compare(&S::primary_key_from_batch(&self.selected, mid), key).is_lt()
             {
-                return Poll::Ready(
-                    this.item_buf
-                        .replace((key.clone(), S::from_batch(this.batch, *offset as usize).1))
-                        .map(Ok),
-                );
+                start = mid + 1;
+            } else {
+                end = mid;
             }
         }
-        Poll::Ready(this.item_buf.take().map(Ok))
+        self.inner.start = start;
+    }
+}
+
+impl<S> Stream for IndexBatchStream<S>
+where
+    S: Schema,
+{
+    type Item = Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>;
+
+    fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let selected = &*this.selected;
+        Poll::Ready(this.inner.next().map(|offset| {
+            let (key, value) = S::from_batch(selected, offset);
+            let ts = schema::timestamps(selected).value(offset);
+            Ok(mask(this.filter.as_ref(), key, ts, value))
+        }))
     }
 }
 
@@ -56,55 +85,87 @@ impl<S> IndexBatch<S>
 where
     S: Schema,
 {
+    /// Selects the newest version visible at `ts` of each key in range with Arrow kernels, in one
+    /// vectorized pass over the rows instead of decoding each of them. The versions under an
+    /// operand are selected along with it, see [`Schema::is_operand`].
     pub(crate) async fn range(
         &self,
-        lower: Option<&S::PrimaryKey>,
-        upper: Option<&S::PrimaryKey>,
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
         ts: &TimeStamp,
-    ) -> Result<IndexBatchStream<S>, StreamError<S::PrimaryKey, S>> {
-        let mut iterator = IndexBatchStream {
-            batch: &self.batch,
-            inner: self.index.range((
-                lower
-                    .map(|k| {
-                        Bound::Included(InternalKey {
-                            key: k.clone(),
-                            ts: *ts,
-                        })
-                    })
-                    .unwrap_or(Bound::Unbounded),
-                upper
-                    .map(|k| {
-                        Bound::Included(InternalKey {
-                            key: k.clone(),
-                            ts: TimeStamp::default(),
-                        })
-                    })
-                    .unwrap_or(Bound::Unbounded),
-            )),
-            item_buf: None,
-            ts: *ts,
+    ) -> Result<IndexBatchStream<S>, ScanError<S::PrimaryKey, S>> {
+        let start = match lower {
+            Bound::Included(key) => self.lower_bound(key, *ts),
+            Bound::Excluded(key) => self.upper_bound(key, TimeStamp::MIN),
+            Bound::Unbounded => 0,
         };
+        let end = match upper {
+            Bound::Included(key) => self.upper_bound(key, TimeStamp::MIN),
+            Bound::Excluded(key) => self.lower_bound(key, TimeStamp::MAX),
+            Bound::Unbounded => self.len(),
+        }
+        .max(start);
+
+        let selected = self
+            .select(start, end - start, *ts)
+            .map_err(ScanError::Arrow)?;
 
-        {
-            let mut iterator = pin!(&mut iterator);
-            // filling first item
-            let _ = iterator.next().await;
+        Ok(IndexBatchStream {
+            inner: 0..selected.num_rows(),
+            selected,
+            filter: None,
+            _p: PhantomData,
+        })
+    }
+
+    fn select(
+        &self,
+        offset: usize,
+        len: usize,
+        ts: TimeStamp,
+    ) -> Result<RecordBatch, arrow::error::ArrowError> {
+        let rows = self.batch.slice(offset, len);
+        let visible = lt_eq(
+            &self.timestamps.slice(offset, len),
+            &UInt64Array::new_scalar(ts),
+        )?;
+        if len <= 1 {
+            return filter_record_batch(&rows, &visible);
         }
+        // versions of a key are newest first, so the newest visible one follows either another
+        // key or a version too new to see
+        let keys = rows.column(0);
+        let new_key = distinct(&keys.slice(1, len - 1), &keys.slice(0, len - 1))?;
+        let follows = or(&new_key, &not(&visible.slice(0, len - 1))?)?;
+        let follows = concat(&[&BooleanArray::from(vec![true]), &follows])?;
+        let newest = and(&visible, follows.as_boolean())?;
 
-        Ok(iterator)
+        let ops = rows.column(OP_COLUMN).as_primitive::<Int8Type>();
+        if !ops.values().contains(&(Op::Merge as i8)) {
+            return filter_record_batch(&rows, &newest);
+        }
+        // the versions under a selected operand are selected too, for the merge to fold them into
+        // it
+        let mut selected = Vec::with_capacity(len);
+        for offset in 0..len {
+            let under_operand = offset > 0
+                && selected[offset - 1]
+                && ops.value(offset - 1) == Op::Merge as i8
+                && !new_key.value(offset - 1);
+            selected.push(newest.value(offset) || under_operand);
+        }
+        filter_record_batch(&rows, &BooleanArray::from(selected))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::ops::Bound;
+
     use executor::futures::StreamExt;
     use futures::executor::block_on;
 
-    use crate::{
-        mem_table::MemTable, oracle::LocalOracle, tests::UserInner,
-        wal::provider::in_mem::InMemProvider, Db,
-    };
+    use crate::{mem_table::MemTable, tests::UserInner};
 
     #[test]
     fn range() {
@@ -149,17 +210,19 @@ mod tests {
             );
             mem_table.insert(3, 0, None);
 
-            let batch = Db::<UserInner, LocalOracle<u64>, InMemProvider>::freeze(mem_table)
+            let batch = mem_table.to_batches(usize::MAX).remove(0);
+
+            let mut iterator = batch
+                .range(Bound::Included(&1), Bound::Included(&2), &1)
                 .await
                 .unwrap();
 
-            let mut iterator = batch.range(Some(&1), Some(&2), &1).await.unwrap();
-
-            assert_eq!(iterator.next().await.unwrap().unwrap(), (1, None));
+            assert_eq!(iterator.next().await.unwrap().unwrap(), (1, 1, None));
             assert_eq!(
                 iterator.next().await.unwrap().unwrap(),
                 (
                     2,
+                    0,
                     Some(UserInner::new(
                         2,
                         "2".to_string(),
@@ -175,7 +238,59 @@ mod tests {
                     ))
                 )
             );
-            assert!(iterator.next().await.is_none())
+            assert!(iterator.next().await.is_none());
+            drop(iterator);
+
+            let mut iterator = batch
+                .range(Bound::Excluded(&0), Bound::Excluded(&2), &1)
+                .await
+                .unwrap();
+            assert_eq!(iterator.next().await.unwrap().unwrap(), (1, 1, None));
+            assert!(iterator.next().await.is_none());
+            drop(iterator);
+
+            let mut iterator = batch
+                .range(Bound::Excluded(&1), Bound::Unbounded, &0)
+                .await
+                .unwrap();
+            assert_eq!(iterator.next().await.unwrap().unwrap().0, 2);
+            assert_eq!(iterator.next().await.unwrap().unwrap().0, 3);
+            assert!(iterator.next().await.is_none());
+            drop(iterator);
+
+            // the newest version of 1 is too new to see
+            let mut iterator = batch
+                .range(Bound::Excluded(&0), Bound::Included(&1), &0)
+                .await
+                .unwrap();
+            assert_eq!(
+                iterator.next().await.unwrap().unwrap(),
+                (
+                    1,
+                    0,
+                    Some(UserInner::new(
+                        1,
+                        "1".to_string(),
+                        false,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0
+                    ))
+                )
+            );
+            assert!(iterator.next().await.is_none());
+            drop(iterator);
+
+            let mut iterator = batch
+                .range(Bound::Excluded(&3), Bound::Unbounded, &1)
+                .await
+                .unwrap();
+            assert!(iterator.next().await.is_none());
         })
     }
 }
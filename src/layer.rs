@@ -0,0 +1,559 @@
+//! Decorators over a [`crate::Db`], or over each other, that transactions run against like against
+//! the db itself.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error, io,
+    marker::PhantomData,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    comparator::Comparator,
+    idempotency::Reservation,
+    oracle::{ConflictChecker, OracleState, TimeStamp, TimestampProvider, WriteCommitError},
+    reaper::TxnLease,
+    record::{RangeDelete, RecordType, SystemRecord},
+    schema::Schema,
+    stream::{EStreamImpl, ScanError},
+    transaction::{Transaction, TxnListener},
+    validate::ValidationError,
+    watermark::InFlight,
+    GetWrite,
+};
+
+macro_rules! forward_oracle {
+    ($layer:ident<$($param:ident),*>) => {
+        impl<$($param),*> TimestampProvider for $layer<$($param),*>
+        where
+            S: Schema,
+            DB: TimestampProvider,
+        {
+            fn start_read(&self) -> TimeStamp {
+                self.inner.start_read()
+            }
+
+            fn read_commit(&self, ts: TimeStamp) {
+                self.inner.read_commit(ts)
+            }
+
+            fn start_write(&self) -> TimeStamp {
+                self.inner.start_write()
+            }
+
+            fn now(&self) -> TimeStamp {
+                self.inner.now()
+            }
+
+            fn advance(&self, ts: TimeStamp) -> TimeStamp {
+                self.inner.advance(ts)
+            }
+
+            fn oldest_read(&self) -> TimeStamp {
+                self.inner.oldest_read()
+            }
+
+            fn active_reads(&self) -> Vec<(TimeStamp, usize)> {
+                self.inner.active_reads()
+            }
+        }
+    };
+}
+
+/// Serves lookups of the latest version of recently written keys from memory. Every write to the
+/// inner store has to go through the cache, otherwise lookups may miss newer versions.
+#[derive(Debug)]
+pub struct WriteThroughCache<S, DB>
+where
+    S: Schema,
+{
+    inner: DB,
+    capacity: usize,
+    entries: Mutex<HashMap<S::PrimaryKey, (TimeStamp, Option<S>)>>,
+}
+
+impl<S, DB> WriteThroughCache<S, DB>
+where
+    S: Schema,
+    DB: GetWrite<S>,
+{
+    /// Caches the latest version of at most `capacity` keys.
+    pub fn new(inner: DB, capacity: usize) -> Self {
+        WriteThroughCache {
+            inner,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn new_txn(self: &Arc<Self>) -> Transaction<S, Self> {
+        Transaction::new(self.clone())
+    }
+
+    pub fn txn(&self) -> Transaction<S, Self, &Self> {
+        Transaction::new(self)
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.inner
+    }
+
+    fn cache(&self, key: S::PrimaryKey, ts: TimeStamp, value: Option<S>) {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(&key) {
+            // batches of concurrent commits may be applied out of order
+            Some(entry) if entry.0 >= ts => {}
+            Some(entry) => *entry = (ts, value),
+            None => {
+                if entries.len() >= self.capacity {
+                    let Some(evicted) = entries.keys().next().cloned() else {
+                        return;
+                    };
+                    entries.remove(&evicted);
+                }
+                entries.insert(key, (ts, value));
+            }
+        }
+    }
+}
+
+forward_oracle!(WriteThroughCache<S, DB>);
+
+impl<S, DB> ConflictChecker<S::PrimaryKey> for WriteThroughCache<S, DB>
+where
+    S: Schema,
+    DB: ConflictChecker<S::PrimaryKey>,
+{
+    fn check_commit(
+        &self,
+        read_at: TimeStamp,
+        write_at: TimeStamp,
+        in_write: HashSet<S::PrimaryKey>,
+        oldest_read: TimeStamp,
+    ) -> Result<(), WriteCommitError<S::PrimaryKey>> {
+        self.inner
+            .check_commit(read_at, write_at, in_write, oldest_read)
+    }
+
+    fn describe(&self, state: &mut OracleState) {
+        self.inner.describe(state)
+    }
+}
+
+impl<S, DB> GetWrite<S> for WriteThroughCache<S, DB>
+where
+    S: Schema,
+    DB: GetWrite<S>,
+{
+    async fn get(&self, key: &S::PrimaryKey, ts: &TimeStamp) -> Option<S> {
+        if let Some((cached_at, value)) = self.entries.lock().unwrap().get(key) {
+            if cached_at <= ts {
+                return value.clone();
+            }
+        }
+        self.inner.get(key, ts).await
+    }
+
+    fn begin_write(&self) -> (TimeStamp, InFlight) {
+        self.inner.begin_write()
+    }
+
+    async fn write(
+        &self,
+        record_type: RecordType,
+        ts: TimeStamp,
+        value: S,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        self.inner.write(record_type, ts, value.clone()).await?;
+        self.cache(value.primary_key(), ts, Some(value));
+        Ok(())
+    }
+
+    async fn remove(
+        &self,
+        record_type: RecordType,
+        ts: TimeStamp,
+        key: S::PrimaryKey,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        self.inner.remove(record_type, ts, key.clone()).await?;
+        self.cache(key, ts, None);
+        Ok(())
+    }
+
+    async fn write_batch(
+        &self,
+        kvs: impl ExactSizeIterator<Item = (S::PrimaryKey, TimeStamp, Option<S>)>,
+        ranges: Vec<RangeDelete<S::PrimaryKey>>,
+        system: Vec<SystemRecord>,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        let mut written = Vec::with_capacity(kvs.len());
+        let removed = ranges.clone();
+        self.inner
+            .write_batch(kvs.inspect(|kv| written.push(kv.clone())), ranges, system)
+            .await?;
+        // before caching the writes of the commit, which the ranges leave alone
+        self.entries.lock().unwrap().retain(|key, _| {
+            !removed.iter().any(|range| {
+                S::Comparator::contains(range.lower.as_ref(), range.upper.as_ref(), key)
+            })
+        });
+        for (key, ts, value) in written {
+            self.cache(key, ts, value);
+        }
+        Ok(())
+    }
+
+    fn check_size(
+        &self,
+        key: &S::PrimaryKey,
+        value: Option<&S>,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        self.inner.check_size(key, value)
+    }
+
+    fn validate(&self, key: &S::PrimaryKey, value: Option<&S>) -> Result<(), ValidationError> {
+        self.inner.validate(key, value)
+    }
+
+    fn intercept(&self, read_at: TimeStamp, writes: &mut BTreeMap<S::PrimaryKey, Option<S>>) {
+        self.inner.intercept(read_at, writes)
+    }
+
+    fn sample_txn(&self) -> Option<Arc<dyn TxnListener>> {
+        self.inner.sample_txn()
+    }
+
+    fn has_aggregates(&self) -> bool {
+        self.inner.has_aggregates()
+    }
+
+    async fn aggregate_deltas(
+        &self,
+        read_at: TimeStamp,
+        writes: &BTreeMap<S::PrimaryKey, Option<S>>,
+    ) -> BTreeMap<String, i64> {
+        self.inner.aggregate_deltas(read_at, writes).await
+    }
+
+    async fn apply_aggregates(&self, deltas: BTreeMap<String, i64>, ts: TimeStamp) {
+        self.inner.apply_aggregates(deltas, ts).await
+    }
+
+    fn lease_txn(&self, read_at: TimeStamp) -> TxnLease {
+        self.inner.lease_txn(read_at)
+    }
+
+    fn end_txn(&self, lease: &TxnLease, read_at: TimeStamp) {
+        self.inner.end_txn(lease, read_at)
+    }
+
+    async fn inner_range<'a>(
+        &'a self,
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
+        ts: &TimeStamp,
+    ) -> Result<Vec<EStreamImpl<'a, S>>, ScanError<S::PrimaryKey, S>>
+    where
+        S::PrimaryKey: 'a,
+        TimeStamp: 'a,
+        S: 'a,
+    {
+        self.inner.inner_range(lower, upper, ts).await
+    }
+
+    async fn reserve_idempotency_key(&self, key: &str) -> io::Result<Option<Reservation>> {
+        self.inner.reserve_idempotency_key(key).await
+    }
+
+    async fn commit_idempotency_key(&self, reservation: Reservation, record: SystemRecord) {
+        self.inner.commit_idempotency_key(reservation, record).await
+    }
+}
+
+/// What went through a [`Metered`] store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccessStats {
+    pub gets: u64,
+    /// gets that found a value
+    pub hits: u64,
+    /// single rows and batches written, each counted once
+    pub writes: u64,
+    pub written_rows: u64,
+    pub scans: u64,
+    pub conflicts: u64,
+    pub errors: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    gets: AtomicU64,
+    hits: AtomicU64,
+    writes: AtomicU64,
+    written_rows: AtomicU64,
+    scans: AtomicU64,
+    conflicts: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Counts the lookups, writes, scans and commit conflicts going to the inner store.
+#[derive(Debug)]
+pub struct Metered<S, DB> {
+    inner: DB,
+    counters: Counters,
+    _p: PhantomData<fn() -> S>,
+}
+
+impl<S, DB> Metered<S, DB>
+where
+    S: Schema,
+    DB: GetWrite<S>,
+{
+    pub fn new(inner: DB) -> Self {
+        Metered {
+            inner,
+            counters: Counters::default(),
+            _p: Default::default(),
+        }
+    }
+
+    pub fn new_txn(self: &Arc<Self>) -> Transaction<S, Self> {
+        Transaction::new(self.clone())
+    }
+
+    pub fn txn(&self) -> Transaction<S, Self, &Self> {
+        Transaction::new(self)
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.inner
+    }
+
+    pub fn stats(&self) -> AccessStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        AccessStats {
+            gets: load(&self.counters.gets),
+            hits: load(&self.counters.hits),
+            writes: load(&self.counters.writes),
+            written_rows: load(&self.counters.written_rows),
+            scans: load(&self.counters.scans),
+            conflicts: load(&self.counters.conflicts),
+            errors: load(&self.counters.errors),
+        }
+    }
+
+    fn count<T, E>(&self, rows: u64, result: Result<T, E>) -> Result<T, E> {
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        match &result {
+            Ok(_) => self
+                .counters
+                .written_rows
+                .fetch_add(rows, Ordering::Relaxed),
+            Err(_) => self.counters.errors.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+}
+
+forward_oracle!(Metered<S, DB>);
+
+impl<S, DB> ConflictChecker<S::PrimaryKey> for Metered<S, DB>
+where
+    S: Schema,
+    DB: ConflictChecker<S::PrimaryKey>,
+{
+    fn check_commit(
+        &self,
+        read_at: TimeStamp,
+        write_at: TimeStamp,
+        in_write: HashSet<S::PrimaryKey>,
+        oldest_read: TimeStamp,
+    ) -> Result<(), WriteCommitError<S::PrimaryKey>> {
+        let result = self
+            .inner
+            .check_commit(read_at, write_at, in_write, oldest_read);
+        if result.is_err() {
+            self.counters.conflicts.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn describe(&self, state: &mut OracleState) {
+        self.inner.describe(state)
+    }
+}
+
+impl<S, DB> GetWrite<S> for Metered<S, DB>
+where
+    S: Schema,
+    DB: GetWrite<S>,
+{
+    async fn get(&self, key: &S::PrimaryKey, ts: &TimeStamp) -> Option<S> {
+        self.counters.gets.fetch_add(1, Ordering::Relaxed);
+        let value = self.inner.get(key, ts).await;
+        if value.is_some() {
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        value
+    }
+
+    fn begin_write(&self) -> (TimeStamp, InFlight) {
+        self.inner.begin_write()
+    }
+
+    async fn write(
+        &self,
+        record_type: RecordType,
+        ts: TimeStamp,
+        value: S,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        self.count(1, self.inner.write(record_type, ts, value).await)
+    }
+
+    async fn remove(
+        &self,
+        record_type: RecordType,
+        ts: TimeStamp,
+        key: S::PrimaryKey,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        self.count(1, self.inner.remove(record_type, ts, key).await)
+    }
+
+    async fn write_batch(
+        &self,
+        kvs: impl ExactSizeIterator<Item = (S::PrimaryKey, TimeStamp, Option<S>)>,
+        ranges: Vec<RangeDelete<S::PrimaryKey>>,
+        system: Vec<SystemRecord>,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        let rows = kvs.len() as u64;
+        self.count(rows, self.inner.write_batch(kvs, ranges, system).await)
+    }
+
+    fn check_size(
+        &self,
+        key: &S::PrimaryKey,
+        value: Option<&S>,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        self.inner.check_size(key, value)
+    }
+
+    fn validate(&self, key: &S::PrimaryKey, value: Option<&S>) -> Result<(), ValidationError> {
+        self.inner.validate(key, value)
+    }
+
+    fn intercept(&self, read_at: TimeStamp, writes: &mut BTreeMap<S::PrimaryKey, Option<S>>) {
+        self.inner.intercept(read_at, writes)
+    }
+
+    fn sample_txn(&self) -> Option<Arc<dyn TxnListener>> {
+        self.inner.sample_txn()
+    }
+
+    fn has_aggregates(&self) -> bool {
+        self.inner.has_aggregates()
+    }
+
+    async fn aggregate_deltas(
+        &self,
+        read_at: TimeStamp,
+        writes: &BTreeMap<S::PrimaryKey, Option<S>>,
+    ) -> BTreeMap<String, i64> {
+        self.inner.aggregate_deltas(read_at, writes).await
+    }
+
+    async fn apply_aggregates(&self, deltas: BTreeMap<String, i64>, ts: TimeStamp) {
+        self.inner.apply_aggregates(deltas, ts).await
+    }
+
+    fn lease_txn(&self, read_at: TimeStamp) -> TxnLease {
+        self.inner.lease_txn(read_at)
+    }
+
+    fn end_txn(&self, lease: &TxnLease, read_at: TimeStamp) {
+        self.inner.end_txn(lease, read_at)
+    }
+
+    async fn inner_range<'a>(
+        &'a self,
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
+        ts: &TimeStamp,
+    ) -> Result<Vec<EStreamImpl<'a, S>>, ScanError<S::PrimaryKey, S>>
+    where
+        S::PrimaryKey: 'a,
+        TimeStamp: 'a,
+        S: 'a,
+    {
+        self.counters.scans.fetch_add(1, Ordering::Relaxed);
+        let result = self.inner.inner_range(lower, upper, ts).await;
+        if result.is_err() {
+            self.counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    async fn reserve_idempotency_key(&self, key: &str) -> io::Result<Option<Reservation>> {
+        self.inner.reserve_idempotency_key(key).await
+    }
+
+    async fn commit_idempotency_key(&self, reservation: Reservation, record: SystemRecord) {
+        self.inner.commit_idempotency_key(reservation, record).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{AccessStats, Metered, WriteThroughCache};
+    use crate::{
+        tests::{with_db, UserInner},
+        transaction::CommitError,
+    };
+
+    #[test]
+    fn layered_txns() {
+        with_db(|db| async move {
+            let store = Arc::new(Metered::new(WriteThroughCache::new(db, 1)));
+            let user = |id, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+
+            let mut txn = store.new_txn();
+            txn.set(0, user(0, "0"));
+            txn.set(1, user(1, "1"));
+            txn.commit().await.unwrap();
+
+            let mut t0 = store.txn();
+            let mut t1 = store.txn();
+            assert_eq!(t0.get(&0).await, Some(user(0, "0")));
+            assert_eq!(t1.get(&1).await, Some(user(1, "1")));
+            assert_eq!(t0.get(&2).await, None);
+            t0.remove(0);
+            t1.set(0, user(0, "1"));
+            t0.commit().await.unwrap();
+            assert!(matches!(
+                t1.commit().await,
+                Err(CommitError::WriteConflict(_))
+            ));
+
+            assert_eq!(store.txn().get(&0).await, None);
+            assert_eq!(store.txn().get(&1).await, Some(user(1, "1")));
+            assert_eq!(
+                store.stats(),
+                AccessStats {
+                    gets: 5,
+                    hits: 3,
+                    writes: 2,
+                    written_rows: 3,
+                    scans: 0,
+                    conflicts: 1,
+                    errors: 0,
+                }
+            );
+        });
+    }
+}
@@ -1,82 +1,515 @@
+pub mod aggregate;
+mod background;
+pub mod blocking;
+pub mod builder;
 mod compactor;
+pub mod comparator;
 mod consistent_hash;
+pub mod counter;
+pub mod dyn_db;
+pub mod fallback;
+mod idempotency;
 pub(crate) mod index_batch;
+pub mod layer;
+mod load;
 pub(crate) mod mem_table;
-pub(crate) mod oracle;
-pub(crate) mod record;
-pub(crate) mod schema;
+pub mod oracle;
+mod priority;
+pub mod queue;
+mod range_delete;
+mod range_lock;
+pub mod raw;
+mod reaper;
+pub mod record;
+pub mod registry;
+pub mod schema;
 pub(crate) mod scope;
+mod sequencer;
 pub mod serdes;
+pub mod sstable;
+mod staleness;
 pub mod stream;
+pub mod system;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod timeseries;
 pub mod transaction;
+pub mod tuning;
 pub(crate) mod utils;
+pub mod validate;
 mod version;
 pub mod wal;
+mod watermark;
 
 use std::{
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, BTreeSet, VecDeque},
     error,
-    fmt::Debug,
+    fmt::{self, Debug},
     future::Future,
-    io, mem,
-    ops::DerefMut,
+    io, iter,
+    marker::PhantomData,
+    mem,
+    ops::{Bound, DerefMut, RangeBounds},
     path::PathBuf,
     pin::pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use async_lock::{Mutex, RwLock};
-use consistent_hash::jump_consistent_hash;
+use aggregate::Aggregate;
+use async_lock::{Mutex, RwLock, RwLockReadGuard, RwLockReadGuardArc};
+use background::{BackgroundPool, TaskPriority};
+use comparator::Comparator;
+pub use consistent_hash::MoveError;
+use consistent_hash::{shard_of, vnode_of, Router, VNODES};
+#[cfg(feature = "derive")]
+pub use elsm_marco::elsm_schema;
 use executor::{
     futures::{AsyncRead, StreamExt},
     shard::Shard,
     spawn,
 };
+use fallback::Fallback;
 use futures::{
     channel::{
         mpsc::{channel, Sender},
         oneshot,
     },
     executor::block_on,
-    AsyncWrite,
+    future::ready,
+    AsyncWrite, SinkExt,
 };
+use idempotency::{IdempotencyTable, Reservation};
+use load::ShardLoad;
 use mem_table::MemTable;
-use oracle::Oracle;
-use record::{Record, RecordType};
+use oracle::{ConflictChecker, Oracle, OracleState, TimestampProvider, WriteCommitError};
+use priority::{PriorityGate, ReadGate};
+use range_delete::RangeDeletes;
+use range_lock::RangeLocks;
+use reaper::{Reaper, TxnLease};
+use record::{EncodeError, RangeDelete, Record, RecordType, SystemRecord, WalEntry};
+use registry::RegistryError;
+use sequencer::{Sequencer, Ticket};
 use serdes::Encode;
 use snowflake::ProcessUniqueId;
-use tracing::error;
-use transaction::Transaction;
-use wal::{provider::WalProvider, WalFile, WalManager, WalWrite, WriteError};
+use staleness::StalenessTracker;
+use system::SystemTable;
+use tracing::{error, warn};
+use transaction::{CommitError, CommitInterceptor, Transaction, TxnListener};
+use tuning::{AdaptiveSize, SizeTuner};
+use validate::{AnyValidator, ValidationError, Validator};
+use wal::{
+    group_commit::GroupCommit,
+    provider::{
+        replica::{Repairing, Replica},
+        tiered::Tier,
+        HintedTables, StorageProvider, TableStore, TableStoreRef,
+    },
+    RecoverError, WalFile, WalManager, WalWrite, WriteError,
+};
+use watermark::{InFlight, Watermark};
 
 use crate::{
-    compactor::Compactor,
+    compactor::{CompactionError, Compactor},
     index_batch::IndexBatch,
     oracle::TimeStamp,
-    schema::Builder,
     serdes::Decode,
-    stream::{buf_stream::BufStream, merge_stream::MergeStream, EStreamImpl, StreamError},
-    version::{cleaner::Cleaner, set::VersionSet, Version},
+    stream::{
+        buf_stream::BufStream,
+        mask,
+        merge_stream::{ChangeStream, MergeStream, TimestampedStream},
+        record_batch_stream::RecordBatchStream,
+        EStreamImpl, ScanError, ScanFilter,
+    },
+    version::{
+        cleaner::Cleaner, edit::VersionEdit, migrator::Migrator, read::VersionRead,
+        set::VersionSet, Version, VersionError, MAX_LEVEL,
+    },
     wal::WalRecover,
 };
 
 pub type Offset = i64;
 pub(crate) type Immutable<S> = Arc<RwLock<VecDeque<IndexBatch<S>>>>;
+/// Frozen mem tables, oldest first, read as they are until encoded into the immutable queue.
+/// Always locked before the immutable queue.
+pub(crate) type Encoding<S> = Arc<RwLock<VecDeque<Arc<MemTable<S>>>>>;
 
 #[derive(Debug)]
 pub enum CompactTask {
     Flush(Option<oneshot::Sender<()>>),
+    Merge,
+    /// compacts a table into the level below, e.g. one reads found damaged or keep reading past
+    Rewrite(ProcessUniqueId),
 }
 
 #[derive(Debug)]
 pub struct DbOption {
     pub path: PathBuf,
+    /// Where the size starts from with `adaptive_mem_table_size`.
     pub max_mem_table_size: usize,
+    /// See [`DbOption::with_adaptive_mem_table_size`].
+    pub adaptive_mem_table_size: Option<AdaptiveSize>,
     pub immutable_chunk_num: usize,
+    pub immutable_merge_threshold: usize,
+    pub compaction_style: CompactionStyle,
+    /// Scans over mostly deleted keys, e.g. the drained head of a queue, compact the key range
+    /// they went over down the levels, which drops the deletions no read needs anymore.
+    pub deletion_compaction: Option<DeletionTrigger>,
     pub major_threshold_with_sst_size: usize,
     pub level_sst_magnification: usize,
-    pub max_sst_file_size: usize,
+    /// Size compaction cuts level 1 tables at, each deeper level multiplies it by
+    /// `target_file_size_multiplier`.
+    pub target_file_size_base: usize,
+    pub target_file_size_multiplier: usize,
+    /// How many tasks a single major compaction is split across, by key range.
+    pub max_subcompactions: usize,
     pub clean_channel_buffer: usize,
+    /// The version log, which records the tables of each level, is rewritten with only the live
+    /// tables once it grows past this many bytes, and on every open.
+    pub version_log_max_size: usize,
+    pub idempotency_retention: Duration,
+    pub max_batch_size: usize,
+    pub scan_batch_rows: usize,
+    pub scan_batch_bytes: usize,
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+    /// Tables of levels placed on [`Tier::Remote`] are demoted in the background, which only
+    /// moves them when the storage provider has a remote tier, e.g. [`Tiered`].
+    ///
+    /// [`Tiered`]: wal::provider::tiered::Tiered
+    pub level_tiers: [Tier; MAX_LEVEL],
+    pub wal_sync: WalSync,
+    /// Keeps the store in memory, e.g. for caches and tests: writes skip the wal and frozen mem
+    /// tables stay in the immutable queue instead of being flushed into tables. Nothing is
+    /// recovered on open but the tables of earlier [`Db::snapshot`]s.
+    pub in_memory: bool,
+    /// A warning is logged once a shard took this many times the mean of writes, see
+    /// [`DbStats::shards`].
+    pub shard_imbalance_threshold: f64,
+    /// See [`DbOption::with_validator`].
+    pub validator: Option<AnyValidator>,
+    pub txn_listener: Option<Arc<dyn TxnListener>>,
+    /// only every this many commits are reported to `txn_listener`
+    pub txn_sample_every: u64,
+    /// Transactions running longer are aborted: their read stops holding back compaction and
+    /// conflict tracking, and their commit fails with [`CommitError::Expired`]. Expired
+    /// transactions are reaped as new ones start.
+    pub txn_max_lifetime: Option<Duration>,
+    /// See [`DbOption::with_shard_placement`].
+    pub shard_placement: Option<Arc<dyn ShardPlacement>>,
+    /// See [`DbOption::with_replica`].
+    pub replica: Option<Replica>,
+    /// A table is compacted into the level below once point lookups read it this many times
+    /// without finding their key and went on to the tables below, see [`TableReads::misses`].
+    pub read_compaction_misses: Option<u64>,
+}
+
+/// A table of the current version, see [`Db::live_files`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMetadata<K> {
+    pub id: ProcessUniqueId,
+    pub level: usize,
+    pub min: K,
+    pub max: K,
+    pub size: u64,
+    pub entries: u64,
+    /// milliseconds since the unix epoch
+    pub created_at: u64,
+    pub being_compacted: bool,
+    /// since the db was opened
+    pub reads: TableReads,
+}
+
+/// Point lookups that read a table, see [`Db::level_reads`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TableReads {
+    /// lookups that found their key in the table
+    pub hits: u64,
+    /// lookups that read the table and went on to the tables below it, what compacting it into
+    /// the level below saves, see [`DbOption::read_compaction_misses`]
+    pub misses: u64,
+}
+
+/// What recovering the wal found, see [`Db::recovery_stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryStats {
+    pub records: u64,
+    /// records written while the store ran on another number of shards, which now live on
+    /// another shard than the one they were written by
+    pub moved: u64,
+}
+
+/// Where reads and writes stand, see [`Db::stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbStats {
+    pub oracle: OracleState,
+    /// every write at or below it is visible to reads
+    pub applied: TimeStamp,
+    /// the oldest write not applied yet, which holds `applied` back
+    pub oldest_in_flight: Option<TimeStamp>,
+    /// every write logged up to this sequence is applied to the mem tables
+    pub published: u64,
+    pub shards: Vec<ShardStats>,
+    /// the size mem tables freeze at
+    pub mem_table_size: usize,
+}
+
+/// How much a shard is written and scanned, see [`DbStats::shards`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShardStats {
+    pub writes: u64,
+    pub mem_table_rows: u64,
+    /// rows scans read out of the mem table
+    pub scanned_rows: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    /// How far behind the latest writes a read may be; reads within it skip the mutable shards.
+    pub max_staleness: Duration,
+    /// Whether tables read are kept in the storage provider's cache, if it has one. Large scans
+    /// turn it off so as not to evict the tables point reads keep hitting.
+    pub fill_cache: bool,
+    pub priority: ReadPriority,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            max_staleness: Duration::ZERO,
+            fill_cache: true,
+            priority: ReadPriority::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadPriority {
+    #[default]
+    Foreground,
+    /// For analytical scans: opens a table only while no foreground read is opening one.
+    Background,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WritePriority {
+    #[default]
+    Foreground,
+    /// For imports and backfills: yields the wal to waiting foreground writes, and waits for the
+    /// compactor itself instead of leaving the backlog to foreground writes.
+    Bulk,
+}
+
+/// When a write is acknowledged, relative to flushing its wal record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalSync {
+    /// Right away, the wal is flushed as the mem table freezes.
+    Never,
+    /// Once flushed. The writes waiting within the interval share a single flush of the segment.
+    Interval(Duration),
+}
+
+/// How compactions shape the tables flushed to level 0, see [`DbOption::compaction_style`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompactionStyle {
+    /// Tables are merged into the level below once their level holds too many, see
+    /// [`DbOption::major_threshold_with_sst_size`]. Reads touch the fewest tables.
+    #[default]
+    Leveled,
+    /// Every level 0 table and every level below is a sorted run, the newest on top. Once there
+    /// are `max_runs`, the newest runs are merged into one, for as long as the next run is at
+    /// most `size_ratio` percent larger than those merged so far. Data is rewritten fewer times
+    /// than with [`CompactionStyle::Leveled`], at the cost of reads touching more tables.
+    Tiered { max_runs: usize, size_ratio: u64 },
+}
+
+/// When a scan over mostly deleted keys schedules a compaction of the range it went over, see
+/// [`DbOption::deletion_compaction`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeletionTrigger {
+    /// deleted keys the scan went over, at least
+    pub min_tombstones: u64,
+    /// the share of deleted keys among those the scan went over, at least
+    pub ratio: f64,
+}
+
+/// Runs on the worker of every shard as the db opens, e.g. to pin the worker to a core of the
+/// NUMA node its storage interrupts land on.
+pub trait ShardPlacement: Send + Sync + 'static {
+    fn place(&self, shard: usize);
+}
+
+impl<F> ShardPlacement for F
+where
+    F: Fn(usize) + Send + Sync + 'static,
+{
+    fn place(&self, shard: usize) {
+        self(shard)
+    }
+}
+
+impl Debug for dyn ShardPlacement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ShardPlacement")
+    }
+}
+
+/// What [`Db::upsert_many`] did with a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upsert<V> {
+    Inserted,
+    /// The value replaced, as the batch saw it.
+    Updated(V),
+}
+
+/// Which commit timestamps [`Db::write_bulk_with_timestamps`] accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Below the oracle's current time, into a key range no table holds yet.
+    History,
+    /// Above every timestamp handed out so far, the oracle is moved past them.
+    Ahead,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    pub priority: WritePriority,
+}
+
+/// Scans yielding `(key, value)` rows.
+pub struct KeyValue;
+
+/// Scans yielding `(key, commit_ts, value)` rows, see [`ScanOptions::with_timestamps`].
+pub struct Timestamped;
+
+/// The stream a scan returns, picked by the row kind of its [`ScanOptions`].
+pub trait ScanOutput<'s, S>
+where
+    S: schema::Schema,
+{
+    type Stream;
+
+    fn wrap(stream: MergeStream<'s, S>) -> Self::Stream;
+}
+
+impl<'s, S> ScanOutput<'s, S> for KeyValue
+where
+    S: schema::Schema,
+{
+    type Stream = MergeStream<'s, S>;
+
+    fn wrap(stream: MergeStream<'s, S>) -> Self::Stream {
+        stream
+    }
+}
+
+impl<'s, S> ScanOutput<'s, S> for Timestamped
+where
+    S: schema::Schema,
+{
+    type Stream = TimestampedStream<'s, S>;
+
+    fn wrap(stream: MergeStream<'s, S>) -> Self::Stream {
+        TimestampedStream::new(stream)
+    }
+}
+
+pub struct ScanOptions<S, R = KeyValue>
+where
+    S: schema::Schema,
+{
+    filter: Option<ScanFilter<S>>,
+    limit: Option<usize>,
+    max_bytes: Option<usize>,
+    deadline: Option<Instant>,
+    fill_cache: bool,
+    priority: ReadPriority,
+    _row: PhantomData<R>,
+}
+
+impl<S> Default for ScanOptions<S>
+where
+    S: schema::Schema,
+{
+    fn default() -> Self {
+        Self {
+            filter: None,
+            limit: None,
+            max_bytes: None,
+            deadline: None,
+            fill_cache: true,
+            priority: ReadPriority::Foreground,
+            _row: PhantomData,
+        }
+    }
+}
+
+impl<S> ScanOptions<S>
+where
+    S: schema::Schema,
+{
+    /// Yields the commit timestamp of every row along with it, for change capture and for
+    /// resolving conflicts in the application.
+    pub fn with_timestamps(self) -> ScanOptions<S, Timestamped> {
+        ScanOptions {
+            filter: self.filter,
+            limit: self.limit,
+            max_bytes: self.max_bytes,
+            deadline: self.deadline,
+            fill_cache: self.fill_cache,
+            priority: self.priority,
+            _row: PhantomData,
+        }
+    }
+}
+
+impl<S, R> ScanOptions<S, R>
+where
+    S: schema::Schema,
+{
+    /// Keeps only the rows the filter accepts. It is evaluated as each source is read, before
+    /// rows are merged; a row it rejects shadows older versions of its key like a deletion would.
+    pub fn filter(
+        mut self,
+        filter: impl Fn(&S::PrimaryKey, Option<&S>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.filter = Some(Arc::new(move |key, _, value| filter(key, value)));
+        self
+    }
+
+    /// Stops the scan after `limit` rows, without reading further into any table.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Ends the scan with [`ScanError::Truncated`] once the rows yielded add up to more than
+    /// `max_bytes` encoded, if there are rows left.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Ends the scan with [`ScanError::Truncated`] at the first row left past `deadline`.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// See [`ReadOptions::fill_cache`].
+    pub fn fill_cache(mut self, fill_cache: bool) -> Self {
+        self.fill_cache = fill_cache;
+        self
+    }
+
+    /// See [`ReadOptions::priority`].
+    pub fn priority(mut self, priority: ReadPriority) -> Self {
+        self.priority = priority;
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -87,28 +520,71 @@ where
     mutable: MemTable<S>,
 }
 
+/// What a logged write holds until its records are applied to the mem tables of all its shards,
+/// shared by the tasks applying them, see [`Db::apply`].
+struct Applying {
+    _routes: RwLockReadGuardArc<()>,
+    ticket: Ticket,
+    /// `None` for writes at timestamps not allocated by the db, which the watermark ignores
+    _in_flight: Option<InFlight>,
+}
+
 pub struct Db<S, O, WP>
 where
     S: schema::Schema,
     O: Oracle<S::PrimaryKey>,
-    WP: WalProvider,
+    WP: StorageProvider,
 {
     option: Arc<DbOption>,
     pub(crate) oracle: O,
     wal_manager: Arc<WalManager<WP>>,
-    pub(crate) mutable_shards: Shard<unsend::lock::RwLock<MutableShard<S>>>,
+    table_store: TableStoreRef,
+    /// `table_store` opening tables with the hints of each combination of `fill_cache` and
+    /// priority, see [`Db::tables`].
+    table_reads: Vec<HintedTables>,
+    pub(crate) mutable_shards: Arc<Shard<unsend::lock::RwLock<MutableShard<S>>>>,
     pub(crate) immutable: Immutable<S>,
+    encoding: Encoding<S>,
+    /// `None` with [`DbOption::in_memory`]
     #[allow(clippy::type_complexity)]
-    pub(crate) wal: Arc<Mutex<WalFile<WP::File, S::PrimaryKey, S>>>,
-    pub(crate) compaction_tx: Mutex<Sender<CompactTask>>,
+    pub(crate) wal: Option<Arc<Mutex<WalFile<WP::File, S::PrimaryKey, S>>>>,
+    compaction_tx: Sender<CompactTask>,
+    /// key ranges scans found mostly deleted, see [`DbOption::deletion_compaction`]
+    deletes_tx: Sender<(S::PrimaryKey, S::PrimaryKey)>,
     pub(crate) version_set: VersionSet<S>,
+    system: SystemTable,
+    idempotency: IdempotencyTable,
+    range_deletes: RangeDeletes<S::PrimaryKey, S::Comparator>,
+    watermark: Arc<Watermark>,
+    staleness: Arc<StalenessTracker>,
+    load: Arc<ShardLoad>,
+    mem_table_size: Arc<SizeTuner>,
+    router: Arc<Router>,
+    background: Arc<BackgroundPool>,
+    priority_gate: Arc<PriorityGate>,
+    range_locks: RangeLocks<S::PrimaryKey, S::Comparator>,
+    reaper: Reaper,
+    /// the oldest read timestamp once reads end, compactions drop the versions only older reads
+    /// could see
+    retention: Arc<AtomicU64>,
+    recovery: RecoveryStats,
+    poisoned: Arc<AtomicBool>,
+    group_commit: Arc<GroupCommit>,
+    validator: Option<Arc<dyn Validator<S::PrimaryKey, S>>>,
+    interceptors: Vec<Box<dyn CommitInterceptor<S::PrimaryKey, S>>>,
+    fallback: Option<Box<dyn Fallback<S>>>,
+    aggregates: Vec<Box<dyn Aggregate<S::PrimaryKey, S>>>,
+    /// serializes the appends to the queues of the db, see [`queue::Queue::append`]
+    queue_appends: Mutex<()>,
+    txn_commits: AtomicU64,
+    sequencer: Arc<Sequencer>,
 }
 
 impl<S, O, WP> Db<S, O, WP>
 where
     S: schema::Schema,
     O: Oracle<S::PrimaryKey> + 'static,
-    WP: WalProvider,
+    WP: StorageProvider,
     WP::File: AsyncWrite + AsyncRead,
     io::Error: From<<S as Decode>::Error>,
 {
@@ -117,26 +593,86 @@ where
         wal_provider: WP,
         option: DbOption,
     ) -> Result<Self, WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
-        let wal_manager = Arc::new(WalManager::new(wal_provider));
-        let mutable_shards = Shard::new(|| {
+        let validator = option
+            .validator
+            .as_ref()
+            .map(|validator| {
+                validator.downcast().ok_or_else(|| {
+                    WriteError::Invalid(ValidationError::new(
+                        "the validator is not of the schema's key and value types",
+                    ))
+                })
+            })
+            .transpose()?;
+        // before the wal is recovered, which decodes rows with the schema
+        let system = SystemTable::new(&option).await.map_err(WriteError::Io)?;
+        let registered = registry::check::<S>(&system)
+            .await
+            .map_err(|err| match err {
+                RegistryError::Io(err) => WriteError::Io(err),
+                RegistryError::Mismatch(mismatch) => WriteError::Schema(mismatch),
+            })?;
+        let wal_provider = Arc::new(wal_provider);
+        let (damaged_tx, damaged_rx) = channel(1);
+        let table_store: TableStoreRef = match &option.replica {
+            Some(replica) => Arc::new(Repairing::new(wal_provider.clone(), replica, damaged_tx)),
+            None => wal_provider.clone(),
+        };
+        let wal_manager = Arc::new(WalManager::new(
+            wal_provider,
+            S::defaulted_fields().len() as u32,
+        ));
+        let mutable_shards = Arc::new(Shard::new(|| {
             unsend::lock::RwLock::new(crate::MutableShard {
                 mutable: MemTable::default(),
             })
+        }));
+        if let Some(placement) = &option.shard_placement {
+            for shard in 0..executor::worker_num() {
+                let placement = placement.clone();
+                mutable_shards
+                    .with(shard, move |_| async move { placement.place(shard) })
+                    .await;
+            }
+        }
+        if !option.in_memory {
+            // a segment opened at an existing number would overwrite its rows
+            wal_manager.resume().await.map_err(WriteError::Provider)?;
+        }
+        let wal = (!option.in_memory).then(|| {
+            Arc::new(Mutex::new(
+                block_on(wal_manager.create_wal_file(0)).unwrap(),
+            ))
         });
-        let wal = Arc::new(Mutex::new(block_on(wal_manager.create_wal_file()).unwrap()));
 
         let immutable = Arc::new(RwLock::new(VecDeque::new()));
         let option = Arc::new(option);
 
-        let (task_tx, mut task_rx) = channel(1);
-        let (mut cleaner, clean_sender) = Cleaner::new(option.clone());
+        let (task_tx, task_rx) = channel(1);
+        let (background, background_workers) = BackgroundPool::new();
+        let background = Arc::new(background);
+        let (mut cleaner, clean_sender) =
+            Cleaner::new(&option, table_store.clone(), background.clone());
 
         let version_set = VersionSet::<S>::new(&option, clean_sender.clone())
             .await
             .unwrap();
-        let mut compactor =
-            Compactor::<S>::new(immutable.clone(), option.clone(), version_set.clone());
+        let retention = Arc::new(AtomicU64::new(0));
+        let compactor = Arc::new(Mutex::new(Compactor::<S>::new(
+            immutable.clone(),
+            option.clone(),
+            version_set.clone(),
+            table_store.clone(),
+            retention.clone(),
+        )));
+
+        let (mut migrator, migrate_sender) =
+            Migrator::new(option.clone(), version_set.clone(), table_store.clone());
+        let poisoned = Arc::new(AtomicBool::new(false));
 
+        for worker in background_workers {
+            spawn(worker.run()).detach();
+        }
         spawn(async move {
             if let Err(err) = cleaner.listen().await {
                 error!("[Cleaner Error]: {}", err)
@@ -144,44 +680,190 @@ where
         })
         .detach();
         spawn(async move {
-            loop {
-                match task_rx.next().await {
-                    None => break,
-                    Some(task) => match task {
-                        CompactTask::Flush(option_tx) => {
-                            if let Err(err) = compactor.check_then_compaction(option_tx).await {
+            if let Err(err) = migrator.listen().await {
+                error!("[Migrator Error]: {}", err)
+            }
+        })
+        .detach();
+        let compaction_poisoned = poisoned.clone();
+        let compaction_pool = background.clone();
+        let (rewrite_versions, rewrite_option, rewrite_tables, rewrite_retention) = (
+            version_set.clone(),
+            option.clone(),
+            table_store.clone(),
+            retention.clone(),
+        );
+        // the tables hold on to the sender of damaged tables, so only the db's own sender ends
+        // the task
+        let mut tasks = futures::stream::select(
+            task_rx.map(Some).chain(futures::stream::once(ready(None))),
+            damaged_rx.map(|gen| Some(CompactTask::Rewrite(gen))),
+        );
+        spawn(async move {
+            while let Some(Some(task)) = tasks.next().await {
+                let compactor = compactor.clone();
+                match task {
+                    CompactTask::Flush(option_tx) => {
+                        let poisoned = compaction_poisoned.clone();
+                        let mut migrate_sender = migrate_sender.clone();
+                        compaction_pool.spawn(TaskPriority::Flush, async move {
+                            let result = compactor
+                                .lock()
+                                .await
+                                .check_then_compaction(option_tx)
+                                .await;
+                            if let Err(err) = result {
+                                poisoned.store(true, Ordering::Release);
                                 error!("[Compaction Error]: {}", err)
                             }
-                        }
-                    },
+                            let _ = migrate_sender.try_send(());
+                        })
+                    }
+                    CompactTask::Merge => compaction_pool
+                        .spawn(TaskPriority::Compaction, async move {
+                            compactor.lock().await.merge_immutables().await
+                        }),
+                    CompactTask::Rewrite(gen) => {
+                        let version_set = rewrite_versions.clone();
+                        let option = rewrite_option.clone();
+                        let table_store = rewrite_tables.clone();
+                        let retention = rewrite_retention.load(Ordering::Acquire);
+
+                        compaction_pool.spawn(TaskPriority::Compaction, async move {
+                            if let Err(err) = Compactor::rewrite_table(
+                                &version_set,
+                                &option,
+                                &table_store,
+                                gen,
+                                retention,
+                            )
+                            .await
+                            {
+                                error!("[Compaction Error]: {}", err)
+                            }
+                        })
+                    }
                 }
             }
         })
         .detach();
 
+        let (deletes_tx, mut deletes_rx) = channel(1);
+        let deletes_pool = background.clone();
+        let (deletes_versions, deletes_option, deletes_tables, deletes_retention) = (
+            version_set.clone(),
+            option.clone(),
+            table_store.clone(),
+            retention.clone(),
+        );
+        spawn(async move {
+            while let Some((min, max)) = deletes_rx.next().await {
+                let version_set = deletes_versions.clone();
+                let option = deletes_option.clone();
+                let table_store = deletes_tables.clone();
+                let retention = deletes_retention.load(Ordering::Acquire);
+
+                deletes_pool.spawn(TaskPriority::Compaction, async move {
+                    if let Err(err) = Compactor::compact_key_range(
+                        &version_set,
+                        &option,
+                        &table_store,
+                        min,
+                        max,
+                        retention,
+                    )
+                    .await
+                    {
+                        error!("[Compaction Error]: {}", err)
+                    }
+                })
+            }
+        })
+        .detach();
+
+        let idempotency = IdempotencyTable::new(option.idempotency_retention);
+        idempotency.expire(&system).await.map_err(WriteError::Io)?;
+        let range_deletes = RangeDeletes::load(&system).await.map_err(WriteError::Io)?;
+        let group_commit = Arc::new(GroupCommit::new(match option.wal_sync {
+            WalSync::Interval(interval) => interval,
+            WalSync::Never => Duration::ZERO,
+        }));
+        let read_gate = Arc::new(ReadGate::default());
         let mut db = Db {
             option,
             oracle,
             wal_manager: wal_manager.clone(),
+            table_reads: [true, false]
+                .into_iter()
+                .flat_map(|fill_cache| {
+                    let table_store = table_store.clone();
+                    let read_gate = read_gate.clone();
+                    [ReadPriority::Foreground, ReadPriority::Background].map(move |priority| {
+                        HintedTables::new(
+                            table_store.clone(),
+                            read_gate.clone(),
+                            fill_cache,
+                            priority,
+                        )
+                    })
+                })
+                .collect(),
+            table_store,
             mutable_shards,
             immutable,
+            encoding: Arc::new(RwLock::new(VecDeque::new())),
             wal,
-            compaction_tx: Mutex::new(task_tx),
+            compaction_tx: task_tx,
+            deletes_tx,
             version_set,
+            system,
+            idempotency,
+            range_deletes,
+            watermark: Arc::default(),
+            staleness: Arc::new(StalenessTracker::new(executor::worker_num())),
+            load: Arc::new(ShardLoad::new(executor::worker_num())),
+            mem_table_size: Arc::new(SizeTuner::new(
+                option.max_mem_table_size,
+                option.adaptive_mem_table_size,
+            )),
+            router: Arc::new(Router::new(executor::worker_num())),
+            background,
+            priority_gate: Arc::new(PriorityGate::default()),
+            range_locks: RangeLocks::default(),
+            reaper: Reaper::new(option.txn_max_lifetime),
+            retention,
+            recovery: RecoveryStats::default(),
+            poisoned,
+            group_commit,
+            validator,
+            interceptors: Vec::new(),
+            fallback: None,
+            aggregates: Vec::new(),
+            queue_appends: Mutex::new(()),
+            txn_commits: AtomicU64::new(0),
+            sequencer: Arc::new(Sequencer::new(executor::worker_num())),
         };
-        let mut file_stream = pin!(wal_manager.wal_provider.list());
-
-        while let Some(file) = file_stream.next().await {
-            let file = file.map_err(|err| WriteError::Internal(Box::new(err)))?;
+        if !db.option.in_memory {
+            let mut file_stream = pin!(wal_manager.wal_provider.list());
 
-            db.recover(
-                &mut wal_manager
-                    .pack_wal_file(file)
+            while let Some(file) = file_stream.next().await {
+                let file = file.map_err(WriteError::Provider)?;
+                let (header, mut wal) = wal_manager
+                    .pack_wal_file(file, registered)
                     .await
-                    .map_err(WriteError::Io)?,
-            )
-            .await
-            .map_err(|err| WriteError::Internal(Box::new(err)))?;
+                    .map_err(WriteError::Provider)?;
+                let shards = header
+                    .map(|header| header.shards as usize)
+                    .filter(|shards| *shards > 0);
+
+                db.recover(&mut wal, shards).await?;
+            }
+        }
+        if db.recovery.moved > 0 {
+            warn!(
+                "[Recover]: {} of {} wal records moved to another shard",
+                db.recovery.moved, db.recovery.records
+            );
         }
 
         Ok(db)
@@ -192,456 +874,1918 @@ impl<S, O, WP> Db<S, O, WP>
 where
     S: schema::Schema,
     O: Oracle<S::PrimaryKey>,
-    WP: WalProvider,
+    WP: StorageProvider,
     WP::File: AsyncWrite,
     io::Error: From<<S as Decode>::Error>,
 {
-    pub fn new_txn(self: &Arc<Self>) -> Transaction<S, Self> {
-        Transaction::new(self.clone())
+    /// Lets `interceptor` rewrite the write set of every transaction before it is checked for
+    /// conflicts. Interceptors run in the order they are added. Writes outside of transactions,
+    /// e.g. [`Db::put`], are not intercepted.
+    pub fn with_commit_interceptor(
+        mut self,
+        interceptor: impl CommitInterceptor<S::PrimaryKey, S>,
+    ) -> Self {
+        self.interceptors.push(Box::new(interceptor));
+        self
     }
 
-    async fn write(
-        &self,
-        record_type: RecordType,
-        ts: TimeStamp,
-        value: S,
-    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
-        self.append(record_type, value.primary_key(), ts, Some(value))
-            .await
+    /// Lets lookups of keys the db holds no version of fall through to `fallback` at the same
+    /// timestamp, e.g. to split hot and cold keys across stores. A key deleted in the db does not
+    /// fall through. Scans do not read the fallback.
+    pub fn with_fallback(mut self, fallback: impl Fallback<S>) -> Self {
+        self.fallback = Some(Box::new(fallback));
+        self
     }
 
-    async fn remove(
-        &self,
-        record_type: RecordType,
-        ts: TimeStamp,
-        key: S::PrimaryKey,
-    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
-        self.append(record_type, key, ts, None).await
+    /// Keeps `aggregate` up to date on every commit, in the system keyspace, so that it is read
+    /// with [`Db::aggregate`] instead of scanning. The deltas of a commit are logged in the wal
+    /// along with its rows and recovered with them. Rows written before it was added do not
+    /// count, and bulk imports, which skip the wal, are rejected.
+    pub fn with_aggregate(mut self, aggregate: impl Aggregate<S::PrimaryKey, S>) -> Self {
+        self.aggregates.push(Box::new(aggregate));
+        self
     }
 
-    async fn append(
-        &self,
-        record_type: RecordType,
-        key: S::PrimaryKey,
-        ts: TimeStamp,
-        value: Option<S>,
-    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
-        let consistent_hash =
-            jump_consistent_hash(fxhash::hash64(&key), executor::worker_num()) as usize;
-        let wal_manager = self.wal_manager.clone();
-        let wal = self.wal.clone();
-        let max_mem_table_size = self.option.max_mem_table_size;
-
-        let freeze = self
-            .mutable_shards
-            .with(consistent_hash, move |local| async move {
-                let mut local = local.write().await;
-                wal.lock()
-                    .await
-                    .write(Record::new(record_type, &key, ts, value.as_ref()))
-                    .await?;
+    /// The value of the aggregate named `name` for `group`, see [`Db::with_aggregate`].
+    pub async fn aggregate(&self, name: &str, group: &str) -> i64 {
+        aggregate::value(
+            self.system
+                .get(&aggregate::system_key(name, group))
+                .await
+                .as_deref(),
+        )
+    }
 
-                local.mutable.insert(key, ts, value);
-                if local.mutable.is_excess(max_mem_table_size) {
-                    let mut wal_file = wal_manager
-                        .create_wal_file()
-                        .await
-                        .map_err(WriteError::Io)?;
-                    {
-                        let mut guard = wal.lock().await;
-                        mem::swap(guard.deref_mut(), &mut wal_file);
-                    }
-                    wal_file.close().await.map_err(WriteError::Io)?;
-                    let mut mem_table = MemTable::default();
+    pub fn new_txn(self: &Arc<Self>) -> Transaction<S, Self> {
+        Transaction::new(self.clone())
+    }
 
-                    mem::swap(&mut local.mutable, &mut mem_table);
+    /// A transaction borrowing the db, for when it is not shared through an `Arc`.
+    pub fn txn(&self) -> Transaction<S, Self, &Self> {
+        Transaction::new(self)
+    }
 
-                    return Ok::<
-                        Option<MemTable<S>>,
-                        WriteError<<Record<&S::PrimaryKey, &S> as Encode>::Error>,
-                    >(Some(mem_table));
-                }
-                Ok(None)
-            })
-            .await?;
+    pub fn system(&self) -> &SystemTable {
+        &self.system
+    }
 
-        if let Some(mem_table) = freeze {
-            if mem_table.is_empty() {
-                return Ok(());
-            }
-            let mut guard = self.immutable.write().await;
+    pub fn recovery_stats(&self) -> &RecoveryStats {
+        &self.recovery
+    }
 
-            guard.push_back(Self::freeze(mem_table).await?);
-            if guard.len() > self.option.immutable_chunk_num {
-                if let Some(mut guard) = self.compaction_tx.try_lock() {
-                    let _ = guard.try_send(CompactTask::Flush(None));
-                }
-            }
+    pub fn stats(&self) -> DbStats {
+        DbStats {
+            oracle: self.oracle.debug_state(),
+            applied: self.watermark.applied(),
+            oldest_in_flight: self.watermark.oldest_in_flight(),
+            published: self.sequencer.published(),
+            shards: self.load.stats(),
+            mem_table_size: self.mem_table_size.size(),
         }
-        Ok(())
     }
 
-    async fn get(&self, key: &S::PrimaryKey, ts: &TimeStamp) -> Option<S> {
-        let consistent_hash =
-            jump_consistent_hash(fxhash::hash64(key), executor::worker_num()) as usize;
-
-        // Safety: read-only would not break data.
-        let (key, ts) = unsafe {
-            (
-                mem::transmute::<_, &S::PrimaryKey>(key),
-                mem::transmute::<_, &TimeStamp>(ts),
-            )
-        };
+    /// The virtual node `key` is hashed onto, see [`Db::move_vnode`].
+    pub fn vnode_of(&self, key: &S::PrimaryKey) -> usize {
+        vnode_of(key)
+    }
 
-        println!("A");
-        if let Some(value) = self
-            .mutable_shards
-            .with(consistent_hash, move |local| async move {
-                local.read().await.mutable.get(key, ts).map(|s| s.cloned())
-            })
-            .await
-        {
-            return value;
+    /// Moves the keys of `vnode` to `shard`, taking their versions out of the mem table of the
+    /// shard they were on. Reads and writes wait for the move, which runs on a task of its own
+    /// holding the routes until done, so that a move once started completes even if the
+    /// returned future is dropped.
+    pub async fn move_vnode(&self, vnode: usize, shard: usize) -> Result<(), MoveError> {
+        if vnode >= VNODES {
+            return Err(MoveError::VnodeOutOfRange(vnode));
         }
-        println!("B");
-        let guard = self.immutable.read().await;
-        for index_batch in guard.iter().rev() {
-            if let Some(value) = index_batch.find(key, ts).await {
-                return value;
-            }
+        if shard >= executor::worker_num() {
+            return Err(MoveError::ShardOutOfRange(shard));
         }
-        drop(guard);
+        let routes = self.router.write().await;
+        let router = self.router.clone();
+        let mutable_shards = self.mutable_shards.clone();
+        let staleness = self.staleness.clone();
+
+        let (tx, rx) = oneshot::channel();
+        spawn(async move {
+            let _routes = routes;
+            let from = router.shard_of_vnode(vnode);
+            if from != shard {
+                let versions = mutable_shards
+                    .with(from, move |local| async move {
+                        local
+                            .write()
+                            .await
+                            .mutable
+                            .take_where(|key| vnode_of(key) == vnode)
+                    })
+                    .await;
+                mutable_shards
+                    .with(shard, move |local| async move {
+                        let mut local = local.write().await;
+                        if !versions.is_empty() {
+                            staleness.on_write(shard);
+                        }
+                        for (key, ts, value) in versions {
+                            local.mutable.insert(key, ts, value);
+                        }
+                    })
+                    .await;
+                router.assign(vnode, shard);
+            }
+            let _ = tx.send(());
+        })
+        .detach();
+
+        rx.await.map_err(|_| MoveError::Closed)
+    }
 
-        println!("C");
-        let guard = self.version_set.current().await;
-        if let Ok(Some(record_batch)) = guard.query(key, &self.option).await {
-            return S::from_batch(&record_batch, 0).1;
+    /// Moves the most written virtual node of the most written shard to the least written
+    /// shard, if that narrows the gap between the two. Returns the virtual node moved and the
+    /// shard it moved to, `None` if nothing was moved.
+    pub async fn rebalance(&self) -> Result<Option<(usize, usize)>, MoveError> {
+        let vnodes = self.router.vnodes();
+        let mut loads = vec![0; executor::worker_num()];
+        for (shard, writes) in vnodes.iter() {
+            loads[*shard] += writes;
         }
-        drop(guard);
+        let (Some((busiest, _)), Some((idlest, _))) = (
+            loads.iter().enumerate().max_by_key(|(_, load)| **load),
+            loads.iter().enumerate().min_by_key(|(_, load)| **load),
+        ) else {
+            return Ok(None);
+        };
+        let Some((vnode, (_, writes))) = vnodes
+            .iter()
+            .enumerate()
+            .filter(|(_, (shard, _))| *shard == busiest)
+            .max_by_key(|(_, (_, writes))| *writes)
+        else {
+            return Ok(None);
+        };
+        if loads[idlest] + writes >= loads[busiest] {
+            return Ok(None);
+        }
+        self.move_vnode(vnode, idlest).await?;
 
-        None
+        Ok(Some((vnode, idlest)))
     }
 
-    async fn range(
-        &self,
-        lower: Option<&S::PrimaryKey>,
-        upper: Option<&S::PrimaryKey>,
-        ts: &TimeStamp,
-    ) -> Result<MergeStream<S>, StreamError<S::PrimaryKey, S>> {
-        let iters = self.inner_range(lower, upper, ts).await?;
+    /// The tables of the current version, level by level.
+    pub async fn live_files(&self) -> Vec<FileMetadata<S::PrimaryKey>> {
+        let version = self.version_set.current().await;
 
-        MergeStream::new(iters).await
+        version
+            .level_slice
+            .iter()
+            .enumerate()
+            .flat_map(|(level, scopes)| {
+                scopes.iter().map(move |scope| FileMetadata {
+                    id: scope.gen,
+                    level,
+                    min: scope.min.clone(),
+                    max: scope.max.clone(),
+                    size: scope.stats.size,
+                    entries: scope.stats.rows,
+                    created_at: scope.stats.created_at,
+                    being_compacted: self.version_set.is_compacting(&scope.gen),
+                    reads: self.version_set.reads().get(&scope.gen),
+                })
+            })
+            .collect()
     }
 
-    pub(crate) async fn inner_range<'s>(
-        &'s self,
-        lower: Option<&S::PrimaryKey>,
-        upper: Option<&S::PrimaryKey>,
-        ts: &TimeStamp,
-    ) -> Result<Vec<EStreamImpl<S>>, StreamError<S::PrimaryKey, S>> {
-        let mut iters = futures::future::try_join_all((0..executor::worker_num()).map(|i| {
-            let lower = lower.cloned();
-            let upper = upper.cloned();
-            let ts = *ts;
+    /// The point lookups that read the tables of each level of the current version.
+    pub async fn level_reads(&self) -> Vec<TableReads> {
+        let version = self.version_set.current().await;
 
-            self.mutable_shards.with(i, move |local| async move {
-                let guard = local.read().await;
-                let mut items = Vec::new();
+        version
+            .level_slice
+            .iter()
+            .map(|scopes| {
+                scopes.iter().fold(TableReads::default(), |total, scope| {
+                    let reads = self.version_set.reads().get(&scope.gen);
+                    TableReads {
+                        hits: total.hits + reads.hits,
+                        misses: total.misses + reads.misses,
+                    }
+                })
+            })
+            .collect()
+    }
 
-                let mut iter = pin!(
-                    guard
-                        .mutable
-                        .range(lower.as_ref(), upper.as_ref(), &ts)
-                        .await?,
-                );
+    /// Writes `values` in one transaction, at one timestamp, and returns what each of them did in
+    /// the order given, so that a change log can be made of the batch without reading the keys
+    /// first. A concurrent commit of any of the keys fails the whole batch, nothing of it is
+    /// written then.
+    #[allow(clippy::type_complexity)]
+    pub async fn upsert_many(
+        &self,
+        values: impl IntoIterator<Item = S>,
+    ) -> Result<(TimeStamp, Vec<(S::PrimaryKey, Upsert<S>)>), CommitError<S::PrimaryKey>> {
+        let values = values
+            .into_iter()
+            .map(|value| (value.primary_key(), value))
+            .collect::<Vec<_>>();
+        let mut txn = self.txn();
+        let visible = txn.multi_get(values.iter().map(|(key, _)| key)).await;
 
-                while let Some(item) = iter.next().await {
-                    let (k, v) = item?;
+        // a key given twice replaces the value given before it
+        let mut staged = BTreeMap::new();
+        let mut upserts = Vec::with_capacity(values.len());
+        for ((key, value), visible) in values.into_iter().zip(visible) {
+            let upsert = match staged.insert(key.clone(), value.clone()).or(visible) {
+                Some(previous) => Upsert::Updated(previous),
+                None => Upsert::Inserted,
+            };
+            txn.set(key.clone(), value);
+            upserts.push((key, upsert));
+        }
+        let ts = txn.commit().await?;
 
-                    items.push((k.clone(), v));
-                }
-                Ok(EStreamImpl::Buf(BufStream::new(items)))
-            })
-        }))
-        .await?;
-        let guard = self.immutable.read().await;
+        Ok((ts, upserts))
+    }
+
+    pub async fn remove_returning(
+        self: &Arc<Self>,
+        key: S::PrimaryKey,
+    ) -> Result<Option<S>, CommitError<S::PrimaryKey>> {
+        let mut txn = self.new_txn();
+        let value = txn.take(key).await;
+        txn.commit().await?;
 
-        for batch in guard.iter() {
-            let mut items = Vec::new();
-            let mut stream = pin!(batch.range(lower, upper, ts).await?);
+        Ok(value)
+    }
 
-            while let Some(item) = stream.next().await {
-                let (k, v) = item?;
+    /// Writes `value` outside of a transaction and returns its timestamp, which can be passed to
+    /// [`Db::get_at_least`] to read it back from any core.
+    pub async fn put(
+        &self,
+        value: S,
+    ) -> Result<TimeStamp, WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        self.put_with_options(value, &WriteOptions::default()).await
+    }
 
-                items.push((k.clone(), v));
-            }
-            iters.push(EStreamImpl::Buf(BufStream::new(items)));
-        }
-        drop(guard);
+    pub async fn put_with_options(
+        &self,
+        value: S,
+        options: &WriteOptions,
+    ) -> Result<TimeStamp, WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        let key = value.primary_key();
+        self.put_inner(key, Some(value), options.priority).await
+    }
 
-        self.version_set
-            .current()
+    pub async fn delete(
+        &self,
+        key: S::PrimaryKey,
+    ) -> Result<TimeStamp, WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        self.delete_with_options(key, &WriteOptions::default())
             .await
-            .iters(&mut iters, &self.option, lower, upper)
-            .await?;
+    }
 
-        Ok(iters)
+    pub async fn delete_with_options(
+        &self,
+        key: S::PrimaryKey,
+        options: &WriteOptions,
+    ) -> Result<TimeStamp, WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        self.put_inner(key, None, options.priority).await
     }
 
-    async fn write_batch(
+    async fn put_inner(
         &self,
-        mut kvs: impl ExactSizeIterator<Item = (S::PrimaryKey, TimeStamp, Option<S>)>,
-    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
-        match kvs.len() {
-            0 => Ok(()),
-            1 => {
-                let (key, ts, value) = kvs.next().unwrap();
-                self.append(RecordType::Full, key, ts, value).await
-            }
-            len => {
-                let (key, ts, value) = kvs.next().unwrap();
-                self.append(RecordType::First, key, ts, value).await?;
+        key: S::PrimaryKey,
+        value: Option<S>,
+        priority: WritePriority,
+    ) -> Result<TimeStamp, WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        self.validate(&key, value.as_ref())
+            .map_err(WriteError::Invalid)?;
+        let ts = self.oracle.start_write();
+        let _in_flight = self.watermark.begin(ts);
+        // register the write so that concurrent transactions still detect the conflict
+        self.oracle
+            .write_commit(ts - 1, ts, [key.clone()].into_iter().collect())
+            .map_err(|err| match err {
+                WriteCommitError::Conflict(_) => WriteError::Conflict,
+                WriteCommitError::WindowFull { limit } => WriteError::ConflictWindowFull { limit },
+            })?;
+        let mut deltas = BTreeMap::new();
+        if !self.aggregates.is_empty() {
+            // blind writes are not checked for conflicts, so the version replaced is the one the
+            // writes before this one leave
+            self.watermark.settle(ts).await;
+            let old = self.get(&key, &(ts - 1)).await;
+            self.add_deltas(&mut deltas, &key, old.as_ref(), value.as_ref());
+        }
+        self.write_batch(
+            iter::once((key, ts, value)),
+            Vec::new(),
+            aggregate::records(&deltas, ts),
+            priority,
+        )
+        .await?;
+        GetWrite::apply_aggregates(self, deltas, ts).await;
 
-                for (key, ts, value) in (&mut kvs).take(len - 2) {
-                    self.append(RecordType::Middle, key, ts, value).await?;
-                }
+        Ok(ts)
+    }
 
-                let (key, ts, value) = kvs.next().unwrap();
-                self.append(RecordType::Last, key, ts, value).await
+    /// Adds what replacing `old` of `key` with `new` adds to the aggregates to `deltas`.
+    fn add_deltas(
+        &self,
+        deltas: &mut BTreeMap<String, i64>,
+        key: &S::PrimaryKey,
+        old: Option<&S>,
+        new: Option<&S>,
+    ) {
+        let folded = new
+            .filter(|new| new.is_operand())
+            .map(|operand| S::merge(old, operand));
+        let new = folded.as_ref().or(new);
+        for aggregate in self.aggregates.iter() {
+            if let Some((group, delta)) = aggregate.delta(key, old, new) {
+                *deltas
+                    .entry(aggregate::system_key(aggregate.name(), &group))
+                    .or_default() += delta;
             }
         }
     }
 
-    async fn freeze(
-        mem_table: MemTable<S>,
-    ) -> Result<IndexBatch<S>, WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
-        let mut index = BTreeMap::new();
-
-        let mut builder = S::builder();
-
-        for (offset, (key, value)) in mem_table.data.into_iter().enumerate() {
-            builder.add(&key.key, value);
-            index.insert(key, offset as u32);
+    /// Imports `values` straight into tables, skipping the wal and the mem tables, and returns
+    /// the one timestamp all of them are committed at. The last value of a duplicated key wins.
+    /// Neither conflicts with transactions are detected nor should keys still held in memory
+    /// fall into the imported range, as those would shadow the import until flushed. Writes into
+    /// the range wait for the import, the rest of the keyspace stays writable.
+    pub async fn write_sorted_bulk(
+        &self,
+        values: impl IntoIterator<Item = S>,
+    ) -> Result<TimeStamp, WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        if !self.aggregates.is_empty() {
+            return Err(WriteError::ImportAggregated);
         }
-        let batch = builder.finish();
+        let mut rows = BTreeMap::new();
+        for value in values {
+            let key = value.primary_key();
+            self.validate(&key, Some(&value))
+                .map_err(WriteError::Invalid)?;
+            self.option
+                .check_size::<<Record<S::PrimaryKey, S> as Encode>::Error>(
+                    key.size(),
+                    value.size(),
+                )?;
+            rows.insert(key, value);
+        }
+        let mut rows = rows.into_iter().collect::<Vec<_>>();
+        rows.sort_by(|(key_1, _), (key_2, _)| S::Comparator::compare(key_1, key_2));
+
+        let _range = match (rows.first(), rows.last()) {
+            (Some((min, _)), Some((max, _))) => Some(self.range_locks.lock(min, max).await),
+            _ => None,
+        };
+        let ts = self.oracle.start_write();
+        let _in_flight = self.watermark.begin(ts);
+        let rows = rows
+            .into_iter()
+            .map(|(key, value)| (key, ts, Some(value)))
+            .collect();
+        self.bulk_load(rows, false).await?;
 
-        Ok(IndexBatch { batch, index })
+        Ok(ts)
     }
 
-    async fn recover<W>(
-        &mut self,
-        wal: &mut W,
-    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>>
-    where
-        W: WalRecover<S::PrimaryKey, S>,
-    {
-        let mut stream = pin!(wal.recover());
-        while let Some(record) = stream.next().await {
-            let mut record_type = RecordType::First;
-            let Record { key, ts, value, .. } =
-                record.map_err(|err| WriteError::Internal(Box::new(err)))?;
+    /// Imports versions carrying their own commit timestamps, e.g. history migrated from another
+    /// store, like [`Db::write_sorted_bulk`] does. A key may come with several versions, `None`
+    /// deleting it, and the last of the versions of a key at the same timestamp wins.
+    pub async fn write_bulk_with_timestamps(
+        &self,
+        rows: impl IntoIterator<Item = (S::PrimaryKey, TimeStamp, Option<S>)>,
+        mode: ImportMode,
+    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        if !self.aggregates.is_empty() {
+            return Err(WriteError::ImportAggregated);
+        }
+        let mut rows = rows.into_iter().collect::<Vec<_>>();
+        for (key, _, value) in rows.iter() {
+            self.validate(key, value.as_ref())
+                .map_err(WriteError::Invalid)?;
+            self.option
+                .check_size::<<Record<S::PrimaryKey, S> as Encode>::Error>(
+                    key.size(),
+                    value.as_ref().map(Encode::size).unwrap_or(0),
+                )?;
+        }
+        // reversed first, so that the stable sort leaves the last of equal versions first
+        rows.reverse();
+        rows.sort_by(|(key_1, ts_1, _), (key_2, ts_2, _)| {
+            S::Comparator::compare(key_1, key_2).then_with(|| ts_2.cmp(ts_1))
+        });
+        rows.dedup_by(|(key_1, ts_1, _), (key_2, ts_2, _)| key_1 == key_2 && ts_1 == ts_2);
 
-            self.append(
-                mem::replace(&mut record_type, RecordType::Middle),
-                key,
-                ts,
-                value,
-            )
-            .await?;
+        let timestamps = rows.iter().map(|(_, ts, _)| *ts);
+        let (Some(min_ts), Some(max_ts)) = (timestamps.clone().min(), timestamps.max()) else {
+            return Ok(());
+        };
+        let _range = self
+            .range_locks
+            .lock(&rows[0].0, &rows[rows.len() - 1].0)
+            .await;
+        match mode {
+            ImportMode::History => {
+                let now = self.oracle.now();
+                if max_ts >= now {
+                    return Err(WriteError::ImportTimestamp { ts: max_ts, now });
+                }
+                self.bulk_load(rows, true).await
+            }
+            ImportMode::Ahead => {
+                // the clock moves before the check, so that no write started meanwhile can take
+                // an imported timestamp
+                let _in_flight = self.watermark.begin(max_ts);
+                let now = self.oracle.advance(max_ts);
+                if min_ts <= now {
+                    return Err(WriteError::ImportTimestamp { ts: min_ts, now });
+                }
+                self.bulk_load(rows, false).await
+            }
         }
-        Ok(())
     }
-}
 
-impl<S, O, WP> Oracle<S::PrimaryKey> for Db<S, O, WP>
-where
-    S: schema::Schema,
-    O: Oracle<S::PrimaryKey>,
-    WP: WalProvider,
-{
-    fn start_read(&self) -> TimeStamp {
-        self.oracle.start_read()
+    /// Writes everything held in memory into a level 0 table, e.g. to persist a
+    /// [`DbOption::in_memory`] store, and returns the timestamp up to which every write is in
+    /// it. The store reopened on the same path reads the tables of its snapshots.
+    pub async fn snapshot(
+        &self,
+    ) -> Result<TimeStamp, WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        let ts = self.watermark.applied();
+        // keeps versions from moving between shards while they are copied
+        let _routes = self.router.read().await;
+        let max_batch_size = self.option.max_batch_size;
+        let mut batches = futures::future::join_all((0..executor::worker_num()).map(|i| {
+            self.mutable_shards.with(i, move |local| async move {
+                local.read().await.mutable.to_batches(max_batch_size)
+            })
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+        let encoding = self.encoding.read().await;
+        let immutable = self.immutable.read().await;
+        batches.extend(
+            encoding
+                .iter()
+                .flat_map(|mem_table| mem_table.to_batches(max_batch_size)),
+        );
+        batches.extend(
+            immutable
+                .iter()
+                .map(|batch| IndexBatch::new(batch.batch.clone())),
+        );
+        drop(immutable);
+        drop(encoding);
+        if batches.iter().all(|batch| batch.len() == 0) {
+            return Ok(ts);
+        }
+
+        let merged = IndexBatch::merge(batches.iter());
+        let scope = Compactor::minor_compaction(self.table_store.as_ref(), [merged].into())
+            .await
+            .map_err(compaction_error)?;
+        if let Some(scope) = scope {
+            self.version_set
+                .apply_edits(vec![VersionEdit::Add { level: 0, scope }], None, false)
+                .await
+                .map_err(|err| compaction_error(CompactionError::Version(err)))?;
+        }
+        Ok(ts)
     }
 
-    fn read_commit(&self, ts: TimeStamp) {
-        self.oracle.read_commit(ts)
+    async fn bulk_load(
+        &self,
+        rows: Vec<(S::PrimaryKey, TimeStamp, Option<S>)>,
+        disjoint: bool,
+    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        Compactor::bulk_load(
+            &self.version_set,
+            &self.option,
+            &self.table_store,
+            rows,
+            disjoint,
+            self.retention.load(Ordering::Acquire),
+        )
+        .await
+        .map(|_| ())
+        .map_err(compaction_error)
     }
 
-    fn start_write(&self) -> TimeStamp {
-        self.oracle.start_write()
+    /// The sequence every write is applied up to. Rows carry their commit timestamp as their
+    /// place in it, see [`ScanOptions::with_timestamps`] and [`Db::changes_since`].
+    pub fn latest_sequence(&self) -> TimeStamp {
+        self.watermark.applied()
     }
 
-    fn write_commit(
+    /// The rows changed after the sequence `since`, up to the returned [`Db::latest_sequence`],
+    /// for replicas to consume. Passing the returned sequence as `since` next time resumes
+    /// without repeating a change; a key changed several times in between is yielded once, at its
+    /// latest version. Deletions compacted away below the oldest read are not yielded.
+    pub async fn changes_since(
         &self,
-        read_at: TimeStamp,
-        write_at: TimeStamp,
-        in_write: std::collections::HashSet<S::PrimaryKey>,
-    ) -> Result<(), oracle::WriteConflict<S::PrimaryKey>> {
-        self.oracle.write_commit(read_at, write_at, in_write)
+        since: TimeStamp,
+    ) -> Result<(TimeStamp, ChangeStream<'_, S>), ScanError<S::PrimaryKey, S>> {
+        let upto = self.latest_sequence();
+        let iters = self
+            .inner_range(
+                Bound::Unbounded,
+                Bound::Unbounded,
+                &upto,
+                None,
+                self.tables(false, ReadPriority::Background),
+            )
+            .await?;
+
+        Ok((
+            upto,
+            ChangeStream::new(MergeStream::new(iters).await?, since),
+        ))
     }
-}
 
-pub(crate) trait GetWrite<S>: Oracle<S::PrimaryKey>
-where
-    S: schema::Schema,
-{
-    fn get(&self, key: &S::PrimaryKey, ts: &TimeStamp) -> impl Future<Output = Option<S>>
-    where
-        TimeStamp: Sync;
+    pub async fn get_at_least(&self, key: &S::PrimaryKey, seq: TimeStamp) -> Option<S> {
+        let ts = self.watermark.wait(seq).await;
+        self.get(key, &ts).await
+    }
 
-    fn write(
+    async fn write(
         &self,
         record_type: RecordType,
         ts: TimeStamp,
         value: S,
-    ) -> impl Future<Output = Result<(), Box<dyn error::Error + Send + Sync + 'static>>>;
+    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        self.append(
+            record_type,
+            value.primary_key(),
+            ts,
+            Some(value),
+            WritePriority::Foreground,
+        )
+        .await
+    }
 
     async fn remove(
         &self,
         record_type: RecordType,
         ts: TimeStamp,
         key: S::PrimaryKey,
-    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>>;
+    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        self.append(record_type, key, ts, None, WritePriority::Foreground)
+            .await
+    }
 
-    fn write_batch(
+    async fn append(
         &self,
-        kvs: impl ExactSizeIterator<Item = (S::PrimaryKey, TimeStamp, Option<S>)>,
-    ) -> impl Future<Output = Result<(), Box<dyn error::Error + Send + Sync + 'static>>>;
+        record_type: RecordType,
+        key: S::PrimaryKey,
+        ts: TimeStamp,
+        value: Option<S>,
+        priority: WritePriority,
+    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        self.validate(&key, value.as_ref())
+            .map_err(WriteError::Invalid)?;
+        let write = self.range_locks.write(&key, &key).await;
+        let seq = self
+            .append_unsynced(record_type, key, ts, value, priority)
+            .await?;
+        drop(write);
+        self.sync_wal(seq).await
+    }
 
-    fn inner_range<'a>(
-        &'a self,
-        lower: Option<&S::PrimaryKey>,
-        upper: Option<&S::PrimaryKey>,
-        ts: &TimeStamp,
-    ) -> impl Future<Output = Result<Vec<EStreamImpl<'a, S>>, StreamError<S::PrimaryKey, S>>>
-    where
-        S::PrimaryKey: 'a,
-        TimeStamp: 'a,
-        S: 'a;
-}
+    fn validate(&self, key: &S::PrimaryKey, value: Option<&S>) -> Result<(), ValidationError> {
+        match &self.validator {
+            Some(validator) => validator.validate(key, value),
+            None => Ok(()),
+        }
+    }
 
-impl<S, O, WP> GetWrite<S> for Db<S, O, WP>
-where
-    S: schema::Schema,
-    O: Oracle<S::PrimaryKey>,
-    WP: WalProvider,
-    WP::File: AsyncWrite,
-    io::Error: From<<S as Decode>::Error>,
-{
-    async fn write(
+    /// Waits for the wal to be flushed up to the record `seq`, unless `wal_sync` is `Never` or
+    /// there is no wal.
+    async fn sync_wal(
         &self,
-        record_type: RecordType,
-        ts: TimeStamp,
-        value: S,
-    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
-        Db::write(self, record_type, ts, value).await?;
-        Ok(())
+        seq: u64,
+    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        match (&self.option.wal_sync, &self.wal) {
+            (WalSync::Interval(_), Some(wal)) => self
+                .group_commit
+                .wait(seq, &**wal)
+                .await
+                .map_err(WriteError::Io),
+            _ => Ok(()),
+        }
     }
 
-    async fn remove(
+    /// Returns the group commit sequence of the record.
+    async fn append_unsynced(
         &self,
         record_type: RecordType,
-        ts: TimeStamp,
         key: S::PrimaryKey,
-    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
-        Db::remove(self, record_type, ts, key).await?;
-        Ok(())
+        ts: TimeStamp,
+        value: Option<S>,
+        priority: WritePriority,
+    ) -> Result<u64, WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        self.option
+            .check_size::<<Record<S::PrimaryKey, S> as Encode>::Error>(
+                key.size(),
+                value.as_ref().map(Encode::size).unwrap_or(0),
+            )?;
+        let routes = self.router.read().await;
+        let (seq, ticket) = self
+            .log(
+                iter::once((record_type, &key, ts, value.as_ref())),
+                &[],
+                Vec::new(),
+                priority,
+            )
+            .await?;
+        let applying = Arc::new(Applying {
+            _routes: routes,
+            ticket,
+            _in_flight: None,
+        });
+        let shard = self.router.shard_of(&key);
+        let applied = self.apply(&applying, shard, vec![(key, ts, value)]);
+        drop(applying);
+        let encoded = applied.await?;
+        self.settle(encoded, priority).await;
+        Ok(seq)
     }
 
-    async fn get(&self, key: &S::PrimaryKey, ts: &TimeStamp) -> Option<S> {
-        Db::get(self, key, ts).await
+    /// Writes the range deletes, the records and the system keys of a commit to the wal as one
+    /// run and returns the group commit sequence of the last one, along with the ticket ordering
+    /// their apply. The system keys come last, so that a run cut short is recovered without them.
+    /// The wal is released before the records are applied, so the next batch is logged while
+    /// this one is applied to the mem tables. Must be called while holding the routes, until the
+    /// records are applied.
+    async fn log<'r>(
+        &self,
+        records: impl IntoIterator<Item = (RecordType, &'r S::PrimaryKey, TimeStamp, Option<&'r S>)>,
+        ranges: &[RangeDelete<S::PrimaryKey>],
+        system: Vec<SystemRecord>,
+        priority: WritePriority,
+    ) -> Result<(u64, Ticket), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        let records = records.into_iter().collect::<Vec<_>>();
+        let shards = records
+            .iter()
+            .map(|(_, key, _, _)| self.router.route_write(*key))
+            .collect::<BTreeSet<_>>();
+        let _gate = self.priority_gate.enter(priority).await;
+        let Some(wal) = &self.wal else {
+            return Ok((0, self.sequencer.assign(shards)));
+        };
+        let mut wal = wal.lock().await;
+        if wal.is_torn() {
+            // recovery skips a torn record only at the end of a segment
+            let shard = shards.first().copied().unwrap_or(0);
+            let new_wal = self
+                .wal_manager
+                .create_wal_file(shard as u32)
+                .await
+                .map_err(WriteError::WalRotate)?;
+            let _ = mem::replace(wal.deref_mut(), new_wal).close().await;
+        }
+        let mut seq = 0;
+        for range in ranges {
+            wal.write_range_delete(range.as_ref()).await?;
+            seq = self.group_commit.appended();
+        }
+        for (record_type, key, ts, value) in records {
+            wal.write(Record::new(record_type, key, ts, value)).await?;
+            seq = self.group_commit.appended();
+        }
+        for system in system {
+            wal.write_system(system).await?;
+            seq = self.group_commit.appended();
+        }
+        Ok((seq, self.sequencer.assign(shards)))
     }
 
-    async fn write_batch(
+    /// Inserts logged records into the mem table of `shard`, freezing it once grown past
+    /// [`DbStats::mem_table_size`]. A frozen mem table is only swapped for an empty one here, and
+    /// encoded into the immutable queue by the background pool; the returned receiver is
+    /// notified once it is, and the compaction it calls for is scheduled.
+    ///
+    /// The records are inserted by a task spawned right away, which takes the turn of the write
+    /// in the shard and holds on to `applying` until done, so that records once logged are
+    /// applied even if the write is dropped meanwhile.
+    fn apply(
         &self,
-        kvs: impl ExactSizeIterator<Item = (S::PrimaryKey, TimeStamp, Option<S>)>,
-    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
-        Db::write_batch(self, kvs).await?;
-        Ok(())
-    }
+        applying: &Arc<Applying>,
+        shard: usize,
+        records: Vec<(S::PrimaryKey, TimeStamp, Option<S>)>,
+    ) -> impl Future<
+        Output = Result<
+            Option<oneshot::Receiver<()>>,
+            WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>,
+        >,
+    > {
+        let applying = applying.clone();
+        let mutable_shards = self.mutable_shards.clone();
+        let wal_manager = self.wal_manager.clone();
+        let wal = self.wal.clone();
+        let staleness = self.staleness.clone();
+        let load = self.load.clone();
+        let mem_table_size = self.mem_table_size.clone();
+        let shard_imbalance_threshold = self.option.shard_imbalance_threshold;
 
-    async fn inner_range<'a>(
-        &'a self,
-        lower: Option<&S::PrimaryKey>,
-        upper: Option<&S::PrimaryKey>,
-        ts: &TimeStamp,
-    ) -> Result<Vec<EStreamImpl<'a, S>>, StreamError<S::PrimaryKey, S>>
-    where
-        S::PrimaryKey: 'a,
-        TimeStamp: 'a,
-        S: 'a,
-    {
-        Db::inner_range(self, lower, upper, ts).await
+        let encoding = self.encoding.clone();
+        let immutable = self.immutable.clone();
+        let option = self.option.clone();
+        let compaction_tx = self.compaction_tx.clone();
+        let background = self.background.clone();
+
+        let (tx, rx) = oneshot::channel();
+        spawn(async move {
+            let _turn = applying.ticket.turn(shard).await;
+            let result = mutable_shards
+                .with(shard, move |local| async move {
+                    let mut local = local.write().await;
+                    let writes = records.len() as u64;
+                    for (key, ts, value) in records {
+                        local.mutable.insert(key, ts, value);
+                    }
+                    staleness.on_write(shard);
+                    if let Some((busiest, ratio)) = load.on_write(
+                        shard,
+                        writes,
+                        local.mutable.len(),
+                        shard_imbalance_threshold,
+                    ) {
+                        warn!(
+                            "[Shard Load]: shard {} took {:.1} times the mean of writes",
+                            busiest, ratio
+                        );
+                    }
+                    if !local.mutable.is_excess(mem_table_size.size()) {
+                        return Ok(None);
+                    }
+                    let rotated = match &wal {
+                        Some(wal) => {
+                            let new_wal = wal_manager
+                                .create_wal_file(shard as u32)
+                                .await
+                                .map_err(WriteError::WalRotate)?;
+                            let mut wal = wal.lock().await;
+                            wal.flush().await.map_err(WriteError::Freeze)?;
+                            Some((wal, new_wal))
+                        }
+                        None => None,
+                    };
+                    // taken past the flush, so that reads only wait for the swap
+                    let mut frozen = encoding.write().await;
+
+                    // from here on the mem table moves to the encoding queue without yielding
+                    let wal_file =
+                        rotated.map(|(mut wal, new_wal)| mem::replace(wal.deref_mut(), new_wal));
+                    staleness.on_freeze(shard);
+                    load.on_freeze(shard);
+                    let mem_table = mem::take(&mut local.mutable);
+                    let frozen_at = Instant::now();
+                    mem_table_size.on_freeze(mem_table.written_size(), frozen_at);
+                    let encoded = (!mem_table.is_empty()).then(|| {
+                        let mem_table = Arc::new(mem_table);
+                        let (notify, encoded) = oneshot::channel();
+                        frozen.push_back(mem_table.clone());
+                        background.spawn(TaskPriority::Flush, async move {
+                            encode(
+                                mem_table,
+                                encoding.clone(),
+                                immutable,
+                                option,
+                                compaction_tx,
+                                notify,
+                            )
+                            .await;
+                            mem_table_size.on_flushed(frozen_at.elapsed());
+                        });
+                        encoded
+                    });
+                    drop(frozen);
+                    if let Some(wal_file) = wal_file {
+                        wal_file.close().await.map_err(WriteError::Freeze)?;
+                    }
+
+                    Ok::<_, WriteError<<Record<&S::PrimaryKey, &S> as Encode>::Error>>(encoded)
+                })
+                .await;
+            let _ = tx.send(result);
+        })
+        .detach();
+
+        async move { rx.await.unwrap_or(Err(WriteError::Closed)) }
     }
-}
 
-impl DbOption {
-    pub(crate) fn new(path: impl Into<PathBuf> + Send) -> Self {
-        DbOption {
-            path: path.into(),
-            max_mem_table_size: 8 * 1024 * 1024,
-            immutable_chunk_num: 5,
-            major_threshold_with_sst_size: 10,
-            level_sst_magnification: 10,
-            max_sst_file_size: 64 * 1024 * 1024,
-            clean_channel_buffer: 10,
+    /// Bulk writes wait for the mem table they froze to be encoded, so that they are held back
+    /// by the compactor rather than piling up frozen mem tables.
+    async fn settle(&self, encoded: Option<oneshot::Receiver<()>>, priority: WritePriority) {
+        if let (Some(encoded), WritePriority::Bulk) = (encoded, priority) {
+            let _ = encoded.await;
         }
     }
 
-    pub(crate) fn table_path(&self, gen: &ProcessUniqueId) -> PathBuf {
-        self.path.join(format!("{}.parquet", gen))
-    }
-    pub(crate) fn version_path(&self) -> PathBuf {
-        self.path.join("version.log")
+    async fn get(&self, key: &S::PrimaryKey, ts: &TimeStamp) -> Option<S> {
+        self.get_from(key, ts, self.tables(true, ReadPriority::Foreground))
+            .await
     }
 
-    pub(crate) fn is_threshold_exceeded_major<S>(&self, version: &Version<S>, level: usize) -> bool
-    where
-        S: schema::Schema,
-    {
-        version.tables_len(level)
-            >= (self.major_threshold_with_sst_size * self.level_sst_magnification.pow(level as u32))
+    fn tables(&self, fill_cache: bool, priority: ReadPriority) -> &dyn TableStore {
+        &self.table_reads[usize::from(!fill_cache) * 2 + priority as usize]
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::sync::Arc;
+    async fn get_from(
+        &self,
+        key: &S::PrimaryKey,
+        ts: &TimeStamp,
+        tables: &dyn TableStore,
+    ) -> Option<S> {
+        let value = self.get_newest(key, ts, tables).await;
+        self.fold(key, ts, tables, value).await
+    }
 
-    use arrow::{
-        array::{
-            Array, BooleanArray, BooleanBuilder, Int16Array, Int16Builder, Int32Array,
-            Int32Builder, Int64Array, Int64Builder, Int8Array, Int8Builder, StringArray,
-            StringBuilder, StructArray, StructBuilder, UInt16Array, UInt16Builder, UInt32Array,
-            UInt32Builder, UInt64Array, UInt64Builder, UInt8Array, UInt8Builder,
-        },
-        datatypes::{DataType, Field, Fields, SchemaRef},
-        record_batch::RecordBatch,
-    };
-    use elsm_marco::elsm_schema;
-    use executor::{
-        futures::{AsyncRead, AsyncWrite, StreamExt},
-        ExecutorBuilder,
-    };
-    use lazy_static::lazy_static;
-    use tempfile::TempDir;
+    /// A lookup stops at the newest version of `key`, an operand is folded onto the older ones by
+    /// merging them like a scan does, see [`schema::Schema::is_operand`].
+    async fn fold(
+        &self,
+        key: &S::PrimaryKey,
+        ts: &TimeStamp,
+        tables: &dyn TableStore,
+        value: Option<S>,
+    ) -> Option<S> {
+        match value {
+            Some(value) if value.is_operand() => self.get_masked(key, ts, tables).await,
+            value => value,
+        }
+    }
 
-    use crate::{
-        io,
-        oracle::LocalOracle,
-        record::RecordType,
-        schema::Schema,
-        stream::merge_stream::MergeStream,
-        transaction::CommitError,
-        wal::provider::{fs::Fs, in_mem::InMemProvider},
-        Builder, Db, DbOption, Decode, Encode,
-    };
+    async fn get_newest(
+        &self,
+        key: &S::PrimaryKey,
+        ts: &TimeStamp,
+        tables: &dyn TableStore,
+    ) -> Option<S> {
+        if self.range_deletes.may_cover(key, *ts) {
+            return self.get_masked(key, ts, tables).await;
+        }
+        let routes = self.router.read().await;
+        let consistent_hash = self.router.shard_of(key);
 
-    #[derive(Debug, Eq, PartialEq)]
-    #[elsm_schema]
-    pub(crate) struct User {
-        #[primary_key]
-        pub(crate) id: u64,
+        // Safety: read-only would not break data.
+        let (key, ts) = unsafe {
+            (
+                mem::transmute::<_, &S::PrimaryKey>(key),
+                mem::transmute::<_, &TimeStamp>(ts),
+            )
+        };
+
+        if let Some(value) = self
+            .mutable_shards
+            .with(consistent_hash, move |local| async move {
+                local.read().await.mutable.get(key, ts).map(|s| s.cloned())
+            })
+            .await
+        {
+            return value;
+        }
+        drop(routes);
+        let encoding = self.encoding.read().await;
+        self.get_immutable(encoding, self.immutable.read().await, key, ts, tables)
+            .await
+    }
+
+    pub async fn get_with_options(&self, key: &S::PrimaryKey, options: &ReadOptions) -> Option<S> {
+        let ts = self.watermark.applied();
+        let tables = self.tables(options.fill_cache, options.priority);
+        if self.range_deletes.may_cover(key, ts) {
+            return self.get_masked(key, &ts, tables).await;
+        }
+        let encoding = self.encoding.read().await;
+        let guard = self.immutable.read().await;
+
+        if self.staleness.staleness() <= options.max_staleness {
+            let value = self.get_immutable(encoding, guard, key, &ts, tables).await;
+            return self.fold(key, &ts, tables, value).await;
+        }
+        drop(guard);
+        drop(encoding);
+
+        self.get_from(key, &ts, tables).await
+    }
+
+    /// Merges the versions of `key` like a scan does, since the newest one may be removed by a
+    /// range delete which older ones are not.
+    async fn get_masked(
+        &self,
+        key: &S::PrimaryKey,
+        ts: &TimeStamp,
+        tables: &dyn TableStore,
+    ) -> Option<S> {
+        let iters = self
+            .inner_range(Bound::Included(key), Bound::Included(key), ts, None, tables)
+            .await
+            .ok()?;
+        let mut stream = MergeStream::new(iters).await.ok()?;
+        match stream.next().await {
+            Some(Ok((_, value))) => value,
+            _ => None,
+        }
+    }
+
+    async fn get_immutable(
+        &self,
+        encoding: RwLockReadGuard<'_, VecDeque<Arc<MemTable<S>>>>,
+        guard: RwLockReadGuard<'_, VecDeque<IndexBatch<S>>>,
+        key: &S::PrimaryKey,
+        ts: &TimeStamp,
+        tables: &dyn TableStore,
+    ) -> Option<S> {
+        for mem_table in encoding.iter().rev() {
+            if let Some(value) = mem_table.get(key, ts) {
+                return value.cloned();
+            }
+        }
+        drop(encoding);
+        for index_batch in guard.iter().rev() {
+            if let Some(value) = index_batch.find(key, ts).await {
+                return value;
+            }
+        }
+        drop(guard);
+
+        let version = self.version_set.current().await;
+        let mut probed = Vec::new();
+        let result = VersionRead::new(&version, tables)
+            .get_probed(key, *ts, &mut probed)
+            .await;
+        for gen in self.version_set.reads().record(
+            &probed,
+            matches!(result, Ok(Some(_))),
+            self.option.read_compaction_misses,
+        ) {
+            // a full queue drops the task, the table is picked again once it misses as often
+            let _ = self
+                .compaction_tx
+                .clone()
+                .try_send(CompactTask::Rewrite(gen));
+        }
+        match result {
+            Ok(Some(record_batch)) => S::from_batch(&record_batch, 0).1,
+            Ok(None) => match &self.fallback {
+                Some(fallback) => fallback.get(key, *ts).await,
+                None => None,
+            },
+            Err(err) => {
+                let gen = probed.last().copied();
+                error!("[Read Error]: lookup in table {:?} failed: {}", gen, err);
+                // a damaged table poisons the db, later reads fail rather than miss its rows, and
+                // is rewritten, from a replica if there is one, see `DbOption::with_replica`
+                if let (true, Some(gen)) = (err.is_corruption(), gen) {
+                    self.poisoned.store(true, Ordering::Release);
+                    let _ = self
+                        .compaction_tx
+                        .clone()
+                        .try_send(CompactTask::Rewrite(gen));
+                }
+                None
+            }
+        }
+    }
+
+    async fn range(
+        &self,
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
+        ts: &TimeStamp,
+    ) -> Result<MergeStream<S>, ScanError<S::PrimaryKey, S>> {
+        let iters = self
+            .inner_range(
+                lower,
+                upper,
+                ts,
+                None,
+                self.tables(true, ReadPriority::Foreground),
+            )
+            .await?;
+
+        Ok(self.observe_deletes(MergeStream::new(iters).await?))
+    }
+
+    fn observe_deletes<'s>(&self, stream: MergeStream<'s, S>) -> MergeStream<'s, S> {
+        match self.option.deletion_compaction {
+            Some(trigger) => stream.observe_deletes(trigger, self.deletes_tx.clone()),
+            None => stream,
+        }
+    }
+
+    pub async fn range_with_options<'s, R>(
+        &'s self,
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
+        ts: &TimeStamp,
+        options: &ScanOptions<S, R>,
+    ) -> Result<R::Stream, ScanError<S::PrimaryKey, S>>
+    where
+        R: ScanOutput<'s, S>,
+    {
+        let iters = self
+            .inner_range(
+                lower,
+                upper,
+                ts,
+                options.filter.as_ref(),
+                self.tables(options.fill_cache, options.priority),
+            )
+            .await?;
+
+        let stream = self.observe_deletes(
+            MergeStream::with_filter(iters, options.filter.clone())
+                .await?
+                .budget(options.max_bytes, options.deadline),
+        );
+
+        Ok(R::wrap(match options.limit {
+            Some(limit) => stream.limit(limit),
+            None => stream,
+        }))
+    }
+
+    pub async fn scan_batches(
+        &self,
+        range: impl RangeBounds<S::PrimaryKey>,
+        ts: &TimeStamp,
+    ) -> Result<RecordBatchStream<S>, ScanError<S::PrimaryKey, S>> {
+        let stream = self
+            .range(range.start_bound(), range.end_bound(), ts)
+            .await?;
+
+        Ok(RecordBatchStream::new(
+            stream,
+            self.option.scan_batch_rows,
+            self.option.scan_batch_bytes,
+        ))
+    }
+
+    pub(crate) async fn inner_range<'s>(
+        &'s self,
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
+        ts: &TimeStamp,
+        filter: Option<&ScanFilter<S>>,
+        tables: &'s dyn TableStore,
+    ) -> Result<Vec<EStreamImpl<'s, S>>, ScanError<S::PrimaryKey, S>> {
+        if self.poisoned.load(Ordering::Acquire) {
+            return Err(ScanError::Poisoned);
+        }
+        let masked = self.range_deletes.mask(filter, *ts);
+        let filter = masked.as_ref();
+        let routes = self.router.read().await;
+        let mut iters = futures::future::try_join_all((0..executor::worker_num()).map(|i| {
+            let lower = lower.cloned();
+            let upper = upper.cloned();
+            let ts = *ts;
+            let filter = filter.cloned();
+            let load = self.load.clone();
+
+            self.mutable_shards.with(i, move |local| async move {
+                let guard = local.read().await;
+                let mut items = Vec::new();
+
+                let mut iter = pin!(
+                    guard
+                        .mutable
+                        .range(lower.as_ref(), upper.as_ref(), &ts)
+                        .await?,
+                );
+
+                while let Some(item) = iter.next().await {
+                    let (k, ts, v) = item?;
+
+                    items.push(mask(filter.as_ref(), k.clone(), ts, v));
+                }
+                load.on_scan(i, items.len());
+                Ok(EStreamImpl::Buf(BufStream::new(items)))
+            })
+        }))
+        .await?;
+        drop(routes);
+        // A mem table is swapped out of its shard and into the encoding queue at once, and moves
+        // on to the immutable queue at once, so a shard read before a freeze or one read after
+        // it sees the frozen rows in one of them.
+        let encoding = self.encoding.read().await;
+        let guard = self.immutable.read().await;
+
+        // unlike the shards, which are only readable on their own worker, frozen mem tables and
+        // immutable batches are pulled from lazily as the merge goes
+        for mem_table in encoding.iter().rev() {
+            iters.push(EStreamImpl::SharedMemTable(MemTable::shared_range(
+                mem_table.clone(),
+                lower,
+                upper,
+                *ts,
+                filter,
+            )));
+        }
+
+        for batch in guard.iter().rev() {
+            iters.push(EStreamImpl::IndexBatch(
+                batch.range(lower, upper, ts).await?.mask(filter),
+            ));
+        }
+        // Pin the frozen mem tables and the immutable set until the version is taken, so that
+        // those encoded or flushed meanwhile are seen in one or the other.
+        let version = self.version_set.current().await;
+        drop(guard);
+        drop(encoding);
+
+        VersionRead::new(&version, tables)
+            .streams(&mut iters, lower, upper, *ts, filter)
+            .await?;
+
+        Ok(iters)
+    }
+
+    /// The timestamp of the batch is held in flight by the caller from the moment it is
+    /// allocated, see [`GetWrite::begin_write`].
+    async fn write_batch(
+        &self,
+        kvs: impl ExactSizeIterator<Item = (S::PrimaryKey, TimeStamp, Option<S>)>,
+        ranges: Vec<RangeDelete<S::PrimaryKey>>,
+        system: Vec<SystemRecord>,
+        priority: WritePriority,
+    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        let records = kvs.collect::<Vec<_>>();
+        let keys = records.iter().map(|(key, _, _)| key);
+        let _write = match (S::Comparator::min(keys.clone()), S::Comparator::max(keys)) {
+            (Some(min), Some(max)) => Some(self.range_locks.write(min, max).await),
+            _ if ranges.is_empty() => return Ok(()),
+            _ => None,
+        };
+        self.append_batch(records.into_iter(), ranges, system, priority)
+            .await
+    }
+
+    /// Logs the batch along with the ranges it removes and the system keys it updates, then
+    /// applies it to the mem tables of all its shards at once, each shard after the batches
+    /// logged before, and masks the ranges. Reads see none of it before the watermark passes its
+    /// timestamp, however far the apply got. The system keys are left to the caller to update.
+    async fn append_batch(
+        &self,
+        kvs: impl ExactSizeIterator<Item = (S::PrimaryKey, TimeStamp, Option<S>)>,
+        ranges: Vec<RangeDelete<S::PrimaryKey>>,
+        system: Vec<SystemRecord>,
+        priority: WritePriority,
+    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>> {
+        let records = kvs.collect::<Vec<_>>();
+        for (key, _, value) in records.iter() {
+            self.option
+                .check_size::<<Record<S::PrimaryKey, S> as Encode>::Error>(
+                    key.size(),
+                    value.as_ref().map(Encode::size).unwrap_or(0),
+                )?;
+        }
+        let routes = self.router.read().await;
+        let len = records.len();
+        let (seq, ticket) = self
+            .log(
+                records.iter().enumerate().map(|(i, (key, ts, value))| {
+                    let record_type = match i {
+                        _ if len == 1 => RecordType::Full,
+                        0 => RecordType::First,
+                        _ if i == len - 1 => RecordType::Last,
+                        _ => RecordType::Middle,
+                    };
+                    (record_type, key, *ts, value.as_ref())
+                }),
+                &ranges,
+                system,
+                priority,
+            )
+            .await?;
+        // the batch is at the one timestamp the caller holds in flight, held by the tasks
+        // applying it too
+        let in_flight = records
+            .first()
+            .map(|(_, ts, _)| *ts)
+            .or_else(|| ranges.first().map(|range| range.ts))
+            .map(|ts| self.watermark.begin(ts));
+
+        let mut shards = BTreeMap::<usize, Vec<_>>::new();
+        for record in records {
+            shards
+                .entry(self.router.shard_of(&record.0))
+                .or_default()
+                .push(record);
+        }
+        let applying = Arc::new(Applying {
+            _routes: routes,
+            ticket,
+            _in_flight: in_flight,
+        });
+        let applied = shards
+            .into_iter()
+            .map(|(shard, records)| self.apply(&applying, shard, records))
+            .collect::<Vec<_>>();
+        // masked before the first await, the tombstones are recovered from the wal if the
+        // system keyspace misses them
+        let inserted = self.range_deletes.insert(&self.system, ranges).await;
+        drop(applying);
+        let encoded = futures::future::try_join_all(applied).await?;
+        for encoded in encoded {
+            self.settle(encoded, priority).await;
+        }
+        inserted.map_err(WriteError::Io)?;
+        // one flush covers the whole batch
+        self.sync_wal(seq).await
+    }
+
+    /// Replays a segment written while the store ran on `shards` shards, if known. Every record
+    /// is routed by its key again rather than by the segment it was found in, and the range
+    /// deletes, system keys and aggregate deltas of every commit are applied unless persisted
+    /// already.
+    async fn recover<W, D>(
+        &mut self,
+        wal: &mut W,
+        shards: Option<usize>,
+    ) -> Result<(), WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>>
+    where
+        W: WalRecover<S::PrimaryKey, S, Error = RecoverError<D>>,
+        D: error::Error + Send + Sync + 'static,
+    {
+        let mut ranges = BTreeMap::<TimeStamp, Vec<_>>::new();
+        let mut system = Vec::new();
+        let mut stream = pin!(wal.recover());
+        while let Some(entry) = stream.next().await {
+            let mut record_type = RecordType::First;
+            let Record { key, ts, value, .. } =
+                match entry.map_err(|err| WriteError::Recover(err.into_io()))? {
+                    WalEntry::Record(record) => record,
+                    WalEntry::RangeDelete(range) => {
+                        ranges.entry(range.ts).or_default().push(range);
+                        continue;
+                    }
+                    WalEntry::System(record) => {
+                        system.push(record);
+                        continue;
+                    }
+                };
+
+            self.recovery.records += 1;
+            if matches!(
+                shards,
+                Some(shards) if shard_of(&key, shards) != shard_of(&key, executor::worker_num())
+            ) {
+                self.recovery.moved += 1;
+            }
+
+            self.append_unsynced(
+                mem::replace(&mut record_type, RecordType::Middle),
+                key,
+                ts,
+                value,
+                WritePriority::Foreground,
+            )
+            .await?;
+        }
+        for ranges in ranges.into_values() {
+            self.range_deletes
+                .recover(&self.system, ranges)
+                .await
+                .map_err(WriteError::Io)?;
+        }
+        let mut deltas = Vec::new();
+        for record in system {
+            if idempotency::is_token(&record) {
+                self.idempotency
+                    .recover(&self.system, record)
+                    .await
+                    .map_err(WriteError::Io)?;
+            } else if aggregate::is_delta(&record) {
+                deltas.push(record);
+            }
+        }
+        aggregate::recover(&self.system, deltas)
+            .await
+            .map_err(WriteError::Io)
+    }
+}
+
+/// The error of a bulk load or snapshot, which neither merge nor pick tables.
+fn compaction_error<S>(
+    err: CompactionError<S>,
+) -> WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>
+where
+    S: schema::Schema,
+{
+    match err {
+        CompactionError::Overlap => WriteError::ImportOverlap,
+        CompactionError::Io(err) | CompactionError::Version(VersionError::Io(err)) => {
+            WriteError::Provider(err)
+        }
+        CompactionError::Parquet(err) | CompactionError::Version(VersionError::Parquet(err)) => {
+            WriteError::Parquet(err)
+        }
+        CompactionError::Version(VersionError::Encode(err)) => {
+            WriteError::Encode(EncodeError::Key(err))
+        }
+        CompactionError::Version(VersionError::Send(_)) => WriteError::Closed,
+        CompactionError::Stream(_) | CompactionError::EmptyLevel => {
+            unreachable!("bulk loads and snapshots neither merge nor pick tables")
+        }
+    }
+}
+
+/// Encodes a frozen mem table into the immutable queue, then schedules the compaction the
+/// queue calls for.
+async fn encode<S>(
+    frozen: Arc<MemTable<S>>,
+    encoding: Encoding<S>,
+    immutable: Immutable<S>,
+    option: Arc<DbOption>,
+    mut compaction_tx: Sender<CompactTask>,
+    notify: oneshot::Sender<()>,
+) where
+    S: schema::Schema,
+{
+    let batches = frozen.to_batches(option.max_batch_size);
+
+    let mut encoding = encoding.write().await;
+    let mut immutable = immutable.write().await;
+    encoding.retain(|mem_table| !Arc::ptr_eq(mem_table, &frozen));
+    immutable.extend(batches);
+    let chunks = immutable.iter().map(|batch| batch.chunks).sum::<usize>();
+    let task = if !option.in_memory && chunks > option.immutable_chunk_num {
+        Some(CompactTask::Flush(None))
+    } else if immutable.len() > option.immutable_merge_threshold {
+        Some(CompactTask::Merge)
+    } else {
+        None
+    };
+    drop(immutable);
+    drop(encoding);
+
+    if let Some(task) = task {
+        let _ = compaction_tx.send(task).await;
+    }
+    let _ = notify.send(());
+}
+
+impl<S, O, WP> Db<S, O, WP>
+where
+    S: schema::Schema,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+{
+    /// Releases the reads of the transactions past [`DbOption::txn_max_lifetime`].
+    fn reap_txns(&self) {
+        for read_at in self.reaper.reap(Instant::now()) {
+            warn!(
+                "[Reaper]: transaction reading at {} aborted after txn_max_lifetime",
+                read_at
+            );
+            self.read_commit(read_at);
+        }
+    }
+}
+
+impl<S, O, WP> TimestampProvider for Db<S, O, WP>
+where
+    S: schema::Schema,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+{
+    fn start_read(&self) -> TimeStamp {
+        self.reap_txns();
+        self.oracle.start_read()
+    }
+
+    fn read_commit(&self, ts: TimeStamp) {
+        self.oracle.read_commit(ts);
+        self.retention
+            .fetch_max(self.oracle.oldest_read(), Ordering::AcqRel);
+    }
+
+    fn start_write(&self) -> TimeStamp {
+        self.oracle.start_write()
+    }
+
+    fn now(&self) -> TimeStamp {
+        self.oracle.now()
+    }
+
+    fn advance(&self, ts: TimeStamp) -> TimeStamp {
+        self.oracle.advance(ts)
+    }
+
+    fn oldest_read(&self) -> TimeStamp {
+        self.reap_txns();
+        self.oracle.oldest_read()
+    }
+
+    fn active_reads(&self) -> Vec<(TimeStamp, usize)> {
+        self.oracle.active_reads()
+    }
+}
+
+impl<S, O, WP> ConflictChecker<S::PrimaryKey> for Db<S, O, WP>
+where
+    S: schema::Schema,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+{
+    fn check_commit(
+        &self,
+        read_at: TimeStamp,
+        write_at: TimeStamp,
+        in_write: std::collections::HashSet<S::PrimaryKey>,
+        oldest_read: TimeStamp,
+    ) -> Result<(), WriteCommitError<S::PrimaryKey>> {
+        self.oracle
+            .check_commit(read_at, write_at, in_write, oldest_read)
+    }
+
+    fn describe(&self, state: &mut OracleState) {
+        self.oracle.describe(state)
+    }
+}
+
+pub(crate) trait GetWrite<S>: Oracle<S::PrimaryKey>
+where
+    S: schema::Schema,
+{
+    fn get(&self, key: &S::PrimaryKey, ts: &TimeStamp) -> impl Future<Output = Option<S>>
+    where
+        TimeStamp: Sync;
+
+    /// Allocates the timestamp a commit writes at, which reads waiting on the applied writes
+    /// wait for until the returned guard drops.
+    fn begin_write(&self) -> (TimeStamp, InFlight);
+
+    fn write(
+        &self,
+        record_type: RecordType,
+        ts: TimeStamp,
+        value: S,
+    ) -> impl Future<Output = Result<(), Box<dyn error::Error + Send + Sync + 'static>>>;
+
+    async fn remove(
+        &self,
+        record_type: RecordType,
+        ts: TimeStamp,
+        key: S::PrimaryKey,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>>;
+
+    /// Writes `kvs` and removes the versions of the keys in `ranges` older than the timestamp the
+    /// commit writes at, see [`Transaction::remove_range`], as one logged batch.
+    /// Writes the rows and ranges of a commit, logged along with the system keys it updates.
+    fn write_batch(
+        &self,
+        kvs: impl ExactSizeIterator<Item = (S::PrimaryKey, TimeStamp, Option<S>)>,
+        ranges: Vec<RangeDelete<S::PrimaryKey>>,
+        system: Vec<SystemRecord>,
+    ) -> impl Future<Output = Result<(), Box<dyn error::Error + Send + Sync + 'static>>>;
+
+    fn check_size(
+        &self,
+        key: &S::PrimaryKey,
+        value: Option<&S>,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>>;
+
+    fn validate(&self, key: &S::PrimaryKey, value: Option<&S>) -> Result<(), ValidationError>;
+
+    fn intercept(&self, read_at: TimeStamp, writes: &mut BTreeMap<S::PrimaryKey, Option<S>>);
+
+    /// The listener to report the commit starting to, if it is sampled.
+    fn sample_txn(&self) -> Option<Arc<dyn TxnListener>>;
+
+    /// Whether the db keeps aggregates, so that commits read the rows of their removed ranges to
+    /// count them.
+    fn has_aggregates(&self) -> bool {
+        false
+    }
+
+    /// What the commit of `writes` adds to the aggregates of the db, by system key.
+    async fn aggregate_deltas(
+        &self,
+        _read_at: TimeStamp,
+        _writes: &BTreeMap<S::PrimaryKey, Option<S>>,
+    ) -> BTreeMap<String, i64> {
+        BTreeMap::new()
+    }
+
+    /// Adds the deltas of the commit at `ts`, logged along with it, to the aggregates.
+    async fn apply_aggregates(&self, _deltas: BTreeMap<String, i64>, _ts: TimeStamp) {}
+
+    fn lease_txn(&self, _read_at: TimeStamp) -> TxnLease {
+        TxnLease::default()
+    }
+
+    /// Releases the read of a transaction, unless it was reaped and released already.
+    fn end_txn(&self, lease: &TxnLease, read_at: TimeStamp) {
+        if !lease.is_reaped() {
+            self.read_commit(read_at);
+        }
+    }
+
+    fn inner_range<'a>(
+        &'a self,
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
+        ts: &TimeStamp,
+    ) -> impl Future<Output = Result<Vec<EStreamImpl<'a, S>>, ScanError<S::PrimaryKey, S>>>
+    where
+        S::PrimaryKey: 'a,
+        TimeStamp: 'a,
+        S: 'a;
+
+    /// Reserves `key` for a commit, `None` if a commit of it succeeded already. Waits for the
+    /// commit it is reserved for, if any.
+    fn reserve_idempotency_key(
+        &self,
+        key: &str,
+    ) -> impl Future<Output = io::Result<Option<Reservation>>>;
+
+    /// Marks the key of `reservation` committed, once `record` was logged with the commit.
+    fn commit_idempotency_key(
+        &self,
+        reservation: Reservation,
+        record: SystemRecord,
+    ) -> impl Future<Output = ()>;
+}
+
+impl<S, O, WP> GetWrite<S> for Db<S, O, WP>
+where
+    S: schema::Schema,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+    WP::File: AsyncWrite,
+    io::Error: From<<S as Decode>::Error>,
+{
+    fn begin_write(&self) -> (TimeStamp, InFlight) {
+        let ts = self.oracle.start_write();
+        (ts, self.watermark.begin(ts))
+    }
+
+    async fn write(
+        &self,
+        record_type: RecordType,
+        ts: TimeStamp,
+        value: S,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        Db::write(self, record_type, ts, value).await?;
+        Ok(())
+    }
+
+    async fn remove(
+        &self,
+        record_type: RecordType,
+        ts: TimeStamp,
+        key: S::PrimaryKey,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        Db::remove(self, record_type, ts, key).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &S::PrimaryKey, ts: &TimeStamp) -> Option<S> {
+        Db::get(self, key, ts).await
+    }
+
+    async fn write_batch(
+        &self,
+        kvs: impl ExactSizeIterator<Item = (S::PrimaryKey, TimeStamp, Option<S>)>,
+        ranges: Vec<RangeDelete<S::PrimaryKey>>,
+        system: Vec<SystemRecord>,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        Db::write_batch(self, kvs, ranges, system, WritePriority::Foreground).await?;
+        Ok(())
+    }
+
+    fn check_size(
+        &self,
+        key: &S::PrimaryKey,
+        value: Option<&S>,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        self.option
+            .check_size::<<Record<S::PrimaryKey, S> as Encode>::Error>(
+                key.size(),
+                value.map(Encode::size).unwrap_or(0),
+            )?;
+        Ok(())
+    }
+
+    fn validate(&self, key: &S::PrimaryKey, value: Option<&S>) -> Result<(), ValidationError> {
+        Db::validate(self, key, value)
+    }
+
+    fn intercept(&self, read_at: TimeStamp, writes: &mut BTreeMap<S::PrimaryKey, Option<S>>) {
+        for interceptor in self.interceptors.iter() {
+            interceptor.intercept(read_at, writes);
+        }
+    }
+
+    fn sample_txn(&self) -> Option<Arc<dyn TxnListener>> {
+        let listener = self.option.txn_listener.as_ref()?;
+        let commit = self.txn_commits.fetch_add(1, Ordering::Relaxed);
+        (commit % self.option.txn_sample_every.max(1) == 0).then(|| listener.clone())
+    }
+
+    fn has_aggregates(&self) -> bool {
+        !self.aggregates.is_empty()
+    }
+
+    async fn aggregate_deltas(
+        &self,
+        read_at: TimeStamp,
+        writes: &BTreeMap<S::PrimaryKey, Option<S>>,
+    ) -> BTreeMap<String, i64> {
+        let mut deltas = BTreeMap::new();
+        if self.aggregates.is_empty() {
+            return deltas;
+        }
+        for (key, new) in writes.iter() {
+            // the commit passed its conflict check, so nothing wrote the key since `read_at`
+            let old = Db::get(self, key, &read_at).await;
+            self.add_deltas(&mut deltas, key, old.as_ref(), new.as_ref());
+        }
+        deltas
+    }
+
+    async fn apply_aggregates(&self, deltas: BTreeMap<String, i64>, ts: TimeStamp) {
+        if deltas.is_empty() {
+            return;
+        }
+        // the commit is still in flight, so the watermark stays below it
+        if let Err(err) = aggregate::apply(&self.system, deltas, ts, self.watermark.applied()).await
+        {
+            error!("[Aggregate]: failed to update aggregates: {}", err);
+        }
+    }
+
+    fn lease_txn(&self, read_at: TimeStamp) -> TxnLease {
+        self.reaper.lease(read_at)
+    }
+
+    fn end_txn(&self, lease: &TxnLease, read_at: TimeStamp) {
+        if self.reaper.release(lease) {
+            self.read_commit(read_at);
+        }
+    }
+
+    async fn inner_range<'a>(
+        &'a self,
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
+        ts: &TimeStamp,
+    ) -> Result<Vec<EStreamImpl<'a, S>>, ScanError<S::PrimaryKey, S>>
+    where
+        S::PrimaryKey: 'a,
+        TimeStamp: 'a,
+        S: 'a,
+    {
+        Db::inner_range(
+            self,
+            lower,
+            upper,
+            ts,
+            None,
+            self.tables(true, ReadPriority::Foreground),
+        )
+        .await
+    }
+
+    async fn reserve_idempotency_key(&self, key: &str) -> io::Result<Option<Reservation>> {
+        self.idempotency.reserve(&self.system, key).await
+    }
+
+    async fn commit_idempotency_key(&self, reservation: Reservation, record: SystemRecord) {
+        if let Err(err) = self
+            .idempotency
+            .commit(&self.system, reservation, record)
+            .await
+        {
+            error!("[Idempotency]: failed to persist a committed key: {}", err);
+        }
+    }
+}
+
+impl DbOption {
+    pub fn new(path: impl Into<PathBuf> + Send) -> Self {
+        DbOption {
+            path: path.into(),
+            max_mem_table_size: 8 * 1024 * 1024,
+            adaptive_mem_table_size: None,
+            immutable_chunk_num: 5,
+            immutable_merge_threshold: 3,
+            compaction_style: CompactionStyle::Leveled,
+            deletion_compaction: Some(DeletionTrigger {
+                min_tombstones: 1000,
+                ratio: 0.5,
+            }),
+            major_threshold_with_sst_size: 10,
+            level_sst_magnification: 10,
+            target_file_size_base: 64 * 1024 * 1024,
+            target_file_size_multiplier: 1,
+            max_subcompactions: 4,
+            clean_channel_buffer: 10,
+            version_log_max_size: 4 * 1024 * 1024,
+            idempotency_retention: Duration::from_secs(10 * 60),
+            max_batch_size: 8 * 1024 * 1024,
+            scan_batch_rows: 1024,
+            scan_batch_bytes: 4 * 1024 * 1024,
+            max_key_size: 64 * 1024,
+            max_value_size: 4 * 1024 * 1024,
+            level_tiers: [
+                Tier::Local,
+                Tier::Local,
+                Tier::Local,
+                Tier::Remote,
+                Tier::Remote,
+                Tier::Remote,
+                Tier::Remote,
+            ],
+            wal_sync: WalSync::Never,
+            in_memory: false,
+            shard_imbalance_threshold: 4.0,
+            validator: None,
+            txn_listener: None,
+            txn_sample_every: 1,
+            txn_max_lifetime: None,
+            shard_placement: None,
+            replica: None,
+            read_compaction_misses: Some(1000),
+        }
+    }
+
+    /// Rejects the writes `validator` fails before they reach the wal, see [`Validator`]. Its
+    /// types must be the primary key and the schema of the db opened with this option.
+    pub fn with_validator<K, V>(mut self, validator: impl Validator<K, V>) -> Self
+    where
+        K: 'static,
+        V: 'static,
+    {
+        self.validator = Some(AnyValidator::new(validator));
+        self
+    }
+
+    /// Tunes the size mem tables freeze at, and so the size of wal segments, within `min` and
+    /// `max` after the write rate and how long frozen mem tables take to flush, see
+    /// [`DbStats::mem_table_size`].
+    pub fn with_adaptive_mem_table_size(mut self, min: usize, max: usize) -> Self {
+        self.adaptive_mem_table_size = Some(AdaptiveSize { min, max });
+        self
+    }
+
+    /// Calls `placement` with the index of every shard on the worker the shard lives on, before
+    /// the db touches the shard.
+    pub fn with_shard_placement(mut self, placement: impl ShardPlacement) -> Self {
+        self.shard_placement = Some(Arc::new(placement));
+        self
+    }
+
+    /// Serves reads of table blocks failing their checksum from the copy of the table `provider`
+    /// keeps under the same id, instead of failing them, and rewrites the damaged table with a
+    /// compaction of it into the level below.
+    pub fn with_replica(mut self, provider: impl StorageProvider) -> Self {
+        self.replica = Some(Replica::new(provider));
+        self
+    }
+
+    /// Reports the metrics of one in every `sample_every` transaction commits to `listener`.
+    pub fn with_txn_listener(mut self, listener: impl TxnListener, sample_every: u64) -> Self {
+        self.txn_listener = Some(Arc::new(listener));
+        self.txn_sample_every = sample_every;
+        self
+    }
+
+    /// Sizes are the encoded ones, checked before anything reaches the wal.
+    pub(crate) fn check_size<E>(
+        &self,
+        key_size: usize,
+        value_size: usize,
+    ) -> Result<(), WriteError<E>>
+    where
+        E: error::Error,
+    {
+        if key_size > self.max_key_size {
+            return Err(WriteError::KeyTooLarge {
+                size: key_size,
+                max: self.max_key_size,
+            });
+        }
+        if value_size > self.max_value_size {
+            return Err(WriteError::ValueTooLarge {
+                size: value_size,
+                max: self.max_value_size,
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn version_path(&self) -> PathBuf {
+        self.path.join("version.log")
+    }
+    pub(crate) fn system_path(&self) -> PathBuf {
+        self.path.join("system.log")
+    }
+
+    pub(crate) fn is_threshold_exceeded_major<S>(&self, version: &Version<S>, level: usize) -> bool
+    where
+        S: schema::Schema,
+    {
+        version.tables_len(level)
+            >= (self.major_threshold_with_sst_size * self.level_sst_magnification.pow(level as u32))
+    }
+
+    /// Whether tables flushed to level 0 call for a compaction, after the compaction style.
+    pub(crate) fn is_threshold_exceeded_level_0<S>(&self, version: &Version<S>) -> bool
+    where
+        S: schema::Schema,
+    {
+        match self.compaction_style {
+            CompactionStyle::Leveled => self.is_threshold_exceeded_major(version, 0),
+            CompactionStyle::Tiered { max_runs, .. } => version.sorted_runs() >= max_runs,
+        }
+    }
+
+    pub(crate) fn target_file_size(&self, level: usize) -> usize {
+        self.target_file_size_base
+            * self
+                .target_file_size_multiplier
+                .pow(level.saturating_sub(1) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        future::Future,
+        ops::Bound,
+        pin::pin,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    use arrow::{
+        array::{
+            Array, BooleanArray, BooleanBuilder, Int16Array, Int16Builder, Int32Array,
+            Int32Builder, Int64Array, Int64Builder, Int8Array, Int8Builder, StringArray,
+            StringBuilder, StructArray, StructBuilder, UInt16Array, UInt16Builder, UInt32Array,
+            UInt32Builder, UInt64Array, UInt64Builder, UInt8Array, UInt8Builder,
+        },
+        datatypes::{DataType, Field, Fields, SchemaRef},
+        record_batch::RecordBatch,
+    };
+    use elsm_marco::elsm_schema;
+    use executor::{
+        futures::{AsyncRead, AsyncWrite, StreamExt},
+        ExecutorBuilder,
+    };
+    use futures::{
+        channel::oneshot,
+        future::{ready, select},
+    };
+    use lazy_static::lazy_static;
+    use snowflake::ProcessUniqueId;
+    use tempfile::TempDir;
+
+    use crate::{
+        background::TaskPriority,
+        comparator::{Comparator, OrdComparator},
+        consistent_hash::{shard_of, VNODES},
+        io,
+        mem_table::MemTable,
+        oracle::{
+            LocalClock, LocalOracle, NoConflictCheck, OracleState, SplitOracle, TimestampProvider,
+        },
+        range_delete::RangeDeletes,
+        record::{Record, RecordType},
+        registry::{self, RegistryError},
+        schema::{Builder, Op, Schema},
+        scope::{Scope, TableStats},
+        stream::{merge_stream::MergeStream, ScanError},
+        system::{SystemTable, RANGE_DELETE_PREFIX},
+        transaction::{CommitError, RowUpdate, TxnMetrics, TxnOutcome},
+        validate::ValidationError,
+        version::{edit::VersionEdit, MAX_LEVEL},
+        wal::{
+            header::WalHeader,
+            provider::{fs::Fs, in_mem::InMemProvider, tiered::Tier, WalProvider},
+            RecoverError, WalFile, WalWrite, WriteError,
+        },
+        Db, DbOption, Decode, Encode, FileMetadata, ImportMode, MoveError, ReadOptions,
+        RecoveryStats, ScanOptions, TableReads, Upsert, WalSync, WriteOptions, WritePriority,
+    };
+
+    #[derive(Debug, Eq, PartialEq)]
+    #[elsm_schema]
+    pub(crate) struct User {
+        #[primary_key]
+        pub(crate) id: u64,
         pub(crate) name: String,
         pub(crate) is_human: bool,
         pub(crate) i_number_0: i8,
@@ -654,126 +2798,1723 @@ mod tests {
         pub(crate) u_number_3: u64,
     }
 
+    #[derive(Debug, Eq, PartialEq)]
+    #[elsm_schema]
+    pub(crate) struct Account {
+        #[primary_key]
+        pub(crate) id: u64,
+        pub(crate) name: String,
+    }
+
+    /// [`Account`] with a field added since
+    #[derive(Debug, Eq, PartialEq)]
+    #[elsm_schema]
+    pub(crate) struct AccountV2 {
+        #[primary_key]
+        pub(crate) id: u64,
+        pub(crate) name: String,
+        #[default_value(7)]
+        pub(crate) level: u32,
+    }
+
+    /// Orders ids from the largest down.
+    pub(crate) struct Descending;
+
+    impl Comparator<u64> for Descending {
+        fn compare(lhs: &u64, rhs: &u64) -> std::cmp::Ordering {
+            rhs.cmp(lhs)
+        }
+    }
+
+    #[derive(Debug, Eq, PartialEq)]
+    #[elsm_schema(comparator = "Descending")]
+    pub(crate) struct Countdown {
+        #[primary_key]
+        pub(crate) id: u64,
+        pub(crate) name: String,
+    }
+
+    /// A user named after its id, its other fields left at their defaults.
+    pub(crate) fn user(id: u64) -> UserInner {
+        UserInner::new(id, id.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+    }
+
+    /// Runs `test` on an executor of its own against an empty db of users, whose wal is kept in
+    /// memory and whose system keyspace in a temporary directory.
+    pub(crate) fn with_db<F, Fut>(test: F)
+    where
+        F: FnOnce(Db<UserInner, LocalOracle<u64>, InMemProvider>) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            test(db).await
+        });
+    }
+
+    #[test]
+    fn test_user() {
+        with_db(|db| async move {
+            let db = Arc::new(db);
+            let user_0 = UserInner::new(0, "lizeren".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+            let user_1 = UserInner::new(1, "2333".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+            let user_2 = UserInner::new(2, "ghost".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+
+            let mut t0 = db.new_txn();
+
+            t0.set(user_0.primary_key(), user_0.clone());
+            t0.set(user_1.primary_key(), user_1.clone());
+            t0.set(user_2.primary_key(), user_2.clone());
+
+            t0.commit().await.unwrap();
+
+            let txn = db.new_txn();
+
+            assert_eq!(txn.get(&user_0.primary_key()).await, Some(user_0));
+            assert_eq!(txn.get(&user_1.primary_key()).await, Some(user_1));
+            assert_eq!(txn.get(&user_2.primary_key()).await, Some(user_2));
+        });
+    }
+
+    #[test]
+    fn read_committed() {
+        with_db(|db| async move {
+            let db = Arc::new(db);
+
+            let mut txn = db.new_txn();
+            txn.set(
+                0,
+                UserInner::new(0, "0".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+            txn.set(
+                1,
+                UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+            txn.commit().await.unwrap();
+
+            let mut t0 = db.new_txn();
+            let mut t1 = db.new_txn();
+
+            t0.set(0, t0.get(&1).await.unwrap());
+            t1.set(1, t1.get(&0).await.unwrap());
+
+            t0.commit().await.unwrap();
+            t1.commit().await.unwrap();
+
+            let txn = db.new_txn();
+
+            assert_eq!(
+                txn.get(&Arc::from(0)).await,
+                Some(UserInner::new(
+                    1,
+                    "1".to_string(),
+                    false,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0
+                ))
+            );
+            assert_eq!(
+                txn.get(&Arc::from(1)).await,
+                Some(UserInner::new(
+                    0,
+                    "0".to_string(),
+                    false,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0
+                ))
+            );
+        });
+    }
+
+    #[test]
+    fn range() {
+        with_db(|db| async move {
+            let db = Arc::new(db);
+
+            let mut txn = db.new_txn();
+            txn.set(
+                0,
+                UserInner::new(0, "0".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+            txn.set(
+                1,
+                UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+            txn.set(
+                2,
+                UserInner::new(2, "2".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+            txn.set(
+                3,
+                UserInner::new(3, "3".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+            txn.commit().await.unwrap();
+
+            let mut iter: MergeStream<UserInner> = db
+                .range(Bound::Included(&1), Bound::Included(&2), &1)
+                .await
+                .unwrap();
+
+            assert_eq!(
+                iter.next().await.unwrap().unwrap(),
+                (
+                    1,
+                    Some(UserInner::new(
+                        1,
+                        "1".to_string(),
+                        false,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0
+                    ))
+                )
+            );
+            assert_eq!(
+                iter.next().await.unwrap().unwrap(),
+                (
+                    2,
+                    Some(UserInner::new(
+                        2,
+                        "2".to_string(),
+                        false,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0
+                    ))
+                )
+            );
+
+            let mut txn_1 = db.new_txn();
+            txn_1.set(
+                5,
+                UserInner::new(5, "5".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+            txn_1.set(
+                4,
+                UserInner::new(4, "4".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+
+            let mut txn_2 = db.new_txn();
+            txn_2.set(
+                5,
+                UserInner::new(4, "4".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+            txn_2.set(
+                4,
+                UserInner::new(5, "5".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+            txn_2.commit().await.unwrap();
+
+            let mut iter = txn_1.range(&1..=&4).await.unwrap();
+
+            assert_eq!(
+                iter.next().await.unwrap().unwrap(),
+                (
+                    1,
+                    Some(UserInner::new(
+                        1,
+                        "1".to_string(),
+                        false,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0
+                    ))
+                )
+            );
+            assert_eq!(
+                iter.next().await.unwrap().unwrap(),
+                (
+                    2,
+                    Some(UserInner::new(
+                        2,
+                        "2".to_string(),
+                        false,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0
+                    ))
+                )
+            );
+            assert_eq!(
+                iter.next().await.unwrap().unwrap(),
+                (
+                    3,
+                    Some(UserInner::new(
+                        3,
+                        "3".to_string(),
+                        false,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0
+                    ))
+                )
+            );
+            assert_eq!(
+                iter.next().await.unwrap().unwrap(),
+                (
+                    4,
+                    Some(UserInner::new(
+                        4,
+                        "4".to_string(),
+                        false,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0
+                    ))
+                )
+            );
+            drop(iter);
+
+            let mut iter = txn_1
+                .range((Bound::Excluded(&1), Bound::Excluded(&4)))
+                .await
+                .unwrap();
+
+            assert_eq!(iter.next().await.unwrap().unwrap().0, 2);
+            assert_eq!(iter.next().await.unwrap().unwrap().0, 3);
+            assert!(iter.next().await.is_none());
+            drop(iter);
+
+            let (key, lower, upper) = (3, 5, 3);
+            assert!(txn_1.range(key..key).await.unwrap().next().await.is_none());
+            assert!(txn_1
+                .range(lower..upper)
+                .await
+                .unwrap()
+                .next()
+                .await
+                .is_none());
+            assert!(db
+                .range(Bound::Included(&key), Bound::Excluded(&key), &1)
+                .await
+                .unwrap()
+                .next()
+                .await
+                .is_none());
+            assert!(db
+                .range(Bound::Included(&lower), Bound::Included(&upper), &1)
+                .await
+                .unwrap()
+                .next()
+                .await
+                .is_none());
+        });
+    }
+
+    #[test]
+    fn write_conflicts() {
+        with_db(|db| async move {
+            let db = Arc::new(db);
+
+            let mut txn = db.new_txn();
+            txn.set(
+                0,
+                UserInner::new(0, "0".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+            txn.set(
+                1,
+                UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+            txn.commit().await.unwrap();
+
+            let mut t0 = db.new_txn();
+            let mut t1 = db.new_txn();
+            let mut t2 = db.new_txn();
+
+            t0.set(0, t0.get(&1).await.unwrap());
+            t1.set(0, t1.get(&0).await.unwrap());
+            t1.set(
+                2,
+                UserInner::new(2, "2".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+            t2.set(
+                2,
+                UserInner::new(3, "3".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            );
+
+            t0.commit().await.unwrap();
+
+            let commit = t1.commit().await;
+            assert!(commit.is_err());
+            assert!(t2.commit().await.is_ok());
+            if let Err(CommitError::WriteConflict(keys)) = commit {
+                assert_eq!(
+                    db.new_txn().get(&keys[0]).await,
+                    Some(UserInner::new(
+                        1,
+                        "1".to_string(),
+                        false,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0
+                    ))
+                );
+                return;
+            }
+            panic!("unreachable");
+        });
+    }
+
+    #[test]
+    fn borrowed_txn() {
+        with_db(|db| async move {
+            let user = UserInner::new(0, "0".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+
+            let mut t0 = db.txn();
+            let mut t1 = db.txn();
+            t0.set(0, user.clone());
+            t1.set(0, user.clone());
+            t0.commit().await.unwrap();
+            assert!(matches!(
+                t1.commit().await,
+                Err(CommitError::WriteConflict(_))
+            ));
+
+            assert_eq!(db.txn().get(&0).await, Some(user));
+            assert!(db.stats().oracle.active_reads.is_empty());
+        });
+    }
+
+    #[test]
+    fn validator() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()).with_validator(
+                    |key: &u64, _: Option<&UserInner>| match key {
+                        0..=9 => Ok(()),
+                        _ => Err(ValidationError::new("out of the tenant's range")),
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+
+            let mut txn = db.txn();
+            txn.set(1, user(1));
+            txn.set(10, user(10));
+            assert!(matches!(txn.commit().await, Err(CommitError::Invalid(_))));
+            assert!(matches!(
+                db.put(user(11)).await,
+                Err(WriteError::Invalid(_))
+            ));
+            assert_eq!(db.txn().get(&1).await, None);
+
+            db.put(user(1)).await.unwrap();
+            assert_eq!(db.txn().get(&1).await, Some(user(1)));
+
+            let mismatched = Db::<UserInner, _, _>::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().join("mismatched"))
+                    .with_validator(|_: &String, _: Option<&String>| Ok(())),
+            )
+            .await;
+            assert!(matches!(mismatched, Err(WriteError::Invalid(_))));
+        });
+    }
+
+    #[test]
+    fn update() {
+        with_db(|db| async move {
+            let counter =
+                |n: u64| UserInner::new(0, "counter".to_string(), false, 0, 0, 0, 0, 0, 0, 0, n);
+            let increment = |old: Option<&UserInner>| {
+                Some(counter(old.map_or(0, |old| old.inner.u_number_3) + 1))
+            };
+
+            let mut txn = db.txn();
+            txn.update(0, increment).await;
+            txn.update(0, increment).await;
+            txn.commit().await.unwrap();
+
+            let mut t0 = db.txn();
+            let mut t1 = db.txn();
+            t0.update(0, increment).await;
+            t1.update(0, increment).await;
+            t0.commit().await.unwrap();
+            assert!(matches!(
+                t1.commit().await,
+                Err(CommitError::WriteConflict(_))
+            ));
+            assert_eq!(db.txn().get(&0).await, Some(counter(3)));
+
+            let mut txn = db.txn();
+            txn.update(0, |_| None).await;
+            txn.commit().await.unwrap();
+            assert_eq!(db.txn().get(&0).await, None);
+        });
+    }
+
+    #[test]
+    fn multi_get() {
+        with_db(|db| async move {
+            let user = |id: u64, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+
+            let mut txn = db.txn();
+            txn.set(0, user(0, "0"));
+            txn.set(1, user(1, "1"));
+            txn.commit().await.unwrap();
+
+            let mut txn = db.txn();
+            txn.set(1, user(1, "local"));
+            txn.remove(0);
+            txn.set(3, user(3, "local"));
+            assert_eq!(
+                txn.multi_get(&[3, 0, 2, 1]).await,
+                vec![Some(user(3, "local")), None, None, Some(user(1, "local"))]
+            );
+        });
+    }
+
+    #[test]
+    fn scan_update() {
+        with_db(|db| async move {
+            let user = |id: u64, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+
+            let mut txn = db.txn();
+            for id in 0..2500 {
+                txn.set(id, user(id, "old"));
+            }
+            txn.commit().await.unwrap();
+
+            let mut txn = db.txn();
+            txn.set(2500, user(2500, "old"));
+            let updated = txn
+                .scan_update(1.., |id, _| match id % 2 {
+                    0 => RowUpdate::Set(user(*id, "new")),
+                    _ => RowUpdate::Remove,
+                })
+                .await
+                .unwrap();
+            assert_eq!(updated, 2500);
+            txn.commit().await.unwrap();
+
+            let rows = db
+                .txn()
+                .range(..)
+                .await
+                .unwrap()
+                .map(|row| row.unwrap())
+                .collect::<Vec<_>>()
+                .await;
+            let mut expected = vec![(0, Some(user(0, "old")))];
+            expected.extend((2..=2500).step_by(2).map(|id| (id, Some(user(id, "new")))));
+            assert_eq!(rows, expected);
+        });
+    }
+
+    #[test]
+    fn txn_metrics() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let reported = Arc::new(Mutex::new(Vec::<TxnMetrics>::new()));
+            let listener = reported.clone();
+            let db = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()).with_txn_listener(
+                    move |metrics: &TxnMetrics| listener.lock().unwrap().push(metrics.clone()),
+                    2,
+                ),
+            )
+            .await
+            .unwrap();
+
+            let mut t0 = db.txn();
+            let mut t1 = db.txn();
+            assert_eq!(t0.get(&0).await, None);
+            t0.set(0, user(0));
+            t0.commit().await.unwrap();
+
+            let mut txn = db.txn();
+            txn.set(1, user(1));
+            txn.commit().await.unwrap();
+
+            t1.set(0, user(0));
+            t1.set_retries(2);
+            assert!(t1.commit().await.is_err());
+
+            let reported = reported.lock().unwrap();
+            assert_eq!(reported.len(), 2);
+            assert_eq!(
+                (reported[0].reads, reported[0].writes, reported[0].outcome),
+                (1, 1, TxnOutcome::Committed)
+            );
+            assert!(reported[0].bytes > 0);
+            assert_eq!(
+                (reported[1].retries, reported[1].outcome),
+                (2, TxnOutcome::Conflict)
+            );
+        });
+    }
+
+    #[test]
+    fn commit_interceptor() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let user = |id: u64, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+            // keeps the last written id under key 100, like an index over the table would
+            let db = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap()
+            .with_commit_interceptor(
+                move |_, writes: &mut BTreeMap<u64, Option<UserInner>>| {
+                    if let Some(id) = writes.keys().copied().filter(|id| *id < 100).max() {
+                        writes.insert(100, Some(user(100, &id.to_string())));
+                    }
+                },
+            );
+
+            let mut t0 = db.txn();
+            let mut t1 = db.txn();
+            t0.set(1, user(1, "1"));
+            t1.set(2, user(2, "2"));
+            t0.commit().await.unwrap();
+            match t1.commit().await {
+                Err(CommitError::WriteConflict(keys)) => assert_eq!(keys, vec![100]),
+                _ => panic!("derived key not checked for conflicts"),
+            }
+
+            let txn = db.txn();
+            assert_eq!(txn.get(&1).await, Some(user(1, "1")));
+            assert_eq!(txn.get(&2).await, None);
+            assert_eq!(txn.get(&100).await, Some(user(100, "1")));
+        });
+    }
+
+    #[test]
+    fn last_write_wins() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db = Arc::new(
+                Db::new(
+                    SplitOracle::new(LocalClock::default(), NoConflictCheck),
+                    InMemProvider::default(),
+                    DbOption::new(temp_dir.path().to_path_buf()),
+                )
+                .await
+                .unwrap(),
+            );
+            let user =
+                |name: &str| UserInner::new(0, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+
+            let mut t0 = db.new_txn();
+            let mut t1 = db.new_txn();
+            t0.set(0, user("0"));
+            t1.set(0, user("1"));
+            t0.commit().await.unwrap();
+            t1.commit().await.unwrap();
+
+            assert_eq!(db.new_txn().get(&0).await, Some(user("1")));
+            assert_eq!(db.stats().oracle.committed_txns, 0);
+        });
+    }
+
+    #[test]
+    fn take() {
+        with_db(|db| async move {
+            let db = Arc::new(db);
+            let user_0 = UserInner::new(0, "0".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+            let user_1 = UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+
+            let mut txn = db.new_txn();
+            txn.set(0, user_0.clone());
+            txn.set(1, user_1.clone());
+            txn.commit().await.unwrap();
+
+            let mut t0 = db.new_txn();
+            let mut t1 = db.new_txn();
+
+            assert_eq!(t0.take(0).await, Some(user_0));
+            assert_eq!(t0.get(&0).await, None);
+            t1.set(0, user_1.clone());
+            t1.commit().await.unwrap();
+
+            assert!(matches!(
+                t0.commit().await,
+                Err(CommitError::WriteConflict(_))
+            ));
+
+            assert_eq!(db.remove_returning(1).await.unwrap(), Some(user_1.clone()));
+            assert_eq!(db.remove_returning(1).await.unwrap(), None);
+            assert_eq!(db.new_txn().get(&0).await, Some(user_1));
+        });
+    }
+
+    #[test]
+    fn upsert_many() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            let user = |id: u64, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+            db.put(user(1, "a")).await.unwrap();
+
+            let (ts, upserts) = db
+                .upsert_many([user(0, "a"), user(1, "b"), user(0, "b")])
+                .await
+                .unwrap();
+            assert_eq!(
+                upserts,
+                vec![
+                    (0, Upsert::Inserted),
+                    (1, Upsert::Updated(user(1, "a"))),
+                    (0, Upsert::Updated(user(0, "a"))),
+                ]
+            );
+            assert_eq!(db.get(&0, &ts).await, Some(user(0, "b")));
+            assert_eq!(db.get(&1, &ts).await, Some(user(1, "b")));
+        });
+    }
+
+    #[test]
+    fn remove_range() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Arc<Db<UserInner, _, _>> = Arc::new(
+                Db::new(
+                    LocalOracle::default(),
+                    InMemProvider::default(),
+                    DbOption::new(temp_dir.path().to_path_buf()),
+                )
+                .await
+                .unwrap(),
+            );
+            let user = |id: u64, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+            let mut txn = db.new_txn();
+            for id in 0..10 {
+                txn.set(id, user(id, "a"));
+            }
+            let before = txn.commit().await.unwrap();
+            let live = |ts| {
+                let db = &db;
+                async move {
+                    db.range(Bound::Unbounded, Bound::Unbounded, &ts)
+                        .await
+                        .unwrap()
+                        .map(|result| result.unwrap())
+                        .collect::<Vec<_>>()
+                        .await
+                        .into_iter()
+                        .filter(|(_, value)| value.is_some())
+                        .count()
+                }
+            };
+
+            let mut txn = db.new_txn();
+            txn.set(2, user(2, "b"));
+            txn.remove_range(2..6);
+            txn.set(3, user(3, "b"));
+            assert_eq!(txn.get(&2).await, None);
+            assert_eq!(txn.get(&3).await, Some(user(3, "b")));
+            let keys = txn
+                .range(..)
+                .await
+                .unwrap()
+                .map(|result| result.unwrap())
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .filter_map(|(key, value)| value.map(|_| key))
+                .collect::<Vec<_>>();
+            assert_eq!(keys, vec![0, 1, 3, 6, 7, 8, 9]);
+            let ts = txn.commit().await.unwrap();
+
+            assert_eq!(db.get(&2, &ts).await, None);
+            assert_eq!(db.get(&3, &ts).await, Some(user(3, "b")));
+            assert_eq!(db.get(&4, &before).await, Some(user(4, "a")));
+            assert_eq!(live(ts).await, 7);
+            assert_eq!(live(before).await, 10);
+
+            db.put(user(4, "c")).await.unwrap();
+            let ts = db.latest_sequence();
+            assert_eq!(db.get(&4, &ts).await, Some(user(4, "c")));
+            assert_eq!(live(ts).await, 8);
+            assert!(RangeDeletes::<u64, OrdComparator>::load(&db.system)
+                .await
+                .unwrap()
+                .may_cover(&5, ts));
+        });
+    }
+
+    #[test]
+    fn default_values() {
+        let mut builder = AccountInner::builder();
+        builder.add(&1, 0, Op::Put, Some(AccountInner::new(1, "a".to_string())));
+        builder.add(&2, 0, Op::Delete, None);
+        let batch = builder.finish();
+        assert_eq!(
+            AccountV2Inner::from_batch(&batch, 0),
+            (1, Some(AccountV2Inner::new(1, "a".to_string(), 7)))
+        );
+        assert_eq!(AccountV2Inner::from_batch(&batch, 1), (2, None));
+
+        let mut builder = AccountV2Inner::builder();
+        builder.add(
+            &1,
+            0,
+            Op::Put,
+            Some(AccountV2Inner::new(1, "a".to_string(), 3)),
+        );
+        let (_, account) = AccountV2Inner::from_batch(&builder.finish(), 0);
+        assert_eq!(account.unwrap().inner.level, 3);
+
+        let temp_dir = TempDir::new().unwrap();
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let system = SystemTable::new(&DbOption::new(temp_dir.path().to_path_buf()))
+                .await
+                .unwrap();
+            assert_eq!(registry::check::<AccountInner>(&system).await.unwrap(), 0);
+            assert_eq!(registry::check::<AccountV2Inner>(&system).await.unwrap(), 0);
+            assert_eq!(registry::check::<AccountV2Inner>(&system).await.unwrap(), 1);
+            match registry::check::<AccountInner>(&system).await {
+                Err(RegistryError::Mismatch(mismatch)) => {
+                    assert_eq!(mismatch.diff, vec!["-inner.level: UInt32 not null"])
+                }
+                _ => panic!("removed field not detected"),
+            }
+        });
+    }
+
+    #[test]
+    fn idempotency_key_in_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let option = || DbOption::new(temp_dir.path().to_path_buf());
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db = Arc::new(
+                Db::new(
+                    LocalOracle::default(),
+                    Fs::new(temp_dir.path()).unwrap(),
+                    option(),
+                )
+                .await
+                .unwrap(),
+            );
+            let mut txn = db.new_txn();
+            txn.set(1, user(1));
+            txn.set_idempotency_key("token");
+            txn.commit().await.unwrap();
+            drop(db);
+
+            // the key is recovered from the wal even if the system keyspace lost it
+            std::fs::remove_file(option().system_path()).unwrap();
+            let db: Arc<Db<UserInner, _, _>> = Arc::new(
+                Db::new(
+                    LocalOracle::default(),
+                    Fs::new(temp_dir.path()).unwrap(),
+                    option(),
+                )
+                .await
+                .unwrap(),
+            );
+            let mut txn = db.new_txn();
+            txn.set(2, user(2));
+            txn.set_idempotency_key("token");
+            txn.commit().await.unwrap();
+            assert_eq!(db.get(&1, &u64::MAX).await, Some(user(1)));
+            assert_eq!(db.get(&2, &u64::MAX).await, None);
+        });
+    }
+
+    #[test]
+    fn upgrade_wal() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db = Db::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            db.write(RecordType::Full, 0, AccountInner::new(1, "a".to_string()))
+                .await
+                .unwrap();
+            drop(db);
+
+            let db: Db<AccountV2Inner, _, _> = Db::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                db.get(&1, &0).await,
+                Some(AccountV2Inner::new(1, "a".to_string(), 7))
+            );
+            db.write(
+                RecordType::Full,
+                1,
+                AccountV2Inner::new(2, "b".to_string(), 3),
+            )
+            .await
+            .unwrap();
+            drop(db);
+
+            let db: Db<AccountV2Inner, _, _> = Db::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                db.get(&1, &1).await,
+                Some(AccountV2Inner::new(1, "a".to_string(), 7))
+            );
+            assert_eq!(
+                db.get(&2, &1).await,
+                Some(AccountV2Inner::new(2, "b".to_string(), 3))
+            );
+        });
+    }
+
+    #[test]
+    fn get_at_least() {
+        with_db(|db| async move {
+            let db = Arc::new(db);
+            let user_0 = UserInner::new(0, "0".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+
+            let seq = db.put(user_0.clone()).await.unwrap();
+            assert_eq!(db.get_at_least(&0, seq).await, Some(user_0.clone()));
+
+            let user_1 = UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+            let mut txn = db.new_txn();
+            txn.set(1, user_1.clone());
+            let token = txn.commit().await.unwrap();
+            assert_eq!(db.get_at_least(&1, token).await, Some(user_1));
+
+            let mut txn = db.new_txn();
+            assert_eq!(txn.get(&0).await, Some(user_0.clone()));
+            txn.set(0, user_0.clone());
+
+            let seq = db.delete(0).await.unwrap();
+            assert_eq!(db.get_at_least(&0, seq).await, None);
+            assert!(matches!(
+                txn.commit().await,
+                Err(CommitError::WriteConflict(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn changes_since() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            let changes = |since| {
+                let db = &db;
+                async move {
+                    let (upto, mut stream) = db.changes_since(since).await.unwrap();
+                    let mut changes = Vec::new();
+                    while let Some(change) = stream.next().await {
+                        let (key, ts, value) = change.unwrap();
+                        changes.push((key, ts, value.is_some()));
+                    }
+                    (upto, changes)
+                }
+            };
+
+            db.put(user(0)).await.unwrap();
+            let seq = db.put(user(1)).await.unwrap();
+            let (upto, all) = changes(0).await;
+            assert_eq!(upto, seq);
+            assert_eq!(all.len(), 2);
+
+            db.put(user(0)).await.unwrap();
+            db.delete(1).await.unwrap();
+            let seq = db.put(user(2)).await.unwrap();
+            assert_eq!(db.latest_sequence(), seq);
+            let (_, since) = changes(upto).await;
+            assert_eq!(
+                since
+                    .iter()
+                    .map(|(key, _, put)| (*key, *put))
+                    .collect::<Vec<_>>(),
+                vec![(0, true), (1, false), (2, true)]
+            );
+            assert!(since.iter().all(|(_, ts, _)| *ts > upto && *ts <= seq));
+        });
+    }
+
+    #[test]
+    fn bounded_staleness() {
+        with_db(|db| async move {
+            let user_0 = UserInner::new(0, "0".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+
+            db.put(user_0.clone()).await.unwrap();
+
+            let stale = ReadOptions {
+                max_staleness: Duration::from_secs(60 * 60),
+                ..Default::default()
+            };
+            assert_eq!(db.get_with_options(&0, &stale).await, None);
+            assert_eq!(
+                db.get_with_options(&0, &ReadOptions::default()).await,
+                Some(user_0)
+            );
+        });
+    }
+
+    fn test_items() -> Vec<UserInner> {
+        vec![
+            UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(2, "2".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(3, "3".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(4, "4".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(5, "5".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(6, "6".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(7, "7".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(8, "8".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(9, "9".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(10, "10".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(20, "20".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(30, "30".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(40, "40".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(50, "50".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(60, "60".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(70, "70".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(80, "80".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(90, "90".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(100, "100".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(200, "200".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(300, "300".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(400, "400".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(500, "500".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(600, "600".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(700, "700".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(800, "800".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(900, "900".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(1000, "1000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(2000, "2000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(3000, "3000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(4000, "4000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(5000, "5000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(6000, "6000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(7000, "7000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(8000, "8000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            UserInner::new(9000, "9000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+        ]
+    }
+
+    #[test]
+    fn read_from_disk() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db = Arc::new(
+                Db::new(
+                    LocalOracle::default(),
+                    Fs::new(temp_dir.path()).unwrap(),
+                    DbOption {
+                        // TIPS: kv size in test case is 17
+                        path: temp_dir.path().to_path_buf(),
+                        max_mem_table_size: 25,
+                        immutable_chunk_num: 1,
+                        immutable_merge_threshold: 3,
+                        major_threshold_with_sst_size: 5,
+                        level_sst_magnification: 10,
+                        target_file_size_base: 2 * 1024 * 1024,
+                        target_file_size_multiplier: 1,
+                        max_subcompactions: 4,
+                        clean_channel_buffer: 10,
+                        idempotency_retention: Duration::from_secs(10 * 60),
+                        max_batch_size: 8 * 1024 * 1024,
+                        scan_batch_rows: 1024,
+                        scan_batch_bytes: 4 * 1024 * 1024,
+                        max_key_size: 64 * 1024,
+                        max_value_size: 4 * 1024 * 1024,
+                        level_tiers: [Tier::Local; MAX_LEVEL],
+                        wal_sync: WalSync::Never,
+                    },
+                )
+                .await
+                .unwrap(),
+            );
+
+            let fn_write = |db: Arc<Db<_, _, _>>, user: UserInner| async move {
+                db.write(RecordType::Full, 0, user).await.unwrap();
+            };
+            for item in test_items() {
+                fn_write(db.clone(), item).await;
+            }
+
+            assert_eq!(
+                db.get(&20, &0).await,
+                Some(UserInner::new(
+                    20,
+                    "20".to_string(),
+                    false,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0
+                ))
+            );
+
+            let mut stream: MergeStream<UserInner> = db
+                .range(Bound::Unbounded, Bound::Unbounded, &0)
+                .await
+                .unwrap();
+
+            let mut results = vec![];
+            while let Some(result) = stream.next().await {
+                results.push(result.unwrap().1.unwrap());
+            }
+            assert_eq!(results.len(), test_items().len());
+            assert_eq!(results, test_items());
+
+            drop(stream);
+            drop(db);
+
+            let db = Db::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                DbOption {
+                    // TIPS: kv size in test case is 17
+                    path: temp_dir.path().to_path_buf(),
+                    max_mem_table_size: 25,
+                    immutable_chunk_num: 1,
+                    immutable_merge_threshold: 3,
+                    major_threshold_with_sst_size: 5,
+                    level_sst_magnification: 10,
+                    target_file_size_base: 2 * 1024 * 1024,
+                    target_file_size_multiplier: 1,
+                    max_subcompactions: 4,
+                    clean_channel_buffer: 10,
+                    idempotency_retention: Duration::from_secs(10 * 60),
+                    max_batch_size: 8 * 1024 * 1024,
+                    scan_batch_rows: 1024,
+                    scan_batch_bytes: 4 * 1024 * 1024,
+                    max_key_size: 64 * 1024,
+                    max_value_size: 4 * 1024 * 1024,
+                    level_tiers: [Tier::Local; MAX_LEVEL],
+                    wal_sync: WalSync::Never,
+                },
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(
+                db.get(&20, &0).await,
+                Some(UserInner::new(
+                    20,
+                    "20".to_string(),
+                    false,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0
+                ))
+            );
+            // FIXME: clean unless wal
+            // let mut stream: MergeStream<UserInner> = db.range(None, None, &0).await.unwrap();
+            //
+            // let mut results = vec![];
+            // while let Some(result) = stream.next().await {
+            //     results.push(result.unwrap().1.unwrap());
+            // }
+            // assert_eq!(results.len(), test_items().len());
+            // assert_eq!(results, test_items());
+            //
+            // drop(stream);
+        });
+    }
+
+    #[test]
+    fn range_newest_version() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            let user = |id, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+
+            db.write_bulk_with_timestamps(
+                (1..=4).map(|id| (id, 1, Some(user(id, "table")))),
+                ImportMode::Ahead,
+            )
+            .await
+            .unwrap();
+            let mut mem_table = MemTable::default();
+            mem_table.insert(1, 2, Some(user(1, "immutable")));
+            mem_table.insert(2, 2, Some(user(2, "immutable")));
+            db.immutable
+                .write()
+                .await
+                .extend(mem_table.to_batches(usize::MAX));
+            db.write(RecordType::Full, 3, user(1, "mutable"))
+                .await
+                .unwrap();
+            db.remove(RecordType::Full, 3, 3).await.unwrap();
+            db.write(RecordType::Full, 5, user(2, "mutable"))
+                .await
+                .unwrap();
+
+            let scan = |ts| {
+                let db = &db;
+                async move {
+                    db.range(Bound::Unbounded, Bound::Unbounded, &ts)
+                        .await
+                        .unwrap()
+                        .map(|result| result.unwrap())
+                        .collect::<Vec<_>>()
+                        .await
+                }
+            };
+            assert_eq!(
+                scan(1).await,
+                (1..=4)
+                    .map(|id| (id, Some(user(id, "table"))))
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(
+                scan(4).await,
+                vec![
+                    (1, Some(user(1, "mutable"))),
+                    (2, Some(user(2, "immutable"))),
+                    (4, Some(user(4, "table"))),
+                ]
+            );
+
+            let options = ScanOptions::<UserInner>::default().with_timestamps();
+            let rows = db
+                .range_with_options(Bound::Unbounded, Bound::Unbounded, &4, &options)
+                .await
+                .unwrap()
+                .map(|result| result.unwrap())
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(
+                rows,
+                vec![
+                    (1, 3, Some(user(1, "mutable"))),
+                    (2, 2, Some(user(2, "immutable"))),
+                    (4, 1, Some(user(4, "table"))),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn range_poisoned() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+
+            assert!(db
+                .range(Bound::Unbounded, Bound::Unbounded, &0)
+                .await
+                .is_ok());
+            db.poisoned.store(true, Ordering::Release);
+            assert!(matches!(
+                db.range(Bound::Unbounded, Bound::Unbounded, &0).await,
+                Err(ScanError::Poisoned)
+            ));
+        });
+    }
+
+    #[test]
+    fn get_damaged_table() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let open = || {
+                Db::<UserInner, _, _>::new(
+                    LocalOracle::default(),
+                    Fs::new(temp_dir.path()).unwrap(),
+                    DbOption::new(temp_dir.path().to_path_buf()),
+                )
+            };
+            let db = open().await.unwrap();
+            db.write(
+                RecordType::Full,
+                0,
+                UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+            )
+            .await
+            .unwrap();
+            db.snapshot().await.unwrap();
+            let gen = db.live_files().await[0].id;
+            drop(db);
+
+            let path = temp_dir.path().join(format!("{}.parquet", gen));
+            let mut bytes = std::fs::read(&path).unwrap();
+            *bytes.last_mut().unwrap() ^= 1;
+            std::fs::write(&path, bytes).unwrap();
+
+            let db = open().await.unwrap();
+            assert_eq!(db.get(&1, &0).await, None);
+            assert!(matches!(
+                db.range(Bound::Unbounded, Bound::Unbounded, &0).await,
+                Err(ScanError::Poisoned)
+            ));
+        });
+    }
+
+    #[test]
+    fn freeze_off_write_path() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                DbOption {
+                    max_mem_table_size: 25,
+                    ..DbOption::new(temp_dir.path().to_path_buf())
+                },
+            )
+            .await
+            .unwrap();
+            let (release_tx, release_rx) = oneshot::channel::<()>();
+            db.background.spawn(TaskPriority::Flush, async move {
+                let _ = release_rx.await;
+            });
+
+            db.write(RecordType::Full, 0, user(0)).await.unwrap();
+            assert_eq!(db.encoding.read().await.len(), 1);
+            assert!(db.immutable.read().await.is_empty());
+            assert_eq!(db.get(&0, &0).await, Some(user(0)));
+            let scan = db
+                .range(Bound::Unbounded, Bound::Unbounded, &0)
+                .await
+                .unwrap()
+                .map(|result| result.unwrap())
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(scan, vec![(0, Some(user(0)))]);
+
+            release_tx.send(()).unwrap();
+            let (encoded_tx, encoded_rx) = oneshot::channel();
+            db.background.spawn(TaskPriority::Flush, async move {
+                let _ = encoded_tx.send(());
+            });
+            encoded_rx.await.unwrap();
+            assert!(db.encoding.read().await.is_empty());
+            assert_eq!(db.immutable.read().await.len(), 1);
+            assert_eq!(db.get(&0, &0).await, Some(user(0)));
+        });
+    }
+
+    #[test]
+    fn read_hints() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                DbOption {
+                    max_mem_table_size: 25,
+                    ..DbOption::new(temp_dir.path().to_path_buf())
+                },
+            )
+            .await
+            .unwrap();
+            for id in 0..8 {
+                db.write(RecordType::Full, 0, user(id)).await.unwrap();
+            }
+            let (encoded_tx, encoded_rx) = oneshot::channel();
+            db.background.spawn(TaskPriority::Flush, async move {
+                let _ = encoded_tx.send(());
+            });
+            encoded_rx.await.unwrap();
+
+            let options = ReadOptions {
+                fill_cache: false,
+                priority: ReadPriority::Background,
+                ..Default::default()
+            };
+            assert_eq!(db.get_with_options(&3, &options).await, Some(user(3)));
+            let scan = db
+                .range_with_options(
+                    Bound::Unbounded,
+                    Bound::Unbounded,
+                    &0,
+                    &ScanOptions::<UserInner>::default()
+                        .fill_cache(false)
+                        .priority(ReadPriority::Background),
+                )
+                .await
+                .unwrap()
+                .map(|result| result.unwrap().0)
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(scan, (0..8).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn in_memory() {
+        let temp_dir = TempDir::new().unwrap();
+        let option = || DbOption {
+            max_mem_table_size: 25,
+            immutable_chunk_num: 1,
+            in_memory: true,
+            ..DbOption::new(temp_dir.path().to_path_buf())
+        };
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                option(),
+            )
+            .await
+            .unwrap();
+            for id in 0..32 {
+                db.write(RecordType::Full, 0, user(id)).await.unwrap();
+            }
+            db.remove(RecordType::Full, 1, 31).await.unwrap();
+            let (encoded_tx, encoded_rx) = oneshot::channel();
+            db.background.spawn(TaskPriority::Flush, async move {
+                let _ = encoded_tx.send(());
+            });
+            encoded_rx.await.unwrap();
+
+            let wal = std::fs::read_dir(temp_dir.path()).unwrap().any(|entry| {
+                entry
+                    .unwrap()
+                    .path()
+                    .extension()
+                    .is_some_and(|ext| ext == "wal")
+            });
+            assert!(!wal);
+            assert!(!db.immutable.read().await.is_empty());
+            assert!(db.live_files().await.is_empty());
+            assert_eq!(db.get(&3, &0).await, Some(user(3)));
+
+            db.snapshot().await.unwrap();
+            assert_eq!(db.live_files().await.len(), 1);
+            drop(db);
+
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                option(),
+            )
+            .await
+            .unwrap();
+            for id in 0..31 {
+                assert_eq!(db.get(&id, &1).await, Some(user(id)));
+            }
+            assert_eq!(db.get(&31, &1).await, None);
+        });
+    }
+
+    #[test]
+    fn reads_during_freeze() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption {
+                    max_mem_table_size: 25,
+                    ..DbOption::new(temp_dir.path().to_path_buf())
+                },
+            )
+            .await
+            .unwrap();
+            let written = AtomicU64::new(0);
+
+            futures::join!(
+                async {
+                    for id in 0..64 {
+                        db.write(RecordType::Full, 0, user(id)).await.unwrap();
+                        written.store(id + 1, Ordering::Release);
+                    }
+                },
+                async {
+                    while written.load(Ordering::Acquire) < 64 {
+                        let before = written.load(Ordering::Acquire);
+                        let scanned = db
+                            .range(Bound::Unbounded, Bound::Unbounded, &0)
+                            .await
+                            .unwrap()
+                            .collect::<Vec<_>>()
+                            .await
+                            .len();
+                        assert!(scanned as u64 >= before);
+                        if before > 0 {
+                            assert_eq!(db.get(&(before - 1), &0).await, Some(user(before - 1)));
+                        }
+                    }
+                }
+            );
+        });
+    }
+
     #[test]
-    fn test_user() {
+    fn cancelled_writes() {
         let temp_dir = TempDir::new().unwrap();
+        let option = || DbOption {
+            max_mem_table_size: 25,
+            ..DbOption::new(temp_dir.path().to_path_buf())
+        };
 
         ExecutorBuilder::new().build().unwrap().block_on(async {
-            let db = Arc::new(
-                Db::new(
-                    LocalOracle::default(),
-                    InMemProvider::default(),
-                    DbOption::new(temp_dir.path().to_path_buf()),
-                )
-                .await
-                .unwrap(),
-            );
-            let user_0 = UserInner::new(0, "lizeren".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
-            let user_1 = UserInner::new(1, "2333".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
-            let user_2 = UserInner::new(2, "ghost".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
-
-            let mut t0 = db.new_txn();
-
-            t0.set(user_0.primary_key(), user_0.clone());
-            t0.set(user_1.primary_key(), user_1.clone());
-            t0.set(user_2.primary_key(), user_2.clone());
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                option(),
+            )
+            .await
+            .unwrap();
 
-            t0.commit().await.unwrap();
+            for id in 0..50 {
+                let write = db.write(RecordType::Full, 0, user(id));
+                if id % 2 == 0 {
+                    write.await.unwrap();
+                } else {
+                    let _ = select(pin!(write), ready(())).await;
+                }
+            }
+            // once a write of its own is applied, every shard applied the ones logged before,
+            // cancelled or not
+            for shard in 0..executor::worker_num() {
+                let id = (1000..).find(|id| db.router.shard_of(id) == shard).unwrap();
+                db.write(RecordType::Full, 0, user(id)).await.unwrap();
+            }
+            let mut visible = vec![];
+            for id in 0..50 {
+                if db.get(&id, &0).await.is_some() {
+                    visible.push(id);
+                }
+            }
+            assert!((0..50).step_by(2).all(|id| visible.contains(&id)));
 
-            let txn = db.new_txn();
+            // the timestamps of cancelled puts must not hold the watermark back
+            for id in 50..60 {
+                let _ = select(pin!(db.put(user(id))), ready(())).await;
+            }
+            let seq = db.put(user(60)).await.unwrap();
+            assert_eq!(db.get_at_least(&60, seq).await, Some(user(60)));
+            drop(db);
 
-            assert_eq!(txn.get(&user_0.primary_key()).await, Some(user_0));
-            assert_eq!(txn.get(&user_1.primary_key()).await, Some(user_1));
-            assert_eq!(txn.get(&user_2.primary_key()).await, Some(user_2));
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                option(),
+            )
+            .await
+            .unwrap();
+            // a write cancelled after it was logged is applied all the same, so nothing shows up
+            // only once recovered
+            for id in 0..50 {
+                let expected = visible.contains(&id).then(|| user(id));
+                assert_eq!(db.get(&id, &0).await, expected);
+            }
         });
     }
 
     #[test]
-    fn read_committed() {
+    fn recover_corrupted_wal() {
         let temp_dir = TempDir::new().unwrap();
+        let name = "x".repeat(64);
 
         ExecutorBuilder::new().build().unwrap().block_on(async {
-            let db = Arc::new(
-                Db::new(
-                    LocalOracle::default(),
-                    InMemProvider::default(),
-                    DbOption::new(temp_dir.path().to_path_buf()),
-                )
-                .await
-                .unwrap(),
-            );
-
-            let mut txn = db.new_txn();
-            txn.set(
-                0,
-                UserInner::new(0, "0".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            );
-            txn.set(
-                1,
-                UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            );
-            txn.commit().await.unwrap();
-
-            let mut t0 = db.new_txn();
-            let mut t1 = db.new_txn();
-
-            t0.set(0, t0.get(&1).await.unwrap());
-            t1.set(1, t1.get(&0).await.unwrap());
-
-            t0.commit().await.unwrap();
-            t1.commit().await.unwrap();
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            for id in 0..10 {
+                let user = UserInner::new(id, name.clone(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+                db.write(RecordType::Full, 0, user).await.unwrap();
+            }
+            drop(db);
 
-            let txn = db.new_txn();
+            let wal = std::fs::read_dir(temp_dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .find(|path| {
+                    path.extension().is_some_and(|ext| ext == "wal")
+                        && std::fs::read(path)
+                            .unwrap()
+                            .windows(name.len())
+                            .any(|window| window == name.as_bytes())
+                })
+                .unwrap();
+            let mut bytes = std::fs::read(&wal).unwrap();
+            let at = bytes
+                .windows(name.len())
+                .position(|window| window == name.as_bytes())
+                .unwrap();
+            bytes[at] = b'y';
+            std::fs::write(&wal, bytes).unwrap();
 
-            assert_eq!(
-                txn.get(&Arc::from(0)).await,
-                Some(UserInner::new(
-                    1,
-                    "1".to_string(),
-                    false,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0
-                ))
-            );
-            assert_eq!(
-                txn.get(&Arc::from(1)).await,
-                Some(UserInner::new(
-                    0,
-                    "0".to_string(),
-                    false,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0
-                ))
-            );
+            let reopened = Db::<UserInner, _, _>::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await;
+            assert!(matches!(
+                reopened,
+                Err(WriteError::Recover(RecoverError::Checksum))
+            ));
         });
     }
 
     #[test]
-    fn range() {
+    fn synced_writes() {
         let temp_dir = TempDir::new().unwrap();
 
         ExecutorBuilder::new().build().unwrap().block_on(async {
-            let db = Arc::new(
-                Db::new(
-                    LocalOracle::default(),
-                    InMemProvider::default(),
-                    DbOption::new(temp_dir.path().to_path_buf()),
-                )
-                .await
-                .unwrap(),
-            );
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption {
+                    wal_sync: WalSync::Interval(Duration::from_micros(100)),
+                    ..DbOption::new(temp_dir.path().to_path_buf())
+                },
+            )
+            .await
+            .unwrap();
+
+            futures::future::try_join_all(
+                (0..16).map(|id| db.write(RecordType::Full, 0, user(id))),
+            )
+            .await
+            .unwrap();
+            db.write_batch(
+                (16..32).map(|id| (id, 0, Some(user(id)))),
+                Vec::new(),
+                Vec::new(),
+                WritePriority::Foreground,
+            )
+            .await
+            .unwrap();
+            for id in 0..32 {
+                assert_eq!(db.get(&id, &0).await, Some(user(id)));
+            }
+        });
+    }
+
+    #[test]
+    fn stats() {
+        with_db(|db| async move {
+            let db = Arc::new(db);
 
             let mut txn = db.new_txn();
             txn.set(
@@ -784,161 +4525,153 @@ mod tests {
                 1,
                 UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
             );
-            txn.set(
-                2,
-                UserInner::new(2, "2".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            );
-            txn.set(
-                3,
-                UserInner::new(3, "3".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            );
-            txn.commit().await.unwrap();
-
-            let mut iter: MergeStream<UserInner> = db.range(Some(&1), Some(&2), &1).await.unwrap();
+            let _reader = db.new_txn();
+            assert_eq!(db.stats().oracle.active_reads, vec![(0, 2)]);
 
+            txn.commit().await.unwrap();
+            let stats = db.stats();
             assert_eq!(
-                iter.next().await.unwrap().unwrap(),
-                (
-                    1,
-                    Some(UserInner::new(
-                        1,
-                        "1".to_string(),
-                        false,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0
-                    ))
-                )
-            );
-            assert_eq!(
-                iter.next().await.unwrap().unwrap(),
-                (
-                    2,
-                    Some(UserInner::new(
-                        2,
-                        "2".to_string(),
-                        false,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0
-                    ))
-                )
-            );
-
-            let mut txn_1 = db.new_txn();
-            txn_1.set(
-                5,
-                UserInner::new(5, "5".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            );
-            txn_1.set(
-                4,
-                UserInner::new(4, "4".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            );
-
-            let mut txn_2 = db.new_txn();
-            txn_2.set(
-                5,
-                UserInner::new(4, "4".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            );
-            txn_2.set(
-                4,
-                UserInner::new(5, "5".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+                stats.oracle,
+                OracleState {
+                    now: 1,
+                    active_reads: vec![(0, 1)],
+                    committed_txns: 1,
+                    committed_keys: 2,
+                    conflict_window: Some((1, 1)),
+                }
             );
-            txn_2.commit().await.unwrap();
-
-            let mut iter = txn_1.range(Some(&1), Some(&4)).await.unwrap();
-
+            assert_eq!(stats.applied, 1);
+            assert_eq!(stats.oldest_in_flight, None);
+            assert_eq!(stats.published, 1);
+            assert_eq!(stats.mem_table_size, 8 * 1024 * 1024);
             assert_eq!(
-                iter.next().await.unwrap().unwrap(),
-                (
-                    1,
-                    Some(UserInner::new(
-                        1,
-                        "1".to_string(),
-                        false,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0
-                    ))
-                )
+                stats.shards.iter().map(|shard| shard.writes).sum::<u64>(),
+                2
             );
-            assert_eq!(
-                iter.next().await.unwrap().unwrap(),
-                (
-                    2,
-                    Some(UserInner::new(
-                        2,
-                        "2".to_string(),
-                        false,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0
-                    ))
-                )
+            assert_eq!(
+                stats
+                    .shards
+                    .iter()
+                    .map(|shard| shard.mem_table_rows)
+                    .sum::<u64>(),
+                2
             );
+        });
+    }
+
+    #[test]
+    fn shard_placement() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let placed = Arc::new(Mutex::new(Vec::new()));
+            let placement = placed.clone();
+            let _db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf())
+                    .with_shard_placement(move |shard| placement.lock().unwrap().push(shard)),
+            )
+            .await
+            .unwrap();
+
             assert_eq!(
-                iter.next().await.unwrap().unwrap(),
-                (
-                    3,
-                    Some(UserInner::new(
-                        3,
-                        "3".to_string(),
-                        false,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0
-                    ))
-                )
+                *placed.lock().unwrap(),
+                (0..executor::worker_num()).collect::<Vec<_>>()
             );
+        });
+    }
+
+    #[test]
+    fn rebalance() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            if executor::worker_num() < 2 {
+                return;
+            }
+            let db = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            let user = |id: u64, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+
+            for i in 0..8 {
+                db.put(user(7, &i.to_string())).await.unwrap();
+            }
+            let from = shard_of(&7_u64, executor::worker_num());
+            let (vnode, to) = db.rebalance().await.unwrap().unwrap();
+            assert_eq!(vnode, db.vnode_of(&7));
+            assert_ne!(to, from);
+            assert_eq!(db.rebalance().await, Ok(None));
             assert_eq!(
-                iter.next().await.unwrap().unwrap(),
-                (
-                    4,
-                    Some(UserInner::new(
-                        4,
-                        "4".to_string(),
-                        false,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0
-                    ))
+                db.move_vnode(VNODES, to).await,
+                Err(MoveError::VnodeOutOfRange(VNODES))
+            );
+
+            assert_eq!(db.txn().get(&7).await, Some(user(7, "7")));
+            db.put(user(7, "moved")).await.unwrap();
+            let rows = db
+                .txn()
+                .range(..)
+                .await
+                .unwrap()
+                .map(|row| row.unwrap())
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(rows, vec![(7, Some(user(7, "moved")))]);
+            assert_eq!(db.stats().shards[to].writes, 1);
+        });
+    }
+
+    #[test]
+    fn conflict_window() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db = Arc::new(
+                Db::new(
+                    LocalOracle::default().with_max_committed_txns(2),
+                    InMemProvider::default(),
+                    DbOption::new(temp_dir.path().to_path_buf()),
                 )
+                .await
+                .unwrap(),
             );
+            let commit = |id: u64| {
+                let db = &db;
+                async move {
+                    let mut txn = db.new_txn();
+                    txn.set(
+                        id,
+                        UserInner::new(id, id.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+                    );
+                    txn.commit().await
+                }
+            };
+
+            let reader = db.new_txn();
+            commit(0).await.unwrap();
+            commit(1).await.unwrap();
+            assert!(matches!(
+                commit(2).await,
+                Err(CommitError::ConflictWindowFull(2))
+            ));
+            assert_eq!(db.stats().oracle.committed_txns, 2);
+
+            drop(reader);
+            commit(2).await.unwrap();
+            assert_eq!(db.stats().oracle.committed_txns, 1);
         });
     }
 
     #[test]
-    fn write_conflicts() {
+    fn reap_expired_txns() {
         let temp_dir = TempDir::new().unwrap();
 
         ExecutorBuilder::new().build().unwrap().block_on(async {
@@ -946,211 +4679,304 @@ mod tests {
                 Db::new(
                     LocalOracle::default(),
                     InMemProvider::default(),
-                    DbOption::new(temp_dir.path().to_path_buf()),
+                    DbOption {
+                        txn_max_lifetime: Some(Duration::from_millis(50)),
+                        ..DbOption::new(temp_dir.path().to_path_buf())
+                    },
                 )
                 .await
                 .unwrap(),
             );
+            let user = UserInner::new(0, "0".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0);
+
+            let mut leaked = db.new_txn();
+            leaked.set(0, user.clone());
+            std::thread::sleep(Duration::from_millis(100));
 
             let mut txn = db.new_txn();
-            txn.set(
-                0,
-                UserInner::new(0, "0".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            );
-            txn.set(
-                1,
-                UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            );
+            assert_eq!(db.active_reads(), vec![(txn.read_at, 1)]);
+            txn.set(1, user);
             txn.commit().await.unwrap();
+            assert!(matches!(leaked.commit().await, Err(CommitError::Expired)));
+            assert!(db.active_reads().is_empty());
+            assert_eq!(db.get(&0, &db.now()).await, None);
+        });
+    }
 
-            let mut t0 = db.new_txn();
-            let mut t1 = db.new_txn();
-            let mut t2 = db.new_txn();
+    #[test]
+    fn range_during_freeze() {
+        let temp_dir = TempDir::new().unwrap();
 
-            t0.set(0, t0.get(&1).await.unwrap());
-            t1.set(0, t1.get(&0).await.unwrap());
-            t1.set(
-                2,
-                UserInner::new(2, "2".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            );
-            t2.set(
-                2,
-                UserInner::new(3, "3".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db = Arc::new(
+                Db::new(
+                    LocalOracle::default(),
+                    InMemProvider::default(),
+                    DbOption {
+                        max_mem_table_size: 25,
+                        ..DbOption::new(temp_dir.path().to_path_buf())
+                    },
+                )
+                .await
+                .unwrap(),
             );
 
-            t0.commit().await.unwrap();
-
-            let commit = t1.commit().await;
-            assert!(commit.is_err());
-            assert!(t2.commit().await.is_ok());
-            if let Err(CommitError::WriteConflict(keys)) = commit {
-                assert_eq!(
-                    db.new_txn().get(&keys[0]).await,
-                    Some(UserInner::new(
-                        1,
-                        "1".to_string(),
-                        false,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0
-                    ))
-                );
-                return;
+            for id in 0..100 {
+                db.write(RecordType::Full, 0, user(id)).await.unwrap();
             }
-            panic!("unreachable");
+
+            let write = async {
+                for id in 100..300 {
+                    db.write(RecordType::Full, 1, user(id)).await.unwrap();
+                }
+            };
+            let scan = async {
+                for _ in 0..20 {
+                    let mut stream = db
+                        .range(Bound::Unbounded, Bound::Unbounded, &0)
+                        .await
+                        .unwrap();
+                    let mut keys = vec![];
+
+                    while let Some(result) = stream.next().await {
+                        let (key, value) = result.unwrap();
+
+                        if value.is_some() {
+                            keys.push(key);
+                        }
+                    }
+                    assert_eq!(keys, (0..100).collect::<Vec<_>>());
+                }
+            };
+            futures::join!(write, scan);
         });
     }
 
-    fn test_items() -> Vec<UserInner> {
-        vec![
-            UserInner::new(1, "1".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(2, "2".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(3, "3".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(4, "4".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(5, "5".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(6, "6".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(7, "7".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(8, "8".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(9, "9".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(10, "10".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(20, "20".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(30, "30".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(40, "40".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(50, "50".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(60, "60".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(70, "70".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(80, "80".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(90, "90".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(100, "100".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(200, "200".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(300, "300".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(400, "400".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(500, "500".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(600, "600".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(700, "700".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(800, "800".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(900, "900".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(1000, "1000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(2000, "2000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(3000, "3000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(4000, "4000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(5000, "5000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(6000, "6000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(7000, "7000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(8000, "8000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-            UserInner::new(9000, "9000".to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
-        ]
+    #[test]
+    fn bulk_writes() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption {
+                    max_mem_table_size: 25,
+                    immutable_chunk_num: 1,
+                    ..DbOption::new(temp_dir.path().to_path_buf())
+                },
+            )
+            .await
+            .unwrap();
+            let bulk = WriteOptions {
+                priority: WritePriority::Bulk,
+            };
+
+            let import = async {
+                let mut ts = 0;
+                for id in 0..100 {
+                    ts = db.put_with_options(user(id), &bulk).await.unwrap();
+                }
+                ts
+            };
+            let foreground = async {
+                let mut ts = 0;
+                for id in 100..150 {
+                    ts = db.put(user(id)).await.unwrap();
+                }
+                ts
+            };
+            let (import_ts, foreground_ts) = futures::join!(import, foreground);
+
+            let ts = import_ts.max(foreground_ts);
+            for id in [0, 99, 100, 149] {
+                assert_eq!(db.get_at_least(&id, ts).await, Some(user(id)));
+            }
+        });
     }
 
     #[test]
-    fn read_from_disk() {
+    fn reject_oversized_value() {
         let temp_dir = TempDir::new().unwrap();
 
         ExecutorBuilder::new().build().unwrap().block_on(async {
             let db = Arc::new(
                 Db::new(
                     LocalOracle::default(),
-                    Fs::new(temp_dir.path()).unwrap(),
+                    InMemProvider::default(),
                     DbOption {
-                        // TIPS: kv size in test case is 17
-                        path: temp_dir.path().to_path_buf(),
-                        max_mem_table_size: 25,
-                        immutable_chunk_num: 1,
-                        major_threshold_with_sst_size: 5,
-                        level_sst_magnification: 10,
-                        max_sst_file_size: 2 * 1024 * 1024,
-                        clean_channel_buffer: 10,
+                        max_value_size: 64,
+                        ..DbOption::new(temp_dir.path().to_path_buf())
                     },
                 )
                 .await
                 .unwrap(),
             );
+            let user = |id: u64, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
 
-            let fn_write = |db: Arc<Db<_, _, _>>, user: UserInner| async move {
-                db.write(RecordType::Full, 0, user).await.unwrap();
+            assert!(matches!(
+                db.write(RecordType::Full, 0, user(0, &"0".repeat(64)))
+                    .await,
+                Err(WriteError::ValueTooLarge { max: 64, .. })
+            ));
+            db.write(RecordType::Full, 0, user(1, "1")).await.unwrap();
+
+            let mut txn = db.new_txn();
+            txn.set(1, user(1, "2"));
+            txn.set(2, user(2, &"2".repeat(64)));
+            assert!(matches!(
+                txn.commit().await,
+                Err(CommitError::WriteError(_))
+            ));
+            assert_eq!(db.get(&1, &1).await, Some(user(1, "1")));
+            assert_eq!(db.get(&2, &1).await, None);
+        });
+    }
+
+    #[test]
+    fn range_with_filter() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption {
+                    max_mem_table_size: 25,
+                    ..DbOption::new(temp_dir.path().to_path_buf())
+                },
+            )
+            .await
+            .unwrap();
+            let user = |id: u64, is_human: bool| {
+                UserInner::new(id, id.to_string(), is_human, 0, 0, 0, 0, 0, 0, 0, 0)
             };
-            for item in test_items() {
-                fn_write(db.clone(), item).await;
+
+            for id in 0..20 {
+                db.write(RecordType::Full, 0, user(id, id % 2 == 0))
+                    .await
+                    .unwrap();
+            }
+            // the older version of 4 matches, the newer one must still hide it
+            db.write(RecordType::Full, 1, user(4, false)).await.unwrap();
+            db.remove(RecordType::Full, 1, 6).await.unwrap();
+
+            let options = ScanOptions::<UserInner>::default()
+                .filter(|_, user| user.is_some_and(|user| user.inner.is_human));
+            let mut stream = db
+                .range_with_options(Bound::Unbounded, Bound::Excluded(&12), &1, &options)
+                .await
+                .unwrap();
+            let mut keys = vec![];
+
+            while let Some(result) = stream.next().await {
+                keys.push(result.unwrap().0);
             }
+            assert_eq!(keys, vec![0, 2, 8, 10]);
+        });
+    }
 
-            assert_eq!(
-                db.get(&20, &0).await,
-                Some(UserInner::new(
-                    20,
-                    "20".to_string(),
-                    false,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0
-                ))
-            );
+    #[test]
+    fn scan_budget() {
+        with_db(|db| async move {
+            for id in 0..10 {
+                db.write(RecordType::Full, 0, user(id)).await.unwrap();
+            }
+            let row_size = 0u64.size() + user(0).size();
 
-            let mut stream: MergeStream<UserInner> = db.range(None, None, &0).await.unwrap();
+            let scan = |options: ScanOptions<UserInner>, lower: Option<u64>| {
+                let db = &db;
+                async move {
+                    let lower = lower.as_ref().map_or(Bound::Unbounded, Bound::Excluded);
+                    let mut stream = db
+                        .range_with_options(lower, Bound::Unbounded, &0, &options)
+                        .await
+                        .unwrap();
+                    let mut keys = vec![];
+                    while let Some(result) = stream.next().await {
+                        match result {
+                            Ok((key, _)) => keys.push(key),
+                            Err(ScanError::Truncated(truncated)) => {
+                                return (keys, Some(truncated.resume_after))
+                            }
+                            Err(err) => panic!("{}", err),
+                        }
+                    }
+                    (keys, None)
+                }
+            };
 
-            let mut results = vec![];
-            while let Some(result) = stream.next().await {
-                results.push(result.unwrap().1.unwrap());
+            let mut rows = vec![];
+            let mut lower = None;
+            loop {
+                let options = ScanOptions::default().max_bytes(row_size * 3);
+                let (keys, truncated) = scan(options, lower).await;
+                assert!(keys.len() <= 4);
+                rows.extend(keys);
+                match truncated {
+                    Some(resume_after) => lower = resume_after,
+                    None => break,
+                }
             }
-            assert_eq!(results.len(), test_items().len());
-            assert_eq!(results, test_items());
+            assert_eq!(rows, (0..10).collect::<Vec<_>>());
 
-            drop(stream);
-            drop(db);
+            let options = ScanOptions::default().deadline(Instant::now());
+            assert_eq!(scan(options, None).await, (vec![], Some(None)));
+            let options = ScanOptions::default().max_bytes(usize::MAX);
+            assert_eq!(scan(options, Some(8)).await, (vec![9], None));
+        });
+    }
 
+    #[test]
+    fn scan_batches() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
             let db = Db::new(
                 LocalOracle::default(),
-                Fs::new(temp_dir.path()).unwrap(),
+                InMemProvider::default(),
                 DbOption {
-                    // TIPS: kv size in test case is 17
-                    path: temp_dir.path().to_path_buf(),
-                    max_mem_table_size: 25,
-                    immutable_chunk_num: 1,
-                    major_threshold_with_sst_size: 5,
-                    level_sst_magnification: 10,
-                    max_sst_file_size: 2 * 1024 * 1024,
-                    clean_channel_buffer: 10,
+                    scan_batch_rows: 4,
+                    ..DbOption::new(temp_dir.path().to_path_buf())
                 },
             )
             .await
             .unwrap();
 
-            assert_eq!(
-                db.get(&20, &0).await,
-                Some(UserInner::new(
-                    20,
-                    "20".to_string(),
-                    false,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
+            for id in 0..10 {
+                db.write(
+                    RecordType::Full,
                     0,
-                    0
-                ))
-            );
-            // FIXME: clean unless wal
-            // let mut stream: MergeStream<UserInner> = db.range(None, None, &0).await.unwrap();
-            //
-            // let mut results = vec![];
-            // while let Some(result) = stream.next().await {
-            //     results.push(result.unwrap().1.unwrap());
-            // }
-            // assert_eq!(results.len(), test_items().len());
-            // assert_eq!(results, test_items());
-            //
-            // drop(stream);
+                    UserInner::new(id, id.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0),
+                )
+                .await
+                .unwrap();
+            }
+            db.remove(RecordType::Full, 0, 3).await.unwrap();
+
+            let mut stream = db.scan_batches(..=&9, &0).await.unwrap();
+            let mut keys = vec![];
+            let mut rows = vec![];
+
+            while let Some(batch) = stream.next().await {
+                let batch = batch.unwrap();
+
+                rows.push(batch.num_rows());
+                keys.extend(
+                    batch
+                        .column(0)
+                        .as_any()
+                        .downcast_ref::<UInt64Array>()
+                        .unwrap()
+                        .values()
+                        .iter()
+                        .copied(),
+                );
+            }
+            assert_eq!(rows, vec![4, 4, 1]);
+            assert_eq!(keys, vec![0, 1, 2, 4, 5, 6, 7, 8, 9]);
         });
     }
 
@@ -1224,4 +5050,377 @@ mod tests {
             );
         });
     }
+
+    #[test]
+    fn recover_range_delete() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let open = || {
+                Db::<UserInner, _, _>::new(
+                    LocalOracle::default(),
+                    Fs::new(temp_dir.path()).unwrap(),
+                    DbOption::new(temp_dir.path().to_path_buf()),
+                )
+            };
+            let db = open().await.unwrap();
+
+            let mut txn = db.new_txn();
+            for id in 0..4 {
+                txn.set(id, user(id));
+            }
+            txn.commit().await.unwrap();
+            let mut txn = db.new_txn();
+            txn.remove_range(1..3);
+            let ts = txn.commit().await.unwrap();
+            // as if the db stopped before the tombstone was persisted
+            db.system
+                .retain_prefix(RANGE_DELETE_PREFIX, |_| false)
+                .await
+                .unwrap();
+            drop(db);
+
+            let db = open().await.unwrap();
+            assert_eq!(db.get(&0, &ts).await, Some(user(0)));
+            assert_eq!(db.get(&1, &ts).await, None);
+            assert_eq!(db.get(&2, &ts).await, None);
+            assert_eq!(db.get(&3, &ts).await, Some(user(3)));
+            assert!(RangeDeletes::<u64, OrdComparator>::load(&db.system)
+                .await
+                .unwrap()
+                .may_cover(&1, ts));
+        });
+    }
+
+    #[test]
+    fn pipelined_commits() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db = Arc::new(
+                Db::new(
+                    LocalOracle::default(),
+                    Fs::new(temp_dir.path()).unwrap(),
+                    DbOption::new(temp_dir.path().to_path_buf()),
+                )
+                .await
+                .unwrap(),
+            );
+
+            futures::future::join_all((0..32).map(|i| {
+                let mut txn = db.new_txn();
+                for id in i * 8..(i + 1) * 8 {
+                    txn.set(id, user(id));
+                }
+                txn.commit()
+            }))
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+            for id in 0..256 {
+                assert_eq!(db.txn().get(&id).await, Some(user(id)));
+            }
+            drop(db);
+
+            let db = Db::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            for id in 0..256 {
+                assert_eq!(db.get(&id, &u64::MAX).await, Some(user(id)));
+            }
+        });
+    }
+
+    #[test]
+    fn recover_moved_records() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let users = (0..16)
+                .map(|id| UserInner::new(id, id.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0))
+                .collect::<Vec<_>>();
+            let shards = executor::worker_num() + 1;
+
+            // a segment left behind while the store ran on one more shard
+            let mut file = Fs::new(temp_dir.path()).unwrap().open(42).await.unwrap();
+            WalHeader::new(0, shards as u32, 0)
+                .encode(&mut file)
+                .await
+                .unwrap();
+            let mut wal = WalFile::<_, u64, UserInner>::new(file);
+            for user in users.iter() {
+                wal.write(Record::new(
+                    RecordType::Full,
+                    &user.primary_key(),
+                    1,
+                    Some(user),
+                ))
+                .await
+                .unwrap();
+            }
+            wal.close().await.unwrap();
+
+            let db = Db::new(
+                LocalOracle::default(),
+                Fs::new(temp_dir.path()).unwrap(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+
+            let moved = users
+                .iter()
+                .filter(|user| {
+                    shard_of(&user.primary_key(), shards)
+                        != shard_of(&user.primary_key(), executor::worker_num())
+                })
+                .count();
+            assert_eq!(
+                db.recovery_stats(),
+                &RecoveryStats {
+                    records: users.len() as u64,
+                    moved: moved as u64,
+                }
+            );
+            for user in users {
+                assert_eq!(db.get(&user.primary_key(), &1).await, Some(user));
+            }
+        });
+    }
+
+    #[test]
+    fn live_files() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            assert!(db.live_files().await.is_empty());
+
+            let gen = ProcessUniqueId::new();
+            db.version_set
+                .apply_edits(
+                    vec![VersionEdit::Add {
+                        level: 1,
+                        scope: Scope {
+                            min: 1,
+                            max: 9,
+                            gen,
+                            stats: TableStats {
+                                rows: 9,
+                                size: 512,
+                                created_at: 42,
+                                ..Default::default()
+                            },
+                        },
+                    }],
+                    None,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(
+                db.live_files().await,
+                vec![FileMetadata {
+                    id: gen,
+                    level: 1,
+                    min: 1,
+                    max: 9,
+                    size: 512,
+                    entries: 9,
+                    created_at: 42,
+                    being_compacted: false,
+                    reads: TableReads::default(),
+                }]
+            );
+        });
+    }
+
+    #[test]
+    fn write_sorted_bulk() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            let user = |id, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+
+            let ts_1 = db
+                .write_sorted_bulk(
+                    (1..=9)
+                        .rev()
+                        .map(|id| user(id, "old"))
+                        .chain([user(3, "dup")]),
+                )
+                .await
+                .unwrap();
+            let ts_2 = db
+                .write_sorted_bulk((4..=6).map(|id| user(id, "new")))
+                .await
+                .unwrap();
+
+            let files = db.live_files().await;
+            assert_eq!(
+                files
+                    .iter()
+                    .map(|file| (file.level, file.min, file.max, file.entries))
+                    .collect::<Vec<_>>(),
+                vec![(MAX_LEVEL - 3, 4, 6, 3), (MAX_LEVEL - 2, 1, 9, 9)]
+            );
+
+            assert_eq!(db.get(&3, &ts_2).await, Some(user(3, "dup")));
+            assert_eq!(db.get(&5, &ts_1).await, Some(user(5, "old")));
+            assert_eq!(db.get(&5, &ts_2).await, Some(user(5, "new")));
+            assert_eq!(db.get(&5, &(ts_1 - 1)).await, None);
+        });
+    }
+
+    #[test]
+    fn custom_comparator() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db = Arc::new(
+                Db::new(
+                    LocalOracle::default(),
+                    InMemProvider::default(),
+                    DbOption::new(temp_dir.path().to_path_buf()),
+                )
+                .await
+                .unwrap(),
+            );
+            let countdown = |id: u64| CountdownInner::new(id, id.to_string());
+
+            let mut txn = db.new_txn();
+            for id in 0..10 {
+                txn.set(id, countdown(id));
+            }
+            txn.commit().await.unwrap();
+            // imported straight into a table, ordered from 30 down to 20
+            db.write_sorted_bulk((20..=30).map(countdown))
+                .await
+                .unwrap();
+            let files = db.live_files().await;
+            assert_eq!(
+                files
+                    .iter()
+                    .map(|file| (file.min, file.max, file.entries))
+                    .collect::<Vec<_>>(),
+                vec![(30, 20, 11)]
+            );
+
+            let mut txn = db.new_txn();
+            txn.set(15, countdown(15));
+            assert_eq!(txn.get(&22).await, Some(countdown(22)));
+            let keys = txn
+                .range(&25..=&5)
+                .await
+                .unwrap()
+                .map(|row| row.unwrap().0)
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(
+                keys,
+                (20..=25)
+                    .rev()
+                    .chain([15])
+                    .chain((5..10).rev())
+                    .collect::<Vec<_>>()
+            );
+            assert!(txn.range(&5..=&25).await.unwrap().next().await.is_none());
+
+            txn.remove_range(&8..=&3);
+            let keys = txn
+                .range(..)
+                .await
+                .unwrap()
+                .map(|row| row.unwrap())
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .filter_map(|(key, value)| value.map(|_| key))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                keys,
+                (20..=30)
+                    .rev()
+                    .chain([15, 9])
+                    .chain((0..3).rev())
+                    .collect::<Vec<_>>()
+            );
+        });
+    }
+
+    #[test]
+    fn write_bulk_with_timestamps() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db: Db<UserInner, _, _> = Db::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+            let user = |id, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+
+            db.write_bulk_with_timestamps(
+                [
+                    (1, 7, Some(user(1, "b"))),
+                    (2, 9, None),
+                    (1, 5, Some(user(1, "a"))),
+                    (2, 6, Some(user(2, "c"))),
+                ],
+                ImportMode::Ahead,
+            )
+            .await
+            .unwrap();
+            assert_eq!(db.now(), 9);
+            assert_eq!(db.get(&1, &6).await, Some(user(1, "a")));
+            assert_eq!(db.get(&1, &9).await, Some(user(1, "b")));
+            assert_eq!(db.get(&2, &8).await, Some(user(2, "c")));
+            assert_eq!(db.get(&2, &9).await, None);
+            assert!(matches!(
+                db.write_bulk_with_timestamps([(3, 9, None)], ImportMode::Ahead)
+                    .await,
+                Err(WriteError::ImportTimestamp { ts: 9, now: 9 })
+            ));
+
+            db.write_bulk_with_timestamps([(10, 3, Some(user(10, "x")))], ImportMode::History)
+                .await
+                .unwrap();
+            assert_eq!(db.get(&10, &9).await, Some(user(10, "x")));
+            assert!(matches!(
+                db.write_bulk_with_timestamps([(11, 20, None)], ImportMode::History)
+                    .await,
+                Err(WriteError::ImportTimestamp { ts: 20, now: 9 })
+            ));
+            assert!(matches!(
+                db.write_bulk_with_timestamps([(2, 1, None)], ImportMode::History)
+                    .await,
+                Err(WriteError::ImportOverlap)
+            ));
+        });
+    }
 }
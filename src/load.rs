@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::ShardStats;
+
+/// Writes between two checks of the balance of the shards.
+const CHECK_EVERY: u64 = 4096;
+
+#[derive(Debug)]
+pub(crate) struct ShardLoad {
+    shards: Vec<Counters>,
+    writes: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    writes: AtomicU64,
+    mem_table_rows: AtomicU64,
+    scanned_rows: AtomicU64,
+}
+
+impl ShardLoad {
+    pub(crate) fn new(shard_num: usize) -> Self {
+        ShardLoad {
+            shards: (0..shard_num).map(|_| Counters::default()).collect(),
+            writes: AtomicU64::new(0),
+        }
+    }
+
+    /// Must be called while holding the shard's write lock. Every [`CHECK_EVERY`] writes, returns
+    /// the busiest shard and how many times the mean of writes it took, if above `threshold`.
+    pub(crate) fn on_write(
+        &self,
+        shard: usize,
+        writes: u64,
+        mem_table_rows: usize,
+        threshold: f64,
+    ) -> Option<(usize, f64)> {
+        let counters = &self.shards[shard];
+        counters.writes.fetch_add(writes, Ordering::Relaxed);
+        counters
+            .mem_table_rows
+            .store(mem_table_rows as u64, Ordering::Relaxed);
+
+        let total = self.writes.fetch_add(writes, Ordering::Relaxed) + writes;
+        if self.shards.len() < 2 || total / CHECK_EVERY == (total - writes) / CHECK_EVERY {
+            return None;
+        }
+        let mean = total as f64 / self.shards.len() as f64;
+        self.stats()
+            .iter()
+            .enumerate()
+            .map(|(shard, stats)| (shard, stats.writes as f64 / mean))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .filter(|(_, ratio)| *ratio > threshold)
+    }
+
+    /// Must be called while holding the shard's write lock, once its mem table is swapped.
+    pub(crate) fn on_freeze(&self, shard: usize) {
+        self.shards[shard]
+            .mem_table_rows
+            .store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_scan(&self, shard: usize, rows: usize) {
+        self.shards[shard]
+            .scanned_rows
+            .fetch_add(rows as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> Vec<ShardStats> {
+        self.shards
+            .iter()
+            .map(|counters| ShardStats {
+                writes: counters.writes.load(Ordering::Relaxed),
+                mem_table_rows: counters.mem_table_rows.load(Ordering::Relaxed),
+                scanned_rows: counters.scanned_rows.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ShardLoad, CHECK_EVERY};
+
+    #[test]
+    fn imbalance() {
+        let load = ShardLoad::new(4);
+
+        assert_eq!(load.on_write(0, CHECK_EVERY - 1, 10, 2.0), None);
+        assert_eq!(
+            load.on_write(1, 1, 1, 2.0),
+            Some((0, 4.0 - 4.0 / CHECK_EVERY as f64))
+        );
+        load.on_scan(1, 3);
+
+        let stats = load.stats();
+        assert_eq!(stats[0].writes, CHECK_EVERY - 1);
+        assert_eq!(
+            (
+                stats[1].writes,
+                stats[1].mem_table_rows,
+                stats[1].scanned_rows
+            ),
+            (1, 1, 3)
+        );
+
+        for shard in 0..4 {
+            load.on_write(shard, CHECK_EVERY, 0, 2.0);
+        }
+        assert_eq!(load.on_write(2, CHECK_EVERY, 0, 2.0), None);
+    }
+}
@@ -1,36 +1,83 @@
 pub(crate) mod stream;
 
-use std::{cmp, cmp::Ordering, collections::BTreeMap, ops::Bound, pin::pin};
+use std::{
+    cmp,
+    cmp::Ordering,
+    collections::BTreeMap,
+    fmt::{self, Debug},
+    marker::PhantomData,
+    mem,
+    ops::Bound,
+    pin::pin,
+};
 
 use futures::StreamExt;
 
 use crate::{
-    oracle::TimeStamp, record::RecordType, schema::Schema, serdes::Encode, wal::WalRecover,
+    comparator::Comparator,
+    index_batch::IndexBatch,
+    oracle::TimeStamp,
+    record::{RecordType, WalEntry},
+    schema::{Builder, Op, Schema},
+    serdes::Encode,
+    wal::WalRecover,
 };
 
-#[derive(PartialEq, Eq, Debug)]
-pub(crate) struct InternalKey<K> {
+/// Ordered by key in the order of `C`, then by descending timestamp.
+pub(crate) struct InternalKey<K, C> {
     pub(crate) key: K,
     pub(crate) ts: TimeStamp,
+    _c: PhantomData<C>,
+}
+
+impl<K, C> InternalKey<K, C> {
+    pub(crate) fn new(key: K, ts: TimeStamp) -> Self {
+        InternalKey {
+            key,
+            ts,
+            _c: PhantomData,
+        }
+    }
 }
 
-impl<K> PartialOrd<Self> for InternalKey<K>
+impl<K, C> Debug for InternalKey<K, C>
 where
-    K: Ord,
+    K: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InternalKey")
+            .field("key", &self.key)
+            .field("ts", &self.ts)
+            .finish()
+    }
+}
+
+impl<K, C> PartialEq<Self> for InternalKey<K, C>
+where
+    C: Comparator<K>,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl<K, C> Eq for InternalKey<K, C> where C: Comparator<K> {}
+
+impl<K, C> PartialOrd<Self> for InternalKey<K, C>
+where
+    C: Comparator<K>,
 {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl<K> Ord for InternalKey<K>
+impl<K, C> Ord for InternalKey<K, C>
 where
-    K: Ord,
+    C: Comparator<K>,
 {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.key
-            .cmp(&other.key)
-            .then_with(|| other.ts.cmp(&self.ts))
+        C::compare(&self.key, &other.key).then_with(|| other.ts.cmp(&self.ts))
     }
 }
 
@@ -39,7 +86,7 @@ pub(crate) struct MemTable<S>
 where
     S: Schema,
 {
-    pub(crate) data: BTreeMap<InternalKey<S::PrimaryKey>, Option<S>>,
+    pub(crate) data: BTreeMap<InternalKey<S::PrimaryKey, S::Comparator>, Option<S>>,
     max_ts: TimeStamp,
     written_size: usize,
 }
@@ -78,8 +125,11 @@ where
     {
         let mut stream = pin!(wal.recover());
         let mut batch = None;
-        while let Some(record) = stream.next().await {
-            let record = record?;
+        while let Some(entry) = stream.next().await {
+            // range tombstones and system keys are kept by the db, not in mem tables
+            let WalEntry::Record(record) = entry? else {
+                continue;
+            };
             match record.record_type {
                 RecordType::Full => self.insert(record.key, record.ts, record.value),
                 RecordType::First => {
@@ -106,6 +156,9 @@ where
                     }
                     panic!("last record should in a batch");
                 }
+                RecordType::RangeDelete | RecordType::System => {
+                    unreachable!("range deletes and system keys are not decoded as records")
+                }
             }
         }
         Ok(())
@@ -124,22 +177,62 @@ where
         self.data.is_empty()
     }
 
+    /// Cuts the mem table into batches of about `max_batch_size` encoded bytes. All versions of
+    /// a key stay in one batch, since lookups stop at the first batch holding the key.
+    pub(crate) fn to_batches(&self, max_batch_size: usize) -> Vec<IndexBatch<S>> {
+        let mut batches = Vec::new();
+        let mut builder = S::builder();
+        let mut size = 0;
+        let mut last_key = None;
+
+        for (key, value) in self.data.iter() {
+            if size >= max_batch_size && last_key != Some(&key.key) {
+                batches.push(IndexBatch::new(builder.finish()));
+                size = 0;
+            }
+            size += key.key.size() + key.ts.size() + value.as_ref().map(Encode::size).unwrap_or(0);
+
+            builder.add(&key.key, key.ts, Op::of(value.as_ref()), value.clone());
+            last_key = Some(&key.key);
+        }
+        batches.push(IndexBatch::new(builder.finish()));
+
+        batches
+    }
+
+    pub(crate) fn written_size(&self) -> usize {
+        self.written_size
+    }
+
     pub(crate) fn len(&self) -> usize {
         self.data.len()
     }
 
+    /// Removes and returns every version of the keys `f` picks.
+    pub(crate) fn take_where(
+        &mut self,
+        mut f: impl FnMut(&S::PrimaryKey) -> bool,
+    ) -> Vec<(S::PrimaryKey, TimeStamp, Option<S>)> {
+        let (taken, kept) = mem::take(&mut self.data)
+            .into_iter()
+            .partition::<BTreeMap<_, _>, _>(|(key, _)| f(&key.key));
+        self.data = kept;
+
+        taken
+            .into_iter()
+            .map(|(key, value)| (key.key, key.ts, value))
+            .collect()
+    }
+
     pub(crate) fn insert(&mut self, key: S::PrimaryKey, ts: TimeStamp, value: Option<S>) {
         self.max_ts = cmp::max(self.max_ts, ts);
-        self.written_size = key.size() + ts.size() + value.as_ref().map(Encode::size).unwrap_or(0);
+        self.written_size += key.size() + ts.size() + value.as_ref().map(Encode::size).unwrap_or(0);
 
-        let _ = self.data.insert(InternalKey { key, ts }, value);
+        let _ = self.data.insert(InternalKey::new(key, ts), value);
     }
 
     pub(crate) fn get(&self, key: &S::PrimaryKey, ts: &TimeStamp) -> Option<Option<&S>> {
-        let internal_key = InternalKey {
-            key: key.clone(),
-            ts: *ts,
-        };
+        let internal_key = InternalKey::new(key.clone(), *ts);
 
         self.data
             .range((Bound::Included(&internal_key), Bound::Unbounded))
@@ -1,6 +1,7 @@
 use std::{
     collections::{btree_map, Bound},
     pin::{pin, Pin},
+    sync::Arc,
     task::{Context, Poll},
 };
 
@@ -8,10 +9,12 @@ use executor::futures::{util::StreamExt, Stream};
 use pin_project::pin_project;
 
 use crate::{
+    comparator::OrdComparator,
     mem_table::{InternalKey, MemTable},
     oracle::TimeStamp,
     schema::Schema,
-    stream::StreamError,
+    stream::{mask, ScanError, ScanFilter},
+    utils::is_empty_range,
 };
 
 #[pin_project]
@@ -19,8 +22,8 @@ pub(crate) struct MemTableStream<'a, S>
 where
     S: Schema,
 {
-    inner: btree_map::Range<'a, InternalKey<S::PrimaryKey>, Option<S>>,
-    item_buf: Option<(S::PrimaryKey, Option<S>)>,
+    inner: btree_map::Range<'a, InternalKey<S::PrimaryKey, S::Comparator>, Option<S>>,
+    item_buf: Option<(S::PrimaryKey, TimeStamp, Option<S>)>,
     ts: TimeStamp,
 }
 
@@ -28,34 +31,121 @@ impl<'a, S> Stream for MemTableStream<'a, S>
 where
     S: Schema,
 {
-    type Item = Result<(S::PrimaryKey, Option<S>), StreamError<S::PrimaryKey, S>>;
+    type Item = Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>;
 
     fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.project();
-        for (InternalKey { key, ts }, value) in this.inner.by_ref() {
+        for (InternalKey { key, ts, .. }, value) in this.inner.by_ref() {
+            // the versions under an operand are yielded too, for the merge to fold them into it
             if ts <= this.ts
                 && matches!(
-                    this.item_buf.as_ref().map(|(k, _)| k != key),
+                    this.item_buf
+                        .as_ref()
+                        .map(|(k, _, v)| { k != key || v.as_ref().is_some_and(S::is_operand) }),
                     Some(true) | None
                 )
             {
-                return Poll::Ready(this.item_buf.replace((key.clone(), value.clone())).map(Ok));
+                return Poll::Ready(
+                    this.item_buf
+                        .replace((key.clone(), *ts, value.clone()))
+                        .map(Ok),
+                );
             }
         }
         Poll::Ready(this.item_buf.take().map(Ok))
     }
 }
 
+/// Like [`MemTableStream`], over a mem table it holds on to rather than borrows, e.g. one being
+/// encoded, which stays readable for as long as the scan runs. Each key is found by a seek past
+/// the previous one, or past the previous version if that is an operand, so nothing is buffered.
+#[pin_project]
+pub(crate) struct SharedMemTableStream<S>
+where
+    S: Schema,
+{
+    mem_table: Arc<MemTable<S>>,
+    lower: Bound<InternalKey<S::PrimaryKey, S::Comparator>>,
+    upper: Bound<InternalKey<S::PrimaryKey, S::Comparator>>,
+    ts: TimeStamp,
+    filter: Option<ScanFilter<S>>,
+}
+
+impl<S> Stream for SharedMemTableStream<S>
+where
+    S: Schema,
+{
+    type Item = Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>;
+
+    fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let read_at = *this.ts;
+        // the bounds may be empty, or a seek may move the lower one past the upper one
+        if is_empty_range::<_, OrdComparator>(this.lower.as_ref(), this.upper.as_ref()) {
+            return Poll::Ready(None);
+        }
+        let next = this
+            .mem_table
+            .data
+            .range((this.lower.as_ref(), this.upper.as_ref()))
+            .find(|(InternalKey { ts, .. }, _)| *ts <= read_at)
+            .map(|(InternalKey { key, ts, .. }, value)| (key.clone(), *ts, value.clone()));
+        let Some((key, ts, value)) = next else {
+            return Poll::Ready(None);
+        };
+        let past = match &value {
+            Some(value) if value.is_operand() => ts,
+            _ => TimeStamp::MIN,
+        };
+        *this.lower = Bound::Excluded(InternalKey::new(key.clone(), past));
+        Poll::Ready(Some(Ok(mask(this.filter.as_ref(), key, ts, value))))
+    }
+}
+
+impl<S> SharedMemTableStream<S>
+where
+    S: Schema,
+{
+    /// Moves to the first key at or after `key`, which must come after the keys yielded.
+    pub(crate) fn seek(&mut self, key: &S::PrimaryKey) {
+        self.lower = Bound::Included(InternalKey::new(key.clone(), self.ts));
+    }
+}
+
+/// Versions of a key are ordered by descending timestamp, so excluding a key means starting after
+/// its oldest version or stopping before its newest one.
+#[allow(clippy::type_complexity)]
+fn bounds<K: Clone, C>(
+    lower: Bound<&K>,
+    upper: Bound<&K>,
+    ts: TimeStamp,
+) -> (Bound<InternalKey<K, C>>, Bound<InternalKey<K, C>>) {
+    let internal_key = |key: &K, ts| InternalKey::new(key.clone(), ts);
+    let lower = match lower {
+        Bound::Included(key) => Bound::Included(internal_key(key, ts)),
+        Bound::Excluded(key) => Bound::Excluded(internal_key(key, TimeStamp::MIN)),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    let upper = match upper {
+        Bound::Included(key) => Bound::Included(internal_key(key, TimeStamp::MIN)),
+        Bound::Excluded(key) => Bound::Excluded(internal_key(key, TimeStamp::MAX)),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (lower, upper)
+}
+
 impl<S> MemTable<S>
 where
     S: Schema,
 {
-    pub(crate) async fn iter(&self) -> Result<MemTableStream<S>, StreamError<S::PrimaryKey, S>> {
+    pub(crate) async fn iter(&self) -> Result<MemTableStream<S>, ScanError<S::PrimaryKey, S>> {
         let mut iterator = MemTableStream {
-            inner: self.data.range::<InternalKey<S::PrimaryKey>, (
-                Bound<InternalKey<S::PrimaryKey>>,
-                Bound<InternalKey<S::PrimaryKey>>,
-            )>((Bound::Unbounded, Bound::Unbounded)),
+            inner: self
+                .data
+                .range::<InternalKey<S::PrimaryKey, S::Comparator>, (
+                    Bound<InternalKey<S::PrimaryKey, S::Comparator>>,
+                    Bound<InternalKey<S::PrimaryKey, S::Comparator>>,
+                )>((Bound::Unbounded, Bound::Unbounded)),
             item_buf: None,
             ts: self.max_ts,
         };
@@ -69,29 +159,17 @@ where
 
     pub(crate) async fn range(
         &self,
-        lower: Option<&S::PrimaryKey>,
-        upper: Option<&S::PrimaryKey>,
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
         ts: &TimeStamp,
-    ) -> Result<MemTableStream<S>, StreamError<S::PrimaryKey, S>> {
+    ) -> Result<MemTableStream<S>, ScanError<S::PrimaryKey, S>> {
+        let inner = if is_empty_range::<_, S::Comparator>(lower, upper) {
+            btree_map::Range::default()
+        } else {
+            self.data.range(bounds(lower, upper, *ts))
+        };
         let mut iterator = MemTableStream {
-            inner: self.data.range((
-                lower
-                    .map(|k| {
-                        Bound::Included(InternalKey {
-                            key: k.clone(),
-                            ts: *ts,
-                        })
-                    })
-                    .unwrap_or(Bound::Unbounded),
-                upper
-                    .map(|k| {
-                        Bound::Included(InternalKey {
-                            key: k.clone(),
-                            ts: TimeStamp::default(),
-                        })
-                    })
-                    .unwrap_or(Bound::Unbounded),
-            )),
+            inner,
             item_buf: None,
             ts: *ts,
         };
@@ -104,13 +182,38 @@ where
 
         Ok(iterator)
     }
+
+    /// Scans `mem_table` like [`MemTable::range`] for as long as the stream lives, masking the
+    /// rows `filter` rejects.
+    pub(crate) fn shared_range(
+        mem_table: Arc<Self>,
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
+        ts: TimeStamp,
+        filter: Option<&ScanFilter<S>>,
+    ) -> SharedMemTableStream<S> {
+        let (lower, upper) = bounds(lower, upper, ts);
+        SharedMemTableStream {
+            mem_table,
+            lower,
+            upper,
+            ts,
+            filter: filter.cloned(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{collections::Bound, sync::Arc};
+
     use executor::futures::{future::block_on, StreamExt};
 
-    use crate::{mem_table::MemTable, tests::UserInner};
+    use crate::{
+        mem_table::MemTable,
+        stream::ScanFilter,
+        tests::{user, UserInner},
+    };
 
     #[test]
     fn iterator() {
@@ -175,6 +278,7 @@ mod tests {
             assert_eq!(
                 iterator.next().await.unwrap().unwrap(),
                 (
+                    1,
                     1,
                     Some(UserInner::new(
                         2,
@@ -195,6 +299,7 @@ mod tests {
                 iterator.next().await.unwrap().unwrap(),
                 (
                     2,
+                    0,
                     Some(UserInner::new(
                         1,
                         "1".to_string(),
@@ -216,7 +321,7 @@ mod tests {
 
             let mut iterator = mem_table.iter().await.unwrap();
 
-            assert_eq!(iterator.next().await.unwrap().unwrap(), (1, None));
+            assert_eq!(iterator.next().await.unwrap().unwrap(), (1, 3, None));
         });
     }
 
@@ -317,6 +422,7 @@ mod tests {
                 iterator.next().await.unwrap().unwrap(),
                 (
                     1,
+                    0,
                     Some(UserInner::new(
                         1,
                         "1".to_string(),
@@ -336,6 +442,7 @@ mod tests {
                 iterator.next().await.unwrap().unwrap(),
                 (
                     2,
+                    1,
                     Some(UserInner::new(
                         3,
                         "3".to_string(),
@@ -355,6 +462,7 @@ mod tests {
                 iterator.next().await.unwrap().unwrap(),
                 (
                     3,
+                    0,
                     Some(UserInner::new(
                         3,
                         "3".to_string(),
@@ -374,6 +482,7 @@ mod tests {
                 iterator.next().await.unwrap().unwrap(),
                 (
                     4,
+                    0,
                     Some(UserInner::new(
                         4,
                         "4".to_string(),
@@ -391,12 +500,16 @@ mod tests {
             );
             assert!(iterator.next().await.is_none());
 
-            let mut iterator = mem_table.range(Some(&2), Some(&3), &0).await.unwrap();
+            let mut iterator = mem_table
+                .range(Bound::Included(&2), Bound::Included(&3), &0)
+                .await
+                .unwrap();
 
             assert_eq!(
                 iterator.next().await.unwrap().unwrap(),
                 (
                     2,
+                    0,
                     Some(UserInner::new(
                         2,
                         "2".to_string(),
@@ -416,6 +529,7 @@ mod tests {
                 iterator.next().await.unwrap().unwrap(),
                 (
                     3,
+                    0,
                     Some(UserInner::new(
                         3,
                         "3".to_string(),
@@ -431,7 +545,72 @@ mod tests {
                     ))
                 )
             );
-            assert!(iterator.next().await.is_none())
+            assert!(iterator.next().await.is_none());
+            drop(iterator);
+
+            let mut iterator = mem_table
+                .range(Bound::Excluded(&1), Bound::Excluded(&3), &1)
+                .await
+                .unwrap();
+            assert_eq!(iterator.next().await.unwrap().unwrap().0, 2);
+            assert!(iterator.next().await.is_none());
+            drop(iterator);
+
+            let mut iterator = mem_table
+                .range(Bound::Excluded(&2), Bound::Unbounded, &0)
+                .await
+                .unwrap();
+            assert_eq!(iterator.next().await.unwrap().unwrap().0, 3);
+            assert_eq!(iterator.next().await.unwrap().unwrap().0, 4);
+            assert!(iterator.next().await.is_none());
+            drop(iterator);
+
+            let mem_table = Arc::new(mem_table);
+            for (lower, upper) in [
+                (Bound::Included(&3), Bound::Excluded(&3)),
+                (Bound::Excluded(&3), Bound::Excluded(&3)),
+                (Bound::Included(&5), Bound::Excluded(&3)),
+                (Bound::Included(&5), Bound::Included(&3)),
+            ] {
+                let mut iterator = mem_table.range(lower, upper, &1).await.unwrap();
+                assert!(iterator.next().await.is_none());
+                let mut iterator = MemTable::shared_range(mem_table.clone(), lower, upper, 1, None);
+                assert!(iterator.next().await.is_none());
+            }
+        });
+    }
+
+    #[test]
+    fn shared_range() {
+        block_on(async {
+            let mut mem_table = MemTable::default();
+            mem_table.insert(1, 0, Some(user(1)));
+            mem_table.insert(1, 2, None);
+            mem_table.insert(2, 0, None);
+            mem_table.insert(3, 0, Some(user(3)));
+            mem_table.insert(4, 0, Some(user(4)));
+            mem_table.insert(5, 2, Some(user(5)));
+            let filter: ScanFilter<UserInner> = Arc::new(|key, _, _| *key != 3);
+
+            let rows = MemTable::shared_range(
+                Arc::new(mem_table),
+                Bound::Excluded(&0),
+                Bound::Unbounded,
+                1,
+                Some(&filter),
+            )
+            .map(|item| item.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+            assert_eq!(
+                rows,
+                vec![
+                    (1, 0, Some(user(1))),
+                    (2, 0, None),
+                    (3, 0, None),
+                    (4, 0, Some(user(4))),
+                ]
+            );
         });
     }
 }
@@ -1,34 +1,105 @@
 use std::{
-    collections::{btree_map::Entry, BTreeMap, HashSet},
+    collections::{btree_map::Entry, hash_map::RandomState, BTreeMap, HashSet},
     fmt::Debug,
-    hash::Hash,
-    ops::Bound,
+    hash::{BuildHasher, Hash},
+    ops::{Bound, Range},
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Mutex,
     },
+    thread,
 };
 
 use thiserror::Error;
 
-pub(crate) type TimeStamp = u64;
+pub type TimeStamp = u64;
 
-pub trait Oracle<K>: Sized
-where
-    K: Ord,
-{
+/// How many write sets [`LocalConflictChecker`] keeps for conflict checks unless told otherwise.
+pub const DEFAULT_MAX_COMMITTED_TXNS: usize = 1 << 20;
+
+/// Hands out the timestamps of reads and writes and tracks the reads still held.
+pub trait TimestampProvider {
     fn start_read(&self) -> TimeStamp;
 
     fn read_commit(&self, ts: TimeStamp);
 
     fn start_write(&self) -> TimeStamp;
 
+    /// The latest timestamp handed out.
+    fn now(&self) -> TimeStamp;
+
+    /// Moves the clock to at least `ts` and returns the time before, so that later writes are
+    /// ordered after `ts`.
+    fn advance(&self, ts: TimeStamp) -> TimeStamp;
+
+    /// The oldest read still held, or [`TimestampProvider::now`] when none is. No read committed
+    /// later is older.
+    fn oldest_read(&self) -> TimeStamp;
+
+    /// Read timestamps still held, with how many reads hold each.
+    fn active_reads(&self) -> Vec<(TimeStamp, usize)> {
+        Vec::new()
+    }
+}
+
+/// Detects transactions writing a key that another one wrote since they started reading.
+pub trait ConflictChecker<K> {
+    /// Fails if a write set committed after `read_at` and before `write_at` shares a key with
+    /// `in_write`, otherwise records `in_write` at `write_at`. Write sets at or below
+    /// `oldest_read` no longer conflict with anything.
+    fn check_commit(
+        &self,
+        read_at: TimeStamp,
+        write_at: TimeStamp,
+        in_write: HashSet<K>,
+        oldest_read: TimeStamp,
+    ) -> Result<(), WriteCommitError<K>>;
+
+    /// Fills in the conflict-check fields of `state`.
+    fn describe(&self, _state: &mut OracleState) {}
+}
+
+/// A [`TimestampProvider`] together with a [`ConflictChecker`], see [`SplitOracle`] to pair up
+/// two separate ones.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` is not an oracle for keys of type `{K}`",
+    note = "set one with `DbBuilder::oracle`, e.g. `LocalOracle::default()`"
+)]
+pub trait Oracle<K>: TimestampProvider + ConflictChecker<K> + Sized {
     fn write_commit(
         &self,
         read_at: TimeStamp,
         write_at: TimeStamp,
         in_write: HashSet<K>,
-    ) -> Result<(), WriteConflict<K>>;
+    ) -> Result<(), WriteCommitError<K>> {
+        self.check_commit(read_at, write_at, in_write, self.oldest_read())
+    }
+
+    /// What the oracle is tracking right now, for diagnosing stuck reads and conflict checks.
+    fn debug_state(&self) -> OracleState {
+        let mut state = OracleState {
+            now: self.now(),
+            active_reads: self.active_reads(),
+            ..Default::default()
+        };
+        self.describe(&mut state);
+        state
+    }
+}
+
+impl<K, T> Oracle<K> for T where T: TimestampProvider + ConflictChecker<K> {}
+
+/// A snapshot of an oracle's in-flight state, see [`Oracle::debug_state`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OracleState {
+    pub now: TimeStamp,
+    /// read timestamps still held, with how many reads hold each
+    pub active_reads: Vec<(TimeStamp, usize)>,
+    /// write sets kept for conflict checks
+    pub committed_txns: usize,
+    pub committed_keys: usize,
+    /// commit timestamps of the oldest and newest write set kept
+    pub conflict_window: Option<(TimeStamp, TimeStamp)>,
 }
 
 #[derive(Debug, Error)]
@@ -38,41 +109,257 @@ pub struct WriteConflict<K> {
 }
 
 impl<K> WriteConflict<K> {
+    pub fn new(keys: Vec<K>) -> Self {
+        WriteConflict { keys }
+    }
+
     pub fn to_keys(self) -> Vec<K> {
         self.keys
     }
 }
 
-#[derive(Debug)]
-pub(crate) struct LocalOracle<K>
+#[derive(Debug, Error)]
+pub enum WriteCommitError<K> {
+    #[error(transparent)]
+    Conflict(#[from] WriteConflict<K>),
+    /// Every kept write set may still conflict with a running transaction, so none could be
+    /// dropped to make room.
+    #[error("conflict window full: {limit} write sets are kept for running transactions")]
+    WindowFull { limit: usize },
+}
+
+/// Pairs a timestamp source with a conflict checker, e.g. a hybrid logical clock with
+/// [`LocalConflictChecker`], or any clock with [`NoConflictCheck`] for last-write-wins.
+#[derive(Debug, Default)]
+pub struct SplitOracle<T, C> {
+    pub timestamps: T,
+    pub conflicts: C,
+}
+
+impl<T, C> SplitOracle<T, C> {
+    pub fn new(timestamps: T, conflicts: C) -> Self {
+        SplitOracle {
+            timestamps,
+            conflicts,
+        }
+    }
+}
+
+impl<T, K> SplitOracle<T, LocalConflictChecker<K>>
 where
     K: Ord,
 {
-    now: AtomicU64,
-    in_read: Mutex<BTreeMap<u64, usize>>,
-    committed_txns: Mutex<BTreeMap<u64, HashSet<K>>>,
+    /// See [`LocalConflictChecker::with_max_committed_txns`].
+    pub fn with_max_committed_txns(mut self, max_committed_txns: usize) -> Self {
+        self.conflicts = self.conflicts.with_max_committed_txns(max_committed_txns);
+        self
+    }
+
+    /// See [`LocalConflictChecker::with_conflict_keys`].
+    pub fn with_conflict_keys(mut self, conflict_keys: ConflictKeys) -> Self {
+        self.conflicts = self.conflicts.with_conflict_keys(conflict_keys);
+        self
+    }
 }
 
-impl<K> Default for LocalOracle<K>
+impl<T, C> TimestampProvider for SplitOracle<T, C>
 where
-    K: Ord,
+    T: TimestampProvider,
 {
-    fn default() -> Self {
-        Self {
-            now: Default::default(),
-            in_read: Default::default(),
-            committed_txns: Default::default(),
-        }
+    fn start_read(&self) -> TimeStamp {
+        self.timestamps.start_read()
+    }
+
+    fn read_commit(&self, ts: TimeStamp) {
+        self.timestamps.read_commit(ts)
+    }
+
+    fn start_write(&self) -> TimeStamp {
+        self.timestamps.start_write()
+    }
+
+    fn now(&self) -> TimeStamp {
+        self.timestamps.now()
+    }
+
+    fn advance(&self, ts: TimeStamp) -> TimeStamp {
+        self.timestamps.advance(ts)
+    }
+
+    fn oldest_read(&self) -> TimeStamp {
+        self.timestamps.oldest_read()
+    }
+
+    fn active_reads(&self) -> Vec<(TimeStamp, usize)> {
+        self.timestamps.active_reads()
     }
 }
 
-impl<K> Oracle<K> for LocalOracle<K>
+impl<T, C, K> ConflictChecker<K> for SplitOracle<T, C>
 where
-    K: Ord + Hash + Clone,
+    C: ConflictChecker<K>,
 {
+    fn check_commit(
+        &self,
+        read_at: TimeStamp,
+        write_at: TimeStamp,
+        in_write: HashSet<K>,
+        oldest_read: TimeStamp,
+    ) -> Result<(), WriteCommitError<K>> {
+        self.conflicts
+            .check_commit(read_at, write_at, in_write, oldest_read)
+    }
+
+    fn describe(&self, state: &mut OracleState) {
+        self.conflicts.describe(state)
+    }
+}
+
+pub type LocalOracle<K> = SplitOracle<LocalClock, LocalConflictChecker<K>>;
+
+/// Counts timestamps up from zero, in memory.
+#[derive(Debug, Default)]
+pub struct LocalClock {
+    now: AtomicU64,
+    reads: Reads,
+}
+
+impl TimestampProvider for LocalClock {
     fn start_read(&self) -> TimeStamp {
+        self.reads.start(|| self.now.load(Ordering::Relaxed))
+    }
+
+    fn read_commit(&self, ts: TimeStamp) {
+        self.reads.commit(ts)
+    }
+
+    fn start_write(&self) -> TimeStamp {
+        self.now.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn now(&self) -> TimeStamp {
+        self.now.load(Ordering::Relaxed)
+    }
+
+    fn advance(&self, ts: TimeStamp) -> TimeStamp {
+        self.now.fetch_max(ts, Ordering::Relaxed)
+    }
+
+    fn oldest_read(&self) -> TimeStamp {
+        self.reads.oldest(|| self.now.load(Ordering::Relaxed))
+    }
+
+    fn active_reads(&self) -> Vec<(TimeStamp, usize)> {
+        self.reads.active()
+    }
+}
+
+/// How many write timestamps [`LeasedClock`] leases to a thread at once unless told otherwise.
+pub const DEFAULT_LEASE_SIZE: u64 = 64;
+
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SLOT: usize = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts timestamps up from zero like [`LocalClock`], but leases blocks of write timestamps to
+/// each thread, so that starting a write mostly touches only the thread's own block. Writes of
+/// different threads are then not numbered in the order they started; a thread drops the rest
+/// of its block once a read started at or past it, so that a write still commits after every
+/// read started before it.
+#[derive(Debug)]
+pub struct LeasedClock {
+    lease_size: u64,
+    /// the last timestamp leased to a thread
+    leased: AtomicU64,
+    /// the latest timestamp handed out to a write
+    now: AtomicU64,
+    /// the latest read started, or time advanced to
+    horizon: AtomicU64,
+    /// timestamps left to hand out, per slot of threads
+    blocks: Box<[Mutex<Range<TimeStamp>>]>,
+    reads: Reads,
+}
+
+impl LeasedClock {
+    pub fn new(lease_size: u64) -> Self {
+        let slots = thread::available_parallelism().map_or(1, usize::from);
+        LeasedClock {
+            lease_size: lease_size.max(1),
+            leased: AtomicU64::new(0),
+            now: AtomicU64::new(0),
+            horizon: AtomicU64::new(0),
+            blocks: (0..slots).map(|_| Mutex::new(0..0)).collect(),
+            reads: Reads::default(),
+        }
+    }
+}
+
+impl Default for LeasedClock {
+    fn default() -> Self {
+        LeasedClock::new(DEFAULT_LEASE_SIZE)
+    }
+}
+
+impl TimestampProvider for LeasedClock {
+    fn start_read(&self) -> TimeStamp {
+        self.reads.start(|| {
+            let now = self.now.load(Ordering::Relaxed);
+            self.horizon.fetch_max(now, Ordering::Relaxed);
+            now
+        })
+    }
+
+    fn read_commit(&self, ts: TimeStamp) {
+        self.reads.commit(ts)
+    }
+
+    fn start_write(&self) -> TimeStamp {
+        let slot = SLOT.with(|slot| *slot) % self.blocks.len();
+        let mut block = self.blocks[slot].lock().unwrap();
+        if block.is_empty() || block.start <= self.horizon.load(Ordering::Relaxed) {
+            // every timestamp leased is past the horizon, which only moves to ones handed out
+            let start = self.leased.fetch_add(self.lease_size, Ordering::Relaxed) + 1;
+            *block = start..start + self.lease_size;
+        }
+        let ts = block.start;
+        block.start += 1;
+        if self.now.load(Ordering::Relaxed) < ts {
+            self.now.fetch_max(ts, Ordering::Relaxed);
+        }
+        ts
+    }
+
+    fn now(&self) -> TimeStamp {
+        self.now.load(Ordering::Relaxed)
+    }
+
+    fn advance(&self, ts: TimeStamp) -> TimeStamp {
+        self.leased.fetch_max(ts, Ordering::Relaxed);
+        self.horizon.fetch_max(ts, Ordering::Relaxed);
+        self.now.fetch_max(ts, Ordering::Relaxed)
+    }
+
+    fn oldest_read(&self) -> TimeStamp {
+        self.reads.oldest(|| self.now.load(Ordering::Relaxed))
+    }
+
+    fn active_reads(&self) -> Vec<(TimeStamp, usize)> {
+        self.reads.active()
+    }
+}
+
+/// Read timestamps still held, with how many reads hold each.
+#[derive(Debug, Default)]
+struct Reads {
+    in_read: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl Reads {
+    fn start(&self, now: impl FnOnce() -> TimeStamp) -> TimeStamp {
         let mut in_read = self.in_read.lock().unwrap();
-        let now = self.now.load(Ordering::Relaxed);
+        let now = now();
         match in_read.entry(now) {
             Entry::Vacant(v) => {
                 v.insert(1);
@@ -84,7 +371,7 @@ where
         now
     }
 
-    fn read_commit(&self, ts: TimeStamp) {
+    fn commit(&self, ts: TimeStamp) {
         match self.in_read.lock().unwrap().entry(ts) {
             Entry::Vacant(_) => panic!("commit non-existing read"),
             Entry::Occupied(mut o) => match o.get_mut() {
@@ -98,27 +385,257 @@ where
         }
     }
 
-    fn start_write(&self) -> TimeStamp {
-        self.now.fetch_add(1, Ordering::Relaxed) + 1
+    fn oldest(&self, now: impl FnOnce() -> TimeStamp) -> TimeStamp {
+        // reads start under the lock, so none can start below the time loaded here
+        let in_read = self.in_read.lock().unwrap();
+        in_read
+            .first_key_value()
+            .map(|(ts, _)| *ts)
+            .unwrap_or_else(now)
     }
 
-    fn write_commit(
+    fn active(&self) -> Vec<(TimeStamp, usize)> {
+        self.in_read
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ts, count)| (*ts, *count))
+            .collect()
+    }
+}
+
+/// Keeps the write sets of recent commits in memory and drops them once no running transaction
+/// read before them.
+#[derive(Debug)]
+pub struct LocalConflictChecker<K>
+where
+    K: Ord,
+{
+    committed_txns: Mutex<BTreeMap<u64, WriteSet<K>>>,
+    max_committed_txns: usize,
+    conflict_keys: ConflictKeys,
+    hasher: RandomState,
+}
+
+/// How [`LocalConflictChecker`] tells the keys of write sets apart.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictKeys {
+    /// Compares the keys themselves.
+    #[default]
+    Exact,
+    /// Compares 64-bit fingerprints of the keys and keeps no keys, so a fingerprint collision is
+    /// reported as a conflict.
+    Fingerprint,
+    /// Compares fingerprints, and the keys only when those match.
+    StrictFingerprint,
+}
+
+#[derive(Debug)]
+struct WriteSet<K> {
+    fingerprints: HashSet<u64>,
+    keys: Option<HashSet<K>>,
+}
+
+impl<K> WriteSet<K>
+where
+    K: Hash + Eq,
+{
+    fn contains(&self, fingerprint: u64, key: &K) -> bool {
+        match &self.keys {
+            Some(keys) if self.fingerprints.is_empty() => keys.contains(key),
+            Some(keys) => self.fingerprints.contains(&fingerprint) && keys.contains(key),
+            None => self.fingerprints.contains(&fingerprint),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.keys
+            .as_ref()
+            .map_or(self.fingerprints.len(), HashSet::len)
+    }
+}
+
+impl<K> Default for LocalConflictChecker<K>
+where
+    K: Ord,
+{
+    fn default() -> Self {
+        Self {
+            committed_txns: Default::default(),
+            max_committed_txns: DEFAULT_MAX_COMMITTED_TXNS,
+            conflict_keys: ConflictKeys::default(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<K> LocalConflictChecker<K>
+where
+    K: Ord,
+{
+    /// Caps the write sets kept for conflict checks, commits fail once that many are still
+    /// needed by running transactions.
+    pub fn with_max_committed_txns(mut self, max_committed_txns: usize) -> Self {
+        self.max_committed_txns = max_committed_txns;
+        self
+    }
+
+    /// Trades exact conflict detection for cheaper commits of large write sets, see
+    /// [`ConflictKeys`].
+    pub fn with_conflict_keys(mut self, conflict_keys: ConflictKeys) -> Self {
+        self.conflict_keys = conflict_keys;
+        self
+    }
+}
+
+impl<K> ConflictChecker<K> for LocalConflictChecker<K>
+where
+    K: Ord + Hash + Clone,
+{
+    fn check_commit(
         &self,
         read_at: TimeStamp,
         write_at: TimeStamp,
         in_write: HashSet<K>,
-    ) -> Result<(), WriteConflict<K>> {
+        oldest_read: TimeStamp,
+    ) -> Result<(), WriteCommitError<K>> {
         let mut committed_txns = self.committed_txns.lock().unwrap();
+        *committed_txns = committed_txns.split_off(&(oldest_read + 1));
+        if committed_txns.len() >= self.max_committed_txns {
+            return Err(WriteCommitError::WindowFull {
+                limit: self.max_committed_txns,
+            });
+        }
+
+        let fingerprints: Vec<_> = match self.conflict_keys {
+            ConflictKeys::Exact => in_write.iter().map(|key| (0, key)).collect(),
+            ConflictKeys::Fingerprint | ConflictKeys::StrictFingerprint => in_write
+                .iter()
+                .map(|key| (self.hasher.hash_one(key), key))
+                .collect(),
+        };
         let conflicts: Vec<_> = committed_txns
             .range((Bound::Excluded(read_at), Bound::Excluded(write_at)))
-            .flat_map(|(_, txn)| txn.intersection(&in_write))
-            .cloned()
+            .flat_map(|(_, txn)| {
+                fingerprints
+                    .iter()
+                    .filter(|(fingerprint, key)| txn.contains(*fingerprint, key))
+            })
+            .map(|(_, key)| (*key).clone())
             .collect();
 
         if !conflicts.is_empty() {
-            return Err(WriteConflict { keys: conflicts });
+            return Err(WriteConflict { keys: conflicts }.into());
         }
-        committed_txns.insert(write_at, in_write);
+        let write_set = match self.conflict_keys {
+            ConflictKeys::Exact => WriteSet {
+                fingerprints: HashSet::new(),
+                keys: Some(in_write),
+            },
+            ConflictKeys::Fingerprint => WriteSet {
+                fingerprints: fingerprints
+                    .iter()
+                    .map(|(fingerprint, _)| *fingerprint)
+                    .collect(),
+                keys: None,
+            },
+            ConflictKeys::StrictFingerprint => WriteSet {
+                fingerprints: fingerprints
+                    .iter()
+                    .map(|(fingerprint, _)| *fingerprint)
+                    .collect(),
+                keys: Some(in_write),
+            },
+        };
+        committed_txns.insert(write_at, write_set);
         Ok(())
     }
+
+    fn describe(&self, state: &mut OracleState) {
+        let committed_txns = self.committed_txns.lock().unwrap();
+
+        state.committed_txns = committed_txns.len();
+        state.committed_keys = committed_txns.values().map(WriteSet::len).sum();
+        state.conflict_window = committed_txns
+            .first_key_value()
+            .zip(committed_txns.last_key_value())
+            .map(|((oldest, _), (newest, _))| (*oldest, *newest));
+    }
+}
+
+/// Lets every commit through, the last write of a key wins.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoConflictCheck;
+
+impl<K> ConflictChecker<K> for NoConflictCheck {
+    fn check_commit(
+        &self,
+        _: TimeStamp,
+        _: TimeStamp,
+        _: HashSet<K>,
+        _: TimeStamp,
+    ) -> Result<(), WriteCommitError<K>> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, thread};
+
+    use super::{
+        ConflictChecker, ConflictKeys, LeasedClock, LocalConflictChecker, TimestampProvider,
+        WriteCommitError,
+    };
+
+    #[test]
+    fn conflict_keys() {
+        for conflict_keys in [
+            ConflictKeys::Exact,
+            ConflictKeys::Fingerprint,
+            ConflictKeys::StrictFingerprint,
+        ] {
+            let checker = LocalConflictChecker::default().with_conflict_keys(conflict_keys);
+            let keys = |keys: &[&str]| -> HashSet<String> {
+                keys.iter().map(|key| key.to_string()).collect()
+            };
+
+            checker.check_commit(0, 1, keys(&["a", "b"]), 0).unwrap();
+            checker.check_commit(0, 2, keys(&["c"]), 0).unwrap();
+            match checker.check_commit(0, 3, keys(&["b", "d"]), 0) {
+                Err(WriteCommitError::Conflict(conflict)) => {
+                    assert_eq!(conflict.to_keys(), vec!["b".to_string()])
+                }
+                other => panic!("{conflict_keys:?}: {other:?}"),
+            }
+            checker.check_commit(1, 3, keys(&["b", "d"]), 0).unwrap();
+        }
+    }
+
+    #[test]
+    fn leased_writes_follow_reads() {
+        let clock = LeasedClock::new(4);
+
+        let write = clock.start_write();
+        let other = thread::scope(|scope| scope.spawn(|| clock.start_write()).join().unwrap());
+        assert_ne!(write, other);
+        assert_eq!(clock.now(), write.max(other));
+
+        let read = clock.start_read();
+        assert_eq!(read, clock.now());
+        assert!(clock.start_write() > read);
+        clock.read_commit(read);
+
+        clock.advance(100);
+        assert_eq!(clock.start_write(), 101);
+        let mut written = (0..64).map(|_| clock.start_write()).collect::<HashSet<_>>();
+        written.extend(thread::scope(|scope| {
+            scope
+                .spawn(|| (0..64).map(|_| clock.start_write()).collect::<Vec<_>>())
+                .join()
+                .unwrap()
+        }));
+        assert_eq!(written.len(), 128);
+        assert!(written.iter().all(|ts| *ts > 101));
+    }
 }
@@ -0,0 +1,103 @@
+use std::sync::Mutex;
+
+use async_lock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use futures::channel::oneshot;
+
+use crate::WritePriority;
+
+/// Orders writers in front of the wal. The lock prefers writers, so once a foreground write is
+/// waiting no new bulk write gets in, and it only waits for the bulk writes already inside.
+#[derive(Debug, Default)]
+pub(crate) struct PriorityGate {
+    gate: RwLock<()>,
+}
+
+#[allow(dead_code)]
+pub(crate) enum GateGuard<'a> {
+    Foreground(RwLockWriteGuard<'a, ()>),
+    Bulk(RwLockReadGuard<'a, ()>),
+}
+
+impl PriorityGate {
+    pub(crate) async fn enter(&self, priority: WritePriority) -> GateGuard<'_> {
+        match priority {
+            WritePriority::Foreground => GateGuard::Foreground(self.gate.write().await),
+            WritePriority::Bulk => GateGuard::Bulk(self.gate.read().await),
+        }
+    }
+}
+
+/// Orders table reads: background reads wait until no foreground read is opening a table.
+#[derive(Debug, Default)]
+pub(crate) struct ReadGate {
+    state: Mutex<ReadState>,
+}
+
+#[derive(Debug, Default)]
+struct ReadState {
+    foreground: usize,
+    waiters: Vec<oneshot::Sender<()>>,
+}
+
+pub(crate) struct ForegroundRead<'a> {
+    gate: &'a ReadGate,
+}
+
+impl ReadGate {
+    pub(crate) fn foreground(&self) -> ForegroundRead<'_> {
+        self.state.lock().unwrap().foreground += 1;
+        ForegroundRead { gate: self }
+    }
+
+    pub(crate) async fn background(&self) {
+        loop {
+            let idle = {
+                let mut state = self.state.lock().unwrap();
+                if state.foreground == 0 {
+                    return;
+                }
+                let (tx, rx) = oneshot::channel();
+                state.waiters.push(tx);
+                rx
+            };
+            let _ = idle.await;
+        }
+    }
+}
+
+impl Drop for ForegroundRead<'_> {
+    fn drop(&mut self) {
+        let mut state = self.gate.state.lock().unwrap();
+        state.foreground -= 1;
+        if state.foreground == 0 {
+            for waiter in state.waiters.drain(..) {
+                let _ = waiter.send(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, pin_mut, FutureExt};
+
+    use super::ReadGate;
+
+    #[test]
+    fn background_reads_wait() {
+        block_on(async {
+            let gate = ReadGate::default();
+            gate.background().await;
+
+            let foreground = gate.foreground();
+            let background = gate.background();
+            pin_mut!(background);
+            assert!((&mut background).now_or_never().is_none());
+            let other = gate.foreground();
+            drop(foreground);
+            assert!((&mut background).now_or_never().is_none());
+            drop(other);
+            background.await;
+        });
+    }
+}
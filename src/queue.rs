@@ -0,0 +1,258 @@
+//! FIFO queues kept in a db: items are appended under increasing keys and consumed from the head,
+//! which is trimmed as they are done with. The head and tail of each queue are tracked in the
+//! system keyspace, so scans start past the deletions of trimmed items rather than over them, and
+//! [`crate::DbOption::deletion_compaction`] drops those deletions once scans run into them.
+
+use std::{io, ops::Bound};
+
+use futures::{AsyncWrite, StreamExt};
+use thiserror::Error;
+
+use crate::{
+    comparator::Comparator,
+    oracle::Oracle,
+    record::Record,
+    schema::Schema,
+    serdes::{Decode, Encode},
+    stream::ScanError,
+    system::QUEUE_PREFIX,
+    transaction::CommitError,
+    wal::{provider::StorageProvider, WriteError},
+    Db, ScanOptions,
+};
+
+/// Ends the queue in a key made by [`queue_key`].
+const SEPARATOR: char = '\0';
+
+/// A key for item `seq` of `queue` in dbs keyed by strings, in fixed width hex so that the items
+/// of a queue sort in order. `queue` must not contain `'\0'`.
+pub fn queue_key(queue: &str, seq: u64) -> String {
+    debug_assert!(!queue.contains(SEPARATOR));
+    format!("{}{}{:016x}", queue, SEPARATOR, seq)
+}
+
+#[derive(Debug, Error)]
+pub enum QueueError<S>
+where
+    S: Schema,
+{
+    #[error("queue system table error: {0}")]
+    System(#[source] io::Error),
+    #[error("queue append error: {0}")]
+    Append(#[source] WriteError<<Record<S::PrimaryKey, S> as Encode>::Error>),
+    #[error("queue consume error: {0}")]
+    Consume(#[source] ScanError<S::PrimaryKey, S>),
+    #[error("queue trim error: {0:?}")]
+    Trim(CommitError<S::PrimaryKey>),
+}
+
+/// Where a queue stands: items `head..tail` are in it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueWatermarks {
+    /// the first item not trimmed
+    pub head: u64,
+    /// the next item appended
+    pub tail: u64,
+}
+
+impl QueueWatermarks {
+    pub fn len(&self) -> u64 {
+        self.tail - self.head
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+}
+
+/// A queue in a db, see [`Db::queue`]. Item `seq` is stored under `key(seq)`, which must sort in
+/// the order of `seq` by the schema's comparator and not collide with other rows or queues, e.g.
+/// [`queue_key`].
+pub struct Queue<'a, S, O, WP, F>
+where
+    S: Schema,
+{
+    db: &'a Db<S, O, WP>,
+    name: String,
+    key: F,
+}
+
+impl<S, O, WP> Db<S, O, WP>
+where
+    S: Schema,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+    WP::File: AsyncWrite,
+    io::Error: From<<S as Decode>::Error>,
+{
+    /// The queue `name`, with its items stored under `key(seq)`.
+    pub fn queue<F>(&self, name: impl Into<String>, key: F) -> Queue<'_, S, O, WP, F>
+    where
+        F: Fn(u64) -> S::PrimaryKey,
+    {
+        Queue {
+            db: self,
+            name: name.into(),
+            key,
+        }
+    }
+}
+
+impl<S, O, WP, F> Queue<'_, S, O, WP, F>
+where
+    S: Schema,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+    WP::File: AsyncWrite,
+    io::Error: From<<S as Decode>::Error>,
+    F: Fn(u64) -> S::PrimaryKey,
+{
+    fn system_key(&self, watermark: &str) -> String {
+        format!("{}{}/{}", QUEUE_PREFIX, self.name, watermark)
+    }
+
+    async fn watermark(&self, watermark: &str) -> u64 {
+        self.db
+            .system
+            .get(&self.system_key(watermark))
+            .await
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u64::from_le_bytes)
+            .unwrap_or(0)
+    }
+
+    pub async fn watermarks(&self) -> QueueWatermarks {
+        QueueWatermarks {
+            head: self.watermark("head").await,
+            tail: self.watermark("tail").await,
+        }
+    }
+
+    /// Appends the value `value` makes of the key of the next item, and returns its sequence.
+    /// Appends of a db are serialized, so that items become visible in order and a failed append
+    /// leaves no gap behind.
+    pub async fn append(
+        &self,
+        value: impl FnOnce(S::PrimaryKey) -> S,
+    ) -> Result<u64, QueueError<S>> {
+        let _appending = self.db.queue_appends.lock().await;
+        let seq = self.watermark("tail").await;
+        let value = value((self.key)(seq));
+        debug_assert!(value.primary_key() == (self.key)(seq));
+
+        self.db.put(value).await.map_err(QueueError::Append)?;
+        self.db
+            .system
+            .set(self.system_key("tail"), (seq + 1).to_le_bytes().to_vec())
+            .await
+            .map_err(QueueError::System)?;
+
+        Ok(seq)
+    }
+
+    /// Up to `max` items from the head, oldest first, without removing them, see
+    /// [`Queue::trim`].
+    pub async fn consume(&self, max: usize) -> Result<Vec<(u64, S)>, QueueError<S>> {
+        let QueueWatermarks { head, tail } = self.watermarks().await;
+        if head == tail || max == 0 {
+            return Ok(Vec::new());
+        }
+        let (lower, upper) = ((self.key)(head), (self.key)(tail - 1));
+        let mut stream = self
+            .db
+            .range_with_options(
+                Bound::Included(&lower),
+                Bound::Included(&upper),
+                &self.db.latest_sequence(),
+                &ScanOptions::default().limit(max),
+            )
+            .await
+            .map_err(QueueError::Consume)?;
+
+        let mut items = Vec::new();
+        let mut seq = head;
+        while let Some(item) = stream.next().await {
+            let (key, value) = item.map_err(QueueError::Consume)?;
+            let Some(value) = value else {
+                continue;
+            };
+            // trimmed items are deleted, so the first live one past the head is the oldest
+            while S::Comparator::compare(&(self.key)(seq), &key).is_lt() {
+                seq += 1;
+            }
+            items.push((seq, value));
+        }
+        Ok(items)
+    }
+
+    /// Deletes the items up to `through` and moves the head past them, so that the next
+    /// [`Queue::consume`] starts after them.
+    pub async fn trim(&self, through: u64) -> Result<(), QueueError<S>> {
+        let QueueWatermarks { head, tail } = self.watermarks().await;
+        let through = through.min(tail.saturating_sub(1));
+        if tail == 0 || through < head {
+            return Ok(());
+        }
+        let mut txn = self.db.txn();
+        for seq in head..=through {
+            txn.remove((self.key)(seq));
+        }
+        txn.commit().await.map_err(QueueError::Trim)?;
+
+        // a failure leaves the head where it was, trimming again deletes the items again
+        self.db
+            .system
+            .update(&self.system_key("head"), |current| {
+                let current = current
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .map(u64::from_le_bytes)
+                    .unwrap_or(0);
+                (current <= through).then(|| (through + 1).to_le_bytes().to_vec())
+            })
+            .await
+            .map_err(QueueError::System)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{queue_key, QueueWatermarks};
+    use crate::tests::{user, with_db};
+
+    #[test]
+    fn append_consume_trim() {
+        with_db(|db| async move {
+            let jobs = db.queue("jobs", |seq| 1000 + seq);
+            let other = db.queue("other", |seq| 2000 + seq);
+
+            for _ in 0..5 {
+                jobs.append(user).await.unwrap();
+            }
+            assert_eq!(other.append(user).await.unwrap(), 0);
+            assert_eq!(
+                jobs.watermarks().await,
+                QueueWatermarks { head: 0, tail: 5 }
+            );
+
+            let items = jobs.consume(2).await.unwrap();
+            assert_eq!(items, vec![(0, user(1000)), (1, user(1001))]);
+            jobs.trim(1).await.unwrap();
+            let items = jobs.consume(10).await.unwrap();
+            assert_eq!(
+                items.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(),
+                vec![2, 3, 4]
+            );
+
+            jobs.trim(10).await.unwrap();
+            assert!(jobs.watermarks().await.is_empty());
+            assert!(jobs.consume(10).await.unwrap().is_empty());
+            assert_eq!(jobs.append(user).await.unwrap(), 5);
+            assert_eq!(jobs.consume(10).await.unwrap(), vec![(5, user(1005))]);
+            assert_eq!(other.consume(10).await.unwrap(), vec![(0, user(2000))]);
+        });
+
+        assert!(queue_key("jobs", 9) < queue_key("jobs", 10));
+        assert!(queue_key("jobs", u64::MAX) < queue_key("jobs/a", 0));
+    }
+}
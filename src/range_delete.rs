@@ -0,0 +1,194 @@
+//! Tombstones of key ranges removed by [`crate::transaction::Transaction::remove_range`]. They are
+//! logged to the wal along with the rows of the commit, then kept in the system keyspace rather
+//! than written per key, and every source of a read masks the versions they cover as deletions,
+//! see [`RangeDeletes::mask`].
+
+use std::{
+    io,
+    marker::PhantomData,
+    ops::Bound,
+    sync::{Arc, RwLock},
+};
+
+use futures::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    comparator::Comparator,
+    oracle::TimeStamp,
+    record::RangeDelete,
+    schema::Schema,
+    serdes::{Decode, Encode},
+    stream::ScanFilter,
+    system::{SystemTable, RANGE_DELETE_PREFIX},
+};
+
+impl<K> RangeDelete<K> {
+    /// Whether the version of `key` written at `ts` was removed, versions written since, and the
+    /// ones the removing commit wrote itself, are not.
+    fn covers<C: Comparator<K>>(&self, key: &K, ts: TimeStamp) -> bool {
+        ts < self.ts && self.contains::<C>(key)
+    }
+
+    fn contains<C: Comparator<K>>(&self, key: &K) -> bool {
+        C::contains(self.lower.as_ref(), self.upper.as_ref(), key)
+    }
+}
+
+/// The tombstones of a db, whose ranges are in the order of `C`.
+#[derive(Debug)]
+pub(crate) struct RangeDeletes<K, C> {
+    tombstones: RwLock<Arc<Vec<RangeDelete<K>>>>,
+    _c: PhantomData<C>,
+}
+
+impl<K, C> RangeDeletes<K, C>
+where
+    K: Clone + Encode + Decode + Send + Sync + 'static,
+    C: Comparator<K>,
+{
+    pub(crate) async fn load(system: &SystemTable) -> io::Result<Self> {
+        let mut tombstones = Vec::new();
+        for (key, value) in system.scan_prefix(RANGE_DELETE_PREFIX).await {
+            let ts = TimeStamp::from_str_radix(&key[RANGE_DELETE_PREFIX.len()..], 16)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let mut reader = value.as_slice();
+            while !reader.is_empty() {
+                tombstones.push(RangeDelete {
+                    lower: decode_bound(&mut reader).await?,
+                    upper: decode_bound(&mut reader).await?,
+                    ts,
+                });
+            }
+        }
+        Ok(RangeDeletes {
+            tombstones: RwLock::new(Arc::new(tombstones)),
+            _c: PhantomData,
+        })
+    }
+
+    /// Masks the ranges one logged commit removed from the reads at its timestamp or later, then
+    /// persists their tombstones under one system key. A tombstone failed to persist is still
+    /// recovered from the wal.
+    pub(crate) async fn insert(
+        &self,
+        system: &SystemTable,
+        ranges: Vec<RangeDelete<K>>,
+    ) -> io::Result<()> {
+        let Some(ts) = ranges.first().map(|range| range.ts) else {
+            return Ok(());
+        };
+        {
+            let mut tombstones = self.tombstones.write().unwrap();
+            let mut updated = Vec::clone(&tombstones);
+            updated.extend(ranges.iter().cloned());
+            *tombstones = Arc::new(updated);
+        }
+        let mut value = Vec::new();
+        for range in ranges.iter() {
+            encode_bound(&mut value, &range.lower).await?;
+            encode_bound(&mut value, &range.upper).await?;
+        }
+        system
+            .set(format!("{}{:016x}", RANGE_DELETE_PREFIX, ts), value)
+            .await
+    }
+
+    /// Inserts the ranges of a commit recovered from the wal, unless they were persisted before.
+    pub(crate) async fn recover(
+        &self,
+        system: &SystemTable,
+        ranges: Vec<RangeDelete<K>>,
+    ) -> io::Result<()> {
+        let persisted = ranges.first().is_some_and(|range| {
+            self.tombstones
+                .read()
+                .unwrap()
+                .iter()
+                .any(|tombstone| tombstone.ts == range.ts)
+        });
+        if persisted {
+            return Ok(());
+        }
+        self.insert(system, ranges).await
+    }
+
+    /// The tombstones reads at `read_at` see, `None` if there are none.
+    fn visible(&self, read_at: TimeStamp) -> Option<Vec<RangeDelete<K>>> {
+        let tombstones = self.tombstones.read().unwrap().clone();
+        let visible = tombstones
+            .iter()
+            .filter(|tombstone| tombstone.ts <= read_at)
+            .cloned()
+            .collect::<Vec<_>>();
+        (!visible.is_empty()).then_some(visible)
+    }
+
+    /// Whether a tombstone reads at `read_at` see may cover some version of `key`, in which case
+    /// a point lookup has to merge its versions rather than stop at the newest one it finds.
+    pub(crate) fn may_cover(&self, key: &K, read_at: TimeStamp) -> bool {
+        self.tombstones
+            .read()
+            .unwrap()
+            .iter()
+            .any(|tombstone| tombstone.ts <= read_at && tombstone.contains::<C>(key))
+    }
+
+    /// `filter` rejecting, in addition, the versions removed by the tombstones reads at `read_at`
+    /// see.
+    pub(crate) fn mask<S>(
+        &self,
+        filter: Option<&ScanFilter<S>>,
+        read_at: TimeStamp,
+    ) -> Option<ScanFilter<S>>
+    where
+        S: Schema<PrimaryKey = K, Comparator = C>,
+    {
+        let Some(tombstones) = self.visible(read_at) else {
+            return filter.cloned();
+        };
+        let filter = filter.cloned();
+        let masked: ScanFilter<S> = Arc::new(move |key: &K, ts, value: Option<&S>| {
+            !tombstones
+                .iter()
+                .any(|tombstone| tombstone.covers::<C>(key, ts))
+                && filter.as_ref().is_none_or(|filter| filter(key, ts, value))
+        });
+        Some(masked)
+    }
+}
+
+async fn encode_bound<K>(writer: &mut Vec<u8>, bound: &Bound<K>) -> io::Result<()>
+where
+    K: Encode,
+{
+    let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+    match bound {
+        Bound::Unbounded => writer.write_all(&[0]).await,
+        Bound::Included(key) => {
+            writer.write_all(&[1]).await?;
+            key.encode(writer).await.map_err(invalid)
+        }
+        Bound::Excluded(key) => {
+            writer.write_all(&[2]).await?;
+            key.encode(writer).await.map_err(invalid)
+        }
+    }
+}
+
+async fn decode_bound<K>(reader: &mut &[u8]) -> io::Result<Bound<K>>
+where
+    K: Decode,
+{
+    let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+    let mut tag = [0];
+    reader.read_exact(&mut tag).await?;
+    match tag[0] {
+        0 => Ok(Bound::Unbounded),
+        1 => Ok(Bound::Included(K::decode(reader).await.map_err(invalid)?)),
+        2 => Ok(Bound::Excluded(K::decode(reader).await.map_err(invalid)?)),
+        tag => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid range bound {}", tag),
+        )),
+    }
+}
@@ -0,0 +1,185 @@
+use std::{marker::PhantomData, sync::Mutex};
+
+use futures::channel::oneshot;
+
+use crate::comparator::Comparator;
+
+/// Key ranges locked by admin operations, e.g. bulk imports, which hold back the writes into them
+/// while the rest of the keyspace stays writable. Ranges are inclusive, in the order of `C`.
+#[derive(Debug)]
+pub(crate) struct RangeLocks<K, C> {
+    state: Mutex<State<K>>,
+    _c: PhantomData<C>,
+}
+
+#[derive(Debug)]
+struct State<K> {
+    next_id: u64,
+    /// locked ranges, including those still waiting for the writes into them to drain
+    ranges: Vec<(u64, K, K)>,
+    /// the key span of every write in flight
+    writes: Vec<(u64, K, K)>,
+    waiters: Vec<oneshot::Sender<()>>,
+}
+
+pub(crate) struct RangeGuard<'a, K, C> {
+    locks: &'a RangeLocks<K, C>,
+    id: u64,
+}
+
+pub(crate) struct WriteGuard<'a, K, C> {
+    locks: &'a RangeLocks<K, C>,
+    id: u64,
+}
+
+impl<K, C> Default for RangeLocks<K, C> {
+    fn default() -> Self {
+        RangeLocks {
+            state: Mutex::new(State {
+                next_id: 0,
+                ranges: Vec::new(),
+                writes: Vec::new(),
+                waiters: Vec::new(),
+            }),
+            _c: PhantomData,
+        }
+    }
+}
+
+impl<K, C> RangeLocks<K, C>
+where
+    K: Clone,
+    C: Comparator<K>,
+{
+    /// Waits for the locked ranges overlapping `min..=max` to be released, then for the writes
+    /// into it to complete. Writes into it wait for the guard from the moment it is taken.
+    pub(crate) async fn lock(&self, min: &K, max: &K) -> RangeGuard<'_, K, C> {
+        let guard = loop {
+            let released = {
+                let mut state = self.state.lock().unwrap();
+                if !state
+                    .ranges
+                    .iter()
+                    .any(|(_, lower, upper)| overlaps::<K, C>(lower, upper, min, max))
+                {
+                    let id = state.next();
+                    state.ranges.push((id, min.clone(), max.clone()));
+                    break RangeGuard { locks: self, id };
+                }
+                state.wait()
+            };
+            let _ = released.await;
+        };
+        loop {
+            let completed = {
+                let mut state = self.state.lock().unwrap();
+                if !state
+                    .writes
+                    .iter()
+                    .any(|(_, lower, upper)| overlaps::<K, C>(lower, upper, min, max))
+                {
+                    return guard;
+                }
+                state.wait()
+            };
+            let _ = completed.await;
+        }
+    }
+
+    /// Waits for the locked ranges overlapping the keys of a write, `min..=max`, to be released.
+    pub(crate) async fn write(&self, min: &K, max: &K) -> WriteGuard<'_, K, C> {
+        loop {
+            let released = {
+                let mut state = self.state.lock().unwrap();
+                if !state
+                    .ranges
+                    .iter()
+                    .any(|(_, lower, upper)| overlaps::<K, C>(lower, upper, min, max))
+                {
+                    let id = state.next();
+                    state.writes.push((id, min.clone(), max.clone()));
+                    return WriteGuard { locks: self, id };
+                }
+                state.wait()
+            };
+            let _ = released.await;
+        }
+    }
+}
+
+impl<K> State<K> {
+    fn next(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn wait(&mut self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        self.waiters.push(tx);
+        rx
+    }
+
+    fn wake(&mut self) {
+        for waiter in self.waiters.drain(..) {
+            let _ = waiter.send(());
+        }
+    }
+}
+
+fn overlaps<K, C: Comparator<K>>(lower: &K, upper: &K, min: &K, max: &K) -> bool {
+    C::compare(lower, max).is_le() && C::compare(min, upper).is_le()
+}
+
+impl<K, C> Drop for RangeGuard<'_, K, C> {
+    fn drop(&mut self) {
+        let mut state = self.locks.state.lock().unwrap();
+        state.ranges.retain(|(id, _, _)| *id != self.id);
+        state.wake();
+    }
+}
+
+impl<K, C> Drop for WriteGuard<'_, K, C> {
+    fn drop(&mut self) {
+        let mut state = self.locks.state.lock().unwrap();
+        state.writes.retain(|(id, _, _)| *id != self.id);
+        state.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, pin_mut, FutureExt};
+
+    use super::RangeLocks;
+    use crate::comparator::OrdComparator;
+
+    #[test]
+    fn writes_wait_for_ranges() {
+        block_on(async {
+            let locks = RangeLocks::<u64, OrdComparator>::default();
+
+            let range = locks.lock(&10, &20).await;
+            drop(locks.write(&0, &5).await);
+            let write = locks.write(&5, &15);
+            pin_mut!(write);
+            assert!((&mut write).now_or_never().is_none());
+            let other = locks.lock(&20, &30);
+            pin_mut!(other);
+            assert!((&mut other).now_or_never().is_none());
+            drop(range);
+            let write = write.await;
+
+            let range = locks.lock(&10, &12);
+            pin_mut!(range);
+            assert!((&mut range).now_or_never().is_none());
+            let blocked = locks.write(&11, &11);
+            pin_mut!(blocked);
+            assert!((&mut blocked).now_or_never().is_none());
+            drop(write);
+            let range = range.await;
+            drop(other.await);
+            drop(range);
+            blocked.await;
+        });
+    }
+}
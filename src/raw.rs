@@ -0,0 +1,387 @@
+//! Byte keys and values, ordered by their bytes, for embedders that do not model their rows in
+//! rust. [`RawDb`] is a facade over a typed [`Db`] of [`Entry`] rows, it shares the wal, mem
+//! tables and arrow tables of every other schema rather than being a layer below them yet, see
+//! ethe/elsm#synth-1179~2 in the backlog.
+
+use std::{io, mem::size_of, ops::Bound, pin::pin, sync::Arc};
+
+use arrow::{
+    array::{
+        AsArray, Int8Builder, LargeBinaryArray, LargeBinaryBuilder, RecordBatch, StructBuilder,
+        UInt64Builder,
+    },
+    datatypes::{DataType, Field, Fields, LargeBinaryType, Schema as ArrowSchema, SchemaRef},
+};
+use bytes::Bytes;
+use executor::futures::StreamExt;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use once_cell::sync::Lazy;
+
+use crate::{
+    comparator::OrdComparator,
+    oracle::{Oracle, TimeStamp, TimestampProvider},
+    record::Record,
+    schema::{Builder, Op, Schema, OP_COLUMN_NAME, TS_COLUMN_NAME},
+    serdes::{Decode, Encode},
+    stream::ScanError,
+    transaction::Transaction,
+    wal::{provider::StorageProvider, WriteError},
+    Db, DbOption,
+};
+
+static ENTRY_INNER_FIELDS: Lazy<Fields> =
+    Lazy::new(|| Fields::from(vec![Field::new("value", DataType::LargeBinary, false)]));
+static ENTRY_SCHEMA: Lazy<SchemaRef> = Lazy::new(|| {
+    Arc::new(ArrowSchema::new(vec![
+        Field::new("key", DataType::LargeBinary, false),
+        Field::new("value", DataType::LargeBinary, false),
+    ]))
+});
+static ENTRY_INNER_SCHEMA: Lazy<SchemaRef> = Lazy::new(|| {
+    Arc::new(ArrowSchema::new(vec![
+        Field::new("key", DataType::LargeBinary, false),
+        Field::new("inner", DataType::Struct(ENTRY_INNER_FIELDS.clone()), true),
+        Field::new(TS_COLUMN_NAME, DataType::UInt64, false),
+        Field::new(OP_COLUMN_NAME, DataType::Int8, false),
+    ]))
+});
+
+async fn encode_bytes<W: AsyncWrite + Unpin + Send>(
+    bytes: &[u8],
+    writer: &mut W,
+) -> io::Result<()> {
+    writer
+        .write_all(&(bytes.len() as u32).to_le_bytes())
+        .await?;
+    writer.write_all(bytes).await
+}
+
+async fn decode_bytes<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = {
+        let mut len = [0; size_of::<u32>()];
+        reader.read_exact(&mut len).await?;
+        u32::from_le_bytes(len) as usize
+    };
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes).await?;
+
+    Ok(bytes)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Key(pub Bytes);
+
+impl Encode for Key {
+    type Error = io::Error;
+
+    async fn encode<W: AsyncWrite + Unpin + Send + Sync>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), Self::Error> {
+        encode_bytes(&self.0, writer).await
+    }
+
+    fn size(&self) -> usize {
+        size_of::<u32>() + self.0.len()
+    }
+}
+
+impl Decode for Key {
+    type Error = io::Error;
+
+    async fn decode<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, Self::Error> {
+        Ok(Key(decode_bytes(reader).await?.into()))
+    }
+}
+
+/// Schema of opaque byte keys and values, for embedders that do not model their rows in rust.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub key: Key,
+    pub value: Bytes,
+}
+
+impl Encode for Entry {
+    type Error = io::Error;
+
+    async fn encode<W: AsyncWrite + Unpin + Send + Sync>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), Self::Error> {
+        self.key.encode(writer).await?;
+        encode_bytes(&self.value, writer).await
+    }
+
+    fn size(&self) -> usize {
+        self.key.size() + size_of::<u32>() + self.value.len()
+    }
+}
+
+impl Decode for Entry {
+    type Error = io::Error;
+
+    async fn decode<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, Self::Error> {
+        let key = Key::decode(reader).await?;
+        let value = decode_bytes(reader).await?.into();
+
+        Ok(Entry { key, value })
+    }
+}
+
+impl Schema for Entry {
+    type PrimaryKey = Key;
+    type Builder = EntryBuilder;
+    type PrimaryKeyArray = LargeBinaryArray;
+    type Comparator = OrdComparator;
+
+    fn arrow_schema() -> SchemaRef {
+        ENTRY_SCHEMA.clone()
+    }
+
+    fn inner_schema() -> SchemaRef {
+        ENTRY_INNER_SCHEMA.clone()
+    }
+
+    fn primary_key(&self) -> Self::PrimaryKey {
+        self.key.clone()
+    }
+
+    fn builder() -> Self::Builder {
+        EntryBuilder {
+            key: LargeBinaryBuilder::new(),
+            ts: UInt64Builder::new(),
+            op: Int8Builder::new(),
+            inner: StructBuilder::new(
+                ENTRY_INNER_FIELDS.clone(),
+                vec![Box::new(LargeBinaryBuilder::new())],
+            ),
+        }
+    }
+
+    fn from_batch(batch: &RecordBatch, offset: usize) -> (Self::PrimaryKey, Option<Self>) {
+        let key = Self::primary_key_from_batch(batch, offset);
+        if Op::from_batch(batch, offset).is_tombstone() {
+            return (key, None);
+        }
+        let inner = batch.column(1).as_struct();
+        let value =
+            Bytes::copy_from_slice(inner.column(0).as_bytes::<LargeBinaryType>().value(offset));
+
+        (key.clone(), Some(Entry { key, value }))
+    }
+
+    fn primary_key_from_batch(batch: &RecordBatch, offset: usize) -> Self::PrimaryKey {
+        Key(Bytes::copy_from_slice(
+            batch.column(0).as_bytes::<LargeBinaryType>().value(offset),
+        ))
+    }
+
+    fn to_primary_key_array(keys: Vec<Self::PrimaryKey>) -> Self::PrimaryKeyArray {
+        LargeBinaryArray::from_iter_values(keys.iter().map(|key| key.0.as_ref()))
+    }
+}
+
+pub struct EntryBuilder {
+    key: LargeBinaryBuilder,
+    ts: UInt64Builder,
+    op: Int8Builder,
+    inner: StructBuilder,
+}
+
+impl Builder<Entry> for EntryBuilder {
+    fn add(&mut self, primary_key: &Key, ts: TimeStamp, op: Op, schema: Option<Entry>) {
+        self.key.append_value(&primary_key.0);
+        self.ts.append_value(ts);
+        self.op.append_value(op as i8);
+
+        let value = self.inner.field_builder::<LargeBinaryBuilder>(0).unwrap();
+        match schema {
+            Some(entry) => {
+                value.append_value(&entry.value);
+                self.inner.append(true);
+            }
+            None => {
+                value.append_null();
+                self.inner.append_null();
+            }
+        }
+    }
+
+    fn finish(&mut self) -> RecordBatch {
+        RecordBatch::try_new(
+            Entry::inner_schema(),
+            vec![
+                Arc::new(self.key.finish()),
+                Arc::new(self.inner.finish()),
+                Arc::new(self.ts.finish()),
+                Arc::new(self.op.finish()),
+            ],
+        )
+        .unwrap()
+    }
+}
+
+/// Byte ordered key value store over a [`Db`] of [`Entry`], for embedders that only want a KV and
+/// no schema of their own.
+pub struct RawDb<O, WP>
+where
+    O: Oracle<Key>,
+    WP: StorageProvider,
+{
+    inner: Arc<Db<Entry, O, WP>>,
+}
+
+impl<O, WP> RawDb<O, WP>
+where
+    O: Oracle<Key> + 'static,
+    WP: StorageProvider,
+    WP::File: AsyncWrite + AsyncRead,
+{
+    pub async fn new(
+        oracle: O,
+        wal_provider: WP,
+        option: DbOption,
+    ) -> Result<Self, WriteError<<Record<Key, Entry> as Encode>::Error>> {
+        Ok(RawDb {
+            inner: Arc::new(Db::new(oracle, wal_provider, option).await?),
+        })
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Option<Bytes> {
+        let key = Key(Bytes::copy_from_slice(key));
+        let ts = self.inner.start_read();
+        let entry = self.inner.get(&key, &ts).await;
+        self.inner.read_commit(ts);
+
+        entry.map(|entry| entry.value)
+    }
+
+    pub async fn put(
+        &self,
+        key: impl Into<Bytes>,
+        value: impl Into<Bytes>,
+    ) -> Result<TimeStamp, WriteError<<Record<Key, Entry> as Encode>::Error>> {
+        self.inner
+            .put(Entry {
+                key: Key(key.into()),
+                value: value.into(),
+            })
+            .await
+    }
+
+    pub async fn delete(
+        &self,
+        key: impl Into<Bytes>,
+    ) -> Result<TimeStamp, WriteError<<Record<Key, Entry> as Encode>::Error>> {
+        self.inner.delete(Key(key.into())).await
+    }
+
+    /// Returns the live pairs between the bounds, in key order.
+    pub async fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<Vec<(Bytes, Bytes)>, ScanError<Key, Entry>> {
+        let lower = lower.map(|lower| Key(Bytes::copy_from_slice(lower)));
+        let upper = upper.map(|upper| Key(Bytes::copy_from_slice(upper)));
+        let ts = self.inner.start_read();
+
+        let result = async {
+            let mut stream = pin!(
+                self.inner
+                    .range(lower.as_ref(), upper.as_ref(), &ts)
+                    .await?
+            );
+            let mut items = Vec::new();
+
+            while let Some(item) = stream.next().await {
+                if let (_, Some(entry)) = item? {
+                    items.push((entry.key.0, entry.value));
+                }
+            }
+            Ok(items)
+        }
+        .await;
+        self.inner.read_commit(ts);
+
+        result
+    }
+
+    pub fn new_txn(&self) -> Transaction<Entry, Db<Entry, O, WP>> {
+        self.inner.new_txn()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use bytes::Bytes;
+    use executor::ExecutorBuilder;
+    use tempfile::TempDir;
+
+    use super::{Entry, Key, RawDb};
+    use crate::{
+        mem_table::MemTable, oracle::LocalOracle, wal::provider::in_mem::InMemProvider, DbOption,
+    };
+
+    #[test]
+    fn freeze() {
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let entry = Entry {
+                key: Key(Bytes::from_static(b"a")),
+                value: Bytes::from_static(b"0"),
+            };
+            let mut mem_table = MemTable::default();
+            mem_table.insert(entry.key.clone(), 0, Some(entry.clone()));
+            mem_table.insert(Key(Bytes::from_static(b"b")), 0, None);
+
+            let batch = mem_table.to_batches(usize::MAX).remove(0);
+
+            assert_eq!(batch.find(&entry.key, &0).await, Some(Some(entry)));
+            assert_eq!(
+                batch.find(&Key(Bytes::from_static(b"b")), &0).await,
+                Some(None)
+            );
+            assert_eq!(batch.find(&Key(Bytes::from_static(b"c")), &0).await, None);
+        });
+    }
+
+    #[test]
+    fn raw_db() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let db = RawDb::new(
+                LocalOracle::default(),
+                InMemProvider::default(),
+                DbOption::new(temp_dir.path().to_path_buf()),
+            )
+            .await
+            .unwrap();
+
+            db.put("a", "0").await.unwrap();
+            db.put("b", "1").await.unwrap();
+            db.put("c", "2").await.unwrap();
+            db.delete("b").await.unwrap();
+
+            assert_eq!(db.get(b"a").await, Some(Bytes::from_static(b"0")));
+            assert_eq!(db.get(b"b").await, None);
+            assert_eq!(
+                db.scan(Bound::Included(b"a".as_slice()), Bound::Unbounded)
+                    .await
+                    .unwrap(),
+                vec![
+                    (Bytes::from_static(b"a"), Bytes::from_static(b"0")),
+                    (Bytes::from_static(b"c"), Bytes::from_static(b"2")),
+                ]
+            );
+            assert_eq!(
+                db.scan(Bound::Unbounded, Bound::Excluded(b"c".as_slice()))
+                    .await
+                    .unwrap(),
+                vec![(Bytes::from_static(b"a"), Bytes::from_static(b"0"))]
+            );
+        });
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::oracle::TimeStamp;
+
+/// Tracks running transactions and unpins the reads of those running past
+/// [`crate::DbOption::txn_max_lifetime`], so that a leaked transaction does not hold back the
+/// oldest read forever.
+#[derive(Debug)]
+pub(crate) struct Reaper {
+    max_lifetime: Option<Duration>,
+    next_id: AtomicU64,
+    /// by id, and so oldest first
+    txns: Mutex<BTreeMap<u64, Pinned>>,
+}
+
+#[derive(Debug)]
+struct Pinned {
+    read_at: TimeStamp,
+    started: Instant,
+    reaped: Arc<AtomicBool>,
+}
+
+/// A transaction's registration with the [`Reaper`].
+#[derive(Debug, Default)]
+pub(crate) struct TxnLease {
+    id: u64,
+    reaped: Arc<AtomicBool>,
+}
+
+impl TxnLease {
+    pub(crate) fn is_reaped(&self) -> bool {
+        self.reaped.load(Ordering::Acquire)
+    }
+}
+
+impl Reaper {
+    pub(crate) fn new(max_lifetime: Option<Duration>) -> Self {
+        Reaper {
+            max_lifetime,
+            next_id: AtomicU64::new(1),
+            txns: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub(crate) fn lease(&self, read_at: TimeStamp) -> TxnLease {
+        if self.max_lifetime.is_none() {
+            return TxnLease::default();
+        }
+        let lease = TxnLease {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            reaped: Arc::new(AtomicBool::new(false)),
+        };
+        self.txns.lock().unwrap().insert(
+            lease.id,
+            Pinned {
+                read_at,
+                started: Instant::now(),
+                reaped: lease.reaped.clone(),
+            },
+        );
+        lease
+    }
+
+    /// Returns whether the read of the transaction is still pinned, for the caller to release.
+    pub(crate) fn release(&self, lease: &TxnLease) -> bool {
+        if self.max_lifetime.is_none() {
+            return true;
+        }
+        self.txns.lock().unwrap().remove(&lease.id).is_some()
+    }
+
+    /// Marks the transactions started more than the max lifetime before `now` as reaped and
+    /// returns their reads, for the caller to release.
+    pub(crate) fn reap(&self, now: Instant) -> Vec<TimeStamp> {
+        let Some(max_lifetime) = self.max_lifetime else {
+            return Vec::new();
+        };
+        let mut txns = self.txns.lock().unwrap();
+        let mut reaped = Vec::new();
+        while let Some(entry) = txns.first_entry() {
+            if now.duration_since(entry.get().started) <= max_lifetime {
+                break;
+            }
+            let pinned = entry.remove();
+            pinned.reaped.store(true, Ordering::Release);
+            reaped.push(pinned.read_at);
+        }
+        reaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Reaper;
+
+    #[test]
+    fn reap_expired() {
+        let reaper = Reaper::new(Some(Duration::from_secs(1)));
+        let old = reaper.lease(1);
+        let young = reaper.lease(2);
+        assert!(reaper.reap(Instant::now()).is_empty());
+
+        let later = Instant::now() + Duration::from_secs(2);
+        assert_eq!(reaper.reap(later), vec![1, 2]);
+        assert!(old.is_reaped() && young.is_reaped());
+        assert!(!reaper.release(&old));
+
+        let lease = reaper.lease(3);
+        assert!(reaper.release(&lease));
+        assert!(reaper.reap(later + Duration::from_secs(2)).is_empty());
+
+        let unbounded = Reaper::new(None);
+        let lease = unbounded.lease(1);
+        assert!(unbounded.reap(later).is_empty());
+        assert!(unbounded.release(&lease));
+    }
+}
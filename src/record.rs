@@ -1,11 +1,15 @@
-use std::{io, mem::size_of};
+//! The wal record format. Records are written to and read from any `futures::io` stream, so
+//! tools reading wal segments need neither the db nor its executor.
+
+use std::{io, mem::size_of, ops::Bound};
 
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use thiserror::Error;
 
 use crate::{
     oracle::TimeStamp,
-    serdes::{Decode, Encode},
+    schema::Schema,
+    serdes::{option, Decode, Encode},
 };
 
 #[derive(Debug)]
@@ -66,8 +70,20 @@ where
     async fn decode<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, Self::Error> {
         let mut record_type = [0];
         reader.read_exact(&mut record_type).await?;
-        let record_type = RecordType::from(record_type[0]);
+        Self::decode_body(RecordType::from(record_type[0]), reader).await
+    }
+}
 
+impl<K, V> Record<K, V>
+where
+    K: Decode,
+    V: Decode,
+{
+    /// Decodes what follows the type of the record.
+    async fn decode_body<R: AsyncRead + Unpin>(
+        record_type: RecordType,
+        reader: &mut R,
+    ) -> Result<Self, DecodeError<K::Error, <Option<V> as Decode>::Error>> {
         let key = K::decode(reader).await.map_err(DecodeError::Key)?;
         let ts = TimeStamp::decode(reader)
             .await
@@ -83,6 +99,257 @@ where
     }
 }
 
+/// The key range one commit removed, up to the versions written at `ts`. Logged ahead of the rows
+/// of the commit, as a [`RecordType::RangeDelete`] record.
+#[derive(Debug, Clone)]
+pub struct RangeDelete<K> {
+    pub lower: Bound<K>,
+    pub upper: Bound<K>,
+    pub ts: TimeStamp,
+}
+
+impl<K> RangeDelete<K> {
+    pub fn as_ref(&self) -> RangeDelete<&K> {
+        RangeDelete {
+            lower: self.lower.as_ref(),
+            upper: self.upper.as_ref(),
+            ts: self.ts,
+        }
+    }
+}
+
+/// A key of the system keyspace one commit updates, logged after the rows of the commit as a
+/// [`RecordType::System`] record so that the update is recovered along with them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemRecord {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub ts: TimeStamp,
+}
+
+/// An entry of a wal segment, a row, the range of a [`RecordType::RangeDelete`] record or the
+/// system key of a [`RecordType::System`] one.
+#[derive(Debug)]
+pub enum WalEntry<K, V> {
+    Record(Record<K, V>),
+    RangeDelete(RangeDelete<K>),
+    System(SystemRecord),
+}
+
+impl<K, V> Encode for WalEntry<K, V>
+where
+    K: Encode,
+    V: Encode,
+{
+    type Error = <Record<K, V> as Encode>::Error;
+
+    async fn encode<W>(&self, writer: &mut W) -> Result<(), Self::Error>
+    where
+        W: AsyncWrite + Unpin + Send + Sync,
+    {
+        let range = match self {
+            WalEntry::Record(record) => return record.encode(writer).await,
+            WalEntry::RangeDelete(range) => range,
+            WalEntry::System(system) => {
+                writer.write_all(&[RecordType::System as u8]).await?;
+                system.key.encode(writer).await?;
+                writer
+                    .write_all(&(system.value.len() as u32).to_le_bytes())
+                    .await?;
+                writer.write_all(&system.value).await?;
+                return system
+                    .ts
+                    .encode(writer)
+                    .await
+                    .map_err(EncodeError::Timsetamp);
+            }
+        };
+        writer.write_all(&[RecordType::RangeDelete as u8]).await?;
+        encode_bound(writer, &range.lower).await?;
+        encode_bound(writer, &range.upper).await?;
+        range
+            .ts
+            .encode(writer)
+            .await
+            .map_err(EncodeError::Timsetamp)
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            WalEntry::Record(record) => record.size(),
+            WalEntry::RangeDelete(range) => {
+                size_of::<u8>()
+                    + bound_size(&range.lower)
+                    + bound_size(&range.upper)
+                    + range.ts.size()
+            }
+            WalEntry::System(system) => {
+                size_of::<u8>()
+                    + system.key.size()
+                    + size_of::<u32>()
+                    + system.value.len()
+                    + system.ts.size()
+            }
+        }
+    }
+}
+
+impl<K, V> Decode for WalEntry<K, V>
+where
+    K: Decode,
+    V: Decode,
+{
+    type Error = <Record<K, V> as Decode>::Error;
+
+    async fn decode<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, Self::Error> {
+        let mut record_type = [0];
+        reader.read_exact(&mut record_type).await?;
+        match RecordType::from(record_type[0]) {
+            RecordType::RangeDelete => {
+                Ok(WalEntry::RangeDelete(decode_range_delete(reader).await?))
+            }
+            RecordType::System => Ok(WalEntry::System(decode_system(reader).await?)),
+            record_type => Ok(WalEntry::Record(
+                Record::decode_body(record_type, reader).await?,
+            )),
+        }
+    }
+}
+
+impl<K, V> WalEntry<K, V>
+where
+    K: Decode,
+    V: Schema,
+{
+    /// Decodes an entry whose row was encoded with only the first `defaulted` of
+    /// [`Schema::defaulted_fields`], see [`Schema::decode_defaulted`].
+    pub(crate) async fn decode_defaulted<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        defaulted: usize,
+    ) -> Result<Self, <Self as Decode>::Error> {
+        let mut record_type = [0];
+        reader.read_exact(&mut record_type).await?;
+        let record_type = match RecordType::from(record_type[0]) {
+            RecordType::RangeDelete => {
+                return Ok(WalEntry::RangeDelete(decode_range_delete(reader).await?))
+            }
+            RecordType::System => return Ok(WalEntry::System(decode_system(reader).await?)),
+            record_type => record_type,
+        };
+        let key = K::decode(reader).await.map_err(DecodeError::Key)?;
+        let ts = TimeStamp::decode(reader)
+            .await
+            .map_err(DecodeError::Timetamp)?;
+        let mut tag = [0];
+        reader.read_exact(&mut tag).await?;
+        let value = match tag[0] {
+            option::NONE_TAG => None,
+            option::SOME_TAG => Some(
+                V::decode_defaulted(reader, defaulted)
+                    .await
+                    .map_err(|err| DecodeError::Value(option::DecodeError::Inner(err)))?,
+            ),
+            tag => return Err(DecodeError::Value(option::DecodeError::InvalidTag(tag))),
+        };
+
+        Ok(WalEntry::Record(Record {
+            record_type,
+            key,
+            ts,
+            value,
+        }))
+    }
+}
+
+async fn decode_system<K, V, R>(reader: &mut R) -> Result<SystemRecord, DecodeError<K, V>>
+where
+    K: std::error::Error,
+    V: std::error::Error,
+    R: AsyncRead + Unpin,
+{
+    let key = String::decode(reader).await?;
+    let mut len = [0; size_of::<u32>()];
+    reader.read_exact(&mut len).await?;
+    let mut value = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut value).await?;
+    let ts = TimeStamp::decode(reader)
+        .await
+        .map_err(DecodeError::Timetamp)?;
+
+    Ok(SystemRecord { key, value, ts })
+}
+
+async fn decode_range_delete<K, V, R>(
+    reader: &mut R,
+) -> Result<RangeDelete<K>, DecodeError<K::Error, V>>
+where
+    K: Decode,
+    V: std::error::Error,
+    R: AsyncRead + Unpin,
+{
+    Ok(RangeDelete {
+        lower: decode_bound(reader).await?,
+        upper: decode_bound(reader).await?,
+        ts: TimeStamp::decode(reader)
+            .await
+            .map_err(DecodeError::Timetamp)?,
+    })
+}
+
+// the tags of the range bounds kept in the system keyspace, see `crate::range_delete`
+const UNBOUNDED_TAG: u8 = 0;
+const INCLUDED_TAG: u8 = 1;
+const EXCLUDED_TAG: u8 = 2;
+
+async fn encode_bound<K, T, V, W>(
+    writer: &mut W,
+    bound: &Bound<K>,
+) -> Result<(), EncodeError<K::Error, T, V>>
+where
+    K: Encode,
+    T: std::error::Error,
+    V: std::error::Error,
+    W: AsyncWrite + Unpin + Send + Sync,
+{
+    let (tag, key) = match bound {
+        Bound::Unbounded => (UNBOUNDED_TAG, None),
+        Bound::Included(key) => (INCLUDED_TAG, Some(key)),
+        Bound::Excluded(key) => (EXCLUDED_TAG, Some(key)),
+    };
+    writer.write_all(&[tag]).await?;
+    if let Some(key) = key {
+        key.encode(writer).await.map_err(EncodeError::Key)?;
+    }
+    Ok(())
+}
+
+fn bound_size<K: Encode>(bound: &Bound<K>) -> usize {
+    match bound {
+        Bound::Unbounded => size_of::<u8>(),
+        Bound::Included(key) | Bound::Excluded(key) => size_of::<u8>() + key.size(),
+    }
+}
+
+async fn decode_bound<K, V, R>(reader: &mut R) -> Result<Bound<K>, DecodeError<K::Error, V>>
+where
+    K: Decode,
+    V: std::error::Error,
+    R: AsyncRead + Unpin,
+{
+    let mut tag = [0];
+    reader.read_exact(&mut tag).await?;
+    match tag[0] {
+        UNBOUNDED_TAG => Ok(Bound::Unbounded),
+        INCLUDED_TAG => Ok(Bound::Included(
+            K::decode(reader).await.map_err(DecodeError::Key)?,
+        )),
+        EXCLUDED_TAG => Ok(Bound::Excluded(
+            K::decode(reader).await.map_err(DecodeError::Key)?,
+        )),
+        tag => Err(DecodeError::InvalidBound(tag)),
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum RecordType {
@@ -90,6 +357,10 @@ pub enum RecordType {
     First,
     Middle,
     Last,
+    /// Carries a [`RangeDelete`] rather than a row.
+    RangeDelete,
+    /// Carries a [`SystemRecord`] rather than a row.
+    System,
 }
 
 impl From<u8> for RecordType {
@@ -99,6 +370,8 @@ impl From<u8> for RecordType {
             1 => Self::First,
             2 => Self::Middle,
             3 => Self::Last,
+            4 => Self::RangeDelete,
+            5 => Self::System,
             _ => unreachable!(),
         }
     }
@@ -135,4 +408,6 @@ where
     Timetamp(#[source] <TimeStamp as Decode>::Error),
     #[error("value error: {0}")]
     Value(#[source] V),
+    #[error("invalid range bound tag: {0}")]
+    InvalidBound(u8),
 }
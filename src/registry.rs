@@ -0,0 +1,161 @@
+//! The arrow schema rows of a db were written with, recorded in the system keyspace on the first
+//! open, so that opening the db with a schema that decodes them differently fails rather than
+//! reading garbage.
+
+use std::io;
+
+use arrow::datatypes::{DataType, Fields};
+use thiserror::Error;
+
+use crate::{
+    schema::Schema,
+    system::{SystemTable, SCHEMA_KEY},
+};
+
+/// The schema on disk and the one the db was opened with differ, in the fields listed by
+/// [`SchemaMismatch::diff`].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error(
+    "schema mismatch between version {stored_version} on disk and version {version} opening it:\n{}",
+    .diff.join("\n")
+)]
+pub struct SchemaMismatch {
+    pub stored_version: u32,
+    pub version: u32,
+    /// fields only on disk prefixed with `-`, fields only in the opening schema with `+`
+    pub diff: Vec<String>,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum RegistryError {
+    #[error(transparent)]
+    Io(io::Error),
+    #[error(transparent)]
+    Mismatch(SchemaMismatch),
+}
+
+/// One line per field, the fields of a struct following it, prefixed with its name.
+fn describe(prefix: &str, fields: &Fields, lines: &mut Vec<String>) {
+    for field in fields.iter() {
+        let name = format!("{}{}", prefix, field.name());
+        let not_null = if field.is_nullable() { "" } else { " not null" };
+        match field.data_type() {
+            DataType::Struct(children) => {
+                lines.push(format!("{}: Struct{}", name, not_null));
+                describe(&format!("{}.", name), children, lines);
+            }
+            data_type => lines.push(format!("{}: {:?}{}", name, data_type, not_null)),
+        }
+    }
+}
+
+fn encode(version: u32, fields: &[String]) -> Vec<u8> {
+    let mut bytes = version.to_le_bytes().to_vec();
+    bytes.extend(fields.join("\n").into_bytes());
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> io::Result<(u32, Vec<String>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid registered schema");
+    let (version, fields) = bytes.split_first_chunk::<4>().ok_or_else(invalid)?;
+    let fields = std::str::from_utf8(fields).map_err(|_| invalid())?;
+    Ok((
+        u32::from_le_bytes(*version),
+        fields.split('\n').map(str::to_string).collect(),
+    ))
+}
+
+/// Registers the schema of `S` if the db has none yet, or checks it against the registered one.
+/// The registered schema is replaced if `S` only appends [`Schema::defaulted_fields`] to it.
+/// Returns how many of them the rows written before carry.
+pub(crate) async fn check<S>(system: &SystemTable) -> Result<u32, RegistryError>
+where
+    S: Schema,
+{
+    let mut fields = Vec::new();
+    describe("", S::inner_schema().fields(), &mut fields);
+    let Some(stored) = system.get(SCHEMA_KEY).await else {
+        system
+            .set(SCHEMA_KEY, encode(S::VERSION, &fields))
+            .await
+            .map_err(RegistryError::Io)?;
+        return Ok(S::defaulted_fields().len() as u32);
+    };
+    let (stored_version, stored_fields) = decode(&stored).map_err(RegistryError::Io)?;
+    if stored_version == S::VERSION && stored_fields == fields {
+        return Ok(S::defaulted_fields().len() as u32);
+    }
+    let removed = stored_fields
+        .iter()
+        .filter(|field| !fields.contains(field))
+        .map(|field| format!("-{}", field));
+    let added = fields
+        .iter()
+        .filter(|field| !stored_fields.contains(field))
+        .map(|field| format!("+{}", field));
+    let diff = removed.chain(added).collect::<Vec<_>>();
+
+    let registered = S::defaulted_fields()
+        .iter()
+        .take_while(|field| {
+            stored_fields
+                .iter()
+                .any(|line| line.starts_with(&format!("inner.{}: ", field)))
+        })
+        .count();
+    let added = &S::defaulted_fields()[registered..];
+    let defaulted = |line: &String| {
+        added
+            .iter()
+            .any(|field| line.starts_with(&format!("+inner.{}: ", field)))
+    };
+    // rows lacking a defaulted field lack every one after it
+    if stored_version == S::VERSION && diff.len() == added.len() && diff.iter().all(defaulted) {
+        system
+            .set(SCHEMA_KEY, encode(S::VERSION, &fields))
+            .await
+            .map_err(RegistryError::Io)?;
+        return Ok(registered as u32);
+    }
+    Err(RegistryError::Mismatch(SchemaMismatch {
+        stored_version,
+        version: S::VERSION,
+        diff,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use executor::ExecutorBuilder;
+    use tempfile::TempDir;
+
+    use super::{check, RegistryError};
+    use crate::{raw::Entry, system::SystemTable, tests::UserInner, DbOption};
+
+    #[test]
+    fn mismatch() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let option = DbOption::new(temp_dir.path().to_path_buf());
+            let system = SystemTable::new(&option).await.unwrap();
+            check::<UserInner>(&system).await.unwrap();
+            drop(system);
+
+            let system = SystemTable::new(&option).await.unwrap();
+            check::<UserInner>(&system).await.unwrap();
+            match check::<Entry>(&system).await {
+                Err(RegistryError::Mismatch(mismatch)) => {
+                    assert_eq!((mismatch.stored_version, mismatch.version), (0, 0));
+                    assert_eq!(mismatch.diff.first().unwrap(), "-id: UInt64 not null");
+                    assert!(mismatch
+                        .diff
+                        .iter()
+                        .any(|field| field == "+key: LargeBinary not null"));
+                    assert!(!mismatch.diff.iter().any(|field| field.contains("_ts")));
+                }
+                _ => panic!("schema mismatch not detected"),
+            }
+        });
+    }
+}
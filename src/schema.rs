@@ -1,32 +1,147 @@
-use std::{fmt::Debug, hash::Hash};
+use std::{fmt::Debug, future::Future, hash::Hash};
 
 use arrow::{
-    array::{Array, RecordBatch},
-    datatypes::SchemaRef,
+    array::{Array, AsArray, RecordBatch, UInt64Array},
+    datatypes::{Int8Type, SchemaRef, UInt64Type},
 };
+use futures::AsyncRead;
 
-use crate::serdes::{Decode, Encode};
+use crate::{
+    comparator::Comparator,
+    oracle::TimeStamp,
+    serdes::{Decode, Encode},
+};
+
+/// Every `Schema::inner_schema` batch holds the primary key, the nullable `inner` struct and
+/// then the commit timestamp of each row under this name.
+pub const TS_COLUMN_NAME: &str = "_ts";
+pub(crate) const TS_COLUMN: usize = 2;
+/// Follows the timestamp column and holds the [`Op`] of each row.
+pub const OP_COLUMN_NAME: &str = "_op";
+pub(crate) const OP_COLUMN: usize = 3;
+
+/// What a row records, so that a null `inner` is never mistaken for a deletion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i8)]
+pub enum Op {
+    Put = 0,
+    Delete = 1,
+    RangeDelete = 2,
+    Merge = 3,
+}
+
+impl Op {
+    pub fn from_batch(batch: &RecordBatch, offset: usize) -> Op {
+        let op = batch
+            .column(OP_COLUMN)
+            .as_primitive::<Int8Type>()
+            .value(offset);
+
+        Op::try_from(op).unwrap_or_else(|op| panic!("unknown op {op} in column {OP_COLUMN_NAME}"))
+    }
+
+    pub(crate) fn of<S: Schema>(value: Option<&S>) -> Op {
+        match value {
+            Some(value) if value.is_operand() => Op::Merge,
+            Some(_) => Op::Put,
+            None => Op::Delete,
+        }
+    }
+
+    /// Whether rows of this op carry no value.
+    pub fn is_tombstone(self) -> bool {
+        matches!(self, Op::Delete | Op::RangeDelete)
+    }
+}
+
+impl TryFrom<i8> for Op {
+    type Error = i8;
+
+    fn try_from(op: i8) -> Result<Self, Self::Error> {
+        match op {
+            0 => Ok(Op::Put),
+            1 => Ok(Op::Delete),
+            2 => Ok(Op::RangeDelete),
+            3 => Ok(Op::Merge),
+            op => Err(op),
+        }
+    }
+}
 
 pub trait Schema: Debug + Clone + Encode + Decode + 'static {
     type PrimaryKey: Debug + Clone + Ord + Hash + Encode + Decode + 'static;
     type Builder: Builder<Self> + Send;
     type PrimaryKeyArray: Array;
+    /// The order of primary keys in mem tables, tables and scans,
+    /// [`crate::comparator::OrdComparator`] for the order of their `Ord`. Changing it for an
+    /// existing db leaves its tables unordered.
+    type Comparator: Comparator<Self::PrimaryKey>;
+
+    /// Recorded along with the arrow schema on the first open of a db, and checked against on the
+    /// next ones. Bump it when rows are decoded differently under the same arrow schema.
+    const VERSION: u32 = 0;
 
     fn arrow_schema() -> SchemaRef;
 
     fn inner_schema() -> SchemaRef;
 
+    /// The fields of the inner struct [`Schema::from_batch`] fills with a default in rows of
+    /// tables written before they were added, e.g. by `#[default_value(..)]` of the
+    /// `elsm_schema` macro, in the order they were added. They have to come after the other
+    /// fields, whose columns are read by position. Opening a db whose registered schema lacks
+    /// only some of the last of them registers the new schema.
+    fn defaulted_fields() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Decodes a row encoded while the schema had only the first `defaulted` of its
+    /// [`Schema::defaulted_fields`], filling the others with their defaults. Wal segments record
+    /// in their header how many their rows carry.
+    fn decode_defaulted<R>(
+        reader: &mut R,
+        defaulted: usize,
+    ) -> impl Future<Output = Result<Self, <Self as Decode>::Error>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let _ = defaulted;
+        Self::decode(reader)
+    }
+
     fn primary_key(&self) -> Self::PrimaryKey;
 
     fn builder() -> Self::Builder;
 
     fn from_batch(batch: &RecordBatch, offset: usize) -> (Self::PrimaryKey, Option<Self>);
 
+    fn primary_key_from_batch(batch: &RecordBatch, offset: usize) -> Self::PrimaryKey;
+
     fn to_primary_key_array(keys: Vec<Self::PrimaryKey>) -> Self::PrimaryKeyArray;
+
+    /// Whether the value is an operand to fold onto the older versions of its key with
+    /// [`Schema::merge`] rather than one replacing them, e.g. an increment of a
+    /// [`crate::counter::Counter`]. Operands are written as [`Op::Merge`] rows, so that
+    /// [`Schema::from_batch`] can tell them apart.
+    fn is_operand(&self) -> bool {
+        false
+    }
+
+    /// Folds `operand` onto `older`, the version of its key before it, `None` if the key had
+    /// none or was deleted. The result has to be an operand again only if `older` is one, and
+    /// folding has to be associative, since compactions fold the operands of their inputs before
+    /// the older versions are read.
+    fn merge(older: Option<&Self>, operand: &Self) -> Self {
+        let _ = older;
+        operand.clone()
+    }
 }
 
 pub trait Builder<S: Schema> {
-    fn add(&mut self, primary_key: &S::PrimaryKey, schema: Option<S>);
+    fn add(&mut self, primary_key: &S::PrimaryKey, ts: TimeStamp, op: Op, schema: Option<S>);
 
     fn finish(&mut self) -> RecordBatch;
 }
+
+pub(crate) fn timestamps(batch: &RecordBatch) -> &UInt64Array {
+    batch.column(TS_COLUMN).as_primitive::<UInt64Type>()
+}
@@ -1,10 +1,15 @@
-use executor::futures::{
-    util::{AsyncReadExt, AsyncWriteExt},
-    AsyncRead, AsyncWrite,
+use std::{
+    ops::Bound,
+    time::{SystemTime, UNIX_EPOCH},
 };
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use snowflake::ProcessUniqueId;
 
-use crate::serdes::{Decode, Encode};
+use crate::{
+    comparator::Comparator,
+    serdes::{Decode, Encode},
+};
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct Scope<K>
@@ -14,6 +19,46 @@ where
     pub(crate) min: K,
     pub(crate) max: K,
     pub(crate) gen: ProcessUniqueId,
+    pub(crate) stats: TableStats,
+}
+
+/// Counted while a table is written and kept in the version log, so that compaction can tell
+/// how much of a table is garbage without reading it.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub(crate) struct TableStats {
+    pub(crate) rows: u64,
+    pub(crate) tombstones: u64,
+    /// rows that are not the newest version of their key in the table
+    pub(crate) shadowed: u64,
+    pub(crate) size: u64,
+    /// milliseconds since the unix epoch
+    pub(crate) created_at: u64,
+}
+
+impl TableStats {
+    pub(crate) fn new() -> Self {
+        TableStats {
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn add(&mut self, tombstone: bool, shadowed: bool) {
+        self.rows += 1;
+        self.tombstones += tombstone as u64;
+        self.shadowed += shadowed as u64;
+    }
+
+    /// Bytes a compaction is estimated to reclaim, assuming rows of even size.
+    pub(crate) fn garbage_bytes(&self) -> u64 {
+        if self.rows == 0 {
+            return 0;
+        }
+        self.size * (self.tombstones + self.shadowed).min(self.rows) / self.rows
+    }
 }
 
 impl<K> Clone for Scope<K>
@@ -25,6 +70,7 @@ where
             min: self.min.clone(),
             max: self.max.clone(),
             gen: self.gen,
+            stats: self.stats,
         }
     }
 }
@@ -33,15 +79,28 @@ impl<K> Scope<K>
 where
     K: Encode + Decode + Ord + Clone,
 {
-    pub(crate) fn is_between(&self, key: &K) -> bool {
-        self.min.le(key) && self.max.ge(key)
+    /// Keys are compared in the order of `C`, like those of the table were sorted in.
+    pub(crate) fn is_between<C: Comparator<K>>(&self, key: &K) -> bool {
+        C::compare(&self.min, key).is_le() && C::compare(&self.max, key).is_ge()
     }
 
-    pub(crate) fn is_meet(&self, target: &Scope<K>) -> bool {
-        (self.min.le(&target.min) && self.max.ge(&target.min))
-            || (self.min.le(&target.max) && self.max.ge(&target.max))
-            || (self.min.le(&target.min)) && self.max.ge(&target.max)
-            || (self.min.ge(&target.min)) && self.max.le(&target.max)
+    pub(crate) fn is_meet<C: Comparator<K>>(&self, target: &Scope<K>) -> bool {
+        C::compare(&self.min, &target.max).is_le() && C::compare(&target.min, &self.max).is_le()
+    }
+
+    /// Whether the table may hold keys between the bounds.
+    pub(crate) fn overlaps<C: Comparator<K>>(&self, lower: Bound<&K>, upper: Bound<&K>) -> bool {
+        let above = match lower {
+            Bound::Included(lower) => C::compare(&self.max, lower).is_ge(),
+            Bound::Excluded(lower) => C::compare(&self.max, lower).is_gt(),
+            Bound::Unbounded => true,
+        };
+        above
+            && match upper {
+                Bound::Included(upper) => C::compare(&self.min, upper).is_le(),
+                Bound::Excluded(upper) => C::compare(&self.min, upper).is_lt(),
+                Bound::Unbounded => true,
+            }
     }
 }
 
@@ -61,12 +120,21 @@ where
         writer
             .write_all(&bincode::serialize(&self.gen).unwrap())
             .await?;
+        for stat in [
+            self.stats.rows,
+            self.stats.tombstones,
+            self.stats.shadowed,
+            self.stats.size,
+            self.stats.created_at,
+        ] {
+            writer.write_all(&stat.to_le_bytes()).await?;
+        }
         Ok(())
     }
 
     fn size(&self) -> usize {
-        // ProcessUniqueId: usize + u64
-        self.min.size() + self.max.size() + 16
+        // ProcessUniqueId: usize + u64, then five u64 stats
+        self.min.size() + self.max.size() + 16 + 5 * 8
     }
 }
 
@@ -85,7 +153,25 @@ where
             reader.read_exact(&mut slice).await?;
             bincode::deserialize(&slice).unwrap()
         };
+        let mut stats = [0; 5];
+        for stat in stats.iter_mut() {
+            let mut bytes = [0; 8];
+            reader.read_exact(&mut bytes).await?;
+            *stat = u64::from_le_bytes(bytes);
+        }
+        let [rows, tombstones, shadowed, size, created_at] = stats;
 
-        Ok(Scope { min, max, gen })
+        Ok(Scope {
+            min,
+            max,
+            gen,
+            stats: TableStats {
+                rows,
+                tombstones,
+                shadowed,
+                size,
+                created_at,
+            },
+        })
     }
 }
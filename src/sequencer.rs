@@ -0,0 +1,194 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    mem,
+    sync::{Arc, Mutex},
+};
+
+use futures::channel::oneshot;
+
+/// Orders the writes applied to the mem tables by their place in the wal: every shard applies
+/// them one after another in that order, and a write is published once it and every write
+/// logged before it are applied.
+#[derive(Debug)]
+pub(crate) struct Sequencer {
+    batches: Queue,
+    shards: Vec<Queue>,
+}
+
+impl Sequencer {
+    pub(crate) fn new(shards: usize) -> Self {
+        Sequencer {
+            batches: Queue::default(),
+            shards: (0..shards).map(|_| Queue::default()).collect(),
+        }
+    }
+
+    /// Must be called while holding the wal lock, right after the write is logged.
+    pub(crate) fn assign(self: &Arc<Self>, shards: impl IntoIterator<Item = usize>) -> Ticket {
+        Ticket {
+            seq: self.batches.assign(),
+            shards: shards
+                .into_iter()
+                .map(|shard| (shard, self.shards[shard].assign()))
+                .collect(),
+            sequencer: self.clone(),
+        }
+    }
+
+    /// Every write logged up to this sequence is applied.
+    pub(crate) fn published(&self) -> u64 {
+        self.batches.state.lock().unwrap().done
+    }
+}
+
+/// The place of a logged write in the sequence. Dropping it gives up the write's turns left, so
+/// that a write failed or dropped before it is applied holds none of the later ones back.
+#[derive(Debug)]
+pub(crate) struct Ticket {
+    seq: u64,
+    shards: Vec<(usize, u64)>,
+    sequencer: Arc<Sequencer>,
+}
+
+impl Ticket {
+    /// Waits until `shard` applied the writes logged before this one.
+    pub(crate) async fn turn(&self, shard: usize) -> Turn<'_> {
+        let (_, seq) = self
+            .shards
+            .iter()
+            .find(|(s, _)| *s == shard)
+            .expect("the write was not logged for the shard");
+        let queue = &self.sequencer.shards[shard];
+        queue.wait(seq - 1).await;
+
+        Turn { queue, seq: *seq }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        for (shard, seq) in self.shards.iter() {
+            self.sequencer.shards[*shard].finish(*seq);
+        }
+        self.sequencer.batches.finish(self.seq);
+    }
+}
+
+/// Lets the next write apply to the shard once dropped.
+#[derive(Debug)]
+pub(crate) struct Turn<'a> {
+    queue: &'a Queue,
+    seq: u64,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.queue.finish(self.seq)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    assigned: u64,
+    /// every sequence up to it is finished
+    done: u64,
+    finished: BTreeSet<u64>,
+    waiters: BTreeMap<u64, Vec<oneshot::Sender<()>>>,
+}
+
+impl Queue {
+    fn assign(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.assigned += 1;
+        state.assigned
+    }
+
+    /// Finishing a sequence twice changes nothing.
+    fn finish(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+        if seq <= state.done {
+            return;
+        }
+        state.finished.insert(seq);
+        let mut done = state.done;
+        while state.finished.remove(&(done + 1)) {
+            done += 1;
+        }
+        state.done = done;
+        let waiting = state.waiters.split_off(&(done + 1));
+        for waiter in mem::replace(&mut state.waiters, waiting)
+            .into_values()
+            .flatten()
+        {
+            let _ = waiter.send(());
+        }
+    }
+
+    async fn wait(&self, seq: u64) {
+        loop {
+            let rx = {
+                let mut state = self.state.lock().unwrap();
+                if state.done >= seq {
+                    return;
+                }
+                let (tx, rx) = oneshot::channel();
+                state.waiters.entry(seq).or_default().push(tx);
+                rx
+            };
+            let _ = rx.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::{executor::block_on, future::join_all};
+
+    use super::Sequencer;
+
+    #[test]
+    fn applies_in_log_order() {
+        block_on(async {
+            let sequencer = Arc::new(Sequencer::new(2));
+            let tickets = (0..4).map(|_| sequencer.assign([0, 1])).collect::<Vec<_>>();
+            let skipped = sequencer.assign([1]);
+            let last = sequencer.assign([1]);
+            let applied = Mutex::new(Vec::new());
+
+            drop(skipped);
+            let applies = tickets.into_iter().enumerate().rev().map(|(i, ticket)| {
+                let applied = &applied;
+                async move {
+                    for shard in [1, 0] {
+                        let _turn = ticket.turn(shard).await;
+                        applied.lock().unwrap().push((shard, i + 1));
+                    }
+                }
+            });
+            join_all(applies).await;
+            assert_eq!(sequencer.published(), 5);
+
+            let _turn = last.turn(1).await;
+            assert_eq!(
+                applied.into_inner().unwrap(),
+                vec![
+                    (1, 1),
+                    (0, 1),
+                    (1, 2),
+                    (0, 2),
+                    (1, 3),
+                    (0, 3),
+                    (1, 4),
+                    (0, 4)
+                ]
+            );
+        });
+    }
+}
@@ -1,3 +1,7 @@
+//! `Option<V>` is framed as a one byte tag followed by the encoding of `V` only when the tag is
+//! [`SOME_TAG`]. This layout is version [`OPTION_FRAMING_VERSION`] and stays stable so that
+//! external codecs can produce it, e.g. for ingestion files.
+
 use std::io;
 
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
@@ -5,6 +9,10 @@ use thiserror::Error;
 
 use super::{Decode, Encode};
 
+pub const OPTION_FRAMING_VERSION: u8 = 1;
+pub const NONE_TAG: u8 = 0;
+pub const SOME_TAG: u8 = 1;
+
 #[derive(Debug, Error)]
 #[error("option encode error")]
 pub enum EncodeError<E>
@@ -27,6 +35,8 @@ where
     Io(#[from] io::Error),
     #[error("inner error: {0}")]
     Inner(#[source] E),
+    #[error("invalid option tag: {0}")]
+    InvalidTag(u8),
 }
 
 impl<V> Encode for Option<V>
@@ -40,9 +50,9 @@ where
         writer: &mut W,
     ) -> Result<(), Self::Error> {
         match self {
-            None => writer.write_all(&[0]).await?,
+            None => writer.write_all(&[NONE_TAG]).await?,
             Some(v) => {
-                writer.write_all(&[1]).await?;
+                writer.write_all(&[SOME_TAG]).await?;
                 v.encode(writer).await.map_err(EncodeError::Inner)?;
             }
         }
@@ -50,7 +60,7 @@ where
     }
 
     fn size(&self) -> usize {
-        1
+        1 + self.as_ref().map(Encode::size).unwrap_or(0)
     }
 }
 
@@ -64,9 +74,45 @@ where
         let mut o = [0];
         reader.read_exact(&mut o).await?;
         match o[0] {
-            0 => Ok(None),
-            1 => Ok(Some(V::decode(reader).await.map_err(DecodeError::Inner)?)),
-            _ => panic!("invalid option tag"),
+            NONE_TAG => Ok(None),
+            SOME_TAG => Ok(Some(V::decode(reader).await.map_err(DecodeError::Inner)?)),
+            tag => Err(DecodeError::InvalidTag(tag)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, io::Cursor};
+
+    use super::{DecodeError, NONE_TAG, SOME_TAG};
+    use crate::serdes::{Decode, Encode};
+
+    #[test]
+    fn tagged_encoding() {
+        block_on(async {
+            let mut bytes = Vec::new();
+            let none: Option<String> = None;
+            none.encode(&mut Cursor::new(&mut bytes)).await.unwrap();
+            assert_eq!(bytes, vec![NONE_TAG]);
+            assert_eq!(none.size(), bytes.len());
+
+            let mut bytes = Vec::new();
+            let empty = Some(String::new());
+            empty.encode(&mut Cursor::new(&mut bytes)).await.unwrap();
+            assert_eq!(bytes, vec![SOME_TAG, 0, 0]);
+            assert_eq!(empty.size(), bytes.len());
+            assert_eq!(
+                Option::<String>::decode(&mut Cursor::new(&bytes))
+                    .await
+                    .unwrap(),
+                Some(String::new())
+            );
+
+            assert!(matches!(
+                Option::<String>::decode(&mut Cursor::new(vec![2])).await,
+                Err(DecodeError::InvalidTag(2))
+            ));
+        });
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    fs::File,
+    io,
+    marker::PhantomData,
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+};
+
+use executor::futures::Stream;
+use parquet::arrow::async_reader::AsyncFileReader;
+
+use crate::{
+    oracle::TimeStamp,
+    schema::Schema,
+    stream::{table_stream::TableStream, ScanError},
+    wal::provider::table::VerifiedTable,
+};
+
+/// A table file read directly, outside of any database, e.g. by repair and migration tools.
+///
+/// Tables are never modified once written, so every scan sees the same rows.
+pub struct SsTable<S>
+where
+    S: Schema,
+{
+    path: PathBuf,
+    size: u64,
+    _p: PhantomData<S>,
+}
+
+impl<S> SsTable<S>
+where
+    S: Schema,
+{
+    /// Opens the table at `path` and validates its footer.
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let size = std::fs::metadata(&path)?.len();
+        let table = SsTable {
+            path,
+            size,
+            _p: PhantomData,
+        };
+        table.reader().await?;
+
+        Ok(table)
+    }
+
+    /// Every version of the keys within `range`, in key order and newest first.
+    pub async fn scan(
+        &self,
+        range: impl RangeBounds<S::PrimaryKey>,
+    ) -> Result<
+        impl Stream<
+                Item = Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>,
+            > + '_,
+        ScanError<S::PrimaryKey, S>,
+    > {
+        let reader = self.reader().await.map_err(ScanError::Io)?;
+
+        TableStream::<S>::from_file(
+            reader,
+            range.start_bound(),
+            range.end_bound(),
+            TimeStamp::MAX,
+            None,
+        )
+        .await
+    }
+
+    async fn reader(&self) -> io::Result<Box<dyn AsyncFileReader>> {
+        let file: executor::fs::File = File::open(&self.path)?.into();
+
+        VerifiedTable::open(Box::new(file), self.size)
+            .await
+            .map(|table| Box::new(table) as Box<dyn AsyncFileReader>)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use bytes::Bytes;
+    use executor::{futures::StreamExt, ExecutorBuilder};
+    use parquet::arrow::ArrowWriter;
+    use snowflake::ProcessUniqueId;
+    use tempfile::TempDir;
+
+    use super::SsTable;
+    use crate::{
+        schema::{Builder, Op, Schema},
+        tests::{user, UserInner},
+        wal::provider::{fs::Fs, TableStore},
+    };
+
+    #[test]
+    fn scan_table() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let mut builder = UserInner::builder();
+            builder.add(&1, 1, Op::Put, Some(user(1)));
+            builder.add(&2, 2, Op::Delete, None);
+            builder.add(&2, 1, Op::Put, Some(user(2)));
+            builder.add(&3, 1, Op::Put, Some(user(3)));
+
+            let mut writer =
+                ArrowWriter::try_new(Vec::new(), UserInner::inner_schema(), None).unwrap();
+            writer.write(&builder.finish()).unwrap();
+            let gen = ProcessUniqueId::new();
+            let store = Fs::new(temp_dir.path()).unwrap();
+            TableStore::create_table(&store, &gen, Bytes::from(writer.into_inner().unwrap()))
+                .await
+                .unwrap();
+
+            let table =
+                SsTable::<UserInner>::open(temp_dir.path().join(format!("{}.parquet", gen)))
+                    .await
+                    .unwrap();
+            let rows = table
+                .scan((Bound::Included(2), Bound::Unbounded))
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(
+                rows,
+                vec![(2, 2, None), (2, 1, Some(user(2))), (3, 1, Some(user(3)))]
+            );
+
+            assert!(
+                SsTable::<UserInner>::open(temp_dir.path().join("missing.parquet"))
+                    .await
+                    .is_err()
+            );
+        });
+    }
+}
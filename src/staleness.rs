@@ -0,0 +1,65 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Tracks how far the immutable view lags behind the mutable shards.
+#[derive(Debug)]
+pub(crate) struct StalenessTracker {
+    oldest_unfrozen: Vec<Mutex<Option<Instant>>>,
+}
+
+impl StalenessTracker {
+    pub(crate) fn new(shard_num: usize) -> Self {
+        Self {
+            oldest_unfrozen: (0..shard_num).map(|_| Mutex::new(None)).collect(),
+        }
+    }
+
+    /// Must be called while holding the shard's write lock.
+    pub(crate) fn on_write(&self, shard: usize) {
+        let mut oldest = self.oldest_unfrozen[shard].lock().unwrap();
+
+        if oldest.is_none() {
+            *oldest = Some(Instant::now());
+        }
+    }
+
+    /// Must be called while holding the shard's write lock and the encoding queue's, as the mem
+    /// table is swapped into it.
+    pub(crate) fn on_freeze(&self, shard: usize) {
+        *self.oldest_unfrozen[shard].lock().unwrap() = None;
+    }
+
+    pub(crate) fn staleness(&self) -> Duration {
+        self.oldest_unfrozen
+            .iter()
+            .filter_map(|oldest| *oldest.lock().unwrap())
+            .min()
+            .map(|oldest| oldest.elapsed())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::StalenessTracker;
+
+    #[test]
+    fn staleness() {
+        let tracker = StalenessTracker::new(2);
+
+        assert_eq!(tracker.staleness(), Duration::ZERO);
+
+        tracker.on_write(0);
+        std::thread::sleep(Duration::from_millis(1));
+        tracker.on_write(1);
+        assert!(tracker.staleness() >= Duration::from_millis(1));
+
+        tracker.on_freeze(0);
+        tracker.on_freeze(1);
+        assert_eq!(tracker.staleness(), Duration::ZERO);
+    }
+}
@@ -8,7 +8,11 @@ use arrow::record_batch::RecordBatch;
 use executor::futures::{FutureExt, Stream};
 use pin_project::pin_project;
 
-use crate::{schema::Schema, stream::StreamError};
+use crate::{
+    oracle::TimeStamp,
+    schema::{self, Schema},
+    stream::ScanError,
+};
 
 #[pin_project]
 pub(crate) struct BatchStream<S>
@@ -34,12 +38,13 @@ where
 
     async fn decode_item(
         &mut self,
-    ) -> Result<(S::PrimaryKey, Option<S>), StreamError<S::PrimaryKey, S>> {
+    ) -> Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>> {
         // Safety: already check offset
         let (id, item) = S::from_batch(&self.inner, self.pos);
+        let ts = schema::timestamps(&self.inner).value(self.pos);
 
         self.pos += 1;
-        Ok((id, item))
+        Ok((id, ts, item))
     }
 }
 
@@ -47,7 +52,7 @@ impl<S> Stream for BatchStream<S>
 where
     S: Schema,
 {
-    type Item = Result<(S::PrimaryKey, Option<S>), StreamError<S::PrimaryKey, S>>;
+    type Item = Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if self.pos < self.inner.num_rows() {
@@ -7,6 +7,8 @@ use std::{
 
 use executor::futures::Stream;
 
+use crate::{comparator::Comparator, oracle::TimeStamp};
+
 unsafe impl<K, V, E> Send for BufStream<'_, K, V, E>
 where
     K: Ord + Clone + Sync,
@@ -26,7 +28,7 @@ pub(crate) struct BufStream<'a, K, V, E>
 where
     K: Ord + Clone,
 {
-    inner: NonNull<Vec<(K, Option<V>)>>,
+    inner: NonNull<Vec<(K, TimeStamp, Option<V>)>>,
     pos: usize,
     _p: PhantomData<&'a E>,
 }
@@ -36,7 +38,7 @@ where
     K: Ord + Clone,
     V: 'a,
 {
-    pub(crate) fn new(items: Vec<(K, Option<V>)>) -> Self {
+    pub(crate) fn new(items: Vec<(K, TimeStamp, Option<V>)>) -> Self {
         BufStream {
             inner: Box::leak(Box::new(items)).into(),
             pos: 0,
@@ -44,15 +46,23 @@ where
         }
     }
 
-    unsafe fn inner(&self) -> &'a [(K, Option<V>)] {
+    unsafe fn inner(&self) -> &'a [(K, TimeStamp, Option<V>)] {
         self.inner.as_ref()
     }
 
-    unsafe fn take_item(&mut self) -> (K, Option<V>) {
-        let value = self.inner.as_mut()[self.pos].1.take();
-        let key = self.inner.as_ref()[self.pos].0.clone();
+    /// Moves to the first item at or after `key` in the order of `C`, by a binary search over
+    /// those left.
+    pub(crate) fn seek<C: Comparator<K>>(&mut self, key: &K) {
+        self.pos += unsafe {
+            self.inner()[self.pos..].partition_point(|(item, _, _)| C::compare(item, key).is_lt())
+        };
+    }
+
+    unsafe fn take_item(&mut self) -> (K, TimeStamp, Option<V>) {
+        let value = self.inner.as_mut()[self.pos].2.take();
+        let (key, ts, _) = &self.inner.as_ref()[self.pos];
 
-        (key, value)
+        (key.clone(), *ts, value)
     }
 }
 
@@ -61,7 +71,7 @@ where
     K: Ord + Clone + 'a,
     V: 'a,
 {
-    type Item = Result<(K, Option<V>), E>;
+    type Item = Result<(K, TimeStamp, Option<V>), E>;
 
     fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         Poll::Ready(unsafe { self.pos < self.inner().len() }.then(|| {
@@ -101,12 +111,12 @@ mod tests {
             let value_1 = "value_1".to_owned();
 
             let mut iter: Pin<&mut BufStream<_, _, Infallible>> = pin!(BufStream::new(vec![
-                (key_1.clone(), Some(value_1.clone())),
-                (key_2.clone(), None),
+                (key_1.clone(), 1, Some(value_1.clone())),
+                (key_2.clone(), 0, None),
             ]));
 
-            assert_eq!(iter.next().await.unwrap(), Ok((key_1, Some(value_1))));
-            assert_eq!(iter.next().await.unwrap(), Ok((key_2, None)));
+            assert_eq!(iter.next().await.unwrap(), Ok((key_1, 1, Some(value_1))));
+            assert_eq!(iter.next().await.unwrap(), Ok((key_2, 0, None)));
             assert_eq!(iter.next().await, None);
         });
     }
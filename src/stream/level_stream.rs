@@ -1,5 +1,6 @@
 use std::{
     collections::VecDeque,
+    ops::Bound,
     pin::{pin, Pin},
     task::{Context, Poll},
 };
@@ -10,9 +11,10 @@ use pin_project::pin_project;
 use snowflake::ProcessUniqueId;
 
 use crate::{
+    oracle::TimeStamp,
     schema::Schema,
-    stream::{table_stream::TableStream, StreamError},
-    DbOption,
+    stream::{table_stream::TableStream, ScanError, ScanFilter},
+    wal::provider::TableStore,
 };
 
 #[pin_project]
@@ -20,11 +22,13 @@ pub(crate) struct LevelStream<'stream, S>
 where
     S: Schema,
 {
-    lower: Option<S::PrimaryKey>,
-    upper: Option<S::PrimaryKey>,
-    option: &'stream DbOption,
+    lower: Bound<S::PrimaryKey>,
+    upper: Bound<S::PrimaryKey>,
+    table_store: &'stream dyn TableStore,
     gens: VecDeque<ProcessUniqueId>,
+    ts: TimeStamp,
     stream: Option<TableStream<'stream, S>>,
+    filter: Option<ScanFilter<S>>,
 }
 
 impl<'stream, S> LevelStream<'stream, S>
@@ -32,24 +36,30 @@ where
     S: Schema,
 {
     pub(crate) async fn new(
-        option: &'stream DbOption,
+        table_store: &'stream dyn TableStore,
         gens: Vec<ProcessUniqueId>,
-        lower: Option<&S::PrimaryKey>,
-        upper: Option<&S::PrimaryKey>,
-    ) -> Result<Self, StreamError<S::PrimaryKey, S>> {
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
+        ts: TimeStamp,
+        filter: Option<ScanFilter<S>>,
+    ) -> Result<Self, ScanError<S::PrimaryKey, S>> {
         let mut gens = VecDeque::from(gens);
         let mut stream = None;
 
         if let Some(gen) = gens.pop_front() {
-            stream = Some(TableStream::<S>::new(option, &gen, lower, upper).await?);
+            stream = Some(
+                TableStream::<S>::new(table_store, &gen, lower, upper, ts, filter.clone()).await?,
+            );
         }
 
         Ok(Self {
             lower: lower.cloned(),
             upper: upper.cloned(),
-            option,
+            table_store,
             gens,
+            ts,
             stream,
+            filter,
         })
     }
 }
@@ -58,7 +68,7 @@ impl<S> Stream for LevelStream<'_, S>
 where
     S: Schema,
 {
-    type Item = Result<(S::PrimaryKey, Option<S>), StreamError<S::PrimaryKey, S>>;
+    type Item = Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if let Some(stream) = &mut self.stream {
@@ -66,13 +76,15 @@ where
                 Poll::Ready(None) => match self.gens.pop_front() {
                     None => Poll::Ready(None),
                     Some(gen) => {
-                        let min = self.lower.clone();
-                        let max = self.upper.clone();
+                        let lower = self.lower.clone();
+                        let upper = self.upper.clone();
                         let mut future = pin!(TableStream::<S>::new(
-                            self.option,
+                            self.table_store,
                             &gen,
-                            min.as_ref(),
-                            max.as_ref()
+                            lower.as_ref(),
+                            upper.as_ref(),
+                            self.ts,
+                            self.filter.clone()
                         ));
 
                         match future.as_mut().poll(cx) {
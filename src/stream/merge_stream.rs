@@ -2,28 +2,86 @@ use std::{
     cmp::Reverse,
     collections::BinaryHeap,
     pin::{pin, Pin},
-    task::{Context, Poll},
+    task::{ready, Context, Poll},
+    time::Instant,
 };
 
 use executor::futures::StreamExt;
-use futures::Stream;
+use futures::{channel::mpsc::Sender, future::poll_fn, Stream};
 use pin_project::pin_project;
 
 use crate::{
+    comparator::Comparator,
+    oracle::TimeStamp,
     schema::Schema,
-    stream::{EStreamImpl, StreamError},
+    serdes::Encode,
+    stream::{EStreamImpl, ScanError, ScanFilter, ScanTruncated},
     utils::CmpKeyItem,
+    DeletionTrigger,
 };
 
+/// Merges sources given in precedence order, newest first: mutable shards, immutable batches,
+/// level 0 tables and then the deeper levels. Of the versions of a key only the newest is kept,
+/// and of those at the same timestamp the one of the first source, unless it is an operand, see
+/// [`Schema::is_operand`], which the older versions are folded into until one is not. Deleted
+/// keys are left out, except by [`MergeStream::next_versioned`].
+///
+/// The head of every source is kept in a heap, so each row costs `O(log sources)` however many
+/// tables and shards feed the scan.
 #[pin_project]
 pub struct MergeStream<'stream, S>
 where
     S: Schema,
 {
     #[allow(clippy::type_complexity)]
-    heap: BinaryHeap<Reverse<(CmpKeyItem<S::PrimaryKey, Option<S>>, usize)>>,
+    heap: BinaryHeap<
+        Reverse<(
+            CmpKeyItem<S::PrimaryKey, Option<S>, S::Comparator>,
+            Reverse<TimeStamp>,
+            usize,
+        )>,
+    >,
     iters: Vec<EStreamImpl<'stream, S>>,
-    item_buf: Option<(S::PrimaryKey, Option<S>)>,
+    item_buf: Option<(S::PrimaryKey, TimeStamp, Option<S>)>,
+    /// timestamp of the oldest version folded into `item_buf`
+    folded_at: TimeStamp,
+    keep_above: Option<TimeStamp>,
+    filter: Option<ScanFilter<S>>,
+    remaining: Option<usize>,
+    budget: Option<Budget<S::PrimaryKey>>,
+    deletes: Option<DeleteTally<S::PrimaryKey>>,
+}
+
+/// Counts the deleted keys a scan goes over, and reports the key range it went over once dropped
+/// if they pull the trigger.
+struct DeleteTally<K> {
+    trigger: DeletionTrigger,
+    report: Sender<(K, K)>,
+    range: Option<(K, K)>,
+    live: u64,
+    tombstones: u64,
+}
+
+impl<K> Drop for DeleteTally<K> {
+    fn drop(&mut self) {
+        let keys = self.live + self.tombstones;
+        if self.tombstones < self.trigger.min_tombstones
+            || (self.tombstones as f64) < self.trigger.ratio * keys as f64
+        {
+            return;
+        }
+        if let Some(range) = self.range.take() {
+            // a full queue drops the report, the next scan over the range reports it again
+            let _ = self.report.try_send(range);
+        }
+    }
+}
+
+struct Budget<K> {
+    max_bytes: Option<usize>,
+    deadline: Option<Instant>,
+    bytes: usize,
+    last_key: Option<K>,
 }
 
 impl<'stream, S> MergeStream<'stream, S>
@@ -31,21 +89,34 @@ where
     S: Schema,
 {
     pub(crate) async fn new(
+        iters: Vec<EStreamImpl<'stream, S>>,
+    ) -> Result<Self, ScanError<S::PrimaryKey, S>> {
+        Self::with_filter(iters, None).await
+    }
+
+    pub(crate) async fn with_filter(
         mut iters: Vec<EStreamImpl<'stream, S>>,
-    ) -> Result<Self, StreamError<S::PrimaryKey, S>> {
-        let mut heap = BinaryHeap::new();
+        filter: Option<ScanFilter<S>>,
+    ) -> Result<Self, ScanError<S::PrimaryKey, S>> {
+        let mut heap = BinaryHeap::with_capacity(iters.len());
 
         for (i, iter) in iters.iter_mut().enumerate() {
             if let Some(result) = Pin::new(iter).next().await {
-                let (key, value) = result?;
+                let (key, ts, value) = result?;
 
-                heap.push(Reverse((CmpKeyItem { key, _value: value }, i)));
+                heap.push(Reverse((CmpKeyItem::new(key, value), Reverse(ts), i)));
             }
         }
         let mut iterator = MergeStream {
             iters,
             heap,
             item_buf: None,
+            folded_at: TimeStamp::MAX,
+            keep_above: None,
+            filter,
+            remaining: None,
+            budget: None,
+            deletes: None,
         };
 
         {
@@ -55,53 +126,333 @@ where
 
         Ok(iterator)
     }
-}
 
-impl<'stream, S> Stream for MergeStream<'stream, S>
-where
-    S: Schema,
-{
-    type Item = Result<(S::PrimaryKey, Option<S>), StreamError<S::PrimaryKey, S>>;
+    /// Yields every version newer than `below` rather than only the newest of each key, then the
+    /// newest at or below it, folded as far as the sources go. Operands are yielded as they are
+    /// rather than folded onto nothing, for compactions keeping what reads since `below` see.
+    pub(crate) fn keep_versions(mut self, below: TimeStamp) -> Self {
+        self.keep_above = Some(below);
+        self
+    }
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let this = self.project();
-        while let Some(Reverse((
-            CmpKeyItem {
-                key: item_key,
-                _value: item_value,
-            },
-            idx,
-        ))) = this.heap.pop()
+    /// Ends the stream after `limit` rows that are not deleted. The sources are dropped as soon as
+    /// the last one is yielded, so that tables partially read stop being read.
+    pub(crate) fn limit(mut self, limit: usize) -> Self {
+        self.remaining = Some(limit);
+        if limit == 0 {
+            self.release();
+        }
+        self
+    }
+
+    /// Ends the stream with [`ScanError::Truncated`] once the rows yielded add up to more than
+    /// `max_bytes` encoded, or once `deadline` passed, if there are rows left.
+    pub(crate) fn budget(mut self, max_bytes: Option<usize>, deadline: Option<Instant>) -> Self {
+        if max_bytes.is_some() || deadline.is_some() {
+            self.budget = Some(Budget {
+                max_bytes,
+                deadline,
+                bytes: 0,
+                last_key: None,
+            });
+        }
+        self
+    }
+
+    /// Reports the key range the stream went over to `report` once dropped, if the deleted keys
+    /// it went over pull `trigger`.
+    pub(crate) fn observe_deletes(
+        mut self,
+        trigger: DeletionTrigger,
+        report: Sender<(S::PrimaryKey, S::PrimaryKey)>,
+    ) -> Self {
+        self.deletes = Some(DeleteTally {
+            trigger,
+            report,
+            range: None,
+            live: 0,
+            tombstones: 0,
+        });
+        self
+    }
+
+    /// Moves the stream to the first key at or after `key` without reopening its sources, e.g.
+    /// to probe the keys of a join in order. It only moves forward: a key not past the next one
+    /// leaves the stream where it is.
+    pub async fn seek(&mut self, key: &S::PrimaryKey) -> Result<(), ScanError<S::PrimaryKey, S>> {
+        if matches!(&self.item_buf, Some((next, _, _)) if S::Comparator::compare(next, key).is_ge())
         {
+            return Ok(());
+        }
+        self.item_buf = None;
+        while let Some(Reverse((head, _, idx))) = self.heap.peek() {
+            if S::Comparator::compare(&head.key, key).is_ge() {
+                break;
+            }
+            let idx = *idx;
+            let _ = self.heap.pop();
+            if let Some(item) = self.iters[idx].seek(key).await {
+                let (row_key, ts, value) = item?;
+                self.heap
+                    .push(Reverse((CmpKeyItem::new(row_key, value), Reverse(ts), idx)));
+            }
+        }
+        // buffers the newest version of the key the stream moved to
+        match poll_fn(|cx| Pin::new(&mut *self).poll_merged(cx)).await {
+            Some(Err(err)) => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn release(&mut self) {
+        self.heap.clear();
+        self.iters.clear();
+        self.item_buf = None;
+    }
+
+    /// Like `next`, but also yields the commit timestamp of each row, and deleted keys as `None`.
+    #[allow(clippy::type_complexity)]
+    pub(crate) async fn next_versioned(
+        &mut self,
+    ) -> Option<Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_versioned(cx)).await
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn poll_versioned(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>>>
+    {
+        if self.remaining == Some(0) {
+            return Poll::Ready(None);
+        }
+        loop {
+            let item = ready!(self.as_mut().poll_merged(cx));
+
+            match (&item, &self.filter) {
+                (Some(Ok((key, ts, value))), Some(filter)) if !filter(key, *ts, value.as_ref()) => {
+                    continue
+                }
+                _ => (),
+            }
+            if let (Some(Ok((key, _, value))), Some(budget)) = (&item, &mut self.budget) {
+                if budget.max_bytes.is_some_and(|max| budget.bytes > max)
+                    || budget
+                        .deadline
+                        .is_some_and(|deadline| Instant::now() >= deadline)
+                {
+                    let resume_after = budget.last_key.take();
+                    self.remaining = Some(0);
+                    self.release();
+                    return Poll::Ready(Some(Err(ScanError::Truncated(ScanTruncated {
+                        resume_after,
+                    }))));
+                }
+                budget.bytes += key.size() + value.as_ref().map(Encode::size).unwrap_or(0);
+                budget.last_key = Some(key.clone());
+            }
+            if let (Some(Ok((key, _, value))), Some(deletes)) = (&item, &mut self.deletes) {
+                match value {
+                    Some(_) => deletes.live += 1,
+                    None => deletes.tombstones += 1,
+                }
+                match &mut deletes.range {
+                    Some((_, max)) => *max = key.clone(),
+                    None => deletes.range = Some((key.clone(), key.clone())),
+                }
+            }
+            if let (Some(Ok((_, _, Some(_)))), Some(remaining)) = (&item, self.remaining) {
+                self.remaining = Some(remaining - 1);
+                if remaining == 1 {
+                    self.release();
+                }
+            }
+            return Poll::Ready(item);
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn poll_merged(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>>>
+    {
+        let this = self.project();
+        while let Some(head) = this.heap.pop() {
+            let idx = head.0 .2;
             match Pin::new(&mut this.iters[idx]).poll_next(cx) {
                 Poll::Ready(Some(item)) => {
-                    let (key, value) = item?;
+                    let (key, ts, value) = item?;
                     this.heap
-                        .push(Reverse((CmpKeyItem { key, _value: value }, idx)));
-
-                    if let Some((buf_key, _)) = &this.item_buf {
-                        if buf_key == &item_key {
-                            continue;
-                        }
-                    }
+                        .push(Reverse((CmpKeyItem::new(key, value), Reverse(ts), idx)));
                 }
                 Poll::Ready(None) => (),
-                Poll::Pending => return Poll::Pending,
+                Poll::Pending => {
+                    this.heap.push(head);
+                    return Poll::Pending;
+                }
             };
-            return Poll::Ready(this.item_buf.replace((item_key, item_value)).map(Ok));
+            let Reverse((
+                CmpKeyItem {
+                    key: item_key,
+                    _value: item_value,
+                    ..
+                },
+                Reverse(item_ts),
+                _,
+            )) = head;
+
+            // the newest version of the key was buffered first, older ones are dropped whichever
+            // source they come from, or folded into it while it is an operand
+            if let Some((buf_key, buf_ts, buf_value)) = this.item_buf.as_mut() {
+                // a version at the timestamp of one buffered already is that one, read from
+                // another source
+                if buf_key == &item_key && item_ts >= *this.folded_at {
+                    continue;
+                }
+                let kept = this.keep_above.is_some_and(|below| *buf_ts > below);
+                if buf_key == &item_key && !kept {
+                    if let Some(operand) = buf_value.as_ref().filter(|value| value.is_operand()) {
+                        *buf_value = Some(S::merge(item_value.as_ref(), operand));
+                        *this.folded_at = item_ts;
+                    }
+                    continue;
+                }
+            }
+            *this.folded_at = item_ts;
+            let keep_operands = this.keep_above.is_some();
+            return Poll::Ready(
+                this.item_buf
+                    .replace((item_key, item_ts, item_value))
+                    .map(|item| Ok(resolve(item, keep_operands))),
+            );
+        }
+        let keep_operands = this.keep_above.is_some();
+        Poll::Ready(
+            this.item_buf
+                .take()
+                .map(|item| Ok(resolve(item, keep_operands))),
+        )
+    }
+}
+
+/// Folds an operand no older version was left for onto nothing, unless `keep_operands`.
+fn resolve<S: Schema>(
+    (key, ts, value): (S::PrimaryKey, TimeStamp, Option<S>),
+    keep_operands: bool,
+) -> (S::PrimaryKey, TimeStamp, Option<S>) {
+    match value {
+        Some(operand) if operand.is_operand() && !keep_operands => {
+            (key, ts, Some(S::merge(None, &operand)))
+        }
+        value => (key, ts, value),
+    }
+}
+
+impl<'stream, S> Stream for MergeStream<'stream, S>
+where
+    S: Schema,
+{
+    type Item = Result<(S::PrimaryKey, Option<S>), ScanError<S::PrimaryKey, S>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(self.as_mut().poll_versioned(cx)) {
+                Some(Ok((_, _, None))) => continue,
+                item => {
+                    return Poll::Ready(
+                        item.map(|result| result.map(|(key, _, value)| (key, value))),
+                    )
+                }
+            }
+        }
+    }
+}
+
+/// A [`MergeStream`] yielding the commit timestamp of every row along with it.
+pub struct TimestampedStream<'stream, S>
+where
+    S: Schema,
+{
+    inner: MergeStream<'stream, S>,
+}
+
+impl<'stream, S> TimestampedStream<'stream, S>
+where
+    S: Schema,
+{
+    pub(crate) fn new(inner: MergeStream<'stream, S>) -> Self {
+        TimestampedStream { inner }
+    }
+
+    /// See [`MergeStream::seek`].
+    pub async fn seek(&mut self, key: &S::PrimaryKey) -> Result<(), ScanError<S::PrimaryKey, S>> {
+        self.inner.seek(key).await
+    }
+}
+
+impl<'stream, S> Stream for TimestampedStream<'stream, S>
+where
+    S: Schema,
+{
+    type Item = Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(Pin::new(&mut self.inner).poll_versioned(cx)) {
+                Some(Ok((_, _, None))) => continue,
+                item => return Poll::Ready(item),
+            }
+        }
+    }
+}
+
+/// A [`MergeStream`] yielding the rows committed after `since`, deletions included as `None`,
+/// see [`crate::Db::changes_since`].
+pub struct ChangeStream<'stream, S>
+where
+    S: Schema,
+{
+    inner: MergeStream<'stream, S>,
+    since: TimeStamp,
+}
+
+impl<'stream, S> ChangeStream<'stream, S>
+where
+    S: Schema,
+{
+    pub(crate) fn new(inner: MergeStream<'stream, S>, since: TimeStamp) -> Self {
+        ChangeStream { inner, since }
+    }
+}
+
+impl<'stream, S> Stream for ChangeStream<'stream, S>
+where
+    S: Schema,
+{
+    type Item = Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(Pin::new(&mut self.inner).poll_versioned(cx)) {
+                Some(Ok((_, ts, _))) if ts <= self.since => continue,
+                item => return Poll::Ready(item),
+            }
         }
-        Poll::Ready(this.item_buf.take().map(Ok))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::cmp::Reverse;
+
     use executor::futures::StreamExt;
-    use futures::executor::block_on;
+    use futures::{executor::block_on, future::ready, TryStreamExt};
 
     use crate::{
         stream::{buf_stream::BufStream, merge_stream::MergeStream, EStreamImpl},
-        tests::UserInner,
+        tests::{user, UserInner},
     };
 
     #[test]
@@ -110,6 +461,7 @@ mod tests {
             let iter_1 = BufStream::new(vec![
                 (
                     1,
+                    0,
                     Some(UserInner::new(
                         1,
                         "1".to_string(),
@@ -124,12 +476,13 @@ mod tests {
                         0,
                     )),
                 ),
-                (3, None),
+                (3, 0, None),
             ]);
             let iter_2 = BufStream::new(vec![
-                (1, None),
+                (1, 0, None),
                 (
                     2,
+                    0,
                     Some(UserInner::new(
                         2,
                         "2".to_string(),
@@ -144,11 +497,12 @@ mod tests {
                         0,
                     )),
                 ),
-                (4, None),
+                (4, 0, None),
             ]);
             let iter_3 = BufStream::new(vec![
                 (
                     5,
+                    0,
                     Some(UserInner::new(
                         3,
                         "3".to_string(),
@@ -163,7 +517,7 @@ mod tests {
                         0,
                     )),
                 ),
-                (6, None),
+                (6, 0, None),
             ]);
 
             let mut iterator = MergeStream::<UserInner>::new(vec![
@@ -174,7 +528,6 @@ mod tests {
             .await
             .unwrap();
 
-            assert_eq!(iterator.next().await.unwrap().unwrap(), (1, None));
             assert_eq!(
                 iterator.next().await.unwrap().unwrap(),
                 (
@@ -194,8 +547,6 @@ mod tests {
                     ))
                 )
             );
-            assert_eq!(iterator.next().await.unwrap().unwrap(), (3, None));
-            assert_eq!(iterator.next().await.unwrap().unwrap(), (4, None));
             assert_eq!(
                 iterator.next().await.unwrap().unwrap(),
                 (
@@ -215,7 +566,185 @@ mod tests {
                     ))
                 )
             );
-            assert_eq!(iterator.next().await.unwrap().unwrap(), (6, None));
+            assert!(iterator.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn seek() {
+        block_on(async {
+            let newer = BufStream::new(vec![
+                (2, 1, None),
+                (4, 1, Some(user(40))),
+                (7, 1, Some(user(7))),
+            ]);
+            let older = BufStream::new(
+                (0..10)
+                    .map(|key| (key, 0, Some(user(key))))
+                    .collect::<Vec<_>>(),
+            );
+            let mut iterator = MergeStream::<UserInner>::new(vec![
+                EStreamImpl::Buf(newer),
+                EStreamImpl::Buf(older),
+            ])
+            .await
+            .unwrap();
+
+            iterator.seek(&2).await.unwrap();
+            assert_eq!(iterator.next().await.unwrap().unwrap().0, 3);
+            iterator.seek(&4).await.unwrap();
+            assert_eq!(iterator.next().await.unwrap().unwrap(), (4, Some(user(40))));
+            // behind the stream already
+            iterator.seek(&1).await.unwrap();
+            assert_eq!(iterator.next().await.unwrap().unwrap().0, 5);
+            iterator.seek(&7).await.unwrap();
+            iterator.seek(&7).await.unwrap();
+            assert_eq!(iterator.next().await.unwrap().unwrap(), (7, Some(user(7))));
+            iterator.seek(&20).await.unwrap();
+            assert!(iterator.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn combinators() {
+        block_on(async {
+            let stream = || async {
+                MergeStream::<UserInner>::new(vec![EStreamImpl::Buf(BufStream::new(
+                    (0..10)
+                        .map(|key| (key, 0, (key != 3).then(|| user(key))))
+                        .collect(),
+                ))])
+                .await
+                .unwrap()
+            };
+
+            // spelled out, as the executor's stream extensions are in scope too
+            let rows = TryStreamExt::try_collect::<Vec<_>>(stream().await)
+                .await
+                .unwrap();
+            assert_eq!(rows.len(), 9);
+            let keys = futures::StreamExt::take_while(
+                futures::StreamExt::map(stream().await, |row| row.unwrap().0),
+                |key| ready(*key < 5),
+            );
+            let keys = futures::StreamExt::collect::<Vec<_>>(keys).await;
+            assert_eq!(keys, vec![0, 1, 2, 4]);
+        });
+    }
+
+    #[test]
+    fn many_sources() {
+        block_on(async {
+            let iters = (0..32)
+                .map(|i| {
+                    let mut items = (i..128)
+                        .step_by(32)
+                        .map(|key| (key, 1, Some(user(key))))
+                        .collect::<Vec<_>>();
+                    // an older deletion, shadowed by the put above it
+                    items.push((i + 32, 0, None));
+                    items.sort_by_key(|(key, ts, _)| (*key, Reverse(*ts)));
+
+                    EStreamImpl::Buf(BufStream::new(items))
+                })
+                .collect();
+
+            let iterator = MergeStream::<UserInner>::new(iters).await.unwrap();
+            let keys = iterator
+                .map(|item| item.unwrap().0)
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(keys, (0..128).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn resolve_versions() {
+        block_on(async {
+            let user = |id, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+            let newer = BufStream::new(vec![
+                (1, 2, None),
+                (2, 1, Some(user(2, "newer"))),
+                (3, 1, Some(user(3, "newer"))),
+            ]);
+            let older = BufStream::new(vec![
+                (1, 1, Some(user(1, "older"))),
+                (2, 3, Some(user(2, "older"))),
+                (3, 1, Some(user(3, "older"))),
+                (4, 1, Some(user(4, "older"))),
+            ]);
+
+            let mut iterator = MergeStream::<UserInner>::new(vec![
+                EStreamImpl::Buf(newer),
+                EStreamImpl::Buf(older),
+            ])
+            .await
+            .unwrap();
+            assert_eq!(
+                iterator.next_versioned().await.unwrap().unwrap(),
+                (1, 2, None)
+            );
+            assert_eq!(
+                iterator.next().await.unwrap().unwrap(),
+                (2, Some(user(2, "older")))
+            );
+            assert_eq!(
+                iterator.next().await.unwrap().unwrap(),
+                (3, Some(user(3, "newer")))
+            );
+            assert_eq!(
+                iterator.next().await.unwrap().unwrap(),
+                (4, Some(user(4, "older")))
+            );
+            assert!(iterator.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn dedup_exhausted_source() {
+        block_on(async {
+            let user = |id, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+            let newer = BufStream::new(vec![(1, 2, Some(user(1, "newer"))), (2, 1, None)]);
+            let older = BufStream::new(vec![(1, 1, Some(user(1, "older")))]);
+
+            let iterator = MergeStream::<UserInner>::new(vec![
+                EStreamImpl::Buf(newer),
+                EStreamImpl::Buf(older),
+            ])
+            .await
+            .unwrap();
+            let items = iterator.map(|item| item.unwrap()).collect::<Vec<_>>().await;
+            assert_eq!(items, vec![(1, Some(user(1, "newer")))]);
+        });
+    }
+
+    #[test]
+    fn limit() {
+        block_on(async {
+            let iters = vec![
+                EStreamImpl::Buf(BufStream::new(vec![(1, 1, None), (3, 1, Some(user(3)))])),
+                EStreamImpl::Buf(BufStream::new(vec![
+                    (2, 1, Some(user(2))),
+                    (4, 1, Some(user(4))),
+                    (5, 1, Some(user(5))),
+                ])),
+            ];
+
+            let mut iterator = MergeStream::<UserInner>::new(iters).await.unwrap().limit(2);
+            assert_eq!(iterator.next().await.unwrap().unwrap().0, 2);
+            assert_eq!(iterator.next().await.unwrap().unwrap().0, 3);
+            assert!(iterator.iters.is_empty());
+            assert!(iterator.next().await.is_none());
+
+            let iterator = MergeStream::<UserInner>::new(vec![])
+                .await
+                .unwrap()
+                .limit(0);
+            assert!(iterator.collect::<Vec<_>>().await.is_empty());
         });
     }
 }
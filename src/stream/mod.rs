@@ -1,16 +1,19 @@
 use std::{
     fmt::Debug,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
 };
 
-use executor::futures::Stream;
+use executor::futures::{Stream, StreamExt};
 use pin_project::pin_project;
 use thiserror::Error;
 
 use crate::{
+    comparator::Comparator,
     index_batch::stream::IndexBatchStream,
-    mem_table::stream::MemTableStream,
+    mem_table::stream::{MemTableStream, SharedMemTableStream},
+    oracle::TimeStamp,
     schema::Schema,
     serdes::{Decode, Encode},
     stream::{buf_stream::BufStream, level_stream::LevelStream, table_stream::TableStream},
@@ -21,17 +24,40 @@ pub(crate) mod batch_stream;
 pub(crate) mod buf_stream;
 pub(crate) mod level_stream;
 pub(crate) mod merge_stream;
+pub(crate) mod record_batch_stream;
 pub(crate) mod table_stream;
 
+/// Given the key, the timestamp and the value of each version read.
+pub(crate) type ScanFilter<S> =
+    Arc<dyn Fn(&<S as Schema>::PrimaryKey, TimeStamp, Option<&S>) -> bool + Send + Sync>;
+
+/// Rejected rows are masked as deletions rather than dropped, so that they still shadow older
+/// versions of their key further down the merge.
+pub(crate) fn mask<S>(
+    filter: Option<&ScanFilter<S>>,
+    key: S::PrimaryKey,
+    ts: TimeStamp,
+    value: Option<S>,
+) -> (S::PrimaryKey, TimeStamp, Option<S>)
+where
+    S: Schema,
+{
+    match filter {
+        Some(filter) if value.is_some() && !filter(&key, ts, value.as_ref()) => (key, ts, None),
+        _ => (key, ts, value),
+    }
+}
+
 #[pin_project(project = EStreamImplProj)]
 pub(crate) enum EStreamImpl<'a, S>
 where
     S: Schema,
 {
-    Buf(#[pin] BufStream<'a, S::PrimaryKey, S, StreamError<S::PrimaryKey, S>>),
-    IndexBatch(#[pin] IndexBatchStream<'a, S>),
+    Buf(#[pin] BufStream<'a, S::PrimaryKey, S, ScanError<S::PrimaryKey, S>>),
+    IndexBatch(#[pin] IndexBatchStream<S>),
     MemTable(#[pin] MemTableStream<'a, S>),
-    TransactionInner(#[pin] TransactionStream<'a, S, StreamError<S::PrimaryKey, S>>),
+    SharedMemTable(#[pin] SharedMemTableStream<S>),
+    TransactionInner(#[pin] TransactionStream<'a, S, ScanError<S::PrimaryKey, S>>),
     Table(#[pin] TableStream<'a, S>),
     Level(#[pin] LevelStream<'a, S>),
 }
@@ -40,13 +66,14 @@ impl<'a, S> Stream for EStreamImpl<'a, S>
 where
     S: Schema,
 {
-    type Item = Result<(S::PrimaryKey, Option<S>), StreamError<S::PrimaryKey, S>>;
+    type Item = Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         match self.project() {
             EStreamImplProj::Buf(stream) => stream.poll_next(cx),
             EStreamImplProj::IndexBatch(stream) => stream.poll_next(cx),
             EStreamImplProj::MemTable(stream) => stream.poll_next(cx),
+            EStreamImplProj::SharedMemTable(stream) => stream.poll_next(cx),
             EStreamImplProj::TransactionInner(stream) => stream.poll_next(cx),
             EStreamImplProj::Table(stream) => stream.poll_next(cx),
             EStreamImplProj::Level(stream) => stream.poll_next(cx),
@@ -54,6 +81,35 @@ where
     }
 }
 
+impl<S> EStreamImpl<'_, S>
+where
+    S: Schema,
+{
+    /// The first row at or after `key`, which must come after the rows yielded, skipping those
+    /// before it.
+    #[allow(clippy::type_complexity)]
+    pub(crate) async fn seek(
+        &mut self,
+        key: &S::PrimaryKey,
+    ) -> Option<Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>> {
+        match self {
+            EStreamImpl::Buf(stream) => stream.seek::<S::Comparator>(key),
+            EStreamImpl::IndexBatch(stream) => stream.seek(key),
+            EStreamImpl::SharedMemTable(stream) => stream.seek(key),
+            // the other sources only read forward, so the rows before `key` are read past
+            _ => loop {
+                match self.next().await {
+                    Some(Ok((row_key, _, _))) if S::Comparator::compare(&row_key, key).is_lt() => {
+                        continue
+                    }
+                    item => return item,
+                }
+            },
+        }
+        self.next().await
+    }
+}
+
 impl<S> std::fmt::Display for EStreamImpl<'_, S>
 where
     S: Schema,
@@ -63,6 +119,7 @@ where
             EStreamImpl::Buf(_) => write!(f, "BufStream"),
             EStreamImpl::IndexBatch(_) => write!(f, "IndexBatchStream"),
             EStreamImpl::MemTable(_) => write!(f, "MemTableStream"),
+            EStreamImpl::SharedMemTable(_) => write!(f, "SharedMemTableStream"),
             EStreamImpl::TransactionInner(_) => write!(f, "TransactionStream"),
             EStreamImpl::Table(_) => write!(f, "TableStream"),
             EStreamImpl::Level(_) => write!(f, "LevelStream"),
@@ -70,22 +127,38 @@ where
     }
 }
 
+/// What every scan, stream and compaction input fails with, whatever the schema.
 #[derive(Debug, Error)]
-pub enum StreamError<K, V>
+pub enum ScanError<K, V>
 where
     K: Encode + Decode,
     V: Decode,
 {
-    #[error("compaction key encode error: {0}")]
+    #[error("scan key encode error: {0}")]
     KeyEncode(#[source] <K as Encode>::Error),
-    #[error("compaction key decode error: {0}")]
+    #[error("scan key decode error: {0}")]
     KeyDecode(#[source] <K as Decode>::Error),
-    #[error("compaction value decode error: {0}")]
+    #[error("scan value decode error: {0}")]
     ValueDecode(#[source] <V as Decode>::Error),
-    #[error("compaction io error: {0}")]
+    #[error("scan io error: {0}")]
     Io(#[source] std::io::Error),
-    #[error("compaction arrow error: {0}")]
+    #[error("scan arrow error: {0}")]
     Arrow(#[source] arrow::error::ArrowError),
-    #[error("compaction parquet error: {0}")]
+    #[error("scan parquet error: {0}")]
     Parquet(#[source] parquet::errors::ParquetError),
+    /// A flush failed after taking immutable batches out of memory, so scans would miss their
+    /// rows until the db is reopened and recovers them from the wal.
+    #[error("db is poisoned by a failed flush")]
+    Poisoned,
+    /// The last item of a scan that ran out of its byte or time budget.
+    #[error("scan truncated by its limits")]
+    Truncated(ScanTruncated<K>),
+}
+
+/// Where a scan cut short by [`crate::ScanOptions::max_bytes`] or [`crate::ScanOptions::deadline`]
+/// stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanTruncated<K> {
+    /// The last key yielded, to resume the scan from past it; `None` if none was.
+    pub resume_after: Option<K>,
 }
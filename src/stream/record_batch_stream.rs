@@ -0,0 +1,85 @@
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use arrow::record_batch::RecordBatch;
+use futures::Stream;
+use pin_project::pin_project;
+
+use crate::{
+    schema::{Builder, Op, Schema},
+    serdes::Encode,
+    stream::{merge_stream::MergeStream, ScanError},
+};
+
+/// Packs the live rows of a merged scan into `RecordBatch`es of `S::inner_schema()`, cutting a
+/// batch once it reaches either the row or the byte target.
+#[pin_project]
+pub struct RecordBatchStream<'stream, S>
+where
+    S: Schema,
+{
+    #[pin]
+    inner: MergeStream<'stream, S>,
+    builder: S::Builder,
+    batch_rows: usize,
+    batch_bytes: usize,
+    rows: usize,
+    bytes: usize,
+}
+
+impl<'stream, S> RecordBatchStream<'stream, S>
+where
+    S: Schema,
+{
+    pub(crate) fn new(
+        inner: MergeStream<'stream, S>,
+        batch_rows: usize,
+        batch_bytes: usize,
+    ) -> Self {
+        RecordBatchStream {
+            inner,
+            builder: S::builder(),
+            batch_rows,
+            batch_bytes,
+            rows: 0,
+            bytes: 0,
+        }
+    }
+}
+
+impl<'stream, S> Stream for RecordBatchStream<'stream, S>
+where
+    S: Schema,
+{
+    type Item = Result<RecordBatch, ScanError<S::PrimaryKey, S>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+
+        loop {
+            match ready!(this.inner.as_mut().poll_versioned(cx)) {
+                Some(Ok((key, ts, Some(value)))) => {
+                    *this.rows += 1;
+                    *this.bytes += key.size() + value.size();
+                    this.builder.add(&key, ts, Op::Put, Some(value));
+
+                    if *this.rows >= *this.batch_rows || *this.bytes >= *this.batch_bytes {
+                        *this.rows = 0;
+                        *this.bytes = 0;
+                        return Poll::Ready(Some(Ok(this.builder.finish())));
+                    }
+                }
+                Some(Ok((_, _, None))) => (),
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None if *this.rows > 0 => {
+                    *this.rows = 0;
+                    *this.bytes = 0;
+                    return Poll::Ready(Some(Ok(this.builder.finish())));
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
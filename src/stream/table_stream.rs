@@ -1,32 +1,32 @@
 use std::{
-    fs::File,
     marker::PhantomData,
+    ops::Bound,
     pin::{pin, Pin},
     task::{Context, Poll},
 };
 
 use arrow::{
-    array::{GenericBinaryArray, GenericByteArray, Scalar},
-    compute::kernels::cmp::{gt_eq, lt_eq},
+    array::{GenericBinaryArray, GenericByteArray, Scalar, UInt64Array},
+    compute::kernels::cmp::{gt, gt_eq, lt, lt_eq},
     datatypes::GenericBinaryType,
 };
-use executor::{
-    fs,
-    futures::{Stream, StreamExt},
-};
+use executor::futures::{Stream, StreamExt};
 use parquet::arrow::{
     arrow_reader::{ArrowPredicate, ArrowPredicateFn, ArrowReaderMetadata, RowFilter},
-    async_reader::ParquetRecordBatchStream,
+    async_reader::{AsyncFileReader, ParquetRecordBatchStream},
     ParquetRecordBatchStreamBuilder, ProjectionMask,
 };
 use pin_project::pin_project;
 use snowflake::ProcessUniqueId;
 
 use crate::{
-    schema::Schema,
+    comparator::Comparator,
+    oracle::TimeStamp,
+    schema::{Schema, TS_COLUMN},
     serdes::Encode,
-    stream::{batch_stream::BatchStream, StreamError},
-    DbOption, Offset,
+    stream::{batch_stream::BatchStream, mask, ScanError, ScanFilter},
+    wal::provider::TableStore,
+    Offset,
 };
 
 #[pin_project]
@@ -34,8 +34,12 @@ pub(crate) struct TableStream<'stream, S>
 where
     S: Schema,
 {
-    inner: ParquetRecordBatchStream<fs::File>,
+    inner: ParquetRecordBatchStream<Box<dyn AsyncFileReader>>,
     stream: Option<BatchStream<S>>,
+    filter: Option<ScanFilter<S>>,
+    /// the key bounds not pushed down to the reader, as it compares keys by their encoding
+    lower: Bound<S::PrimaryKey>,
+    upper: Bound<S::PrimaryKey>,
     _p: PhantomData<&'stream ()>,
 }
 
@@ -44,68 +48,117 @@ where
     S: Schema,
 {
     pub(crate) async fn new(
-        option: &DbOption,
+        table_store: &dyn TableStore,
         gen: &ProcessUniqueId,
-        lower: Option<&S::PrimaryKey>,
-        upper: Option<&S::PrimaryKey>,
-    ) -> Result<Self, StreamError<S::PrimaryKey, S>> {
-        let lower = if let Some(l) = lower {
-            Some(Self::to_scalar(l).await?)
-        } else {
-            None
-        };
-        let upper = if let Some(u) = upper {
-            Some(Self::to_scalar(u).await?)
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
+        ts: TimeStamp,
+        filter: Option<ScanFilter<S>>,
+    ) -> Result<Self, ScanError<S::PrimaryKey, S>> {
+        let file = table_store.open_table(gen).await.map_err(ScanError::Io)?;
+
+        Self::from_file(file, lower, upper, ts, filter).await
+    }
+
+    pub(crate) async fn from_file(
+        mut file: Box<dyn AsyncFileReader>,
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
+        ts: TimeStamp,
+        filter: Option<ScanFilter<S>>,
+    ) -> Result<Self, ScanError<S::PrimaryKey, S>> {
+        let (key_bounds, lower, upper) = if S::Comparator::NATIVE {
+            (
+                (Bound::Unbounded, Bound::Unbounded),
+                Self::to_scalar_bound(lower).await?,
+                Self::to_scalar_bound(upper).await?,
+            )
         } else {
-            None
+            ((lower.cloned(), upper.cloned()), None, None)
         };
 
-        let mut file = fs::File::from(File::open(option.table_path(gen)).map_err(StreamError::Io)?);
         let meta = ArrowReaderMetadata::load_async(&mut file, Default::default())
             .await
-            .map_err(StreamError::Parquet)?;
+            .map_err(ScanError::Parquet)?;
         let mut builder = ParquetRecordBatchStreamBuilder::new_with_metadata(file, meta);
         let file_metadata = builder.metadata().file_metadata();
 
-        let mut predicates = Vec::with_capacity(2);
+        let mut predicates = Vec::with_capacity(3);
 
-        if let Some(lower_scalar) = lower {
+        if let Some((lower_scalar, inclusive)) = lower {
             predicates.push(Box::new(ArrowPredicateFn::new(
                 ProjectionMask::roots(file_metadata.schema_descr(), [0]),
-                move |record_batch| gt_eq(record_batch.column(0), &Scalar::new(&lower_scalar)),
+                move |record_batch| {
+                    let lower_scalar = Scalar::new(&lower_scalar);
+                    if inclusive {
+                        gt_eq(record_batch.column(0), &lower_scalar)
+                    } else {
+                        gt(record_batch.column(0), &lower_scalar)
+                    }
+                },
             )) as Box<dyn ArrowPredicate>)
         }
-        if let Some(upper_scalar) = upper {
+        if let Some((upper_scalar, inclusive)) = upper {
             predicates.push(Box::new(ArrowPredicateFn::new(
                 ProjectionMask::roots(file_metadata.schema_descr(), [0]),
-                move |record_batch| lt_eq(record_batch.column(0), &Scalar::new(&upper_scalar)),
+                move |record_batch| {
+                    let upper_scalar = Scalar::new(&upper_scalar);
+                    if inclusive {
+                        lt_eq(record_batch.column(0), &upper_scalar)
+                    } else {
+                        lt(record_batch.column(0), &upper_scalar)
+                    }
+                },
+            )) as Box<dyn ArrowPredicate>)
+        }
+
+        if ts < TimeStamp::MAX {
+            predicates.push(Box::new(ArrowPredicateFn::new(
+                ProjectionMask::roots(file_metadata.schema_descr(), [TS_COLUMN]),
+                move |record_batch| lt_eq(record_batch.column(0), &UInt64Array::new_scalar(ts)),
             )) as Box<dyn ArrowPredicate>)
         }
 
         let row_filter = RowFilter::new(predicates);
         builder = builder.with_row_filter(row_filter);
 
-        let mut reader = builder.build().map_err(StreamError::Parquet)?;
+        let mut reader = builder.build().map_err(ScanError::Parquet)?;
 
         let mut stream = None;
         if let Some(result) = reader.next().await {
-            stream = Some(BatchStream::new(result.map_err(StreamError::Parquet)?));
+            stream = Some(BatchStream::new(result.map_err(ScanError::Parquet)?));
         }
 
         Ok(TableStream {
             inner: reader,
             stream,
+            filter,
+            lower: key_bounds.0,
+            upper: key_bounds.1,
             _p: Default::default(),
         })
     }
 
+    async fn to_scalar_bound(
+        bound: Bound<&S::PrimaryKey>,
+    ) -> Result<
+        Option<(GenericByteArray<GenericBinaryType<Offset>>, bool)>,
+        ScanError<S::PrimaryKey, S>,
+    > {
+        Ok(match bound {
+            Bound::Included(key) => Some((Self::to_scalar(key).await?, true)),
+            Bound::Excluded(key) => Some((Self::to_scalar(key).await?, false)),
+            Bound::Unbounded => None,
+        })
+    }
+
     async fn to_scalar(
         key: &S::PrimaryKey,
-    ) -> Result<GenericByteArray<GenericBinaryType<Offset>>, StreamError<S::PrimaryKey, S>> {
+    ) -> Result<GenericByteArray<GenericBinaryType<Offset>>, ScanError<S::PrimaryKey, S>> {
         let mut key_bytes = Vec::new();
         key.encode(&mut key_bytes)
             .await
-            .map_err(StreamError::KeyEncode)?;
+            .map_err(ScanError::KeyEncode)?;
 
         Ok(GenericBinaryArray::<Offset>::from(vec![
             key_bytes.as_slice()
@@ -117,24 +170,36 @@ impl<S> Stream for TableStream<'_, S>
 where
     S: Schema,
 {
-    type Item = Result<(S::PrimaryKey, Option<S>), StreamError<S::PrimaryKey, S>>;
+    type Item = Result<(S::PrimaryKey, TimeStamp, Option<S>), ScanError<S::PrimaryKey, S>>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if self.stream.is_none() {
-            return Poll::Ready(None);
-        }
-        // Safety: It cannot be none here, because it has been judged above
-        match Pin::new(self.stream.as_mut().unwrap()).poll_next(cx) {
-            Poll::Ready(None) => match Pin::new(&mut self.inner).poll_next(cx) {
-                Poll::Ready(Some(Ok(batch))) => {
-                    self.stream = Some(BatchStream::new(batch));
-                    self.poll_next(cx)
+        loop {
+            let Some(stream) = self.stream.as_mut() else {
+                return Poll::Ready(None);
+            };
+            return match Pin::new(stream).poll_next(cx) {
+                Poll::Ready(None) => match Pin::new(&mut self.inner).poll_next(cx) {
+                    Poll::Ready(Some(Ok(batch))) => {
+                        self.stream = Some(BatchStream::new(batch));
+                        continue;
+                    }
+                    Poll::Ready(Some(Err(err))) => Poll::Ready(Some(Err(ScanError::Parquet(err)))),
+                    Poll::Ready(None) => Poll::Ready(None),
+                    Poll::Pending => Poll::Pending,
+                },
+                Poll::Ready(Some(Ok((key, ts, value)))) => {
+                    // rows are in the order of the comparator, the ones past `upper` end the scan
+                    if !S::Comparator::contains(self.lower.as_ref(), Bound::Unbounded, &key) {
+                        continue;
+                    }
+                    if !S::Comparator::contains(Bound::Unbounded, self.upper.as_ref(), &key) {
+                        self.stream = None;
+                        return Poll::Ready(None);
+                    }
+                    Poll::Ready(Some(Ok(mask(self.filter.as_ref(), key, ts, value))))
                 }
-                Poll::Ready(Some(Err(err))) => Poll::Ready(Some(Err(StreamError::Parquet(err)))),
-                Poll::Ready(None) => Poll::Ready(None),
-                Poll::Pending => Poll::Pending,
-            },
-            poll => poll,
+                poll => poll,
+            };
         }
     }
 }
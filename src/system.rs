@@ -0,0 +1,425 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io,
+    mem::size_of,
+};
+
+use async_lock::Mutex;
+use executor::{
+    fs,
+    futures::{
+        util::{AsyncReadExt, AsyncWriteExt},
+        AsyncRead, AsyncWrite,
+    },
+};
+
+use crate::{
+    serdes::{Decode, Encode},
+    DbOption,
+};
+
+pub(crate) const IDEMPOTENCY_PREFIX: &str = "idempotency/";
+pub(crate) const AGGREGATE_PREFIX: &str = "aggregate/";
+pub(crate) const AGGREGATE_COMMIT_PREFIX: &str = "aggregate_commit/";
+pub(crate) const AGGREGATE_APPLIED_KEY: &str = "aggregate_applied";
+pub(crate) const QUEUE_PREFIX: &str = "queue/";
+pub(crate) const RANGE_DELETE_PREFIX: &str = "range_delete/";
+pub(crate) const SCHEMA_KEY: &str = "schema";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum SystemEdit {
+    Set {
+        key: String,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: String,
+    },
+    /// Edits recovered all or none, e.g. those of one commit.
+    Batch(Vec<SystemEdit>),
+}
+
+impl SystemEdit {
+    fn apply(self, data: &mut BTreeMap<String, Vec<u8>>) {
+        match self {
+            SystemEdit::Set { key, value } => {
+                let _ = data.insert(key, value);
+            }
+            SystemEdit::Remove { key } => {
+                let _ = data.remove(&key);
+            }
+            SystemEdit::Batch(edits) => {
+                for edit in edits {
+                    edit.apply(data);
+                }
+            }
+        }
+    }
+
+    async fn encode_single<W: AsyncWrite + Unpin + Send + Sync>(
+        &self,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        match self {
+            SystemEdit::Set { key, value } => {
+                writer.write_all(&0u8.to_le_bytes()).await?;
+                key.encode(writer).await?;
+                writer
+                    .write_all(&(value.len() as u32).to_le_bytes())
+                    .await?;
+                writer.write_all(value).await?;
+            }
+            SystemEdit::Remove { key } => {
+                writer.write_all(&1u8.to_le_bytes()).await?;
+                key.encode(writer).await?;
+            }
+            SystemEdit::Batch(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "system edit batches do not nest",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes an edit of `edit_type` other than a batch, which do not nest.
+    async fn decode_single<R: AsyncRead + Unpin>(
+        edit_type: u8,
+        reader: &mut R,
+    ) -> io::Result<Self> {
+        let key = String::decode(reader).await?;
+
+        match edit_type {
+            0 => {
+                let len = {
+                    let mut len = [0; size_of::<u32>()];
+                    reader.read_exact(&mut len).await?;
+                    u32::from_le_bytes(len) as usize
+                };
+                let mut value = vec![0; len];
+                reader.read_exact(&mut value).await?;
+
+                Ok(SystemEdit::Set { key, value })
+            }
+            1 => Ok(SystemEdit::Remove { key }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid system edit type",
+            )),
+        }
+    }
+}
+
+impl Encode for SystemEdit {
+    type Error = io::Error;
+
+    async fn encode<W: AsyncWrite + Unpin + Send + Sync>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), Self::Error> {
+        let SystemEdit::Batch(edits) = self else {
+            return self.encode_single(writer).await;
+        };
+        writer.write_all(&2u8.to_le_bytes()).await?;
+        writer
+            .write_all(&(edits.len() as u32).to_le_bytes())
+            .await?;
+        for edit in edits {
+            edit.encode_single(writer).await?;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> usize {
+        size_of::<u8>()
+            + match self {
+                SystemEdit::Set { key, value } => key.size() + size_of::<u32>() + value.len(),
+                SystemEdit::Remove { key } => key.size(),
+                SystemEdit::Batch(edits) => {
+                    size_of::<u32>() + edits.iter().map(Encode::size).sum::<usize>()
+                }
+            }
+    }
+}
+
+impl Decode for SystemEdit {
+    type Error = io::Error;
+
+    async fn decode<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, Self::Error> {
+        let edit_type = {
+            let mut edit_type = [0; size_of::<u8>()];
+            reader.read_exact(&mut edit_type).await?;
+            u8::from_le_bytes(edit_type)
+        };
+        if edit_type != 2 {
+            return SystemEdit::decode_single(edit_type, reader).await;
+        }
+        let len = {
+            let mut len = [0; size_of::<u32>()];
+            reader.read_exact(&mut len).await?;
+            u32::from_le_bytes(len) as usize
+        };
+        // a batch torn by a crash fails to decode as a whole
+        let mut edits = Vec::with_capacity(len);
+        for _ in 0..len {
+            let edit_type = {
+                let mut edit_type = [0; size_of::<u8>()];
+                reader.read_exact(&mut edit_type).await?;
+                u8::from_le_bytes(edit_type)
+            };
+            edits.push(SystemEdit::decode_single(edit_type, reader).await?);
+        }
+        Ok(SystemEdit::Batch(edits))
+    }
+}
+
+struct SystemTableInner {
+    data: BTreeMap<String, Vec<u8>>,
+    log: fs::File,
+}
+
+impl SystemTableInner {
+    async fn apply(&mut self, edit: SystemEdit) -> io::Result<()> {
+        edit.encode(&mut self.log).await?;
+        self.log.flush().await?;
+        edit.apply(&mut self.data);
+        Ok(())
+    }
+
+    async fn apply_all(&mut self, edits: Vec<SystemEdit>) -> io::Result<()> {
+        if edits.is_empty() {
+            return Ok(());
+        }
+        self.apply(SystemEdit::Batch(edits)).await
+    }
+}
+
+/// Small crate-managed keyspace for metadata, kept apart from user data and persisted in
+/// `system.log`.
+pub struct SystemTable {
+    inner: Mutex<SystemTableInner>,
+}
+
+impl SystemTable {
+    pub(crate) async fn new(option: &DbOption) -> io::Result<Self> {
+        let path = option.system_path();
+        let mut data = BTreeMap::new();
+
+        if path.exists() {
+            let mut log = fs::File::from(File::open(&path)?);
+
+            while let Ok(edit) = SystemEdit::decode(&mut log).await {
+                edit.apply(&mut data);
+            }
+        }
+        // rewrite the log with only live entries so that it does not grow across restarts
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut log = fs::File::from(File::create(&tmp_path)?);
+
+            for (key, value) in data.iter() {
+                SystemEdit::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                }
+                .encode(&mut log)
+                .await?;
+            }
+            log.flush().await?;
+        }
+        std::fs::rename(&tmp_path, &path)?;
+
+        let log = fs::File::from(OpenOptions::new().append(true).open(&path)?);
+
+        Ok(SystemTable {
+            inner: Mutex::new(SystemTableInner { data, log }),
+        })
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.inner.lock().await.data.get(key).cloned()
+    }
+
+    pub async fn set(&self, key: impl Into<String>, value: Vec<u8>) -> io::Result<()> {
+        self.inner
+            .lock()
+            .await
+            .apply(SystemEdit::Set {
+                key: key.into(),
+                value,
+            })
+            .await
+    }
+
+    pub async fn remove(&self, key: &str) -> io::Result<()> {
+        let mut guard = self.inner.lock().await;
+
+        if !guard.data.contains_key(key) {
+            return Ok(());
+        }
+        guard
+            .apply(SystemEdit::Remove {
+                key: key.to_string(),
+            })
+            .await
+    }
+
+    pub async fn scan_prefix(&self, prefix: &str) -> Vec<(String, Vec<u8>)> {
+        self.inner
+            .lock()
+            .await
+            .data
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Atomically replaces the value of `key` with the output of `f`, returning whether it was
+    /// replaced. `f` returning `None` leaves the entry untouched.
+    pub(crate) async fn update<F>(&self, key: &str, f: F) -> io::Result<bool>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let mut guard = self.inner.lock().await;
+
+        match f(guard.data.get(key).map(Vec::as_slice)) {
+            Some(value) => {
+                guard
+                    .apply(SystemEdit::Set {
+                        key: key.to_string(),
+                        value,
+                    })
+                    .await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Applies the edits `f` makes of the current entries, recovered all or none.
+    pub(crate) async fn apply_with<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&BTreeMap<String, Vec<u8>>) -> Vec<SystemEdit>,
+    {
+        let mut guard = self.inner.lock().await;
+        let edits = f(&guard.data);
+        guard.apply_all(edits).await
+    }
+
+    pub(crate) async fn retain_prefix<F>(&self, prefix: &str, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        let mut guard = self.inner.lock().await;
+        let removed = guard
+            .data
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, value)| !f(value))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in removed {
+            guard.apply(SystemEdit::Remove { key }).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use executor::ExecutorBuilder;
+    use tempfile::TempDir;
+
+    use crate::{
+        system::{SystemEdit, SystemTable},
+        DbOption,
+    };
+
+    #[test]
+    fn recover() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let option = DbOption::new(temp_dir.path().to_path_buf());
+
+            {
+                let table = SystemTable::new(&option).await.unwrap();
+
+                table.set("a/0", vec![0]).await.unwrap();
+                table.set("a/1", vec![1]).await.unwrap();
+                table.set("b/0", vec![2]).await.unwrap();
+                table.set("a/0", vec![3]).await.unwrap();
+                table.remove("a/1").await.unwrap();
+            }
+            let table = SystemTable::new(&option).await.unwrap();
+
+            assert_eq!(table.get("a/0").await, Some(vec![3]));
+            assert_eq!(table.get("a/1").await, None);
+            assert_eq!(
+                table.scan_prefix("a/").await,
+                vec![("a/0".to_string(), vec![3])]
+            );
+            assert!(!table.update("b/0", |_| None).await.unwrap());
+            assert!(table.update("b/0", |_| Some(vec![4])).await.unwrap());
+            assert_eq!(table.get("b/0").await, Some(vec![4]));
+
+            table
+                .apply_with(|data| {
+                    [("b/0", 1), ("b/1", 2)]
+                        .into_iter()
+                        .map(|(key, item)| SystemEdit::Set {
+                            key: key.to_string(),
+                            value: vec![data.get(key).map_or(0, |value| value[0]) + item],
+                        })
+                        .collect()
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                table.scan_prefix("b/").await,
+                vec![("b/0".to_string(), vec![5]), ("b/1".to_string(), vec![2])]
+            );
+        });
+    }
+
+    #[test]
+    fn torn_batch() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let option = DbOption::new(temp_dir.path().to_path_buf());
+
+            {
+                let table = SystemTable::new(&option).await.unwrap();
+
+                table.set("a/0", vec![0]).await.unwrap();
+                table
+                    .apply_with(|_| {
+                        vec![
+                            SystemEdit::Set {
+                                key: "a/0".to_string(),
+                                value: vec![1],
+                            },
+                            SystemEdit::Remove {
+                                key: "a/0".to_string(),
+                            },
+                        ]
+                    })
+                    .await
+                    .unwrap();
+            }
+            let log = std::fs::read(option.system_path()).unwrap();
+            std::fs::write(option.system_path(), &log[..log.len() - 1]).unwrap();
+
+            let table = SystemTable::new(&option).await.unwrap();
+            assert_eq!(
+                table.scan_prefix("a/").await,
+                vec![("a/0".to_string(), vec![0])]
+            );
+        });
+    }
+}
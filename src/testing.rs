@@ -0,0 +1,333 @@
+//! An in-memory store for unit-testing transaction logic without a wal, tables or executor.
+
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    error, io,
+    ops::Bound,
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_lock::RwLock;
+use executor::futures::StreamExt;
+use futures::future::ready;
+use futures_timer::Delay;
+
+use crate::{
+    idempotency::{Pending, Reservation},
+    mem_table::MemTable,
+    oracle::{
+        ConflictChecker, LocalClock, LocalConflictChecker, OracleState, TimeStamp,
+        TimestampProvider, WriteCommitError, WriteConflict,
+    },
+    record::{RangeDelete, RecordType, SystemRecord},
+    schema::Schema,
+    stream::{buf_stream::BufStream, EStreamImpl, ScanError},
+    transaction::{Transaction, TxnListener},
+    validate::ValidationError,
+    watermark::{InFlight, Watermark},
+    GetWrite,
+};
+
+/// Keeps every version in memory and checks conflicts like [`crate::oracle::LocalOracle`].
+/// Conflicts can be forced on upcoming commits and every read and write can be slowed down.
+#[derive(Debug)]
+pub struct MockStore<S>
+where
+    S: Schema,
+{
+    clock: LocalClock,
+    conflicts: LocalConflictChecker<S::PrimaryKey>,
+    data: RwLock<MemTable<S>>,
+    latency: Duration,
+    watermark: Arc<Watermark>,
+    injected: Mutex<VecDeque<Vec<S::PrimaryKey>>>,
+    idempotency_keys: Mutex<HashSet<String>>,
+    pending: Pending,
+}
+
+impl<S> Default for MockStore<S>
+where
+    S: Schema,
+{
+    fn default() -> Self {
+        MockStore {
+            clock: LocalClock::default(),
+            conflicts: LocalConflictChecker::default(),
+            data: RwLock::new(MemTable::default()),
+            latency: Duration::ZERO,
+            watermark: Arc::default(),
+            injected: Mutex::new(VecDeque::new()),
+            idempotency_keys: Mutex::new(HashSet::new()),
+            pending: Pending::default(),
+        }
+    }
+}
+
+impl<S> MockStore<S>
+where
+    S: Schema,
+{
+    /// Delays every lookup, write and scan by `latency`.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Fails the next commit not failed by an earlier injection with a conflict on `keys`.
+    pub fn inject_conflict(&self, keys: impl IntoIterator<Item = S::PrimaryKey>) {
+        self.injected
+            .lock()
+            .unwrap()
+            .push_back(keys.into_iter().collect());
+    }
+
+    pub fn new_txn(self: &Arc<Self>) -> Transaction<S, Self> {
+        Transaction::new(self.clone())
+    }
+
+    pub fn txn(&self) -> Transaction<S, Self, &Self> {
+        Transaction::new(self)
+    }
+
+    async fn delay(&self) {
+        if !self.latency.is_zero() {
+            Delay::new(self.latency).await;
+        }
+    }
+}
+
+impl<S> TimestampProvider for MockStore<S>
+where
+    S: Schema,
+{
+    fn start_read(&self) -> TimeStamp {
+        self.clock.start_read()
+    }
+
+    fn read_commit(&self, ts: TimeStamp) {
+        self.clock.read_commit(ts)
+    }
+
+    fn start_write(&self) -> TimeStamp {
+        self.clock.start_write()
+    }
+
+    fn now(&self) -> TimeStamp {
+        self.clock.now()
+    }
+
+    fn advance(&self, ts: TimeStamp) -> TimeStamp {
+        self.clock.advance(ts)
+    }
+
+    fn oldest_read(&self) -> TimeStamp {
+        self.clock.oldest_read()
+    }
+
+    fn active_reads(&self) -> Vec<(TimeStamp, usize)> {
+        self.clock.active_reads()
+    }
+}
+
+impl<S> ConflictChecker<S::PrimaryKey> for MockStore<S>
+where
+    S: Schema,
+{
+    fn check_commit(
+        &self,
+        read_at: TimeStamp,
+        write_at: TimeStamp,
+        in_write: HashSet<S::PrimaryKey>,
+        oldest_read: TimeStamp,
+    ) -> Result<(), WriteCommitError<S::PrimaryKey>> {
+        if let Some(keys) = self.injected.lock().unwrap().pop_front() {
+            return Err(WriteConflict::new(keys).into());
+        }
+        self.conflicts
+            .check_commit(read_at, write_at, in_write, oldest_read)
+    }
+
+    fn describe(&self, state: &mut OracleState) {
+        self.conflicts.describe(state)
+    }
+}
+
+impl<S> GetWrite<S> for MockStore<S>
+where
+    S: Schema,
+{
+    async fn get(&self, key: &S::PrimaryKey, ts: &TimeStamp) -> Option<S> {
+        self.delay().await;
+        self.data.read().await.get(key, ts).flatten().cloned()
+    }
+
+    fn begin_write(&self) -> (TimeStamp, InFlight) {
+        let ts = self.clock.start_write();
+        (ts, self.watermark.begin(ts))
+    }
+
+    async fn write(
+        &self,
+        _: RecordType,
+        ts: TimeStamp,
+        value: S,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        self.delay().await;
+        self.data
+            .write()
+            .await
+            .insert(value.primary_key(), ts, Some(value));
+        Ok(())
+    }
+
+    async fn remove(
+        &self,
+        _: RecordType,
+        ts: TimeStamp,
+        key: S::PrimaryKey,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        self.delay().await;
+        self.data.write().await.insert(key, ts, None);
+        Ok(())
+    }
+
+    /// Deletes the keys of the ranges one by one before writing `kvs`, the commit's own writes at
+    /// the timestamp of the ranges replace the deletions.
+    async fn write_batch(
+        &self,
+        kvs: impl ExactSizeIterator<Item = (S::PrimaryKey, TimeStamp, Option<S>)>,
+        ranges: Vec<RangeDelete<S::PrimaryKey>>,
+        _: Vec<SystemRecord>,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        self.delay().await;
+        let mut data = self.data.write().await;
+        let mut keys = Vec::new();
+        for range in ranges.iter() {
+            let mut iter = pin!(
+                data.range(range.lower.as_ref(), range.upper.as_ref(), &range.ts)
+                    .await?
+            );
+            while let Some(item) = iter.next().await {
+                keys.push((item?.0, range.ts));
+            }
+        }
+        for (key, ts) in keys {
+            data.insert(key, ts, None);
+        }
+        for (key, ts, value) in kvs {
+            data.insert(key, ts, value);
+        }
+        Ok(())
+    }
+
+    fn check_size(
+        &self,
+        _: &S::PrimaryKey,
+        _: Option<&S>,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync + 'static>> {
+        Ok(())
+    }
+
+    fn validate(&self, _: &S::PrimaryKey, _: Option<&S>) -> Result<(), ValidationError> {
+        Ok(())
+    }
+
+    fn intercept(&self, _: TimeStamp, _: &mut BTreeMap<S::PrimaryKey, Option<S>>) {}
+
+    fn sample_txn(&self) -> Option<Arc<dyn TxnListener>> {
+        None
+    }
+
+    async fn inner_range<'a>(
+        &'a self,
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
+        ts: &TimeStamp,
+    ) -> Result<Vec<EStreamImpl<'a, S>>, ScanError<S::PrimaryKey, S>>
+    where
+        S::PrimaryKey: 'a,
+        TimeStamp: 'a,
+        S: 'a,
+    {
+        self.delay().await;
+        let data = self.data.read().await;
+        let mut iter = pin!(data.range(lower, upper, ts).await?);
+        let mut items = Vec::new();
+        while let Some(item) = iter.next().await {
+            items.push(item?);
+        }
+        Ok(vec![EStreamImpl::Buf(BufStream::new(items))])
+    }
+
+    async fn reserve_idempotency_key(&self, key: &str) -> io::Result<Option<Reservation>> {
+        self.pending
+            .reserve(key, || {
+                ready(Ok(self.idempotency_keys.lock().unwrap().contains(key)))
+            })
+            .await
+    }
+
+    async fn commit_idempotency_key(&self, reservation: Reservation, _: SystemRecord) {
+        self.idempotency_keys
+            .lock()
+            .unwrap()
+            .insert(reservation.token().to_string());
+        reservation.commit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use executor::futures::StreamExt;
+    use futures::executor::block_on;
+
+    use super::MockStore;
+    use crate::{tests::UserInner, transaction::CommitError};
+
+    #[test]
+    fn scripted_store() {
+        block_on(async {
+            let store = Arc::new(MockStore::default().with_latency(Duration::from_millis(1)));
+            let user = |id, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+
+            let mut txn = store.new_txn();
+            txn.set(0, user(0, "0"));
+            txn.set(1, user(1, "1"));
+            txn.commit().await.unwrap();
+
+            store.inject_conflict([1]);
+            let mut txn = store.txn();
+            txn.remove(0);
+            match txn.commit().await {
+                Err(CommitError::WriteConflict(keys)) => assert_eq!(keys, vec![1]),
+                _ => panic!("conflict not injected"),
+            }
+
+            let mut txn = store.txn();
+            txn.remove(0);
+            txn.commit().await.unwrap();
+
+            let started = Instant::now();
+            let txn = store.txn();
+            assert_eq!(txn.get(&0).await, None);
+            let rows = txn
+                .range(..)
+                .await
+                .unwrap()
+                .map(|row| row.unwrap())
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(rows, vec![(1, Some(user(1, "1")))]);
+            assert!(started.elapsed() >= Duration::from_millis(2));
+        });
+    }
+}
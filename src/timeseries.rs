@@ -0,0 +1,107 @@
+//! Keys of time-series rows, `(series, ts)` encoded so that the points of a series sort together,
+//! latest first, see [`Db::latest`].
+
+use std::{io, ops::Bound};
+
+use futures::{AsyncWrite, StreamExt};
+
+use crate::{
+    oracle::{Oracle, TimeStamp},
+    schema::Schema,
+    serdes::Decode,
+    stream::ScanError,
+    wal::provider::StorageProvider,
+    Db, ScanOptions,
+};
+
+/// Ends the series in a key, it sorts before every character a series may hold.
+const SEPARATOR: char = '\0';
+const TS_DIGITS: usize = 16;
+
+/// The key of the point of `series` at `ts`. The timestamp is stored inverted in fixed width hex,
+/// so that byte order is series ascending, then timestamp descending. `series` must not contain
+/// `'\0'`.
+pub fn series_key(series: &str, ts: u64) -> String {
+    debug_assert!(!series.contains(SEPARATOR));
+    format!("{}{}{:016x}", series, SEPARATOR, !ts)
+}
+
+/// Splits a key made by [`series_key`] back into its series and timestamp.
+pub fn split_series_key(key: &str) -> Option<(&str, u64)> {
+    let (series, ts) = key.rsplit_once(SEPARATOR)?;
+    if ts.len() != TS_DIGITS {
+        return None;
+    }
+    Some((series, !u64::from_str_radix(ts, 16).ok()?))
+}
+
+impl<S, O, WP> Db<S, O, WP>
+where
+    S: Schema<PrimaryKey = String>,
+    O: Oracle<S::PrimaryKey>,
+    WP: StorageProvider,
+    WP::File: AsyncWrite,
+    io::Error: From<<S as Decode>::Error>,
+{
+    /// The latest point of `series` visible at `ts`, with its key, found by a single seek to the
+    /// start of the series since its points are stored latest first.
+    pub async fn latest(
+        &self,
+        series: &str,
+        ts: &TimeStamp,
+    ) -> Result<Option<(String, S)>, ScanError<S::PrimaryKey, S>> {
+        let (lower, upper) = (series_key(series, u64::MAX), series_key(series, 0));
+        let mut stream = self
+            .range_with_options(
+                Bound::Included(&lower),
+                Bound::Included(&upper),
+                ts,
+                &ScanOptions::default().limit(1),
+            )
+            .await?;
+
+        match stream.next().await {
+            Some(Ok((key, Some(value)))) => Ok(Some((key, value))),
+            Some(Err(err)) => Err(err),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{series_key, split_series_key};
+
+    #[test]
+    fn latest_first() {
+        let mut keys = vec![
+            series_key("cpu", 1),
+            series_key("cpu", 300),
+            series_key("cpu", 20),
+            series_key("cpu/1", 5),
+            series_key("c", 7),
+            series_key("cpu", u64::MAX),
+            series_key("cpu", 0),
+        ];
+        keys.sort();
+
+        let points = keys
+            .iter()
+            .map(|key| split_series_key(key).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            points,
+            vec![
+                ("c", 7),
+                ("cpu", u64::MAX),
+                ("cpu", 300),
+                ("cpu", 20),
+                ("cpu", 1),
+                ("cpu", 0),
+                ("cpu/1", 5),
+            ]
+        );
+        assert_eq!(split_series_key("cpu"), None);
+        assert_eq!(split_series_key("cpu\0ff"), None);
+    }
+}
@@ -1,56 +1,162 @@
 use std::{
-    collections::{btree_map, btree_map::Entry, BTreeMap},
-    fmt::Debug,
+    collections::{btree_map::Entry, BTreeMap},
+    fmt::{self, Debug, Formatter},
     marker::PhantomData,
-    ops::Bound,
-    pin::Pin,
-    sync::Arc,
+    mem,
+    ops::{Bound, Deref, RangeBounds},
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
+    time::{Duration, Instant},
+    vec,
 };
 
-use executor::futures::Stream;
+use executor::futures::{Stream, StreamExt};
+use futures::future::join_all;
 use pin_project::pin_project;
 use thiserror::Error;
 
 use crate::{
-    oracle::{TimeStamp, WriteConflict},
+    aggregate,
+    comparator::Comparator,
+    idempotency::{self, Reservation},
+    oracle::{TimeStamp, WriteCommitError},
+    reaper::TxnLease,
+    record::RangeDelete,
     schema::Schema,
-    stream::{merge_stream::MergeStream, EStreamImpl, StreamError},
+    serdes::Encode,
+    stream::{merge_stream::MergeStream, EStreamImpl, ScanError, ScanFilter},
+    utils::is_empty_range,
+    validate::ValidationError,
     GetWrite,
 };
 
+/// Rows [`Transaction::scan_update`] reads before staging their updates and reopening the scan.
+const SCAN_UPDATE_CHUNK: usize = 1024;
+
+/// What [`Transaction::scan_update`] does with a row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RowUpdate<V> {
+    Keep,
+    Set(V),
+    Remove,
+}
+
+/// What a transaction did, handed to the [`TxnListener`] once it committed or failed to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxnMetrics {
+    pub read_at: TimeStamp,
+    /// point lookups and scans
+    pub reads: u64,
+    pub writes: u64,
+    /// encoded size of the keys and values written
+    pub bytes: u64,
+    /// see [`Transaction::set_retries`]
+    pub retries: u32,
+    pub commit_latency: Duration,
+    pub outcome: TxnOutcome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnOutcome {
+    Committed,
+    Conflict,
+    Failed,
+}
+
+/// Receives the metrics of sampled transactions, see [`crate::DbOption::with_txn_listener`].
+pub trait TxnListener: Send + Sync + 'static {
+    fn on_commit(&self, metrics: &TxnMetrics);
+}
+
+impl<F> TxnListener for F
+where
+    F: Fn(&TxnMetrics) + Send + Sync + 'static,
+{
+    fn on_commit(&self, metrics: &TxnMetrics) {
+        self(metrics)
+    }
+}
+
+impl Debug for dyn TxnListener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("TxnListener")
+    }
+}
+
+/// Holds the db through `H`, an `Arc` for [`crate::Db::new_txn`] or a plain reference for
+/// [`crate::Db::txn`].
 #[derive(Debug)]
-pub struct Transaction<S, DB>
+pub struct Transaction<S, DB, H = Arc<DB>>
 where
     S: Schema,
     DB: GetWrite<S>,
+    H: Deref<Target = DB>,
 {
     pub(crate) read_at: TimeStamp,
     pub(crate) local: BTreeMap<S::PrimaryKey, Option<S>>,
-    share: Arc<DB>,
+    /// removed by [`Transaction::remove_range`], the keys staged since are in `local`
+    ranges: Vec<(Bound<S::PrimaryKey>, Bound<S::PrimaryKey>)>,
+    idempotency_key: Option<String>,
+    reads: AtomicU64,
+    retries: u32,
+    written: (u64, u64),
+    lease: TxnLease,
+    share: H,
 }
 
-impl<S, DB> Transaction<S, DB>
+impl<S, DB, H> Transaction<S, DB, H>
 where
     S: Schema,
     DB: GetWrite<S>,
+    H: Deref<Target = DB>,
 {
-    pub(crate) fn new(share: Arc<DB>) -> Self {
+    pub(crate) fn new(share: H) -> Self {
         let read_at = share.start_read();
+        let lease = share.lease_txn(read_at);
         Self {
             read_at,
             local: BTreeMap::new(),
+            ranges: Vec::new(),
+            idempotency_key: None,
+            reads: AtomicU64::new(0),
+            retries: 0,
+            written: (0, 0),
+            lease,
             share,
         }
     }
 
     pub async fn get(&self, key: &S::PrimaryKey) -> Option<S> {
-        match self.local.get(key).and_then(|v| v.as_ref()) {
-            Some(v) => Some(v.clone()),
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        match self.local.get(key) {
+            Some(Some(operand)) if operand.is_operand() => {
+                let older = match self.in_removed_range(key) {
+                    true => None,
+                    false => self.share.get(key, &self.read_at).await,
+                };
+                Some(S::merge(older.as_ref(), operand))
+            }
+            Some(v) => v.clone(),
+            None if self.in_removed_range(key) => None,
             None => self.share.get(key, &self.read_at).await,
         }
     }
 
+    /// Looks all `keys` up concurrently, the values in the order of the keys.
+    pub async fn multi_get<'k>(
+        &self,
+        keys: impl IntoIterator<Item = &'k S::PrimaryKey>,
+    ) -> Vec<Option<S>>
+    where
+        S::PrimaryKey: 'k,
+    {
+        join_all(keys.into_iter().map(|key| self.get(key))).await
+    }
+
     pub fn set(&mut self, key: S::PrimaryKey, value: S) {
         self.entry(key, Some(value))
     }
@@ -59,48 +165,292 @@ where
         self.entry(key, None)
     }
 
+    /// Removes every key of `range`, those staged in this transaction before included, without
+    /// reading them. The keys are not checked for conflicts, a concurrent commit writing into the
+    /// range before this one is removed along with the rest, one writing after it is not. On a db
+    /// with aggregates the rows of the range the transaction sees are read at commit instead and
+    /// removed like [`Transaction::remove`] ones, so that they are counted and checked for
+    /// conflicts.
+    pub fn remove_range(&mut self, range: impl RangeBounds<S::PrimaryKey>) {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.local
+            .retain(|key, _| !S::Comparator::contains(range.0.as_ref(), range.1.as_ref(), key));
+        self.ranges.push(range);
+    }
+
+    fn in_removed_range(&self, key: &S::PrimaryKey) -> bool {
+        self.ranges
+            .iter()
+            .any(|(lower, upper)| S::Comparator::contains(lower.as_ref(), upper.as_ref(), key))
+    }
+
+    /// Removes `key` and returns the value visible at `read_at`; conflicts surface on commit.
+    pub async fn take(&mut self, key: S::PrimaryKey) -> Option<S> {
+        let value = self.get(&key).await;
+        self.remove(key);
+        value
+    }
+
+    /// Stages what `f` makes of the value visible at `read_at`, or of the one staged before, a
+    /// `None` removing the key. A concurrent commit of the key fails this transaction's commit.
+    pub async fn update(&mut self, key: S::PrimaryKey, f: impl FnOnce(Option<&S>) -> Option<S>) {
+        let value = self.get(&key).await;
+        let value = f(value.as_ref());
+        self.entry(key, value)
+    }
+
+    /// Commits with the same key within the retention window after the first one are no-ops.
+    pub fn set_idempotency_key(&mut self, key: impl Into<String>) {
+        self.idempotency_key = Some(key.into());
+    }
+
+    /// How many times the caller retried the work of this transaction after conflicts, reported
+    /// along with its metrics.
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    /// An operand staged over a value staged before is folded into it, see
+    /// [`Schema::is_operand`].
     fn entry(&mut self, key: S::PrimaryKey, value: Option<S>) {
         match self.local.entry(key) {
             Entry::Vacant(v) => {
                 v.insert(value);
             }
-            Entry::Occupied(mut o) => *o.get_mut() = value,
+            Entry::Occupied(mut o) => {
+                let value = match value {
+                    Some(operand) if operand.is_operand() => {
+                        Some(S::merge(o.get().as_ref(), &operand))
+                    }
+                    value => value,
+                };
+                *o.get_mut() = value
+            }
+        }
+    }
+
+    /// Returns a timestamp the commit is visible at, a consistency token to pass to
+    /// [`crate::Db::get_at_least`], e.g. by another service reading its own writes.
+    pub async fn commit(mut self) -> Result<TimeStamp, CommitError<S::PrimaryKey>> {
+        let listener = self.share.sample_txn();
+        let started = Instant::now();
+        let result = self.commit_local().await;
+
+        if let Some(listener) = listener {
+            let (writes, bytes) = self.written;
+            listener.on_commit(&TxnMetrics {
+                read_at: self.read_at,
+                reads: self.reads.load(Ordering::Relaxed),
+                writes,
+                bytes,
+                retries: self.retries,
+                commit_latency: started.elapsed(),
+                outcome: match &result {
+                    Ok(_) => TxnOutcome::Committed,
+                    Err(CommitError::WriteConflict(_)) => TxnOutcome::Conflict,
+                    Err(_) => TxnOutcome::Failed,
+                },
+            });
+        }
+        result
+    }
+
+    async fn commit_local(&mut self) -> Result<TimeStamp, CommitError<S::PrimaryKey>> {
+        if self.lease.is_reaped() {
+            return Err(CommitError::Expired);
+        }
+        if self.local.is_empty() && self.ranges.is_empty() {
+            return Ok(self.read_at);
+        }
+        let reservation = match self.idempotency_key.take() {
+            Some(key) => match self
+                .share
+                .reserve_idempotency_key(&key)
+                .await
+                .map_err(|err| CommitError::WriteError(Box::new(err)))?
+            {
+                Some(reservation) => Some(reservation),
+                // committed before, at some timestamp handed out by now
+                None => return Ok(self.share.now()),
+            },
+            None => None,
+        };
+        // a reservation dropped along with a failed or cancelled commit hands the key over
+        self.write_local(reservation).await
+    }
+
+    /// Stages the removal of every row of the removed ranges the transaction sees, in place of
+    /// the ranges.
+    async fn stage_ranges(&mut self) -> Result<(), ScanError<S::PrimaryKey, S>> {
+        for (lower, upper) in mem::take(&mut self.ranges) {
+            let iters = self
+                .share
+                .inner_range(lower.as_ref(), upper.as_ref(), &self.read_at)
+                .await?;
+            let mut stream = pin!(MergeStream::new(iters).await?);
+            while let Some(item) = stream.next().await {
+                let (key, _) = item?;
+                // keys staged after the range was removed keep their value
+                self.local.entry(key).or_insert(None);
+            }
         }
+        Ok(())
     }
 
-    pub async fn commit(self) -> Result<(), CommitError<S::PrimaryKey>> {
-        self.share.read_commit(self.read_at);
-        if self.local.is_empty() {
-            return Ok(());
+    async fn write_local(
+        &mut self,
+        reservation: Option<Reservation>,
+    ) -> Result<TimeStamp, CommitError<S::PrimaryKey>> {
+        if self.share.has_aggregates() {
+            self.stage_ranges()
+                .await
+                .map_err(|err| CommitError::WriteError(Box::new(err)))?;
+        }
+        self.share.intercept(self.read_at, &mut self.local);
+        // reject invalid and oversized entries up front, a batch failing halfway would leave a
+        // torn wal
+        let mut bytes = 0;
+        for (key, value) in self.local.iter() {
+            self.share
+                .validate(key, value.as_ref())
+                .map_err(CommitError::Invalid)?;
+            self.share.check_size(key, value.as_ref())?;
+            bytes += key.size() + value.as_ref().map(Encode::size).unwrap_or(0);
         }
-        let write_at = self.share.start_write();
+        self.written = (self.local.len() as u64, bytes as u64);
+        // held in flight until the batch is applied, so that no read waits past `write_at` first
+        let (write_at, _in_flight) = self.share.begin_write();
         self.share
             .write_commit(self.read_at, write_at, self.local.keys().cloned().collect())?;
+        let deltas = self.share.aggregate_deltas(self.read_at, &self.local).await;
+        // logged along with the writes, which the tombstones leave alone as they are written at
+        // `write_at` too
+        let ranges = mem::take(&mut self.ranges)
+            .into_iter()
+            .map(|(lower, upper)| RangeDelete {
+                lower,
+                upper,
+                ts: write_at,
+            })
+            .collect();
+        // the key and aggregate deltas of the commit are logged along with it, rather than
+        // persisted ahead of it
+        let token = reservation
+            .as_ref()
+            .map(|reservation| idempotency::record(reservation, write_at));
+        let system = token
+            .iter()
+            .cloned()
+            .chain(aggregate::records(&deltas, write_at))
+            .collect();
         self.share
-            .write_batch(self.local.into_iter().map(|(k, v)| (k, write_at, v)))
+            .write_batch(
+                mem::take(&mut self.local)
+                    .into_iter()
+                    .map(|(k, v)| (k, write_at, v)),
+                ranges,
+                system,
+            )
             .await?;
-        Ok(())
+        self.share.apply_aggregates(deltas, write_at).await;
+        if let (Some(reservation), Some(token)) = (reservation, token) {
+            self.share.commit_idempotency_key(reservation, token).await;
+        }
+        Ok(write_at)
     }
 
     pub async fn range(
         &self,
-        lower: Option<&S::PrimaryKey>,
-        upper: Option<&S::PrimaryKey>,
-    ) -> Result<MergeStream<S>, StreamError<S::PrimaryKey, S>> {
+        range: impl RangeBounds<S::PrimaryKey>,
+    ) -> Result<MergeStream<S>, ScanError<S::PrimaryKey, S>> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        let (lower, upper) = (range.start_bound(), range.end_bound());
+        if is_empty_range::<_, S::Comparator>(lower, upper) {
+            return MergeStream::new(Vec::new()).await;
+        }
         let mut iters = self.share.inner_range(lower, upper, &self.read_at).await?;
-        let range = self
+        // `local` is in the order of `Ord`, which the schema's comparator may not share
+        let mut rows = self
             .local
-            .range::<S::PrimaryKey, (Bound<&S::PrimaryKey>, Bound<&S::PrimaryKey>)>((
-                lower.map(Bound::Included).unwrap_or(Bound::Unbounded),
-                upper.map(Bound::Included).unwrap_or(Bound::Unbounded),
-            ));
+            .iter()
+            .filter(|(key, _)| S::Comparator::contains(lower, upper, key))
+            .collect::<Vec<_>>();
+        rows.sort_by(|(key_1, _), (key_2, _)| S::Comparator::compare(key_1, key_2));
         let iter = TransactionStream {
-            range,
+            rows: rows.into_iter(),
             _p: Default::default(),
         };
         iters.insert(0, EStreamImpl::TransactionInner(iter));
 
-        MergeStream::new(iters).await
+        if self.ranges.is_empty() {
+            return MergeStream::new(iters).await;
+        }
+        // the keys staged in the transaction are the only ones of its removed ranges it sees
+        let ranges = self.ranges.clone();
+        let filter: ScanFilter<S> = Arc::new(move |key, ts, _| {
+            ts == TimeStamp::MAX
+                || !ranges.iter().any(|(lower, upper)| {
+                    S::Comparator::contains(lower.as_ref(), upper.as_ref(), key)
+                })
+        });
+        MergeStream::with_filter(iters, Some(filter)).await
+    }
+
+    /// Stages what `f` makes of every row of `range` visible to the transaction and returns how
+    /// many rows were set or removed. The scan is reopened past the last row read every
+    /// [`SCAN_UPDATE_CHUNK`] rows, so that only the staged rows are held, not the whole range.
+    pub async fn scan_update(
+        &mut self,
+        range: impl RangeBounds<S::PrimaryKey>,
+        mut f: impl FnMut(&S::PrimaryKey, &S) -> RowUpdate<S>,
+    ) -> Result<usize, ScanError<S::PrimaryKey, S>> {
+        let mut lower = range.start_bound().cloned();
+        let upper = range.end_bound().cloned();
+        let mut updated = 0;
+        loop {
+            let mut staged = Vec::new();
+            let mut last = None;
+            {
+                let mut rows = pin!(self.range((lower.clone(), upper.clone())).await?);
+                let mut read = 0;
+                while let Some(row) = rows.next().await {
+                    let (key, value) = row?;
+                    if let Some(value) = value {
+                        match f(&key, &value) {
+                            RowUpdate::Keep => (),
+                            RowUpdate::Set(value) => staged.push((key.clone(), Some(value))),
+                            RowUpdate::Remove => staged.push((key.clone(), None)),
+                        }
+                    }
+                    read += 1;
+                    if read == SCAN_UPDATE_CHUNK {
+                        last = Some(key);
+                        break;
+                    }
+                }
+            }
+            updated += staged.len();
+            for (key, value) in staged {
+                self.entry(key, value);
+            }
+            match last {
+                Some(key) => lower = Bound::Excluded(key),
+                None => return Ok(updated),
+            }
+        }
+    }
+}
+
+/// The read is held until the transaction is gone, committed or not, so that the oracle keeps the
+/// writes it may conflict with until then.
+impl<S, DB, H> Drop for Transaction<S, DB, H>
+where
+    S: Schema,
+    DB: GetWrite<S>,
+    H: Deref<Target = DB>,
+{
+    fn drop(&mut self) {
+        self.share.end_txn(&self.lease, self.read_at);
     }
 }
 
@@ -110,7 +460,7 @@ where
     S: Schema,
 {
     #[pin]
-    range: btree_map::Range<'a, S::PrimaryKey, Option<S>>,
+    rows: vec::IntoIter<(&'a S::PrimaryKey, &'a Option<S>)>,
     _p: PhantomData<E>,
 }
 
@@ -118,27 +468,55 @@ impl<'a, S, E> Stream for TransactionStream<'a, S, E>
 where
     S: Schema,
 {
-    type Item = Result<(S::PrimaryKey, Option<S>), E>;
+    type Item = Result<(S::PrimaryKey, TimeStamp, Option<S>), E>;
 
     fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
+        // local writes are not committed yet, so they are newer than anything else
         Poll::Ready(
-            this.range
+            this.rows
                 .next()
-                .map(|(key, value)| (key.clone(), value.clone()))
+                .map(|(key, value)| (key.clone(), TimeStamp::MAX, value.clone()))
                 .map(Ok),
         )
     }
 }
 
+/// Runs on every transaction commit before the conflict check, see
+/// [`crate::Db::with_commit_interceptor`]. Entries it adds or changes in `writes` are committed
+/// along with the transaction's own and are checked for conflicts like them.
+pub trait CommitInterceptor<K, V>: Send + Sync + 'static {
+    fn intercept(&self, read_at: TimeStamp, writes: &mut BTreeMap<K, Option<V>>);
+}
+
+impl<K, V, F> CommitInterceptor<K, V> for F
+where
+    F: Fn(TimeStamp, &mut BTreeMap<K, Option<V>>) + Send + Sync + 'static,
+{
+    fn intercept(&self, read_at: TimeStamp, writes: &mut BTreeMap<K, Option<V>>) {
+        self(read_at, writes)
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum CommitError<K> {
     WriteConflict(Vec<K>),
+    /// The oracle keeps too many write sets for running transactions to check this one, see
+    /// `LocalOracle::with_max_committed_txns`.
+    ConflictWindowFull(usize),
+    /// Rejected by the db's validator, nothing of the transaction was written.
+    Invalid(ValidationError),
+    /// Ran past [`crate::DbOption::txn_max_lifetime`] and was aborted, nothing of the transaction
+    /// was written.
+    Expired,
     WriteError(#[from] Box<dyn std::error::Error + Send + Sync + 'static>),
 }
 
-impl<K> From<WriteConflict<K>> for CommitError<K> {
-    fn from(e: WriteConflict<K>) -> Self {
-        CommitError::WriteConflict(e.to_keys())
+impl<K> From<WriteCommitError<K>> for CommitError<K> {
+    fn from(e: WriteCommitError<K>) -> Self {
+        match e {
+            WriteCommitError::Conflict(e) => CommitError::WriteConflict(e.to_keys()),
+            WriteCommitError::WindowFull { limit } => CommitError::ConflictWindowFull(limit),
+        }
     }
 }
@@ -0,0 +1,148 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// A mem table is encoded and flushed in well under this part of the time it takes to fill.
+const FLUSH_HEADROOM: f64 = 4.0;
+/// Weight of the latest observation in the moving averages.
+const SMOOTHING: f64 = 0.25;
+
+/// See [`crate::DbOption::with_adaptive_mem_table_size`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveSize {
+    pub min: usize,
+    pub max: usize,
+}
+
+/// Sizes mem tables, and therefore wal segments, after the observed write rate and flush
+/// latency: the size doubles while flushing a mem table takes more than a
+/// `1 / FLUSH_HEADROOM` part of the time writes take to fill one, and shrinks while flushes
+/// have room to spare, so that the db freezes as often as it can afford to.
+#[derive(Debug)]
+pub(crate) struct SizeTuner {
+    size: AtomicUsize,
+    bounds: Option<AdaptiveSize>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    last_freeze: Instant,
+    /// bytes written per second
+    write_rate: Option<f64>,
+    flush_latency: Option<f64>,
+}
+
+impl SizeTuner {
+    pub(crate) fn new(size: usize, bounds: Option<AdaptiveSize>) -> Self {
+        SizeTuner {
+            size: AtomicUsize::new(match bounds {
+                Some(bounds) => size.clamp(bounds.min, bounds.max),
+                None => size,
+            }),
+            bounds,
+            state: Mutex::new(State {
+                last_freeze: Instant::now(),
+                write_rate: None,
+                flush_latency: None,
+            }),
+        }
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Must be called as a mem table of `bytes` is frozen.
+    pub(crate) fn on_freeze(&self, bytes: usize, now: Instant) {
+        if self.bounds.is_none() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let elapsed = now.duration_since(state.last_freeze).as_secs_f64();
+        state.last_freeze = now;
+        if elapsed > 0.0 {
+            state.write_rate = Some(smooth(state.write_rate, bytes as f64 / elapsed));
+        }
+        self.tune(&state);
+    }
+
+    /// Must be called once a frozen mem table is encoded, `latency` after it was frozen.
+    pub(crate) fn on_flushed(&self, latency: Duration) {
+        if self.bounds.is_none() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.flush_latency = Some(smooth(state.flush_latency, latency.as_secs_f64()));
+        self.tune(&state);
+    }
+
+    fn tune(&self, state: &State) {
+        let (Some(bounds), Some(write_rate), Some(flush_latency)) =
+            (self.bounds, state.write_rate, state.flush_latency)
+        else {
+            return;
+        };
+        let size = self.size();
+        let fill_time = size as f64 / write_rate;
+        let tuned = if flush_latency * FLUSH_HEADROOM > fill_time {
+            size.saturating_mul(2)
+        } else if flush_latency * FLUSH_HEADROOM * 4.0 < fill_time {
+            size / 4 * 3
+        } else {
+            size
+        };
+        self.size
+            .store(tuned.clamp(bounds.min, bounds.max), Ordering::Relaxed);
+    }
+}
+
+fn smooth(average: Option<f64>, observed: f64) -> f64 {
+    match average {
+        Some(average) => average + (observed - average) * SMOOTHING,
+        None => observed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{AdaptiveSize, SizeTuner};
+
+    #[test]
+    fn tunes_within_bounds() {
+        let bounds = AdaptiveSize {
+            min: 1024,
+            max: 8 * 1024,
+        };
+        let tuner = SizeTuner::new(1, Some(bounds));
+        assert_eq!(tuner.size(), 1024);
+
+        // written at 100 KiB/s, a 1 KiB mem table fills in 10ms
+        let mut now = Instant::now();
+        let mut freeze = |tuner: &SizeTuner, flush_latency| {
+            now += Duration::from_secs_f64(tuner.size() as f64 / (100.0 * 1024.0));
+            tuner.on_freeze(tuner.size(), now);
+            tuner.on_flushed(flush_latency);
+        };
+        for _ in 0..4 {
+            freeze(&tuner, Duration::from_millis(50));
+        }
+        assert_eq!(tuner.size(), 8 * 1024);
+
+        for _ in 0..32 {
+            freeze(&tuner, Duration::from_micros(10));
+        }
+        assert_eq!(tuner.size(), 1024);
+
+        let fixed = SizeTuner::new(1, None);
+        fixed.on_freeze(1, Instant::now() + Duration::from_millis(1));
+        fixed.on_flushed(Duration::from_secs(1));
+        assert_eq!(fixed.size(), 1);
+    }
+}
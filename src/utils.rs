@@ -1,35 +1,65 @@
-use std::cmp::Ordering;
+use std::{cmp::Ordering, marker::PhantomData, ops::Bound};
 
-pub(crate) struct CmpKeyItem<K: Ord, V> {
+use crate::comparator::Comparator;
+
+/// Ordered by its key alone, in the order of `C`.
+pub(crate) struct CmpKeyItem<K, V, C> {
     pub(crate) key: K,
     pub(crate) _value: V,
+    _c: PhantomData<C>,
 }
 
-impl<K, V> Eq for CmpKeyItem<K, V> where K: Ord {}
+impl<K, V, C> CmpKeyItem<K, V, C> {
+    pub(crate) fn new(key: K, value: V) -> Self {
+        CmpKeyItem {
+            key,
+            _value: value,
+            _c: PhantomData,
+        }
+    }
+}
 
-impl<K, V> PartialEq<Self> for CmpKeyItem<K, V>
+impl<K, V, C> Eq for CmpKeyItem<K, V, C> where C: Comparator<K> {}
+
+impl<K, V, C> PartialEq<Self> for CmpKeyItem<K, V, C>
 where
-    K: Ord,
+    C: Comparator<K>,
 {
     fn eq(&self, other: &Self) -> bool {
-        self.key.eq(&other.key)
+        C::compare(&self.key, &other.key).is_eq()
     }
 }
 
-impl<K, V> PartialOrd<Self> for CmpKeyItem<K, V>
+impl<K, V, C> PartialOrd<Self> for CmpKeyItem<K, V, C>
 where
-    K: Ord,
+    C: Comparator<K>,
 {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl<K, V> Ord for CmpKeyItem<K, V>
+impl<K, V, C> Ord for CmpKeyItem<K, V, C>
 where
-    K: Ord,
+    C: Comparator<K>,
 {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.key.cmp(&other.key)
+        C::compare(&self.key, &other.key)
+    }
+}
+
+/// Whether no key lies between the bounds in the order of `C`, e.g. `k..k` or `5..3`, which
+/// [`BTreeMap::range`] panics on.
+///
+/// [`BTreeMap::range`]: std::collections::BTreeMap::range
+pub(crate) fn is_empty_range<K, C>(lower: Bound<&K>, upper: Bound<&K>) -> bool
+where
+    C: Comparator<K>,
+{
+    match (lower, upper) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+        (Bound::Included(lower), Bound::Included(upper)) => C::compare(lower, upper).is_gt(),
+        (Bound::Included(lower) | Bound::Excluded(lower), Bound::Excluded(upper))
+        | (Bound::Excluded(lower), Bound::Included(upper)) => C::compare(lower, upper).is_ge(),
     }
 }
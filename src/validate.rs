@@ -0,0 +1,67 @@
+use std::{
+    any::Any,
+    fmt::{self, Debug, Formatter},
+    sync::Arc,
+};
+
+use thiserror::Error;
+
+/// Checks every record written, before it is encoded into the wal, e.g. that keys fall into
+/// a tenant's range. Records replayed from the wal are not checked again.
+pub trait Validator<K, V>: Send + Sync + 'static {
+    /// `value` is `None` for removals.
+    fn validate(&self, key: &K, value: Option<&V>) -> Result<(), ValidationError>;
+}
+
+impl<K, V, F> Validator<K, V> for F
+where
+    F: Fn(&K, Option<&V>) -> Result<(), ValidationError> + Send + Sync + 'static,
+{
+    fn validate(&self, key: &K, value: Option<&V>) -> Result<(), ValidationError> {
+        self(key, value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid record: {reason}")]
+pub struct ValidationError {
+    pub reason: String,
+}
+
+impl ValidationError {
+    pub fn new(reason: impl Into<String>) -> Self {
+        ValidationError {
+            reason: reason.into(),
+        }
+    }
+}
+
+/// A [`Validator`] with its key and value types erased, so that [`crate::DbOption`] can hold
+/// it. The db rejects a validator of other types than its schema's when opened.
+#[derive(Clone)]
+pub struct AnyValidator(Arc<dyn Any + Send + Sync>);
+
+impl AnyValidator {
+    pub fn new<K, V>(validator: impl Validator<K, V>) -> Self
+    where
+        K: 'static,
+        V: 'static,
+    {
+        let validator: Arc<dyn Validator<K, V>> = Arc::new(validator);
+        AnyValidator(Arc::new(validator))
+    }
+
+    pub(crate) fn downcast<K, V>(&self) -> Option<Arc<dyn Validator<K, V>>>
+    where
+        K: 'static,
+        V: 'static,
+    {
+        self.0.downcast_ref::<Arc<dyn Validator<K, V>>>().cloned()
+    }
+}
+
+impl Debug for AnyValidator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("AnyValidator")
+    }
+}
@@ -1,10 +1,15 @@
-use std::{collections::BTreeMap, fs, io, sync::Arc};
+use std::{collections::BTreeMap, io, sync::Arc};
 
 use executor::futures::StreamExt;
 use futures::channel::mpsc::{channel, Receiver, Sender};
 use snowflake::ProcessUniqueId;
+use tracing::error;
 
-use crate::DbOption;
+use crate::{
+    background::{BackgroundPool, TaskPriority},
+    wal::provider::TableStoreRef,
+    DbOption,
+};
 
 pub(crate) enum CleanTag {
     Add {
@@ -19,18 +24,24 @@ pub(crate) enum CleanTag {
 pub(crate) struct Cleaner {
     tag_recv: Receiver<CleanTag>,
     gens_map: BTreeMap<usize, (Vec<ProcessUniqueId>, bool)>,
-    option: Arc<DbOption>,
+    table_store: TableStoreRef,
+    background: Arc<BackgroundPool>,
 }
 
 impl Cleaner {
-    pub(crate) fn new(option: Arc<DbOption>) -> (Self, Sender<CleanTag>) {
+    pub(crate) fn new(
+        option: &DbOption,
+        table_store: TableStoreRef,
+        background: Arc<BackgroundPool>,
+    ) -> (Self, Sender<CleanTag>) {
         let (tag_send, tag_recv) = channel(option.clean_channel_buffer);
 
         (
             Cleaner {
                 tag_recv,
                 gens_map: Default::default(),
-                option,
+                table_store,
+                background,
             },
             tag_send,
         )
@@ -52,9 +63,14 @@ impl Cleaner {
                             let _ = self.gens_map.insert(first_version, (gens, false));
                             continue;
                         }
-                        for gen in gens {
-                            fs::remove_file(self.option.table_path(&gen))?;
-                        }
+                        let table_store = self.table_store.clone();
+                        self.background.spawn(TaskPriority::Gc, async move {
+                            for gen in gens {
+                                if let Err(err) = table_store.remove_table(&gen).await {
+                                    error!("[Cleaner Error]: {}", err)
+                                }
+                            }
+                        });
                     }
                 }
             }
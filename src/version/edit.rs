@@ -1,9 +1,6 @@
 use std::mem::size_of;
 
-use executor::futures::{
-    util::{AsyncReadExt, AsyncWriteExt},
-    AsyncRead, AsyncWrite,
-};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use snowflake::ProcessUniqueId;
 
 use crate::{
@@ -16,19 +13,36 @@ pub(crate) enum VersionEdit<K>
 where
     K: Encode + Decode + Ord + Clone,
 {
-    Add { level: u8, scope: Scope<K> },
-    Remove { level: u8, gen: ProcessUniqueId },
+    Add {
+        level: u8,
+        scope: Scope<K>,
+    },
+    Remove {
+        level: u8,
+        gen: ProcessUniqueId,
+    },
+    /// Ends the edits of one `VersionSet::apply_edits`, which recovery applies all or none of.
+    Commit,
 }
 
 impl<K> VersionEdit<K>
 where
     K: Encode + Decode + Ord + Clone,
 {
+    /// Decodes the edits of every batch committed to the log. Logs written before batches were
+    /// committed hold no commit at all, then every edit is taken.
     pub(crate) async fn recover<R: AsyncRead + Unpin>(reader: &mut R) -> Vec<VersionEdit<K>> {
         let mut edits = Vec::new();
+        let mut committed = None;
 
         while let Ok(edit) = VersionEdit::decode(reader).await {
-            edits.push(edit)
+            match edit {
+                VersionEdit::Commit => committed = Some(edits.len()),
+                edit => edits.push(edit),
+            }
+        }
+        if let Some(committed) = committed {
+            edits.truncate(committed);
         }
         edits
     }
@@ -55,6 +69,9 @@ where
                 writer.write_all(&level.to_le_bytes()).await?;
                 writer.write_all(&bincode::serialize(gen).unwrap()).await?;
             }
+            VersionEdit::Commit => {
+                writer.write_all(&2u8.to_le_bytes()).await?;
+            }
         }
 
         Ok(())
@@ -62,10 +79,10 @@ where
 
     fn size(&self) -> usize {
         size_of::<u8>()
-            + size_of::<u8>()
             + match self {
-                VersionEdit::Add { scope, .. } => scope.size(),
-                VersionEdit::Remove { .. } => 16,
+                VersionEdit::Add { scope, .. } => size_of::<u8>() + scope.size(),
+                VersionEdit::Remove { .. } => size_of::<u8>() + 16,
+                VersionEdit::Commit => 0,
             }
     }
 }
@@ -82,6 +99,9 @@ where
             reader.read_exact(&mut len).await?;
             u8::from_le_bytes(len) as usize
         };
+        if edit_type == 2 {
+            return Ok(VersionEdit::Commit);
+        }
         let level = {
             let mut level = [0; size_of::<u8>()];
             reader.read_exact(&mut level).await?;
@@ -123,6 +143,7 @@ mod tests {
                         min: "Min".to_string(),
                         max: "Max".to_string(),
                         gen: Default::default(),
+                        stats: Default::default(),
                     },
                 },
                 VersionEdit::Remove {
@@ -149,4 +170,38 @@ mod tests {
             assert_eq!(edits, decode_edits);
         })
     }
+
+    #[test]
+    fn recover_committed() {
+        block_on(async {
+            let remove = |level| VersionEdit::<String>::Remove {
+                level,
+                gen: Default::default(),
+            };
+            let bytes = {
+                let mut cursor = Cursor::new(vec![]);
+
+                for edit in [
+                    remove(0),
+                    remove(1),
+                    VersionEdit::Commit,
+                    remove(2),
+                    VersionEdit::Commit,
+                    // torn batch
+                    remove(3),
+                ] {
+                    edit.encode(&mut cursor).await.unwrap();
+                }
+                cursor.into_inner()
+            };
+
+            let decode_edits = {
+                let mut cursor = Cursor::new(bytes);
+
+                VersionEdit::<String>::recover(&mut cursor).await
+            };
+
+            assert_eq!(decode_edits, vec![remove(0), remove(1), remove(2)]);
+        })
+    }
 }
@@ -0,0 +1,64 @@
+use std::{io, sync::Arc};
+
+use executor::futures::StreamExt;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+
+use crate::{
+    schema::Schema,
+    version::set::VersionSet,
+    wal::provider::{tiered::Tier, TableStoreRef},
+    DbOption,
+};
+
+/// Demotes the tables of every level placed on [`Tier::Remote`] after each compaction.
+pub(crate) struct Migrator<S>
+where
+    S: Schema,
+{
+    option: Arc<DbOption>,
+    version_set: VersionSet<S>,
+    table_store: TableStoreRef,
+    tick_recv: Receiver<()>,
+}
+
+impl<S> Migrator<S>
+where
+    S: Schema,
+{
+    pub(crate) fn new(
+        option: Arc<DbOption>,
+        version_set: VersionSet<S>,
+        table_store: TableStoreRef,
+    ) -> (Self, Sender<()>) {
+        // a pending tick already covers any edits made before it is handled
+        let (tick_send, tick_recv) = channel(0);
+
+        (
+            Migrator {
+                option,
+                version_set,
+                table_store,
+                tick_recv,
+            },
+            tick_send,
+        )
+    }
+
+    pub(crate) async fn listen(&mut self) -> Result<(), io::Error> {
+        while self.tick_recv.next().await.is_some() {
+            // holding the version keeps the cleaner from removing its tables meanwhile
+            let version = self.version_set.current().await;
+
+            for (level, scopes) in version.level_slice.iter().enumerate() {
+                if self.option.level_tiers[level] != Tier::Remote {
+                    continue;
+                }
+                for scope in scopes {
+                    self.table_store.demote_table(&scope.gen).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
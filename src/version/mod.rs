@@ -1,36 +1,22 @@
 pub(crate) mod cleaner;
 pub(crate) mod edit;
+pub(crate) mod migrator;
+pub(crate) mod read;
 pub(crate) mod set;
 
-use std::{fs::File, mem, sync::Arc};
+use std::sync::Arc;
 
-use arrow::{
-    array::{RecordBatch, Scalar},
-    compute::kernels::cmp::eq,
-};
-use executor::{
-    fs,
-    futures::{util::SinkExt, StreamExt},
-};
+use executor::futures::util::SinkExt;
 use futures::{
     channel::mpsc::{SendError, Sender},
     executor::block_on,
 };
-use parquet::arrow::{
-    arrow_reader::{ArrowPredicateFn, ArrowReaderMetadata, RowFilter},
-    ParquetRecordBatchStreamBuilder, ProjectionMask,
-};
-use snowflake::ProcessUniqueId;
 use thiserror::Error;
 use tracing::error;
 
 use crate::{
-    schema::Schema,
-    scope::Scope,
-    serdes::Encode,
-    stream::{level_stream::LevelStream, table_stream::TableStream, EStreamImpl, StreamError},
+    comparator::Comparator, schema::Schema, scope::Scope, serdes::Encode,
     version::cleaner::CleanTag,
-    DbOption,
 };
 
 pub const MAX_LEVEL: usize = 7;
@@ -71,40 +57,9 @@ impl<S> Version<S>
 where
     S: Schema,
 {
-    pub(crate) async fn query(
-        &self,
-        key: &S::PrimaryKey,
-        option: &DbOption,
-    ) -> Result<Option<RecordBatch>, VersionError<S>> {
-        let key_array = S::to_primary_key_array(vec![key.clone()]);
-
-        for scope in self.level_slice[0].iter().rev() {
-            if !scope.is_between(key) {
-                continue;
-            }
-            if let Some(batch) = Self::read_parquet(&scope.gen, &key_array, option).await? {
-                return Ok(Some(batch));
-            }
-        }
-        for level in self.level_slice[1..6].iter() {
-            if level.is_empty() {
-                continue;
-            }
-            let index = Self::scope_search(key, level);
-            if !level[index].is_between(key) {
-                continue;
-            }
-            if let Some(batch) = Self::read_parquet(&level[index].gen, &key_array, option).await? {
-                return Ok(Some(batch));
-            }
-        }
-
-        Ok(None)
-    }
-
     pub(crate) fn scope_search(key: &S::PrimaryKey, level: &[Scope<S::PrimaryKey>]) -> usize {
         level
-            .binary_search_by(|scope| scope.min.cmp(key))
+            .binary_search_by(|scope| S::Comparator::compare(&scope.min, key))
             .unwrap_or_else(|index| index.saturating_sub(1))
     }
 
@@ -112,6 +67,15 @@ where
         self.level_slice[level].len()
     }
 
+    /// Every level 0 table, then every level below holding tables.
+    pub(crate) fn sorted_runs(&self) -> usize {
+        self.level_slice[0].len()
+            + self.level_slice[1..]
+                .iter()
+                .filter(|scopes| !scopes.is_empty())
+                .count()
+    }
+
     pub(crate) fn level_slice_new() -> [Vec<Scope<S::PrimaryKey>>; 7] {
         [
             Vec::new(),
@@ -123,59 +87,6 @@ where
             Vec::new(),
         ]
     }
-
-    pub(crate) async fn iters<'a>(
-        &self,
-        iters: &mut Vec<EStreamImpl<'a, S>>,
-        option: &'a DbOption,
-        lower: Option<&S::PrimaryKey>,
-        upper: Option<&S::PrimaryKey>,
-    ) -> Result<(), StreamError<S::PrimaryKey, S>> {
-        for scope in self.level_slice[0].iter() {
-            iters.push(EStreamImpl::Table(
-                TableStream::new(option, &scope.gen, lower, upper).await?,
-            ))
-        }
-        for scopes in self.level_slice[1..].iter() {
-            if scopes.is_empty() {
-                continue;
-            }
-            let gens = scopes.iter().map(|scope| scope.gen).collect::<Vec<_>>();
-            iters.push(EStreamImpl::Level(
-                LevelStream::new(option, gens, lower, upper).await?,
-            ));
-        }
-        Ok(())
-    }
-
-    async fn read_parquet(
-        scope_gen: &ProcessUniqueId,
-        key_scalar: &S::PrimaryKeyArray,
-        option: &DbOption,
-    ) -> Result<Option<RecordBatch>, VersionError<S>> {
-        let mut file =
-            fs::File::from(File::open(option.table_path(scope_gen)).map_err(VersionError::Io)?);
-        let meta = ArrowReaderMetadata::load_async(&mut file, Default::default())
-            .await
-            .map_err(VersionError::Parquet)?;
-        let mut builder = ParquetRecordBatchStreamBuilder::new_with_metadata(file, meta);
-        let file_metadata = builder.metadata().file_metadata();
-
-        let key_scalar = unsafe { mem::transmute::<_, &'static S::PrimaryKeyArray>(key_scalar) };
-        let filter = ArrowPredicateFn::new(
-            ProjectionMask::roots(file_metadata.schema_descr(), [0]),
-            move |record_batch| eq(record_batch.column(0), &Scalar::new(&key_scalar)),
-        );
-        let row_filter = RowFilter::new(vec![Box::new(filter)]);
-        builder = builder.with_row_filter(row_filter);
-
-        let mut stream = builder.build().map_err(VersionError::Parquet)?;
-
-        if let Some(result) = stream.next().await {
-            return Ok(Some(result.map_err(VersionError::Parquet)?));
-        }
-        Ok(None)
-    }
 }
 
 impl<S> Drop for Version<S>
@@ -197,6 +108,20 @@ where
     }
 }
 
+impl<S> VersionError<S>
+where
+    S: Schema,
+{
+    /// Whether the error tells a table is damaged, rather than out of reach for the moment.
+    pub(crate) fn is_corruption(&self) -> bool {
+        match self {
+            VersionError::Parquet(_) => true,
+            VersionError::Io(err) => err.kind() == std::io::ErrorKind::InvalidData,
+            VersionError::Encode(_) | VersionError::Send(_) => false,
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum VersionError<S>
 where
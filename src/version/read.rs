@@ -0,0 +1,394 @@
+use std::{collections::HashMap, mem, ops::Bound, sync::Mutex};
+
+use arrow::{
+    array::{Array, AsArray, RecordBatch, Scalar, UInt64Array},
+    compute::kernels::cmp::{eq, lt_eq},
+    datatypes::{
+        DataType, Int16Type, Int32Type, Int64Type, Int8Type, UInt16Type, UInt32Type, UInt64Type,
+        UInt8Type,
+    },
+};
+use executor::futures::StreamExt;
+use parquet::arrow::{
+    arrow_reader::{ArrowPredicateFn, ArrowReaderMetadata, RowFilter},
+    ParquetRecordBatchStreamBuilder, ProjectionMask,
+};
+use snowflake::ProcessUniqueId;
+
+use crate::{
+    oracle::TimeStamp,
+    schema::{Schema, TS_COLUMN},
+    stream::{
+        level_stream::LevelStream, table_stream::TableStream, EStreamImpl, ScanError, ScanFilter,
+    },
+    version::{Version, VersionError},
+    wal::provider::TableStore,
+    TableReads,
+};
+
+/// Reads the tables of a version in the order lookups see them: level 0 newest first, then the
+/// deeper levels. Tables whose key range, or for lookups whose bloom filter, rules the key out are
+/// never read.
+pub(crate) struct VersionRead<'v, 'a, S>
+where
+    S: Schema,
+{
+    version: &'v Version<S>,
+    table_store: &'a dyn TableStore,
+}
+
+impl<'v, 'a, S> VersionRead<'v, 'a, S>
+where
+    S: Schema,
+{
+    pub(crate) fn new(version: &'v Version<S>, table_store: &'a dyn TableStore) -> Self {
+        VersionRead {
+            version,
+            table_store,
+        }
+    }
+
+    /// Returns a batch whose first row is the newest version of `key` visible at `ts`.
+    pub(crate) async fn get(
+        &self,
+        key: &S::PrimaryKey,
+        ts: TimeStamp,
+    ) -> Result<Option<RecordBatch>, VersionError<S>> {
+        self.get_probed(key, ts, &mut Vec::new()).await
+    }
+
+    /// Like [`VersionRead::get`], pushing the tables it reads onto `probed` in order.
+    pub(crate) async fn get_probed(
+        &self,
+        key: &S::PrimaryKey,
+        ts: TimeStamp,
+        probed: &mut Vec<ProcessUniqueId>,
+    ) -> Result<Option<RecordBatch>, VersionError<S>> {
+        let key_array = S::to_primary_key_array(vec![key.clone()]);
+
+        let level_0 = self.version.level_slice[0]
+            .iter()
+            .rev()
+            .filter(|scope| scope.is_between::<S::Comparator>(key));
+        let levels = self.version.level_slice[1..].iter().filter_map(|scopes| {
+            let scope = scopes.get(Version::<S>::scope_search(key, scopes))?;
+            scope.is_between::<S::Comparator>(key).then_some(scope)
+        });
+        for scope in level_0.chain(levels) {
+            probed.push(scope.gen);
+            if let Some(batch) = self.read_table(&scope.gen, &key_array, ts).await? {
+                return Ok(Some(batch));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Pushes a stream per level 0 table, newest first, and per deeper level, over the tables
+    /// overlapping `lower..upper`.
+    pub(crate) async fn streams(
+        &self,
+        iters: &mut Vec<EStreamImpl<'a, S>>,
+        lower: Bound<&S::PrimaryKey>,
+        upper: Bound<&S::PrimaryKey>,
+        ts: TimeStamp,
+        filter: Option<&ScanFilter<S>>,
+    ) -> Result<(), ScanError<S::PrimaryKey, S>> {
+        for scope in self.version.level_slice[0]
+            .iter()
+            .rev()
+            .filter(|scope| scope.overlaps::<S::Comparator>(lower, upper))
+        {
+            iters.push(EStreamImpl::Table(
+                TableStream::new(
+                    self.table_store,
+                    &scope.gen,
+                    lower,
+                    upper,
+                    ts,
+                    filter.cloned(),
+                )
+                .await?,
+            ))
+        }
+        for scopes in self.version.level_slice[1..].iter() {
+            let gens = scopes
+                .iter()
+                .filter(|scope| scope.overlaps::<S::Comparator>(lower, upper))
+                .map(|scope| scope.gen)
+                .collect::<Vec<_>>();
+            if gens.is_empty() {
+                continue;
+            }
+            iters.push(EStreamImpl::Level(
+                LevelStream::new(self.table_store, gens, lower, upper, ts, filter.cloned()).await?,
+            ));
+        }
+        Ok(())
+    }
+
+    async fn read_table(
+        &self,
+        gen: &ProcessUniqueId,
+        key_array: &S::PrimaryKeyArray,
+        ts: TimeStamp,
+    ) -> Result<Option<RecordBatch>, VersionError<S>> {
+        let mut file = self
+            .table_store
+            .open_table(gen)
+            .await
+            .map_err(VersionError::Io)?;
+        let meta = ArrowReaderMetadata::load_async(&mut file, Default::default())
+            .await
+            .map_err(VersionError::Parquet)?;
+        let mut builder = ParquetRecordBatchStreamBuilder::new_with_metadata(file, meta);
+
+        if let Some(key) = bloom_bytes(key_array) {
+            let mut may_contain = false;
+            for row_group in 0..builder.metadata().num_row_groups() {
+                let bloom_filter = builder
+                    .get_row_group_column_bloom_filter(row_group, 0)
+                    .await
+                    .map_err(VersionError::Parquet)?;
+                if bloom_filter.is_none_or(|bloom_filter| bloom_filter.check(key.as_slice())) {
+                    may_contain = true;
+                    break;
+                }
+            }
+            if !may_contain {
+                return Ok(None);
+            }
+        }
+        let file_metadata = builder.metadata().file_metadata();
+
+        let key_scalar = unsafe { mem::transmute::<_, &'static S::PrimaryKeyArray>(key_array) };
+        let filter = ArrowPredicateFn::new(
+            ProjectionMask::roots(file_metadata.schema_descr(), [0]),
+            move |record_batch| eq(record_batch.column(0), &Scalar::new(&key_scalar)),
+        );
+        let ts_filter = ArrowPredicateFn::new(
+            ProjectionMask::roots(file_metadata.schema_descr(), [TS_COLUMN]),
+            move |record_batch| lt_eq(record_batch.column(0), &UInt64Array::new_scalar(ts)),
+        );
+        let row_filter = RowFilter::new(vec![Box::new(filter), Box::new(ts_filter)]);
+        builder = builder.with_row_filter(row_filter);
+
+        let mut stream = builder.build().map_err(VersionError::Parquet)?;
+
+        if let Some(result) = stream.next().await {
+            return Ok(Some(result.map_err(VersionError::Parquet)?));
+        }
+        Ok(None)
+    }
+}
+
+/// Lookups served by and passing through each table of the current version, kept in memory only.
+#[derive(Debug, Default)]
+pub(crate) struct ReadStats {
+    tables: Mutex<HashMap<ProcessUniqueId, TableReads>>,
+}
+
+impl ReadStats {
+    /// Records a lookup that read the tables of `probed` in order, and found the key in the last
+    /// one if `found`. Every other table read is a miss, the lookup went on past it. Returns the
+    /// tables whose misses reached `max_misses`, whose misses are counted anew.
+    pub(crate) fn record(
+        &self,
+        probed: &[ProcessUniqueId],
+        found: bool,
+        max_misses: Option<u64>,
+    ) -> Vec<ProcessUniqueId> {
+        let Some((last, passed)) = probed.split_last() else {
+            return Vec::new();
+        };
+        let mut tables = self.tables.lock().unwrap();
+        let mut hot = Vec::new();
+
+        if found {
+            tables.entry(*last).or_default().hits += 1;
+        }
+        for gen in passed {
+            let reads = tables.entry(*gen).or_default();
+            reads.misses += 1;
+            if max_misses.is_some_and(|max_misses| reads.misses >= max_misses) {
+                reads.misses = 0;
+                hot.push(*gen);
+            }
+        }
+        hot
+    }
+
+    pub(crate) fn get(&self, gen: &ProcessUniqueId) -> TableReads {
+        self.tables
+            .lock()
+            .unwrap()
+            .get(gen)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn remove(&self, gen: &ProcessUniqueId) {
+        self.tables.lock().unwrap().remove(gen);
+    }
+}
+
+/// The single key of `array` the way parquet hashes it into a bloom filter, i.e. plain encoded.
+/// `None` for key types without one.
+fn bloom_bytes(array: &dyn Array) -> Option<Vec<u8>> {
+    Some(match array.data_type() {
+        DataType::Int8 => (array.as_primitive::<Int8Type>().value(0) as i32)
+            .to_le_bytes()
+            .to_vec(),
+        DataType::Int16 => (array.as_primitive::<Int16Type>().value(0) as i32)
+            .to_le_bytes()
+            .to_vec(),
+        DataType::Int32 => array
+            .as_primitive::<Int32Type>()
+            .value(0)
+            .to_le_bytes()
+            .to_vec(),
+        DataType::Int64 => array
+            .as_primitive::<Int64Type>()
+            .value(0)
+            .to_le_bytes()
+            .to_vec(),
+        DataType::UInt8 => (array.as_primitive::<UInt8Type>().value(0) as i32)
+            .to_le_bytes()
+            .to_vec(),
+        DataType::UInt16 => (array.as_primitive::<UInt16Type>().value(0) as i32)
+            .to_le_bytes()
+            .to_vec(),
+        DataType::UInt32 => array
+            .as_primitive::<UInt32Type>()
+            .value(0)
+            .to_le_bytes()
+            .to_vec(),
+        DataType::UInt64 => array
+            .as_primitive::<UInt64Type>()
+            .value(0)
+            .to_le_bytes()
+            .to_vec(),
+        DataType::Utf8 => array.as_string::<i32>().value(0).as_bytes().to_vec(),
+        DataType::LargeUtf8 => array.as_string::<i64>().value(0).as_bytes().to_vec(),
+        DataType::Binary => array.as_binary::<i32>().value(0).to_vec(),
+        DataType::LargeBinary => array.as_binary::<i64>().value(0).to_vec(),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use executor::ExecutorBuilder;
+    use futures::channel::mpsc::channel;
+    use parquet::arrow::async_reader::AsyncFileReader;
+    use snowflake::ProcessUniqueId;
+
+    use super::{ReadStats, VersionRead};
+    use crate::{
+        compactor::Compactor,
+        mem_table::MemTable,
+        schema::Schema,
+        tests::UserInner,
+        version::Version,
+        wal::provider::{in_mem::InMemProvider, TableStore},
+    };
+
+    #[test]
+    fn read_through_levels() {
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let store = InMemProvider::default();
+            let user = |id, name: &str| {
+                UserInner::new(id, name.to_string(), false, 0, 0, 0, 0, 0, 0, 0, 0)
+            };
+            let table = |rows: Vec<(u64, u64, Option<UserInner>)>| {
+                let store = &store;
+                async move {
+                    let mut mem_table = MemTable::default();
+                    for (key, ts, value) in rows {
+                        mem_table.insert(key, ts, value);
+                    }
+                    let batches = mem_table.to_batches(usize::MAX);
+                    Compactor::<UserInner>::minor_compaction(store, VecDeque::from(batches))
+                        .await
+                        .unwrap()
+                        .unwrap()
+                }
+            };
+
+            let (sender, _) = channel(1);
+            let mut version = Version::<UserInner> {
+                num: 0,
+                level_slice: Version::<UserInner>::level_slice_new(),
+                clean_sender: sender,
+            };
+            version.level_slice[2]
+                .push(table(vec![(1, 1, Some(user(1, "a"))), (4, 1, Some(user(4, "d")))]).await);
+            version.level_slice[0].push(table(vec![(1, 2, Some(user(1, "b")))]).await);
+            version.level_slice[0]
+                .push(table(vec![(1, 3, None), (3, 3, Some(user(3, "c")))]).await);
+
+            let mut file = TableStore::open_table(&store, &version.level_slice[2][0].gen)
+                .await
+                .unwrap();
+            let metadata = file.get_metadata().await.unwrap();
+            assert!(metadata
+                .row_group(0)
+                .column(0)
+                .bloom_filter_offset()
+                .is_some());
+
+            let read = VersionRead::new(&version, &store);
+            let get = |key, ts| {
+                let read = &read;
+                async move {
+                    read.get(&key, ts)
+                        .await
+                        .unwrap()
+                        .map(|batch| UserInner::from_batch(&batch, 0).1)
+                }
+            };
+            assert_eq!(get(1, 1).await, Some(Some(user(1, "a"))));
+            assert_eq!(get(1, 2).await, Some(Some(user(1, "b"))));
+            assert_eq!(get(1, 3).await, Some(None));
+            assert_eq!(get(4, 3).await, Some(Some(user(4, "d"))));
+            assert_eq!(get(2, 3).await, None);
+            assert_eq!(get(3, 2).await, None);
+
+            let mut probed = Vec::new();
+            read.get_probed(&1, 1, &mut probed).await.unwrap();
+            assert_eq!(
+                probed,
+                vec![
+                    version.level_slice[0][1].gen,
+                    version.level_slice[0][0].gen,
+                    version.level_slice[2][0].gen
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn count_misses() {
+        let stats = ReadStats::default();
+        let (first, second, last) = (
+            ProcessUniqueId::new(),
+            ProcessUniqueId::new(),
+            ProcessUniqueId::new(),
+        );
+
+        assert!(stats
+            .record(&[first, second, last], true, Some(2))
+            .is_empty());
+        assert!(stats.record(&[last], false, Some(2)).is_empty());
+        assert_eq!(stats.record(&[first, last], false, Some(2)), vec![first]);
+        assert_eq!(stats.get(&first).misses, 0);
+        assert_eq!(stats.get(&second).misses, 1);
+        assert_eq!(stats.get(&last).hits, 1);
+        assert_eq!(stats.get(&last).misses, 0);
+
+        stats.remove(&second);
+        assert_eq!(stats.get(&second).misses, 0);
+        assert!(stats.record(&[first, last], false, None).is_empty());
+    }
+}
@@ -1,17 +1,26 @@
-use std::{fs::OpenOptions, io::SeekFrom, sync::Arc};
+use std::{
+    fs::{File, OpenOptions},
+    ops::RangeInclusive,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
 use async_lock::RwLock;
 use executor::{
     fs,
-    futures::{util::SinkExt, AsyncSeekExt, AsyncWriteExt},
+    futures::{util::SinkExt, AsyncWriteExt},
 };
 use futures::channel::mpsc::Sender;
 use snowflake::ProcessUniqueId;
 
 use crate::{
+    comparator::Comparator,
     schema::Schema,
+    scope::Scope,
     serdes::Encode,
-    version::{cleaner::CleanTag, edit::VersionEdit, Version, VersionError, VersionRef},
+    version::{
+        cleaner::CleanTag, edit::VersionEdit, read::ReadStats, Version, VersionError, VersionRef,
+    },
     DbOption,
 };
 
@@ -21,6 +30,10 @@ where
 {
     current: VersionRef<S>,
     log: fs::File,
+    path: PathBuf,
+    /// bytes in `log`, which is rewritten past `max_log_size`
+    log_size: usize,
+    max_log_size: usize,
 }
 
 pub(crate) struct VersionSet<S>
@@ -29,6 +42,53 @@ where
 {
     inner: Arc<RwLock<VersionSetInner<S>>>,
     clean_sender: Sender<CleanTag>,
+    running: Arc<Mutex<Running<S::PrimaryKey>>>,
+    reads: Arc<ReadStats>,
+}
+
+/// Compactions in flight, each covering its input key range on its levels, e.g. the level it
+/// compacts and the level below.
+struct Running<K> {
+    next_id: u64,
+    compactions: Vec<RunningCompaction<K>>,
+}
+
+struct RunningCompaction<K> {
+    id: u64,
+    levels: RangeInclusive<usize>,
+    min: K,
+    max: K,
+    gens: Vec<ProcessUniqueId>,
+}
+
+/// The inputs of a compaction, whose levels and key range no other compaction touches until it is
+/// dropped.
+pub(crate) struct Reservation<S>
+where
+    S: Schema,
+{
+    pub(crate) version: VersionRef<S>,
+    pub(crate) level: usize,
+    /// the deepest level reserved, which the output goes to
+    pub(crate) last_level: usize,
+    pub(crate) inputs: Vec<Scope<S::PrimaryKey>>,
+    pub(crate) next_inputs: Vec<Scope<S::PrimaryKey>>,
+    pub(crate) min: S::PrimaryKey,
+    pub(crate) max: S::PrimaryKey,
+    id: u64,
+    running: Arc<Mutex<Running<S::PrimaryKey>>>,
+}
+
+impl<S> Drop for Reservation<S>
+where
+    S: Schema,
+{
+    fn drop(&mut self) {
+        let mut running = self.running.lock().unwrap();
+        running
+            .compactions
+            .retain(|compaction| compaction.id != self.id);
+    }
 }
 
 impl<S> Clone for VersionSet<S>
@@ -39,6 +99,8 @@ where
         VersionSet {
             inner: self.inner.clone(),
             clean_sender: self.clean_sender.clone(),
+            running: self.running.clone(),
+            reads: self.reads.clone(),
         }
     }
 }
@@ -51,16 +113,16 @@ where
         option: &DbOption,
         clean_sender: Sender<CleanTag>,
     ) -> Result<Self, VersionError<S>> {
+        let path = option.version_path();
         let mut log = fs::File::from(
             OpenOptions::new()
                 .create(true)
                 .write(true)
                 .read(true)
-                .open(option.version_path())
+                .open(&path)
                 .map_err(VersionError::Io)?,
         );
         let edits = VersionEdit::recover(&mut log).await;
-        log.seek(SeekFrom::End(0)).await.map_err(VersionError::Io)?;
 
         let set = VersionSet::<S> {
             inner: Arc::new(RwLock::new(VersionSetInner {
@@ -70,10 +132,20 @@ where
                     clean_sender: clean_sender.clone(),
                 }),
                 log,
+                path,
+                log_size: 0,
+                max_log_size: option.version_log_max_size,
             })),
             clean_sender,
+            running: Arc::new(Mutex::new(Running {
+                next_id: 0,
+                compactions: Vec::new(),
+            })),
+            reads: Arc::new(ReadStats::default()),
         };
         set.apply_edits(edits, None, true).await?;
+        // a torn batch at the tail of the log would hide the batches appended after it
+        set.inner.write().await.rewrite().await?;
 
         Ok(set)
     }
@@ -82,6 +154,91 @@ where
         self.inner.read().await.current.clone()
     }
 
+    /// Picks the inputs of a compaction of `level` from the current version and reserves them,
+    /// returning `None` when there is nothing to pick or a running compaction overlaps them.
+    pub(crate) async fn reserve(
+        &self,
+        level: usize,
+        pick: impl FnOnce(&Version<S>) -> Option<(Vec<Scope<S::PrimaryKey>>, Vec<Scope<S::PrimaryKey>>)>,
+    ) -> Option<Reservation<S>> {
+        self.reserve_levels(|version| {
+            let (inputs, next_inputs) = pick(version)?;
+            Some((level..=level + 1, inputs, next_inputs))
+        })
+        .await
+    }
+
+    /// Like [`VersionSet::reserve`], for compactions whose levels the pick tells, e.g. those of
+    /// the sorted runs a tiered compaction merges.
+    #[allow(clippy::type_complexity)]
+    pub(crate) async fn reserve_levels(
+        &self,
+        pick: impl FnOnce(
+            &Version<S>,
+        ) -> Option<(
+            RangeInclusive<usize>,
+            Vec<Scope<S::PrimaryKey>>,
+            Vec<Scope<S::PrimaryKey>>,
+        )>,
+    ) -> Option<Reservation<S>> {
+        // holding the version keeps edits from landing between the pick and the reservation
+        let guard = self.inner.read().await;
+        let (levels, inputs, next_inputs) = pick(&guard.current)?;
+
+        let scopes = inputs.iter().chain(next_inputs.iter());
+        let min = S::Comparator::min(scopes.clone().map(|scope| &scope.min))?.clone();
+        let max = S::Comparator::max(scopes.map(|scope| &scope.max))?.clone();
+
+        let mut running = self.running.lock().unwrap();
+        let overlaps = running.compactions.iter().any(|compaction| {
+            compaction.levels.start() <= levels.end()
+                && levels.start() <= compaction.levels.end()
+                && S::Comparator::compare(&compaction.min, &max).is_le()
+                && S::Comparator::compare(&min, &compaction.max).is_le()
+        });
+        if overlaps {
+            return None;
+        }
+        let id = running.next_id;
+        running.next_id += 1;
+        running.compactions.push(RunningCompaction {
+            id,
+            levels: levels.clone(),
+            min: min.clone(),
+            max: max.clone(),
+            gens: inputs
+                .iter()
+                .chain(next_inputs.iter())
+                .map(|scope| scope.gen)
+                .collect(),
+        });
+
+        Some(Reservation {
+            version: guard.current.clone(),
+            level: *levels.start(),
+            last_level: *levels.end(),
+            inputs,
+            next_inputs,
+            min,
+            max,
+            id,
+            running: self.running.clone(),
+        })
+    }
+
+    pub(crate) fn reads(&self) -> &ReadStats {
+        &self.reads
+    }
+
+    pub(crate) fn is_compacting(&self, gen: &ProcessUniqueId) -> bool {
+        let running = self.running.lock().unwrap();
+
+        running
+            .compactions
+            .iter()
+            .any(|compaction| compaction.gens.contains(gen))
+    }
+
     pub(crate) async fn apply_edits(
         &self,
         version_edits: Vec<VersionEdit<S::PrimaryKey>>,
@@ -98,12 +255,23 @@ where
                     .encode(&mut guard.log)
                     .await
                     .map_err(VersionError::Encode)?;
+                guard.log_size += version_edit.size();
             }
             match version_edit {
                 VersionEdit::Add { scope, level } => {
-                    new_version.level_slice[level as usize].push(scope);
+                    let scopes = &mut new_version.level_slice[level as usize];
+                    // levels below 0 are searched by their min keys
+                    let index = if level == 0 {
+                        scopes.len()
+                    } else {
+                        scopes.partition_point(|existing| {
+                            S::Comparator::compare(&existing.min, &scope.min).is_lt()
+                        })
+                    };
+                    scopes.insert(index, scope);
                 }
                 VersionEdit::Remove { gen, level } => {
+                    self.reads.remove(&gen);
                     if let Some(i) = new_version.level_slice[level as usize]
                         .iter()
                         .position(|scope| scope.gen == gen)
@@ -111,8 +279,16 @@ where
                         new_version.level_slice[level as usize].remove(i);
                     }
                 }
+                VersionEdit::Commit => (),
             }
         }
+        if !is_recover {
+            VersionEdit::<S::PrimaryKey>::Commit
+                .encode(&mut guard.log)
+                .await
+                .map_err(VersionError::Encode)?;
+            guard.log_size += VersionEdit::<S::PrimaryKey>::Commit.size();
+        }
         if let Some(delete_gens) = delete_gens {
             new_version
                 .clean_sender
@@ -125,6 +301,120 @@ where
         }
         guard.log.flush().await.map_err(VersionError::Io)?;
         guard.current = Arc::new(new_version);
+
+        if guard.log_size > guard.max_log_size {
+            guard.rewrite().await?;
+        }
+        Ok(())
+    }
+}
+
+impl<S> VersionSetInner<S>
+where
+    S: Schema,
+{
+    /// Replaces the log with a single batch adding the tables of the current version.
+    async fn rewrite(&mut self) -> Result<(), VersionError<S>> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut log = fs::File::from(File::create(&tmp_path).map_err(VersionError::Io)?);
+        let mut log_size = 0;
+
+        let adds = self
+            .current
+            .level_slice
+            .iter()
+            .enumerate()
+            .flat_map(|(level, scopes)| {
+                scopes.iter().map(move |scope| VersionEdit::Add {
+                    level: level as u8,
+                    scope: scope.clone(),
+                })
+            });
+        for edit in adds.chain([VersionEdit::Commit]) {
+            edit.encode(&mut log).await.map_err(VersionError::Encode)?;
+            log_size += edit.size();
+        }
+        log.flush().await.map_err(VersionError::Io)?;
+        std::fs::rename(&tmp_path, &self.path).map_err(VersionError::Io)?;
+
+        self.log = fs::File::from(
+            OpenOptions::new()
+                .append(true)
+                .open(&self.path)
+                .map_err(VersionError::Io)?,
+        );
+        self.log_size = log_size;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use executor::ExecutorBuilder;
+    use futures::channel::mpsc::channel;
+    use snowflake::ProcessUniqueId;
+    use tempfile::TempDir;
+
+    use crate::{
+        scope::Scope,
+        tests::UserInner,
+        version::{edit::VersionEdit, set::VersionSet},
+        DbOption,
+    };
+
+    #[test]
+    fn rewrite_log() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let mut option = DbOption::new(temp_dir.path().to_path_buf());
+            option.version_log_max_size = 1024;
+            let (sender, _receiver) = channel(16);
+            let gens = (0..16).map(|_| ProcessUniqueId::new()).collect::<Vec<_>>();
+
+            {
+                let version_set = VersionSet::<UserInner>::new(&option, sender.clone())
+                    .await
+                    .unwrap();
+                for (i, gen) in gens.iter().enumerate() {
+                    let add = VersionEdit::Add {
+                        level: 1,
+                        scope: Scope {
+                            min: i as u64,
+                            max: i as u64,
+                            gen: *gen,
+                            stats: Default::default(),
+                        },
+                    };
+                    version_set
+                        .apply_edits(vec![add], None, false)
+                        .await
+                        .unwrap();
+                    if i % 2 == 1 {
+                        let remove = VersionEdit::Remove {
+                            level: 1,
+                            gen: *gen,
+                        };
+                        version_set
+                            .apply_edits(vec![remove], None, false)
+                            .await
+                            .unwrap();
+                    }
+                }
+                assert!(
+                    std::fs::metadata(option.version_path()).unwrap().len()
+                        <= option.version_log_max_size as u64
+                );
+            }
+            let version_set = VersionSet::<UserInner>::new(&option, sender).await.unwrap();
+            let version = version_set.current().await;
+            assert_eq!(
+                version.level_slice[1]
+                    .iter()
+                    .map(|scope| scope.gen)
+                    .collect::<Vec<_>>(),
+                gens.iter().step_by(2).copied().collect::<Vec<_>>()
+            );
+        });
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use async_lock::Mutex as AsyncMutex;
+use futures::channel::oneshot;
+use futures_timer::Delay;
+
+use super::WalWrite;
+use crate::serdes::Encode;
+
+/// Coalesces the flushes of concurrent writes: the first write waiting for its record sleeps
+/// out the interval, then flushes the segment once for every record appended meanwhile.
+#[derive(Debug)]
+pub(crate) struct GroupCommit {
+    interval: Duration,
+    appended: AtomicU64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    synced: u64,
+    leading: bool,
+    waiters: Vec<oneshot::Sender<()>>,
+}
+
+impl GroupCommit {
+    pub(crate) fn new(interval: Duration) -> Self {
+        GroupCommit {
+            interval,
+            appended: AtomicU64::new(0),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Counts a record written to the wal and returns the sequence to wait for. Must be called
+    /// while holding the wal lock.
+    pub(crate) fn appended(&self) -> u64 {
+        self.appended.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Resolves once every record up to `seq` is flushed.
+    pub(crate) async fn wait<W, K, V>(&self, seq: u64, wal: &AsyncMutex<W>) -> io::Result<()>
+    where
+        W: WalWrite<K, V>,
+        K: Encode,
+        V: Encode,
+    {
+        loop {
+            let waiter = {
+                let mut state = self.state.lock().unwrap();
+                if state.synced >= seq {
+                    return Ok(());
+                }
+                if state.leading {
+                    let (tx, rx) = oneshot::channel();
+                    state.waiters.push(tx);
+                    Some(rx)
+                } else {
+                    state.leading = true;
+                    None
+                }
+            };
+            match waiter {
+                // woken whenever the leader is done, whether it flushed or not
+                Some(rx) => {
+                    let _ = rx.await;
+                }
+                None => return self.lead(wal).await,
+            }
+        }
+    }
+
+    async fn lead<W, K, V>(&self, wal: &AsyncMutex<W>) -> io::Result<()>
+    where
+        W: WalWrite<K, V>,
+        K: Encode,
+        V: Encode,
+    {
+        let _leader = Leader(self);
+
+        Delay::new(self.interval).await;
+        let mut wal = wal.lock().await;
+        let appended = self.appended.load(Ordering::Relaxed);
+        wal.flush().await?;
+        drop(wal);
+
+        let mut state = self.state.lock().unwrap();
+        state.synced = state.synced.max(appended);
+        Ok(())
+    }
+}
+
+/// Hands leadership back even when the leading write is dropped, so that a waiter takes over.
+struct Leader<'a>(&'a GroupCommit);
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.leading = false;
+        state.waiters.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use async_lock::Mutex;
+    use futures::{executor::block_on, future::join_all, AsyncWrite};
+
+    use super::GroupCommit;
+    use crate::{
+        record::{Record, RecordType},
+        wal::{WalFile, WalWrite},
+    };
+
+    struct CountFlushes(Arc<AtomicUsize>);
+
+    impl AsyncWrite for CountFlushes {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn one_flush_per_interval() {
+        block_on(async {
+            let flushes = Arc::new(AtomicUsize::new(0));
+            let wal = Mutex::new(WalFile::<_, String, String>::new(CountFlushes(
+                flushes.clone(),
+            )));
+            let group_commit = GroupCommit::new(Duration::from_millis(10));
+
+            let writes = (0..16).map(|i| {
+                let wal = &wal;
+                let group_commit = &group_commit;
+                async move {
+                    let seq = {
+                        let mut wal = wal.lock().await;
+                        wal.write(Record::new(
+                            RecordType::Full,
+                            &i.to_string(),
+                            0_u64,
+                            Some(&"value".to_string()),
+                        ))
+                        .await
+                        .unwrap();
+                        group_commit.appended()
+                    };
+                    group_commit.wait(seq, wal).await.unwrap();
+                }
+            });
+            join_all(writes).await;
+            assert_eq!(flushes.load(Ordering::Relaxed), 1);
+
+            let seq = group_commit.appended();
+            group_commit.wait(seq, &wal).await.unwrap();
+            assert_eq!(flushes.load(Ordering::Relaxed), 2);
+        });
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::serdes::{Decode, Encode};
+
+const MAGIC: [u8; 4] = *b"EWAL";
+pub(crate) const FORMAT_VERSION: u8 = 3;
+pub(crate) const CODEC_NONE: u8 = 0;
+
+/// Written at the start of every WAL segment so that segments of other formats can be told apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct WalHeader {
+    pub(crate) version: u8,
+    pub(crate) codec: u8,
+    /// milliseconds since the unix epoch
+    pub(crate) created_at: u64,
+    pub(crate) shard_id: u32,
+    /// how many shards the store ran on when the segment was written, 0 before version 2
+    pub(crate) shards: u32,
+    /// how many of the schema's defaulted fields its rows carry, see
+    /// [`crate::schema::Schema::decode_defaulted`], unknown before version 3
+    pub(crate) defaulted: Option<u32>,
+}
+
+impl WalHeader {
+    pub(crate) fn new(shard_id: u32, shards: u32, defaulted: u32) -> Self {
+        WalHeader {
+            version: FORMAT_VERSION,
+            codec: CODEC_NONE,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            shard_id,
+            shards,
+            defaulted: Some(defaulted),
+        }
+    }
+
+    /// Reads and validates the header, returning `None` for a segment nothing was written to.
+    pub(crate) async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut magic = [0; MAGIC.len()];
+        let mut filled = 0;
+
+        while filled < magic.len() {
+            match reader.read(&mut magic[filled..]).await? {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => filled += n,
+            }
+        }
+        if magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "wal segment header magic mismatch",
+            ));
+        }
+        let header = Self::decode(reader).await?;
+
+        if header.version > FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported wal format version {}", header.version),
+            ));
+        }
+        if header.codec != CODEC_NONE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported wal codec {}", header.codec),
+            ));
+        }
+        Ok(Some(header))
+    }
+}
+
+impl Encode for WalHeader {
+    type Error = io::Error;
+
+    async fn encode<W>(&self, writer: &mut W) -> Result<(), Self::Error>
+    where
+        W: AsyncWrite + Unpin + Send + Sync,
+    {
+        writer.write_all(&MAGIC).await?;
+        self.version.encode(writer).await?;
+        self.codec.encode(writer).await?;
+        self.created_at.encode(writer).await?;
+        self.shard_id.encode(writer).await?;
+        self.shards.encode(writer).await?;
+        self.defaulted.unwrap_or(0).encode(writer).await
+    }
+
+    fn size(&self) -> usize {
+        MAGIC.len()
+            + self.version.size()
+            + self.codec.size()
+            + self.created_at.size()
+            + self.shard_id.size()
+            + self.shards.size()
+            + self.defaulted.unwrap_or(0).size()
+    }
+}
+
+/// Decodes the fields following the magic.
+impl Decode for WalHeader {
+    type Error = io::Error;
+
+    async fn decode<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, Self::Error> {
+        let version = u8::decode(reader).await?;
+        let codec = u8::decode(reader).await?;
+        let created_at = u64::decode(reader).await?;
+        let shard_id = u32::decode(reader).await?;
+        let shards = if version >= 2 {
+            u32::decode(reader).await?
+        } else {
+            0
+        };
+        let defaulted = if version >= 3 {
+            Some(u32::decode(reader).await?)
+        } else {
+            None
+        };
+
+        Ok(WalHeader {
+            version,
+            codec,
+            created_at,
+            shard_id,
+            shards,
+            defaulted,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, io::Cursor};
+
+    use super::{WalHeader, FORMAT_VERSION};
+    use crate::serdes::Encode;
+
+    #[test]
+    fn read_header() {
+        block_on(async {
+            let header = WalHeader::new(3, 4, 1);
+            let mut bytes = Vec::new();
+            header.encode(&mut Cursor::new(&mut bytes)).await.unwrap();
+            assert_eq!(bytes.len(), header.size());
+
+            assert_eq!(
+                WalHeader::read(&mut Cursor::new(&bytes)).await.unwrap(),
+                Some(header.clone())
+            );
+            assert_eq!(
+                WalHeader::read(&mut Cursor::new(Vec::new())).await.unwrap(),
+                None
+            );
+
+            let mut corrupted = bytes.clone();
+            corrupted[0] = 0;
+            assert!(WalHeader::read(&mut Cursor::new(&corrupted)).await.is_err());
+
+            let mut older = bytes[..bytes.len() - 4].to_vec();
+            older[4] = 2;
+            assert_eq!(
+                WalHeader::read(&mut Cursor::new(&older)).await.unwrap(),
+                Some(WalHeader {
+                    version: 2,
+                    defaulted: None,
+                    ..header.clone()
+                })
+            );
+            older.truncate(older.len() - 4);
+            older[4] = 1;
+            assert_eq!(
+                WalHeader::read(&mut Cursor::new(&older)).await.unwrap(),
+                Some(WalHeader {
+                    version: 1,
+                    shards: 0,
+                    defaulted: None,
+                    ..header
+                })
+            );
+
+            let mut newer = bytes.clone();
+            newer[4] = FORMAT_VERSION + 1;
+            assert!(WalHeader::read(&mut Cursor::new(&newer)).await.is_err());
+        });
+    }
+}
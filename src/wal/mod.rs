@@ -1,57 +1,112 @@
 mod checksum;
+pub(crate) mod group_commit;
+pub(crate) mod header;
 pub mod provider;
 
 use std::{
-    error::Error,
     future::Future,
     io,
     marker::PhantomData,
-    sync::atomic::{AtomicU32, Ordering},
+    pin::pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
 };
 
 use async_stream::stream;
 use checksum::{HashReader, HashWriter};
 use futures::{
     io::{BufReader, BufWriter},
-    AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, Stream,
+    AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, Stream, StreamExt,
 };
+use header::WalHeader;
 use thiserror::Error;
 
 use self::provider::WalProvider;
 use crate::{
-    record::Record,
+    oracle::TimeStamp,
+    record::{RangeDelete, Record, SystemRecord, WalEntry},
+    registry::SchemaMismatch,
+    schema::Schema,
     serdes::{Decode, Encode},
+    validate::ValidationError,
 };
 
 #[derive(Debug)]
 pub(crate) struct WalManager<WP> {
-    pub(crate) wal_provider: WP,
+    pub(crate) wal_provider: Arc<WP>,
     file_id: AtomicU32,
+    /// how many defaulted fields the rows of the current schema carry
+    defaulted: u32,
 }
 
 impl<WP> WalManager<WP>
 where
     WP: WalProvider,
 {
-    pub(crate) fn new(wal_provider: WP) -> Self {
+    pub(crate) fn new(wal_provider: Arc<WP>, defaulted: u32) -> Self {
         Self {
             wal_provider,
             file_id: AtomicU32::new(0),
+            defaulted,
         }
     }
 
-    pub(crate) async fn create_wal_file<K, V>(&self) -> io::Result<WalFile<WP::File, K, V>> {
+    /// Numbers the segments opened from now on after the existing ones, segments are never
+    /// removed so that they are numbered from 0 on without gaps.
+    pub(crate) async fn resume(&self) -> io::Result<()> {
+        let mut segments = 0;
+        let mut files = pin!(self.wal_provider.list());
+        while let Some(file) = files.next().await {
+            file?;
+            segments += 1;
+        }
+        self.file_id.store(segments, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Opens a new segment for `shard_id` and writes its header, along with the current shard
+    /// count and how many defaulted fields its rows carry.
+    pub(crate) async fn create_wal_file<K, V>(
+        &self,
+        shard_id: u32,
+    ) -> io::Result<WalFile<WP::File, K, V>>
+    where
+        WP::File: AsyncWrite,
+    {
         let file_id = self.file_id.fetch_add(1, Ordering::Relaxed);
-        let file = self.wal_provider.open(file_id).await?;
+        let mut file = self.wal_provider.open(file_id).await?;
+        WalHeader::new(shard_id, executor::worker_num() as u32, self.defaulted)
+            .encode(&mut file)
+            .await?;
 
-        self.pack_wal_file(file).await
+        Ok(WalFile::new(file))
     }
 
+    /// Validates the header of an existing segment, leaving `file` at its first record. The
+    /// header is `None` for a segment nothing was written to. Rows of segments written before a
+    /// schema upgrade are recovered with defaults for the fields they lack, `registered` is how
+    /// many defaulted fields the rows of segments older than the count in the header carry.
     pub(crate) async fn pack_wal_file<K, V>(
         &self,
-        file: WP::File,
-    ) -> io::Result<WalFile<WP::File, K, V>> {
-        Ok(WalFile::new(file))
+        mut file: WP::File,
+        registered: u32,
+    ) -> io::Result<(Option<WalHeader>, WalFile<WP::File, K, V>)>
+    where
+        WP::File: AsyncRead,
+    {
+        let header = WalHeader::read(&mut file).await?;
+        let mut wal_file = WalFile::new(file);
+        let defaulted = header
+            .as_ref()
+            .and_then(|header| header.defaulted)
+            .unwrap_or(registered);
+        if defaulted < self.defaulted {
+            wal_file.defaulted = Some(defaulted as usize);
+        }
+
+        Ok((header, wal_file))
     }
 }
 
@@ -65,6 +120,16 @@ where
         record: Record<&K, &V>,
     ) -> impl Future<Output = Result<(), WriteError<<Record<&K, &V> as Encode>::Error>>>;
 
+    fn write_range_delete(
+        &mut self,
+        range: RangeDelete<&K>,
+    ) -> impl Future<Output = Result<(), WriteError<<Record<&K, &V> as Encode>::Error>>>;
+
+    fn write_system(
+        &mut self,
+        system: SystemRecord,
+    ) -> impl Future<Output = Result<(), WriteError<<Record<&K, &V> as Encode>::Error>>>;
+
     fn flush(&mut self) -> impl Future<Output = io::Result<()>>;
 
     fn close(self) -> impl Future<Output = io::Result<()>>;
@@ -73,12 +138,15 @@ where
 pub trait WalRecover<K, V> {
     type Error: std::error::Error + Send + Sync + 'static;
 
-    fn recover(&mut self) -> impl Stream<Item = Result<Record<K, V>, Self::Error>>;
+    fn recover(&mut self) -> impl Stream<Item = Result<WalEntry<K, V>, Self::Error>>;
 }
 
 #[derive(Debug)]
 pub(crate) struct WalFile<F, K, V> {
     file: F,
+    torn: bool,
+    /// set when the rows lack some of the defaulted fields of the schema
+    defaulted: Option<usize>,
     _marker: PhantomData<(K, V)>,
 }
 
@@ -86,9 +154,39 @@ impl<F, K, V> WalFile<F, K, V> {
     pub(crate) fn new(file: F) -> Self {
         Self {
             file,
+            torn: false,
+            defaulted: None,
             _marker: PhantomData,
         }
     }
+
+    /// Whether a write was dropped or failed midway, leaving part of a record behind. Nothing
+    /// may be appended after it, recovery only skips a partial record at the end of a segment.
+    pub(crate) fn is_torn(&self) -> bool {
+        self.torn
+    }
+}
+
+impl<F, K, V> WalFile<F, K, V>
+where
+    F: AsyncWrite + Unpin + Send + Sync,
+    K: Encode,
+    V: Encode,
+{
+    async fn append(
+        &mut self,
+        entry: WalEntry<&K, &V>,
+    ) -> Result<(), WriteError<<Record<&K, &V> as Encode>::Error>> {
+        let mut buf = Vec::new();
+        let mut writer = HashWriter::new(&mut buf);
+        entry.encode(&mut writer).await?;
+        writer.eol().await.map_err(WriteError::Io)?;
+
+        self.torn = true;
+        self.file.write_all(&buf).await.map_err(WriteError::Io)?;
+        self.torn = false;
+        Ok(())
+    }
 }
 
 impl<F, K, V> WalWrite<K, V> for WalFile<F, K, V>
@@ -101,10 +199,21 @@ where
         &mut self,
         record: Record<&K, &V>,
     ) -> Result<(), WriteError<<Record<&K, &V> as Encode>::Error>> {
-        let mut writer = HashWriter::new(&mut self.file);
-        record.encode(&mut writer).await?;
-        writer.eol().await.map_err(WriteError::Io)?;
-        Ok(())
+        self.append(WalEntry::Record(record)).await
+    }
+
+    async fn write_range_delete(
+        &mut self,
+        range: RangeDelete<&K>,
+    ) -> Result<(), WriteError<<Record<&K, &V> as Encode>::Error>> {
+        self.append(WalEntry::RangeDelete(range)).await
+    }
+
+    async fn write_system(
+        &mut self,
+        system: SystemRecord,
+    ) -> Result<(), WriteError<<Record<&K, &V> as Encode>::Error>> {
+        self.append(WalEntry::System(system)).await
     }
 
     async fn flush(&mut self) -> io::Result<()> {
@@ -121,13 +230,14 @@ impl<F, K, V> WalRecover<K, V> for WalFile<F, K, V>
 where
     F: AsyncRead + Unpin,
     K: Decode,
-    V: Decode,
+    V: Schema,
 {
     type Error = RecoverError<<Record<K, V> as Decode>::Error>;
 
-    fn recover(&mut self) -> impl Stream<Item = Result<Record<K, V>, Self::Error>> {
+    fn recover(&mut self) -> impl Stream<Item = Result<WalEntry<K, V>, Self::Error>> {
         stream! {
             // Safety: https://github.com/rust-lang/futures-rs/pull/2848 fix this, waiting for release
+            let defaulted = self.defaulted;
             let mut file = BufReader::new(unsafe { std::mem::transmute::<_, &mut F>(std::mem::transmute::<_, &mut BufWriter<Vec<_>>>(&mut self.file).get_mut()) });
 
             loop {
@@ -137,14 +247,27 @@ where
 
                 let mut reader = HashReader::new(&mut file);
 
-                let record = Record::decode(&mut reader).await?;
-
-                if !reader.checksum().await.map_err(RecoverError::Io)? {
-                    yield Err(RecoverError::Checksum);
-                    return;
+                // a record cut short at the end of the segment was never acknowledged
+                let entry = match defaulted {
+                    Some(defaulted) => WalEntry::decode_defaulted(&mut reader, defaulted).await,
+                    None => WalEntry::decode(&mut reader).await,
+                };
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(_) if file.fill_buf().await.map_err(RecoverError::Io)?.is_empty() => return,
+                    Err(err) => Err(err)?,
+                };
+                match reader.checksum().await {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        yield Err(RecoverError::Checksum);
+                        return;
+                    }
+                    Err(_) if file.fill_buf().await.map_err(RecoverError::Io)?.is_empty() => return,
+                    Err(err) => Err(RecoverError::Io(err))?,
                 }
 
-                yield Ok(record);
+                yield Ok(entry);
             }
         }
     }
@@ -158,14 +281,48 @@ pub enum WriteError<E: std::error::Error> {
     Io(#[source] std::io::Error),
     #[error("wal write max size exceeded")]
     MaxSizeExceeded,
+    #[error("wal write key of {size} bytes exceeds max_key_size {max}")]
+    KeyTooLarge { size: usize, max: usize },
+    #[error("wal write value of {size} bytes exceeds max_value_size {max}")]
+    ValueTooLarge { size: usize, max: usize },
     #[error("wal write arrow error: {0}")]
     Arrow(#[source] arrow::error::ArrowError),
-    #[error("wal write internal error: {0}")]
-    Internal(#[source] Box<dyn Error + Send + Sync + 'static>),
+    #[error("write parquet error: {0}")]
+    Parquet(#[source] parquet::errors::ParquetError),
+    #[error("import timestamp {ts} is out of range, the oracle is at {now}")]
+    ImportTimestamp { ts: TimeStamp, now: TimeStamp },
+    #[error("import key range overlaps existing tables")]
+    ImportOverlap,
+    /// Bulk imports skip the wal the deltas of aggregates are logged in.
+    #[error("bulk imports are not counted by the aggregates of the db")]
+    ImportAggregated,
+    /// Flushing or closing the wal of a mem table being frozen failed. The mem table is frozen
+    /// only if closing failed.
+    #[error("mem table freeze error: {0}")]
+    Freeze(#[source] io::Error),
+    /// Creating the next wal segment failed, the write was not applied.
+    #[error("wal rotate error: {0}")]
+    WalRotate(#[source] io::Error),
+    #[error("wal recover error: {0}")]
+    Recover(#[source] RecoverError<io::Error>),
+    /// The storage provider failed to list, open or write files.
+    #[error("storage provider error: {0}")]
+    Provider(#[source] io::Error),
+    #[error("write conflicts with a concurrent transaction")]
+    Conflict,
+    #[error("conflict window full: {limit} write sets are kept for running transactions")]
+    ConflictWindowFull { limit: usize },
+    #[error("db is closed")]
+    Closed,
+    #[error(transparent)]
+    Invalid(ValidationError),
+    /// The db was opened with a schema other than the one its rows were written with.
+    #[error(transparent)]
+    Schema(SchemaMismatch),
 }
 
 #[derive(Debug, Error)]
-pub(crate) enum RecoverError<E: std::error::Error> {
+pub enum RecoverError<E: std::error::Error> {
     #[error("wal recover decode error: {0}")]
     Decode(#[from] E),
     #[error("wal recover checksum error")]
@@ -174,14 +331,75 @@ pub(crate) enum RecoverError<E: std::error::Error> {
     Io(#[source] std::io::Error),
 }
 
+impl<E> RecoverError<E>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    /// Keeps the decode error as the source of an [`io::ErrorKind::InvalidData`] error, so that
+    /// recovery errors of every schema are of one type.
+    pub(crate) fn into_io(self) -> RecoverError<io::Error> {
+        match self {
+            RecoverError::Decode(err) => {
+                RecoverError::Decode(io::Error::new(io::ErrorKind::InvalidData, err))
+            }
+            RecoverError::Checksum => RecoverError::Checksum,
+            RecoverError::Io(err) => RecoverError::Io(err),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::pin::pin;
-
-    use futures::{executor::block_on, io::Cursor, StreamExt};
+    use std::{
+        io,
+        ops::Bound,
+        pin::{pin, Pin},
+        task::{Context, Poll},
+    };
+
+    use futures::{
+        executor::block_on,
+        future::{ready, select},
+        io::Cursor,
+        AsyncWrite, StreamExt,
+    };
 
     use super::{Record, WalFile, WalRecover, WalWrite};
-    use crate::record::RecordType;
+    use crate::{
+        record::{RangeDelete, RecordType, SystemRecord, WalEntry},
+        tests::AccountInner,
+    };
+
+    fn value() -> AccountInner {
+        AccountInner::new(0, "value".to_string())
+    }
+
+    fn record<K, V>(entry: WalEntry<K, V>) -> Record<K, V> {
+        match entry {
+            WalEntry::Record(record) => record,
+            WalEntry::RangeDelete(_) | WalEntry::System(_) => panic!("expected a record"),
+        }
+    }
+
+    struct Stalled;
+
+    impl AsyncWrite for Stalled {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
 
     #[test]
     fn write_and_recover() {
@@ -193,7 +411,7 @@ mod tests {
                     RecordType::Full,
                     &"key".to_string(),
                     0_u64,
-                    Some(&"value".to_string()),
+                    Some(&value()),
                 ))
                 .await
                 .unwrap();
@@ -205,8 +423,8 @@ mod tests {
                 {
                     let mut stream = pin!(wal.recover());
                     assert_eq!(
-                        stream.next().await.unwrap().unwrap().value,
-                        Some("value".to_string())
+                        record(stream.next().await.unwrap().unwrap()).value,
+                        Some(value())
                     );
                 }
 
@@ -214,7 +432,7 @@ mod tests {
                     RecordType::Full,
                     &"key".to_string(),
                     0_u64,
-                    Some(&"value".to_string()),
+                    Some(&value()),
                 ))
                 .await
                 .unwrap();
@@ -226,14 +444,104 @@ mod tests {
 
                 {
                     let mut stream = pin!(wal.recover());
-                    let record: Record<String, _> = stream.next().await.unwrap().unwrap();
-                    assert_eq!(record.key, "key".to_string());
-                    assert_eq!(record.value, Some("value".to_string()));
-                    let record: Record<String, _> = stream.next().await.unwrap().unwrap();
-                    assert_eq!(record.key, "key".to_string());
-                    assert_eq!(record.value, Some("value".to_string()));
+                    for _ in 0..2 {
+                        let record: Record<String, _> =
+                            record(stream.next().await.unwrap().unwrap());
+                        assert_eq!(record.key, "key".to_string());
+                        assert_eq!(record.value, Some(value()));
+                    }
                 }
             }
         });
     }
+
+    #[test]
+    fn cancelled_write() {
+        block_on(async {
+            let mut wal = WalFile::<_, String, AccountInner>::new(Stalled);
+            {
+                let write = wal.write(Record::new(
+                    RecordType::Full,
+                    &"key".to_string(),
+                    0_u64,
+                    Some(&value()),
+                ));
+                let _ = select(pin!(write), ready(())).await;
+            }
+            assert!(wal.is_torn());
+
+            let mut file = Vec::new();
+            {
+                let mut wal = WalFile::new(Cursor::new(&mut file));
+                for key in ["a", "b"] {
+                    wal.write(Record::new(
+                        RecordType::Full,
+                        &key.to_string(),
+                        0_u64,
+                        Some(&value()),
+                    ))
+                    .await
+                    .unwrap();
+                }
+                assert!(!wal.is_torn());
+            }
+            file.truncate(file.len() - 3);
+
+            let mut wal = WalFile::new(Cursor::new(&mut file));
+            let records = wal
+                .recover()
+                .map(|entry| entry.map(|entry: WalEntry<String, AccountInner>| record(entry).key))
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0].as_ref().unwrap(), "a");
+        });
+    }
+
+    #[test]
+    fn range_delete() {
+        let mut file = Vec::new();
+        let system = SystemRecord {
+            key: "idempotency/token".to_string(),
+            value: vec![1, 2, 3],
+            ts: 1,
+        };
+        block_on(async {
+            {
+                let mut wal = WalFile::<_, String, AccountInner>::new(Cursor::new(&mut file));
+                let range = RangeDelete {
+                    lower: Bound::Included("a".to_string()),
+                    upper: Bound::Unbounded,
+                    ts: 1_u64,
+                };
+                wal.write_range_delete(range.as_ref()).await.unwrap();
+                wal.write(Record::new(
+                    RecordType::Full,
+                    &"b".to_string(),
+                    1_u64,
+                    Some(&value()),
+                ))
+                .await
+                .unwrap();
+                wal.write_system(system.clone()).await.unwrap();
+            }
+
+            let mut wal = WalFile::<_, String, AccountInner>::new(Cursor::new(&mut file));
+            let mut stream = pin!(wal.recover());
+            match stream.next().await.unwrap().unwrap() {
+                WalEntry::RangeDelete(range) => {
+                    assert_eq!(range.lower, Bound::Included("a".to_string()));
+                    assert_eq!(range.upper, Bound::Unbounded);
+                    assert_eq!(range.ts, 1);
+                }
+                WalEntry::Record(_) | WalEntry::System(_) => panic!("expected a range delete"),
+            }
+            assert_eq!(record(stream.next().await.unwrap().unwrap()).key, "b");
+            match stream.next().await.unwrap().unwrap() {
+                WalEntry::System(recovered) => assert_eq!(recovered, system),
+                _ => panic!("expected a system key"),
+            }
+            assert!(stream.next().await.is_none());
+        });
+    }
 }
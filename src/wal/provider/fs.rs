@@ -6,11 +6,13 @@ use std::{
 };
 
 use async_stream::stream;
+use bytes::Bytes;
 use executor::futures::Stream;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use snowflake::ProcessUniqueId;
 
-use super::WalProvider;
+use super::{StorageProvider, WalProvider};
 
 static WAL_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\d+\.wal$").unwrap());
 
@@ -25,6 +27,10 @@ impl Fs {
             path: path.as_ref().to_owned(),
         })
     }
+
+    fn table_path(&self, gen: &ProcessUniqueId) -> PathBuf {
+        self.path.join(format!("{}.parquet", gen))
+    }
 }
 
 impl WalProvider for Fs {
@@ -59,3 +65,27 @@ impl WalProvider for Fs {
         }
     }
 }
+
+impl StorageProvider for Fs {
+    type TableFile = executor::fs::File;
+
+    async fn open_table(&self, gen: &ProcessUniqueId) -> io::Result<Self::TableFile> {
+        Ok(fs::File::open(self.table_path(gen))?.into())
+    }
+
+    async fn create_table(&self, gen: &ProcessUniqueId, bytes: Bytes) -> io::Result<()> {
+        fs::write(self.table_path(gen), bytes)
+    }
+
+    async fn remove_table(&self, gen: &ProcessUniqueId) -> io::Result<()> {
+        fs::remove_file(self.table_path(gen))
+    }
+
+    async fn table_size(&self, gen: &ProcessUniqueId) -> io::Result<u64> {
+        Ok(fs::metadata(self.table_path(gen))?.len())
+    }
+
+    async fn read_table(&self, gen: &ProcessUniqueId) -> io::Result<Bytes> {
+        fs::read(self.table_path(gen)).map(Bytes::from)
+    }
+}
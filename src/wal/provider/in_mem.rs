@@ -1,20 +1,33 @@
 use std::{
+    collections::HashMap,
     io,
+    ops::Range,
     pin::{pin, Pin},
-    sync::Arc,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
 };
 
 use async_stream::stream;
+use bytes::Bytes;
 use crossbeam_queue::SegQueue;
 use executor::futures::Stream;
-use futures::{io::Cursor, ready, AsyncRead, AsyncWrite};
+use futures::{
+    future::{self, BoxFuture},
+    io::Cursor,
+    ready, AsyncRead, AsyncWrite, FutureExt,
+};
+use parquet::{
+    arrow::async_reader::AsyncFileReader,
+    file::{footer::parse_metadata, metadata::ParquetMetaData},
+};
+use snowflake::ProcessUniqueId;
 
-use super::WalProvider;
+use super::{StorageProvider, WalProvider};
 
 #[derive(Debug, Default, Clone)]
 pub struct InMemProvider {
     wals: Arc<SegQueue<Vec<u8>>>,
+    tables: Arc<Mutex<HashMap<ProcessUniqueId, Bytes>>>,
 }
 
 impl InMemProvider {
@@ -80,3 +93,57 @@ impl AsyncRead for Buf {
         pin!(self.buf.as_mut().unwrap()).poll_read(cx, buf)
     }
 }
+
+impl StorageProvider for InMemProvider {
+    type TableFile = Table;
+
+    async fn open_table(&self, gen: &ProcessUniqueId) -> io::Result<Self::TableFile> {
+        let tables = self.tables.lock().unwrap();
+
+        tables
+            .get(gen)
+            .cloned()
+            .map(Table)
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    async fn create_table(&self, gen: &ProcessUniqueId, bytes: Bytes) -> io::Result<()> {
+        self.tables.lock().unwrap().insert(*gen, bytes);
+        Ok(())
+    }
+
+    async fn remove_table(&self, gen: &ProcessUniqueId) -> io::Result<()> {
+        self.tables.lock().unwrap().remove(gen);
+        Ok(())
+    }
+
+    async fn table_size(&self, gen: &ProcessUniqueId) -> io::Result<u64> {
+        let tables = self.tables.lock().unwrap();
+
+        tables
+            .get(gen)
+            .map(|table| table.len() as u64)
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    async fn read_table(&self, gen: &ProcessUniqueId) -> io::Result<Bytes> {
+        let tables = self.tables.lock().unwrap();
+
+        tables
+            .get(gen)
+            .cloned()
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+}
+
+pub struct Table(Bytes);
+
+impl AsyncFileReader for Table {
+    fn get_bytes(&mut self, range: Range<usize>) -> BoxFuture<'_, parquet::errors::Result<Bytes>> {
+        future::ready(Ok(self.0.slice(range))).boxed()
+    }
+
+    fn get_metadata(&mut self) -> BoxFuture<'_, parquet::errors::Result<Arc<ParquetMetaData>>> {
+        future::ready(parse_metadata(&self.0).map(Arc::new)).boxed()
+    }
+}
@@ -1,10 +1,26 @@
 pub mod fs;
 pub mod in_mem;
+pub mod replica;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub(crate) mod table;
+pub mod tiered;
 
-use std::{future::Future, io};
+use std::{future::Future, io, sync::Arc};
 
+use bytes::Bytes;
 use executor::futures::Stream;
+use futures::{future::BoxFuture, FutureExt};
+use parquet::arrow::async_reader::AsyncFileReader;
+use snowflake::ProcessUniqueId;
+use table::{TableFooter, VerifiedTable};
 
+use crate::{priority::ReadGate, ReadPriority};
+
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` is not a wal provider",
+    note = "set one with `DbBuilder::wal`, e.g. `Fs::new(path)?`"
+)]
 pub trait WalProvider: Send + Sync + 'static {
     type File: Unpin + Send + Sync + 'static;
 
@@ -12,3 +28,201 @@ pub trait WalProvider: Send + Sync + 'static {
 
     fn list(&self) -> impl Stream<Item = io::Result<Self::File>>;
 }
+
+/// Also keeps the parquet tables that flushes and compactions produce, so that the whole database
+/// lives on the same backend as its wal.
+pub trait StorageProvider: WalProvider {
+    type TableFile: AsyncFileReader + 'static;
+
+    fn open_table(
+        &self,
+        gen: &ProcessUniqueId,
+    ) -> impl Future<Output = io::Result<Self::TableFile>> + Send;
+
+    /// Like `open_table`, but leaves the provider's cache as it was, for reads that should not
+    /// evict the tables others read.
+    fn open_table_uncached(
+        &self,
+        gen: &ProcessUniqueId,
+    ) -> impl Future<Output = io::Result<Self::TableFile>> + Send {
+        self.open_table(gen)
+    }
+
+    /// Stores a whole, already encoded table under `gen`.
+    fn create_table(
+        &self,
+        gen: &ProcessUniqueId,
+        bytes: Bytes,
+    ) -> impl Future<Output = io::Result<()>> + Send;
+
+    fn remove_table(&self, gen: &ProcessUniqueId) -> impl Future<Output = io::Result<()>> + Send;
+
+    fn table_size(&self, gen: &ProcessUniqueId) -> impl Future<Output = io::Result<u64>> + Send;
+
+    /// Reads back the whole table, as it was stored by `create_table`.
+    fn read_table(&self, gen: &ProcessUniqueId) -> impl Future<Output = io::Result<Bytes>> + Send;
+
+    /// Moves the table to a colder tier, if the provider has one.
+    fn demote_table(&self, _gen: &ProcessUniqueId) -> impl Future<Output = io::Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Object safe view of a [`StorageProvider`]'s tables, so that versions and streams need not be
+/// generic over it.
+pub(crate) trait TableStore: Send + Sync {
+    fn open_table<'a>(
+        &'a self,
+        gen: &'a ProcessUniqueId,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncFileReader>>>;
+
+    fn open_table_uncached<'a>(
+        &'a self,
+        gen: &'a ProcessUniqueId,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncFileReader>>>;
+
+    fn create_table<'a>(
+        &'a self,
+        gen: &'a ProcessUniqueId,
+        bytes: Bytes,
+    ) -> BoxFuture<'a, io::Result<()>>;
+
+    fn remove_table<'a>(&'a self, gen: &'a ProcessUniqueId) -> BoxFuture<'a, io::Result<()>>;
+
+    fn demote_table<'a>(&'a self, gen: &'a ProcessUniqueId) -> BoxFuture<'a, io::Result<()>>;
+}
+
+impl<SP> TableStore for SP
+where
+    SP: StorageProvider,
+{
+    fn open_table<'a>(
+        &'a self,
+        gen: &'a ProcessUniqueId,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncFileReader>>> {
+        async move {
+            let size = StorageProvider::table_size(self, gen).await?;
+            let file = StorageProvider::open_table(self, gen).await?;
+
+            verify(file, size).await
+        }
+        .boxed()
+    }
+
+    fn open_table_uncached<'a>(
+        &'a self,
+        gen: &'a ProcessUniqueId,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncFileReader>>> {
+        async move {
+            let size = StorageProvider::table_size(self, gen).await?;
+            let file = StorageProvider::open_table_uncached(self, gen).await?;
+
+            verify(file, size).await
+        }
+        .boxed()
+    }
+
+    fn create_table<'a>(
+        &'a self,
+        gen: &'a ProcessUniqueId,
+        bytes: Bytes,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        async move { StorageProvider::create_table(self, gen, TableFooter::seal(bytes)?).await }
+            .boxed()
+    }
+
+    fn remove_table<'a>(&'a self, gen: &'a ProcessUniqueId) -> BoxFuture<'a, io::Result<()>> {
+        StorageProvider::remove_table(self, gen).boxed()
+    }
+
+    fn demote_table<'a>(&'a self, gen: &'a ProcessUniqueId) -> BoxFuture<'a, io::Result<()>> {
+        StorageProvider::demote_table(self, gen).boxed()
+    }
+}
+
+/// Opens tables with the hints of a read, see [`crate::ReadOptions`].
+pub(crate) struct HintedTables {
+    tables: TableStoreRef,
+    gate: Arc<ReadGate>,
+    fill_cache: bool,
+    priority: ReadPriority,
+}
+
+impl HintedTables {
+    pub(crate) fn new(
+        tables: TableStoreRef,
+        gate: Arc<ReadGate>,
+        fill_cache: bool,
+        priority: ReadPriority,
+    ) -> Self {
+        HintedTables {
+            tables,
+            gate,
+            fill_cache,
+            priority,
+        }
+    }
+
+    async fn open(
+        &self,
+        gen: &ProcessUniqueId,
+        fill_cache: bool,
+    ) -> io::Result<Box<dyn AsyncFileReader>> {
+        let _read = match self.priority {
+            ReadPriority::Foreground => Some(self.gate.foreground()),
+            ReadPriority::Background => {
+                self.gate.background().await;
+                None
+            }
+        };
+        if fill_cache {
+            self.tables.open_table(gen).await
+        } else {
+            self.tables.open_table_uncached(gen).await
+        }
+    }
+}
+
+impl TableStore for HintedTables {
+    fn open_table<'a>(
+        &'a self,
+        gen: &'a ProcessUniqueId,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncFileReader>>> {
+        self.open(gen, self.fill_cache).boxed()
+    }
+
+    fn open_table_uncached<'a>(
+        &'a self,
+        gen: &'a ProcessUniqueId,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncFileReader>>> {
+        self.open(gen, false).boxed()
+    }
+
+    fn create_table<'a>(
+        &'a self,
+        gen: &'a ProcessUniqueId,
+        bytes: Bytes,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        self.tables.create_table(gen, bytes)
+    }
+
+    fn remove_table<'a>(&'a self, gen: &'a ProcessUniqueId) -> BoxFuture<'a, io::Result<()>> {
+        self.tables.remove_table(gen)
+    }
+
+    fn demote_table<'a>(&'a self, gen: &'a ProcessUniqueId) -> BoxFuture<'a, io::Result<()>> {
+        self.tables.demote_table(gen)
+    }
+}
+
+async fn verify(
+    file: impl AsyncFileReader + 'static,
+    size: u64,
+) -> io::Result<Box<dyn AsyncFileReader>> {
+    VerifiedTable::open(Box::new(file), size)
+        .await
+        .map(|table| Box::new(table) as Box<dyn AsyncFileReader>)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+pub(crate) type TableStoreRef = Arc<dyn TableStore>;
@@ -0,0 +1,245 @@
+use std::{
+    fmt::{self, Debug},
+    io,
+    ops::Range,
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use futures::{channel::mpsc::Sender, future::BoxFuture, FutureExt};
+use parquet::{
+    arrow::async_reader::AsyncFileReader, errors::ParquetError, file::metadata::ParquetMetaData,
+};
+use snowflake::ProcessUniqueId;
+use tracing::warn;
+
+use super::{table::BlockMismatch, StorageProvider, TableStore, TableStoreRef};
+
+/// A second copy of the tables of a db, e.g. a backup synced from its storage provider, keeping
+/// the tables under the same ids. Reads of blocks failing their checksum are served from it,
+/// and the damaged table is rewritten by a compaction, see [`crate::DbOption::with_replica`].
+#[derive(Clone)]
+pub struct Replica(pub(crate) TableStoreRef);
+
+impl Replica {
+    pub fn new(provider: impl StorageProvider) -> Self {
+        Replica(Arc::new(provider))
+    }
+}
+
+impl Debug for Replica {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Replica")
+    }
+}
+
+/// Opens the tables of `tables`, falling back to `replica` for damaged blocks and reporting the
+/// damaged tables to be rewritten.
+pub(crate) struct Repairing {
+    tables: TableStoreRef,
+    replica: TableStoreRef,
+    damaged_tx: Sender<ProcessUniqueId>,
+}
+
+impl Repairing {
+    pub(crate) fn new(
+        tables: TableStoreRef,
+        replica: &Replica,
+        damaged_tx: Sender<ProcessUniqueId>,
+    ) -> Self {
+        Repairing {
+            tables,
+            replica: replica.0.clone(),
+            damaged_tx,
+        }
+    }
+
+    fn wrap(&self, gen: &ProcessUniqueId, table: Box<dyn AsyncFileReader>) -> RepairedTable {
+        RepairedTable {
+            gen: *gen,
+            table,
+            replica: self.replica.clone(),
+            replica_table: None,
+            damaged_tx: Some(self.damaged_tx.clone()),
+        }
+    }
+}
+
+impl TableStore for Repairing {
+    fn open_table<'a>(
+        &'a self,
+        gen: &'a ProcessUniqueId,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncFileReader>>> {
+        async move {
+            let table = self.tables.open_table(gen).await?;
+            Ok(Box::new(self.wrap(gen, table)) as Box<dyn AsyncFileReader>)
+        }
+        .boxed()
+    }
+
+    fn open_table_uncached<'a>(
+        &'a self,
+        gen: &'a ProcessUniqueId,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncFileReader>>> {
+        async move {
+            let table = self.tables.open_table_uncached(gen).await?;
+            Ok(Box::new(self.wrap(gen, table)) as Box<dyn AsyncFileReader>)
+        }
+        .boxed()
+    }
+
+    fn create_table<'a>(
+        &'a self,
+        gen: &'a ProcessUniqueId,
+        bytes: Bytes,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        self.tables.create_table(gen, bytes)
+    }
+
+    fn remove_table<'a>(&'a self, gen: &'a ProcessUniqueId) -> BoxFuture<'a, io::Result<()>> {
+        self.tables.remove_table(gen)
+    }
+
+    fn demote_table<'a>(&'a self, gen: &'a ProcessUniqueId) -> BoxFuture<'a, io::Result<()>> {
+        self.tables.demote_table(gen)
+    }
+}
+
+struct RepairedTable {
+    gen: ProcessUniqueId,
+    table: Box<dyn AsyncFileReader>,
+    replica: TableStoreRef,
+    /// opened on the first damaged block
+    replica_table: Option<Box<dyn AsyncFileReader>>,
+    /// taken once the table is reported
+    damaged_tx: Option<Sender<ProcessUniqueId>>,
+}
+
+impl RepairedTable {
+    /// Reports the table to be rewritten and opens its replica, which is verified like the
+    /// table itself. On failure, the read fails with `err`, the damage it found.
+    async fn replica(
+        &mut self,
+        err: ParquetError,
+    ) -> parquet::errors::Result<&mut Box<dyn AsyncFileReader>> {
+        if let Some(mut damaged_tx) = self.damaged_tx.take() {
+            warn!("[Repair]: table {} is damaged: {}", self.gen, err);
+            // a full queue drops the report, the next read of the table reports it again
+            let _ = damaged_tx.try_send(self.gen);
+        }
+        if self.replica_table.is_none() {
+            match self.replica.open_table_uncached(&self.gen).await {
+                Ok(table) => self.replica_table = Some(table),
+                Err(replica_err) => {
+                    warn!(
+                        "[Repair]: replica of table {} unavailable: {}",
+                        self.gen, replica_err
+                    );
+                    return Err(err);
+                }
+            }
+        }
+        Ok(self.replica_table.as_mut().unwrap())
+    }
+}
+
+impl AsyncFileReader for RepairedTable {
+    fn get_bytes(&mut self, range: Range<usize>) -> BoxFuture<'_, parquet::errors::Result<Bytes>> {
+        async move {
+            match self.table.get_bytes(range.clone()).await {
+                Err(err) if BlockMismatch::is(&err) => {
+                    self.replica(err).await?.get_bytes(range).await
+                }
+                result => result,
+            }
+        }
+        .boxed()
+    }
+
+    fn get_metadata(&mut self) -> BoxFuture<'_, parquet::errors::Result<Arc<ParquetMetaData>>> {
+        async move {
+            match self.table.get_metadata().await {
+                Err(err) if BlockMismatch::is(&err) => {
+                    self.replica(err).await?.get_metadata().await
+                }
+                result => result,
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::{RecordBatch, UInt64Array},
+        datatypes::{DataType, Field, Schema},
+    };
+    use bytes::Bytes;
+    use executor::{futures::StreamExt, ExecutorBuilder};
+    use futures::channel::mpsc::channel;
+    use parquet::arrow::{ArrowWriter, ParquetRecordBatchStreamBuilder};
+    use snowflake::ProcessUniqueId;
+
+    use super::{Repairing, Replica};
+    use crate::wal::provider::{in_mem::InMemProvider, StorageProvider, TableStore};
+
+    #[test]
+    fn serve_damaged_blocks() {
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let batch = RecordBatch::try_new(
+                Arc::new(Schema::new(vec![Field::new("v", DataType::UInt64, false)])),
+                vec![Arc::new(UInt64Array::from_iter_values(0..100_000))],
+            )
+            .unwrap();
+            let mut bytes = Vec::new();
+            let mut writer = ArrowWriter::try_new(&mut bytes, batch.schema(), None).unwrap();
+            writer.write(&batch).unwrap();
+            writer.close().unwrap();
+
+            let provider = InMemProvider::default();
+            let backup = InMemProvider::default();
+            let gen = ProcessUniqueId::new();
+            for store in [&provider as &dyn TableStore, &backup] {
+                store
+                    .create_table(&gen, Bytes::from(bytes.clone()))
+                    .await
+                    .unwrap();
+            }
+            let mut sealed = provider.read_table(&gen).await.unwrap().to_vec();
+            sealed[4] ^= 1;
+            StorageProvider::create_table(&provider, &gen, Bytes::from(sealed))
+                .await
+                .unwrap();
+
+            let rows = batch.num_rows();
+            let (damaged_tx, mut damaged_rx) = channel(1);
+            let store = Repairing::new(
+                Arc::new(provider),
+                &Replica::new(backup.clone()),
+                damaged_tx,
+            );
+            let read = |store: &Repairing| {
+                let file = store.open_table(&gen);
+                async move {
+                    ParquetRecordBatchStreamBuilder::new(file.await.unwrap())
+                        .await
+                        .unwrap()
+                        .with_batch_size(rows)
+                        .build()
+                        .unwrap()
+                        .next()
+                        .await
+                        .unwrap()
+                }
+            };
+            assert_eq!(read(&store).await.unwrap(), batch);
+            assert_eq!(damaged_rx.next().await, Some(gen));
+
+            StorageProvider::remove_table(&backup, &gen).await.unwrap();
+            assert!(read(&store).await.is_err());
+        });
+    }
+}
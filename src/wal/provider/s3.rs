@@ -0,0 +1,625 @@
+use std::{
+    collections::HashMap,
+    fs,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use executor::futures::{util::AsyncWriteExt, Stream};
+use futures::channel::oneshot;
+use object_store::{aws::AmazonS3Builder, path::Path as ObjectPath, ObjectStore};
+use snowflake::ProcessUniqueId;
+use tokio::runtime::Runtime;
+use tracing::warn;
+
+use super::{fs::Fs, StorageProvider, WalProvider};
+
+const CACHE_DIR: &str = "cache";
+
+/// Keeps the wal on local disk and uploads every table to an object store, reading tables back
+/// through a local cache that holds at most `cache_capacity` bytes.
+pub struct S3 {
+    fs: Fs,
+    client: ObjectClient,
+    prefix: ObjectPath,
+    cache_path: PathBuf,
+    cache: Mutex<TableCache>,
+}
+
+impl S3 {
+    /// Uses the bucket with the credentials and region from the `AWS_*` environment variables.
+    pub fn new(
+        path: impl AsRef<Path>,
+        bucket: impl Into<String>,
+        cache_capacity: u64,
+    ) -> io::Result<Self> {
+        Self::with_builder(
+            path,
+            AmazonS3Builder::from_env().with_bucket_name(bucket),
+            cache_capacity,
+        )
+    }
+
+    /// Uses the bucket `builder` is configured for, e.g. on an S3 compatible endpoint.
+    pub fn with_builder(
+        path: impl AsRef<Path>,
+        builder: AmazonS3Builder,
+        cache_capacity: u64,
+    ) -> io::Result<Self> {
+        let store = builder.build().map_err(io::Error::from)?;
+
+        Self::with_store(path, Arc::new(store), ObjectPath::default(), cache_capacity)
+    }
+
+    /// Tables are kept under `prefix` of `store`. The cache starts empty.
+    pub fn with_store(
+        path: impl AsRef<Path>,
+        store: Arc<dyn ObjectStore>,
+        prefix: ObjectPath,
+        cache_capacity: u64,
+    ) -> io::Result<Self> {
+        let fs = Fs::new(path.as_ref())?;
+        let cache_path = path.as_ref().join(CACHE_DIR);
+        if cache_path.exists() {
+            fs::remove_dir_all(&cache_path)?;
+        }
+        fs::create_dir_all(&cache_path)?;
+
+        Ok(Self {
+            fs,
+            client: ObjectClient::new(store)?,
+            prefix,
+            cache_path,
+            cache: Mutex::new(TableCache::new(cache_capacity)),
+        })
+    }
+
+    fn object_path(&self, gen: &ProcessUniqueId) -> ObjectPath {
+        self.prefix.child(format!("{}.parquet", gen))
+    }
+
+    /// A file of its own for every copy of a table, so that a copy being removed never takes a
+    /// newer one of the same table with it.
+    fn copy_path(&self, gen: &ProcessUniqueId) -> PathBuf {
+        self.cache_path
+            .join(format!("{}.{}.parquet", gen, ProcessUniqueId::new()))
+    }
+
+    async fn download(&self, gen: &ProcessUniqueId) -> io::Result<(PathBuf, u64)> {
+        let bytes = self.client.get(self.object_path(gen)).await?;
+        let path = self.copy_path(gen);
+        write_file(&path, &bytes).await?;
+
+        Ok((path, bytes.len() as u64))
+    }
+
+    /// Caches the copy of `gen` at `path` pinned, unless a copy is cached already, and returns
+    /// whether it was.
+    fn cache(&self, gen: &ProcessUniqueId, path: &Path, size: u64) -> bool {
+        let evicted = self
+            .cache
+            .lock()
+            .unwrap()
+            .insert(*gen, size, path.to_path_buf());
+        match evicted {
+            Some(evicted) => {
+                for path in evicted {
+                    remove_copy(&path);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Opens the copy at `path`, then unpins it if it is the cached one, or removes it.
+    fn open_copy(
+        &self,
+        gen: &ProcessUniqueId,
+        path: &Path,
+        pinned: bool,
+    ) -> io::Result<executor::fs::File> {
+        let file = fs::File::open(path);
+        if pinned {
+            if let Some(path) = self.cache.lock().unwrap().unpin(gen) {
+                remove_copy(&path);
+            }
+        } else {
+            remove_copy(path);
+        }
+        Ok(file?.into())
+    }
+}
+
+impl WalProvider for S3 {
+    type File = executor::fs::File;
+
+    async fn open(&self, fid: u32) -> io::Result<Self::File> {
+        self.fs.open(fid).await
+    }
+
+    fn list(&self) -> impl Stream<Item = io::Result<Self::File>> {
+        self.fs.list()
+    }
+}
+
+impl StorageProvider for S3 {
+    type TableFile = executor::fs::File;
+
+    async fn open_table(&self, gen: &ProcessUniqueId) -> io::Result<Self::TableFile> {
+        // pinned, so that no concurrent insert evicts the copy before it is opened
+        let cached = self.cache.lock().unwrap().pin(gen);
+        let (path, pinned) = match cached {
+            Some(path) => (path, true),
+            None => {
+                let (path, size) = self.download(gen).await?;
+                let pinned = self.cache(gen, &path, size);
+                (path, pinned)
+            }
+        };
+        self.open_copy(gen, &path, pinned)
+    }
+
+    /// Tables not cached are downloaded to a file of their own, removed once opened.
+    async fn open_table_uncached(&self, gen: &ProcessUniqueId) -> io::Result<Self::TableFile> {
+        let cached = self.cache.lock().unwrap().pin(gen);
+        let (path, pinned) = match cached {
+            Some(path) => (path, true),
+            None => (self.download(gen).await?.0, false),
+        };
+        self.open_copy(gen, &path, pinned)
+    }
+
+    async fn create_table(&self, gen: &ProcessUniqueId, bytes: Bytes) -> io::Result<()> {
+        self.client
+            .put(self.object_path(gen), bytes.clone())
+            .await?;
+        // freshly flushed tables are the most likely to be read next
+        let path = self.copy_path(gen);
+        write_file(&path, &bytes).await?;
+        if !self.cache(gen, &path, bytes.len() as u64) {
+            remove_copy(&path);
+        } else if let Some(path) = self.cache.lock().unwrap().unpin(gen) {
+            remove_copy(&path);
+        }
+        Ok(())
+    }
+
+    async fn remove_table(&self, gen: &ProcessUniqueId) -> io::Result<()> {
+        if let Some(path) = self.cache.lock().unwrap().remove(gen) {
+            remove_copy(&path);
+        }
+        self.client.delete(self.object_path(gen)).await
+    }
+
+    async fn table_size(&self, gen: &ProcessUniqueId) -> io::Result<u64> {
+        let cached = self.cache.lock().unwrap().size(gen);
+        match cached {
+            Some(size) => Ok(size),
+            None => self.client.size(self.object_path(gen)).await,
+        }
+    }
+
+    async fn read_table(&self, gen: &ProcessUniqueId) -> io::Result<Bytes> {
+        self.client.get(self.object_path(gen)).await
+    }
+}
+
+async fn write_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut file = executor::fs::File::from(fs::File::create(path)?);
+    file.write_all(bytes).await?;
+    file.flush().await
+}
+
+/// A copy left behind is only disk space, which the cache directory gives back on restart.
+fn remove_copy(path: &Path) {
+    if let Err(err) = fs::remove_file(path) {
+        warn!(
+            "[S3]: failed to remove table copy {}: {}",
+            path.display(),
+            err
+        );
+    }
+}
+
+/// Sends the requests of an object store to a Tokio runtime of its own, as its HTTP client needs
+/// one and the executor of the db is not one, and hands the responses back over channels.
+struct ObjectClient {
+    store: Arc<dyn ObjectStore>,
+    runtime: Option<Runtime>,
+}
+
+impl ObjectClient {
+    fn new(store: Arc<dyn ObjectStore>) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("elsm-s3")
+            .enable_all()
+            .build()?;
+
+        Ok(ObjectClient {
+            store,
+            runtime: Some(runtime),
+        })
+    }
+
+    async fn run<T, F>(&self, request: F) -> io::Result<T>
+    where
+        F: Future<Output = object_store::Result<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.runtime
+            .as_ref()
+            .expect("runtime is taken on drop only")
+            .spawn(async move {
+                let _ = tx.send(request.await);
+            });
+        // the sender only drops unsent if the runtime shuts down
+        let response = rx
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(response?)
+    }
+
+    async fn get(&self, path: ObjectPath) -> io::Result<Bytes> {
+        let store = self.store.clone();
+        self.run(async move { store.get(&path).await?.bytes().await })
+            .await
+    }
+
+    async fn put(&self, path: ObjectPath, bytes: Bytes) -> io::Result<()> {
+        let store = self.store.clone();
+        self.run(async move { store.put(&path, bytes).await.map(|_| ()) })
+            .await
+    }
+
+    async fn size(&self, path: ObjectPath) -> io::Result<u64> {
+        let store = self.store.clone();
+        self.run(async move { Ok(store.head(&path).await?.size as u64) })
+            .await
+    }
+
+    async fn delete(&self, path: ObjectPath) -> io::Result<()> {
+        let store = self.store.clone();
+        self.run(async move { store.delete(&path).await }).await
+    }
+}
+
+impl Drop for ObjectClient {
+    fn drop(&mut self) {
+        // dropped on the executor of the db, which must not block on the workers of the runtime
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Least recently used bookkeeping of the cached tables, the files themselves live on disk.
+/// Pinned tables are neither evicted nor have their files removed until unpinned.
+#[derive(Debug)]
+struct TableCache {
+    capacity: u64,
+    used: u64,
+    tick: u64,
+    entries: HashMap<ProcessUniqueId, CacheEntry>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+    path: PathBuf,
+    pins: usize,
+    /// Removed while pinned, its file goes once the last pin does.
+    removed: bool,
+}
+
+impl TableCache {
+    fn new(capacity: u64) -> Self {
+        TableCache {
+            capacity,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn live(&mut self, gen: &ProcessUniqueId) -> Option<&mut CacheEntry> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries
+            .get_mut(gen)
+            .filter(|entry| !entry.removed)
+            .map(|entry| {
+                entry.last_used = tick;
+                entry
+            })
+    }
+
+    /// Pins the table, if cached, and returns the file of its copy.
+    fn pin(&mut self, gen: &ProcessUniqueId) -> Option<PathBuf> {
+        self.live(gen).map(|entry| {
+            entry.pins += 1;
+            entry.path.clone()
+        })
+    }
+
+    /// Returns the file to remove, if the table was removed while pinned.
+    fn unpin(&mut self, gen: &ProcessUniqueId) -> Option<PathBuf> {
+        let entry = self.entries.get_mut(gen)?;
+        entry.pins -= 1;
+        if entry.pins > 0 || !entry.removed {
+            return None;
+        }
+        self.entries.remove(gen).map(|entry| entry.path)
+    }
+
+    fn size(&mut self, gen: &ProcessUniqueId) -> Option<u64> {
+        self.live(gen).map(|entry| entry.size)
+    }
+
+    /// Caches the copy of `gen` at `path` pinned and returns the files of the tables evicted to
+    /// make room, `None` if the table is cached already.
+    fn insert(&mut self, gen: ProcessUniqueId, size: u64, path: PathBuf) -> Option<Vec<PathBuf>> {
+        if self.entries.contains_key(&gen) {
+            return None;
+        }
+        self.tick += 1;
+        self.entries.insert(
+            gen,
+            CacheEntry {
+                size,
+                last_used: self.tick,
+                path,
+                pins: 1,
+                removed: false,
+            },
+        );
+        self.used += size;
+
+        let mut evicted = Vec::new();
+        while self.used > self.capacity {
+            let victim = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.pins == 0)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(victim, _)| *victim);
+            match victim.and_then(|victim| self.remove(&victim)) {
+                Some(path) => evicted.push(path),
+                None => break,
+            }
+        }
+        Some(evicted)
+    }
+
+    /// Returns the file to remove, unless the table is pinned and keeps it until unpinned.
+    fn remove(&mut self, gen: &ProcessUniqueId) -> Option<PathBuf> {
+        let entry = self.entries.get_mut(gen).filter(|entry| !entry.removed)?;
+        self.used -= entry.size;
+        if entry.pins > 0 {
+            entry.removed = true;
+            return None;
+        }
+        self.entries.remove(gen).map(|entry| entry.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{self, BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        path::PathBuf,
+        sync::Arc,
+        thread,
+    };
+
+    use bytes::Bytes;
+    use executor::ExecutorBuilder;
+    use object_store::{
+        aws::AmazonS3Builder, memory::InMemory, path::Path as ObjectPath, ObjectStore,
+    };
+    use snowflake::ProcessUniqueId;
+    use tempfile::TempDir;
+
+    use super::{TableCache, S3};
+    use crate::wal::provider::StorageProvider;
+
+    fn cached(provider: &S3, gen: &ProcessUniqueId) -> bool {
+        provider
+            .cache
+            .lock()
+            .unwrap()
+            .entries
+            .get(gen)
+            .is_some_and(|entry| entry.path.exists())
+    }
+
+    #[test]
+    fn read_through_cache() {
+        let temp_dir = TempDir::new().unwrap();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let store = Arc::new(InMemory::new());
+            let provider = S3::with_store(
+                temp_dir.path(),
+                store.clone(),
+                ObjectPath::from("tables"),
+                4,
+            )
+            .unwrap();
+
+            let gen_1 = ProcessUniqueId::new();
+            let gen_2 = ProcessUniqueId::new();
+            provider
+                .create_table(&gen_1, Bytes::from_static(b"abc"))
+                .await
+                .unwrap();
+            provider
+                .create_table(&gen_2, Bytes::from_static(b"de"))
+                .await
+                .unwrap();
+
+            assert!(store.head(&provider.object_path(&gen_1)).await.is_ok());
+            assert!(!cached(&provider, &gen_1));
+            assert!(cached(&provider, &gen_2));
+
+            provider.open_table(&gen_1).await.unwrap();
+            assert!(cached(&provider, &gen_1));
+            assert!(!cached(&provider, &gen_2));
+
+            provider.remove_table(&gen_1).await.unwrap();
+            assert!(!cached(&provider, &gen_1));
+            assert!(store.head(&provider.object_path(&gen_1)).await.is_err());
+            assert!(provider.open_table(&gen_1).await.is_err());
+        });
+    }
+
+    #[test]
+    fn pinned_tables_stay() {
+        let gen_1 = ProcessUniqueId::new();
+        let gen_2 = ProcessUniqueId::new();
+        let gen_3 = ProcessUniqueId::new();
+        let mut cache = TableCache::new(4);
+
+        assert_eq!(cache.insert(gen_1, 3, PathBuf::from("1")), Some(vec![]));
+        assert_eq!(cache.insert(gen_1, 3, PathBuf::from("1.1")), None);
+        // the first copy is still opening, so the cache runs over its capacity instead
+        assert_eq!(cache.insert(gen_2, 2, PathBuf::from("2")), Some(vec![]));
+        assert_eq!(cache.unpin(&gen_2), None);
+        assert_eq!(cache.unpin(&gen_1), None);
+        assert_eq!(
+            cache.insert(gen_3, 1, PathBuf::from("3")),
+            Some(vec![PathBuf::from("1")])
+        );
+
+        // removed while pinned, the file goes with the last pin
+        assert_eq!(cache.pin(&gen_2), Some(PathBuf::from("2")));
+        assert_eq!(cache.remove(&gen_2), None);
+        assert_eq!(cache.pin(&gen_2), None);
+        assert_eq!(cache.size(&gen_2), None);
+        assert_eq!(cache.unpin(&gen_2), Some(PathBuf::from("2")));
+        assert_eq!(cache.used, 1);
+    }
+
+    /// Serves the object requests of the S3 api, path style and without checking signatures, so
+    /// that the provider is tested over HTTP.
+    fn serve_s3() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            let mut objects = HashMap::new();
+            for stream in listener.incoming() {
+                let _ = stream.and_then(|mut stream| serve(&mut stream, &mut objects));
+            }
+        });
+        endpoint
+    }
+
+    fn serve(stream: &mut TcpStream, objects: &mut HashMap<String, Vec<u8>>) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut request = line.split_whitespace();
+        let method = request.next().unwrap_or_default().to_string();
+        let path = request
+            .next()
+            .and_then(|target| target.split('?').next())
+            .unwrap_or_default()
+            .to_string();
+
+        let mut len = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    len = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body)?;
+
+        let (status, object) = match method.as_str() {
+            "PUT" => {
+                objects.insert(path, body);
+                ("200 OK", None)
+            }
+            "GET" | "HEAD" => match objects.get(&path) {
+                Some(object) => ("200 OK", Some(object.clone())),
+                None => ("404 Not Found", None),
+            },
+            "DELETE" => {
+                objects.remove(&path);
+                ("204 No Content", None)
+            }
+            _ => ("405 Method Not Allowed", None),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nETag: \"0\"\r\nLast-Modified: Thu, 01 Jan 2026 \
+             00:00:00 GMT\r\nConnection: close\r\n\r\n",
+            status,
+            object.as_ref().map_or(0, Vec::len)
+        )?;
+        if let (Some(object), false) = (object, method == "HEAD") {
+            stream.write_all(&object)?;
+        }
+        stream.flush()
+    }
+
+    #[test]
+    fn over_http() {
+        let temp_dir = TempDir::new().unwrap();
+        let endpoint = serve_s3();
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let provider = S3::with_builder(
+                temp_dir.path(),
+                AmazonS3Builder::new()
+                    .with_endpoint(endpoint)
+                    .with_allow_http(true)
+                    .with_bucket_name("tables")
+                    .with_region("us-east-1")
+                    .with_access_key_id("elsm")
+                    .with_secret_access_key("elsm"),
+                4,
+            )
+            .unwrap();
+
+            let gen_1 = ProcessUniqueId::new();
+            let gen_2 = ProcessUniqueId::new();
+            provider
+                .create_table(&gen_1, Bytes::from_static(b"abc"))
+                .await
+                .unwrap();
+            provider
+                .create_table(&gen_2, Bytes::from_static(b"de"))
+                .await
+                .unwrap();
+            assert!(!cached(&provider, &gen_1));
+
+            assert_eq!(provider.table_size(&gen_1).await.unwrap(), 3);
+            assert_eq!(
+                provider.read_table(&gen_1).await.unwrap(),
+                Bytes::from_static(b"abc")
+            );
+            provider.open_table(&gen_1).await.unwrap();
+            assert!(cached(&provider, &gen_1));
+
+            provider.remove_table(&gen_1).await.unwrap();
+            assert!(provider.read_table(&gen_1).await.is_err());
+        });
+    }
+}
@@ -0,0 +1,289 @@
+use std::{io, ops::Range, sync::Arc};
+
+use arrow::datatypes::SchemaRef;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{future::BoxFuture, FutureExt};
+use parquet::{
+    arrow::async_reader::AsyncFileReader,
+    errors::ParquetError,
+    file::{
+        footer::{decode_footer, decode_metadata, parse_metadata},
+        metadata::ParquetMetaData,
+        properties::WriterProperties,
+        FOOTER_SIZE,
+    },
+    schema::types::ColumnPath,
+};
+use thiserror::Error;
+
+const MAGIC: [u8; 4] = *b"ETBL";
+pub(crate) const FORMAT_VERSION: u8 = 1;
+const BLOCK_SIZE: u32 = 64 * 1024;
+/// version, block size, data length, index offset, bloom offset, rows, crc and magic
+const FOOTER_LEN: usize = 1 + 4 + 8 + 8 + 8 + 8 + 4 + MAGIC.len();
+
+/// Trails the parquet data of every table: one crc32 per `block_size` bytes of data, then the
+/// fixed fields below, a crc32 over the checksums and fields, and the magic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TableFooter {
+    pub(crate) version: u8,
+    pub(crate) block_size: u32,
+    pub(crate) data_len: u64,
+    /// where the parquet metadata starts
+    pub(crate) index_offset: u64,
+    /// where the first bloom filter starts, 0 when the table has none
+    pub(crate) bloom_offset: u64,
+    pub(crate) num_rows: u64,
+    pub(crate) checksums: Vec<u32>,
+}
+
+impl TableFooter {
+    /// Appends the footer to an encoded parquet table.
+    pub(crate) fn seal(data: Bytes) -> io::Result<Bytes> {
+        let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+        let metadata = parse_metadata(&data).map_err(invalid)?;
+        let tail: &[u8; FOOTER_SIZE] = data[data.len() - FOOTER_SIZE..].try_into().unwrap();
+        let index_offset = data.len() - FOOTER_SIZE - decode_footer(tail).map_err(invalid)?;
+        let bloom_offset = metadata
+            .row_groups()
+            .iter()
+            .flat_map(|row_group| row_group.columns())
+            .filter_map(|column| column.bloom_filter_offset())
+            .min()
+            .unwrap_or(0);
+
+        let footer = TableFooter {
+            version: FORMAT_VERSION,
+            block_size: BLOCK_SIZE,
+            data_len: data.len() as u64,
+            index_offset: index_offset as u64,
+            bloom_offset: bloom_offset as u64,
+            num_rows: metadata.file_metadata().num_rows() as u64,
+            checksums: data
+                .chunks(BLOCK_SIZE as usize)
+                .map(crc32fast::hash)
+                .collect(),
+        };
+        let mut bytes = BytesMut::from(&data[..]);
+        footer.encode(&mut bytes);
+
+        Ok(bytes.freeze())
+    }
+
+    fn encode(&self, bytes: &mut BytesMut) {
+        let start = bytes.len();
+        for checksum in &self.checksums {
+            bytes.put_u32_le(*checksum);
+        }
+        bytes.put_u8(self.version);
+        bytes.put_u32_le(self.block_size);
+        bytes.put_u64_le(self.data_len);
+        bytes.put_u64_le(self.index_offset);
+        bytes.put_u64_le(self.bloom_offset);
+        bytes.put_u64_le(self.num_rows);
+        let crc = crc32fast::hash(&bytes[start..]);
+        bytes.put_u32_le(crc);
+        bytes.put_slice(&MAGIC);
+    }
+
+    /// Reads and validates the footer of a table of `size` bytes.
+    pub(crate) async fn read(
+        reader: &mut Box<dyn AsyncFileReader>,
+        size: u64,
+    ) -> parquet::errors::Result<Self> {
+        let size = size as usize;
+        if size < FOOTER_LEN {
+            return Err(corrupted("table is shorter than its footer"));
+        }
+        let fixed = reader.get_bytes(size - FOOTER_LEN..size).await?;
+        if fixed[FOOTER_LEN - MAGIC.len()..] != MAGIC {
+            return Err(corrupted("table footer magic mismatch"));
+        }
+        let version = fixed[0];
+        if version > FORMAT_VERSION {
+            return Err(corrupted(format!(
+                "unsupported table format version {version}"
+            )));
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(fixed[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(fixed[at..at + 8].try_into().unwrap());
+
+        let block_size = u32_at(1);
+        let data_len = u64_at(5);
+        let blocks = data_len.div_ceil(block_size.max(1) as u64) as usize;
+        let checksums_start = size
+            .checked_sub(FOOTER_LEN + blocks * 4)
+            .filter(|start| *start as u64 == data_len)
+            .ok_or_else(|| corrupted("table footer length mismatch"))?;
+        let checksums = reader.get_bytes(checksums_start..size - FOOTER_LEN).await?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&checksums);
+        hasher.update(&fixed[..FOOTER_LEN - 8]);
+        if hasher.finalize() != u32_at(FOOTER_LEN - 8) {
+            return Err(corrupted("table footer checksum mismatch"));
+        }
+
+        Ok(TableFooter {
+            version,
+            block_size,
+            data_len,
+            index_offset: u64_at(13),
+            bloom_offset: u64_at(21),
+            num_rows: u64_at(29),
+            checksums: checksums
+                .chunks_exact(4)
+                .map(|checksum| u32::from_le_bytes(checksum.try_into().unwrap()))
+                .collect(),
+        })
+    }
+}
+
+/// Every table carries a bloom filter over its key column, which lookups check before reading it.
+pub(crate) fn writer_properties(schema: &SchemaRef) -> WriterProperties {
+    WriterProperties::builder()
+        .set_column_bloom_filter_enabled(ColumnPath::from(schema.field(0).name().as_str()), true)
+        .build()
+}
+
+fn corrupted(message: impl Into<String>) -> ParquetError {
+    ParquetError::General(message.into())
+}
+
+/// A block of table data not matching its checksum, which reads may serve from a replica, see
+/// [`super::replica::Replica`].
+#[derive(Debug, Error)]
+#[error("table block {0} checksum mismatch")]
+pub(crate) struct BlockMismatch(usize);
+
+impl BlockMismatch {
+    pub(crate) fn is(err: &ParquetError) -> bool {
+        matches!(err, ParquetError::External(err) if err.is::<BlockMismatch>())
+    }
+}
+
+/// Reads the parquet data of a table, verifying every block it touches against the footer.
+pub(crate) struct VerifiedTable {
+    reader: Box<dyn AsyncFileReader>,
+    footer: TableFooter,
+}
+
+impl VerifiedTable {
+    pub(crate) async fn open(
+        mut reader: Box<dyn AsyncFileReader>,
+        size: u64,
+    ) -> parquet::errors::Result<Self> {
+        let footer = TableFooter::read(&mut reader, size).await?;
+
+        Ok(VerifiedTable { reader, footer })
+    }
+
+    async fn read(&mut self, range: Range<usize>) -> parquet::errors::Result<Bytes> {
+        let block_size = self.footer.block_size as usize;
+        let data_len = self.footer.data_len as usize;
+        if range.end > data_len {
+            return Err(corrupted("read past the end of the table data"));
+        }
+        let first = range.start / block_size;
+        let last = range.end.div_ceil(block_size);
+        let start = first * block_size;
+        let blocks = self
+            .reader
+            .get_bytes(start..(last * block_size).min(data_len))
+            .await?;
+
+        for (i, block) in blocks.chunks(block_size).enumerate() {
+            if crc32fast::hash(block) != self.footer.checksums[first + i] {
+                return Err(ParquetError::External(Box::new(BlockMismatch(first + i))));
+            }
+        }
+        Ok(blocks.slice(range.start - start..range.end - start))
+    }
+}
+
+impl AsyncFileReader for VerifiedTable {
+    fn get_bytes(&mut self, range: Range<usize>) -> BoxFuture<'_, parquet::errors::Result<Bytes>> {
+        self.read(range).boxed()
+    }
+
+    fn get_metadata(&mut self) -> BoxFuture<'_, parquet::errors::Result<Arc<ParquetMetaData>>> {
+        async move {
+            let index_offset = self.footer.index_offset as usize;
+            let data_len = self.footer.data_len as usize;
+            let metadata = self.read(index_offset..data_len).await?;
+
+            let tail: &[u8; FOOTER_SIZE] =
+                metadata[metadata.len() - FOOTER_SIZE..].try_into().unwrap();
+            if decode_footer(tail)? != metadata.len() - FOOTER_SIZE {
+                return Err(corrupted("table index offset mismatch"));
+            }
+            Ok(Arc::new(decode_metadata(
+                &metadata[..metadata.len() - FOOTER_SIZE],
+            )?))
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::{RecordBatch, UInt64Array},
+        datatypes::{DataType, Field, Schema},
+    };
+    use bytes::Bytes;
+    use executor::{futures::StreamExt, ExecutorBuilder};
+    use parquet::arrow::{ArrowWriter, ParquetRecordBatchStreamBuilder};
+    use snowflake::ProcessUniqueId;
+
+    use crate::wal::provider::{in_mem::InMemProvider, StorageProvider, TableStore};
+
+    fn table() -> (RecordBatch, Bytes) {
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("v", DataType::UInt64, false)])),
+            vec![Arc::new(UInt64Array::from_iter_values(0..100_000))],
+        )
+        .unwrap();
+        let mut bytes = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut bytes, batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        (batch, Bytes::from(bytes))
+    }
+
+    #[test]
+    fn verify_blocks() {
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let provider = InMemProvider::default();
+            let store: &dyn TableStore = &provider;
+            let (batch, bytes) = table();
+            let gen = ProcessUniqueId::new();
+
+            store.create_table(&gen, bytes).await.unwrap();
+            let file = store.open_table(&gen).await.unwrap();
+            let mut stream = ParquetRecordBatchStreamBuilder::new(file)
+                .await
+                .unwrap()
+                .with_batch_size(batch.num_rows())
+                .build()
+                .unwrap();
+            assert_eq!(stream.next().await.unwrap().unwrap(), batch);
+
+            let mut sealed = provider.read_table(&gen).await.unwrap().to_vec();
+            sealed[4] ^= 1;
+            StorageProvider::create_table(&provider, &gen, Bytes::from(sealed))
+                .await
+                .unwrap();
+            let file = store.open_table(&gen).await.unwrap();
+            let mut stream = ParquetRecordBatchStreamBuilder::new(file)
+                .await
+                .unwrap()
+                .build()
+                .unwrap();
+            assert!(stream.next().await.unwrap().is_err());
+        });
+    }
+}
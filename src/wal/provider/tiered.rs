@@ -0,0 +1,227 @@
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use bytes::Bytes;
+use executor::futures::Stream;
+use parquet::arrow::async_reader::AsyncFileReader;
+use snowflake::ProcessUniqueId;
+
+use super::{StorageProvider, WalProvider};
+
+const GEN_SIZE: usize = 16;
+const REMOTE_TAG: u8 = 1;
+const REMOVED_TAG: u8 = 2;
+
+/// Where the tables of a level are kept, see `DbOption::level_tiers`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    Local,
+    Remote,
+}
+
+/// Creates tables on `local` and moves them to `remote` once demoted. Which tables were moved is
+/// recorded in a manifest, so that they are found again after a restart.
+pub struct Tiered<L, R> {
+    local: L,
+    remote: R,
+    manifest: Mutex<Manifest>,
+}
+
+struct Manifest {
+    log: File,
+    remote: HashSet<ProcessUniqueId>,
+}
+
+impl<L, R> Tiered<L, R>
+where
+    L: StorageProvider,
+    R: StorageProvider,
+{
+    pub fn new(local: L, remote: R, manifest: impl AsRef<Path>) -> io::Result<Self> {
+        let mut log = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(manifest)?;
+        let mut remote_gens = HashSet::new();
+
+        let mut bytes = Vec::new();
+        log.read_to_end(&mut bytes)?;
+        // a torn record at the tail was never acknowledged, so it is ignored
+        for record in bytes.chunks_exact(GEN_SIZE + 1) {
+            let gen: ProcessUniqueId = bincode::deserialize(&record[..GEN_SIZE])
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            match record[GEN_SIZE] {
+                REMOTE_TAG => remote_gens.insert(gen),
+                REMOVED_TAG => remote_gens.remove(&gen),
+                tag => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown tier manifest tag {tag}"),
+                    ))
+                }
+            };
+        }
+
+        Ok(Tiered {
+            local,
+            remote,
+            manifest: Mutex::new(Manifest {
+                log,
+                remote: remote_gens,
+            }),
+        })
+    }
+
+    pub fn tier(&self, gen: &ProcessUniqueId) -> Tier {
+        if self.manifest.lock().unwrap().remote.contains(gen) {
+            Tier::Remote
+        } else {
+            Tier::Local
+        }
+    }
+}
+
+impl Manifest {
+    fn append(&mut self, gen: &ProcessUniqueId, tag: u8) -> io::Result<()> {
+        let mut record = bincode::serialize(gen).unwrap();
+        record.push(tag);
+        self.log.write_all(&record)?;
+        self.log.sync_data()?;
+
+        match tag {
+            REMOTE_TAG => self.remote.insert(*gen),
+            _ => self.remote.remove(gen),
+        };
+        Ok(())
+    }
+}
+
+impl<L, R> WalProvider for Tiered<L, R>
+where
+    L: StorageProvider,
+    R: StorageProvider,
+{
+    type File = L::File;
+
+    async fn open(&self, fid: u32) -> io::Result<Self::File> {
+        self.local.open(fid).await
+    }
+
+    fn list(&self) -> impl Stream<Item = io::Result<Self::File>> {
+        self.local.list()
+    }
+}
+
+impl<L, R> StorageProvider for Tiered<L, R>
+where
+    L: StorageProvider,
+    R: StorageProvider,
+{
+    type TableFile = Box<dyn AsyncFileReader>;
+
+    async fn open_table(&self, gen: &ProcessUniqueId) -> io::Result<Self::TableFile> {
+        Ok(match self.tier(gen) {
+            Tier::Local => Box::new(self.local.open_table(gen).await?) as Box<dyn AsyncFileReader>,
+            Tier::Remote => Box::new(self.remote.open_table(gen).await?),
+        })
+    }
+
+    async fn open_table_uncached(&self, gen: &ProcessUniqueId) -> io::Result<Self::TableFile> {
+        Ok(match self.tier(gen) {
+            Tier::Local => {
+                Box::new(self.local.open_table_uncached(gen).await?) as Box<dyn AsyncFileReader>
+            }
+            Tier::Remote => Box::new(self.remote.open_table_uncached(gen).await?),
+        })
+    }
+
+    async fn create_table(&self, gen: &ProcessUniqueId, bytes: Bytes) -> io::Result<()> {
+        self.local.create_table(gen, bytes).await
+    }
+
+    async fn remove_table(&self, gen: &ProcessUniqueId) -> io::Result<()> {
+        match self.tier(gen) {
+            Tier::Local => self.local.remove_table(gen).await,
+            Tier::Remote => {
+                self.remote.remove_table(gen).await?;
+                self.manifest.lock().unwrap().append(gen, REMOVED_TAG)
+            }
+        }
+    }
+
+    async fn table_size(&self, gen: &ProcessUniqueId) -> io::Result<u64> {
+        match self.tier(gen) {
+            Tier::Local => self.local.table_size(gen).await,
+            Tier::Remote => self.remote.table_size(gen).await,
+        }
+    }
+
+    async fn read_table(&self, gen: &ProcessUniqueId) -> io::Result<Bytes> {
+        match self.tier(gen) {
+            Tier::Local => self.local.read_table(gen).await,
+            Tier::Remote => self.remote.read_table(gen).await,
+        }
+    }
+
+    /// The table is uploaded and recorded before the local copy goes away, so a crash in between
+    /// only leaves an orphaned local file behind.
+    async fn demote_table(&self, gen: &ProcessUniqueId) -> io::Result<()> {
+        if self.tier(gen) == Tier::Remote {
+            return Ok(());
+        }
+        let bytes = self.local.read_table(gen).await?;
+        self.remote.create_table(gen, bytes).await?;
+        self.manifest.lock().unwrap().append(gen, REMOTE_TAG)?;
+
+        self.local.remove_table(gen).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use executor::ExecutorBuilder;
+    use snowflake::ProcessUniqueId;
+    use tempfile::TempDir;
+
+    use super::{Tier, Tiered};
+    use crate::wal::provider::{in_mem::InMemProvider, StorageProvider};
+
+    #[test]
+    fn demote_table() {
+        let temp_dir = TempDir::new().unwrap();
+        let manifest = temp_dir.path().join("tiers");
+
+        ExecutorBuilder::new().build().unwrap().block_on(async {
+            let local = InMemProvider::default();
+            let remote = InMemProvider::default();
+            let tiered = Tiered::new(local.clone(), remote.clone(), &manifest).unwrap();
+
+            let gen = ProcessUniqueId::new();
+            let bytes = Bytes::from_static(b"table");
+            tiered.create_table(&gen, bytes.clone()).await.unwrap();
+            assert_eq!(tiered.tier(&gen), Tier::Local);
+            assert!(remote.read_table(&gen).await.is_err());
+
+            tiered.demote_table(&gen).await.unwrap();
+            assert_eq!(tiered.tier(&gen), Tier::Remote);
+            assert!(local.read_table(&gen).await.is_err());
+            assert_eq!(tiered.read_table(&gen).await.unwrap(), bytes);
+
+            let tiered = Tiered::new(local.clone(), remote.clone(), &manifest).unwrap();
+            assert_eq!(tiered.tier(&gen), Tier::Remote);
+
+            tiered.remove_table(&gen).await.unwrap();
+            assert!(remote.read_table(&gen).await.is_err());
+
+            let tiered = Tiered::new(local, remote, &manifest).unwrap();
+            assert_eq!(tiered.tier(&gen), Tier::Local);
+        });
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    cmp,
+    collections::{btree_map::Entry, BTreeMap},
+    mem,
+    sync::{Arc, Mutex},
+};
+
+use futures::channel::oneshot;
+
+use crate::oracle::TimeStamp;
+
+#[derive(Debug, Default)]
+struct WatermarkInner {
+    in_flight: BTreeMap<TimeStamp, usize>,
+    max_done: TimeStamp,
+    waiters: Vec<(TimeStamp, oneshot::Sender<()>)>,
+    settle_waiters: Vec<(TimeStamp, oneshot::Sender<()>)>,
+}
+
+impl WatermarkInner {
+    fn applied(&self) -> TimeStamp {
+        match self.in_flight.first_key_value() {
+            Some((ts, _)) => cmp::min(self.max_done, ts.saturating_sub(1)),
+            None => self.max_done,
+        }
+    }
+
+    fn settled(&self, ts: TimeStamp) -> bool {
+        self.in_flight
+            .first_key_value()
+            .map_or(true, |(first, _)| *first >= ts)
+    }
+}
+
+/// Tracks the highest timestamp below which every write has been applied to the mem tables.
+#[derive(Debug, Default)]
+pub(crate) struct Watermark {
+    inner: Mutex<WatermarkInner>,
+}
+
+impl Watermark {
+    /// Holds the watermark back from `ts` until the returned guard drops, also when the write is
+    /// cancelled.
+    pub(crate) fn begin(self: &Arc<Self>, ts: TimeStamp) -> InFlight {
+        *self.inner.lock().unwrap().in_flight.entry(ts).or_default() += 1;
+        InFlight {
+            watermark: self.clone(),
+            ts,
+        }
+    }
+
+    fn finish(&self, ts: TimeStamp) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Entry::Occupied(mut o) = inner.in_flight.entry(ts) {
+            match o.get_mut() {
+                1 => {
+                    o.remove();
+                }
+                n => {
+                    *n -= 1;
+                }
+            }
+        }
+        inner.max_done = cmp::max(inner.max_done, ts);
+
+        let applied = inner.applied();
+        for (ts, tx) in mem::take(&mut inner.waiters) {
+            if ts <= applied {
+                let _ = tx.send(());
+            } else {
+                inner.waiters.push((ts, tx));
+            }
+        }
+        for (ts, tx) in mem::take(&mut inner.settle_waiters) {
+            if inner.settled(ts) {
+                let _ = tx.send(());
+            } else {
+                inner.settle_waiters.push((ts, tx));
+            }
+        }
+    }
+
+    pub(crate) fn applied(&self) -> TimeStamp {
+        self.inner.lock().unwrap().applied()
+    }
+
+    /// The oldest write not applied yet, which holds the watermark back.
+    pub(crate) fn oldest_in_flight(&self) -> Option<TimeStamp> {
+        self.inner
+            .lock()
+            .unwrap()
+            .in_flight
+            .first_key_value()
+            .map(|(ts, _)| *ts)
+    }
+
+    /// Waits until every write at or below `ts` has been applied and returns the watermark.
+    pub(crate) async fn wait(&self, ts: TimeStamp) -> TimeStamp {
+        let rx = {
+            let mut inner = self.inner.lock().unwrap();
+            let applied = inner.applied();
+
+            if ts <= applied {
+                return applied;
+            }
+            let (tx, rx) = oneshot::channel();
+            inner.waiters.push((ts, tx));
+            rx
+        };
+        let _ = rx.await;
+
+        self.applied()
+    }
+
+    /// Waits until no write below `ts` is in flight, unlike [`Watermark::wait`] also when no
+    /// write took the timestamps right below `ts`.
+    pub(crate) async fn settle(&self, ts: TimeStamp) {
+        let rx = {
+            let mut inner = self.inner.lock().unwrap();
+
+            if inner.settled(ts) {
+                return;
+            }
+            let (tx, rx) = oneshot::channel();
+            inner.settle_waiters.push((ts, tx));
+            rx
+        };
+        let _ = rx.await;
+    }
+}
+
+/// A write at `ts` the watermark waits for, finished once dropped.
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    watermark: Arc<Watermark>,
+    ts: TimeStamp,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.watermark.finish(self.ts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::{executor::block_on, FutureExt};
+
+    use super::Watermark;
+
+    #[test]
+    fn wait() {
+        block_on(async {
+            let watermark = Arc::new(Watermark::default());
+
+            let first = watermark.begin(1);
+            drop(watermark.begin(2));
+            assert_eq!(watermark.applied(), 0);
+
+            let wait = watermark.wait(2);
+            futures::pin_mut!(wait);
+            assert!((&mut wait).now_or_never().is_none());
+
+            drop(first);
+            assert_eq!(wait.await, 2);
+            assert_eq!(watermark.wait(1).await, 2);
+        });
+    }
+
+    #[test]
+    fn settle() {
+        block_on(async {
+            let watermark = Arc::new(Watermark::default());
+
+            let first = watermark.begin(3);
+            let _own = watermark.begin(5);
+            let settle = watermark.settle(5);
+            futures::pin_mut!(settle);
+            assert!((&mut settle).now_or_never().is_none());
+
+            drop(first);
+            settle.await;
+            watermark.settle(3).await;
+        });
+    }
+}